@@ -0,0 +1,303 @@
+//! Report of unit changes made by `switch-to-configuration`.
+//!
+//! Activation output is streamed as it is printed, and then summarized, units which have failed
+//! after activation are reported even if switch-to-configuration didn't notice them.
+//!
+//! In detached mode, switch-to-configuration is started in a transient unit, and fleet only polls
+//! for its result, so that dropped ssh connection doesn't interrupt the activation.
+
+use std::{
+	collections::BTreeSet,
+	path::Path,
+	time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::Utc;
+use fleet_base::host::ConfigHost;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+/// Printed after the activation output, followed by switch-to-configuration exit status
+const STATUS_MARKER: &str = "fleet-activation-status: ";
+/// Printed by the status poll, if the detached activation unit has exited without writing status
+const UNIT_GONE: &str = "fleet-activation-gone";
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+/// Detached activation is abandoned after this long, if no activation timeout is configured
+const DETACHED_DEADLINE: Duration = Duration::from_secs(60 * 60);
+/// Host is considered lost, if it can't be polled for this long
+const RECONNECT_DEADLINE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Default, Debug, PartialEq)]
+pub struct ActivationReport {
+	pub stopped: Vec<String>,
+	pub started: Vec<String>,
+	pub restarted: Vec<String>,
+	pub reloaded: Vec<String>,
+	/// Changed units, which are marked with `restartIfChanged = false`
+	pub not_restarted: Vec<String>,
+	pub failed: Vec<String>,
+}
+
+impl ActivationReport {
+	pub fn parse(output: &str) -> Self {
+		let mut report = Self::default();
+		for line in output.lines() {
+			let Some((message, units)) = line.trim().split_once(": ") else {
+				continue;
+			};
+			let list = match message {
+				"stopping the following units" => &mut report.stopped,
+				"starting the following units" | "the following new units were started" => {
+					&mut report.started
+				}
+				"restarting the following units" => &mut report.restarted,
+				"reloading the following units" => &mut report.reloaded,
+				"NOT restarting the following changed units" => &mut report.not_restarted,
+				"warning: the following units failed" => &mut report.failed,
+				_ => continue,
+			};
+			for unit in units.split(", ") {
+				let unit = unit.trim().to_owned();
+				if !unit.is_empty() && !list.contains(&unit) {
+					list.push(unit);
+				}
+			}
+		}
+		report
+	}
+
+	pub fn print(&self) {
+		for (name, units) in [
+			("stopped", &self.stopped),
+			("started", &self.started),
+			("restarted", &self.restarted),
+			("reloaded", &self.reloaded),
+		] {
+			if !units.is_empty() {
+				info!("{name}: {}", units.join(", "));
+			}
+		}
+		if !self.not_restarted.is_empty() {
+			warn!(
+				"changed, but not restarted: {}",
+				self.not_restarted.join(", ")
+			);
+		}
+		if !self.failed.is_empty() {
+			error!("failed: {}", self.failed.join(", "));
+		}
+	}
+}
+
+/// Units in the failed state
+pub async fn failed_units(host: &ConfigHost) -> Result<BTreeSet<String>> {
+	let mut cmd = host.cmd("systemctl").await?;
+	cmd.arg("list-units")
+		.arg("--failed")
+		.arg("--plain")
+		.arg("--no-legend")
+		.arg("--full");
+	Ok(cmd
+		.query()
+		.run_string()
+		.await?
+		.lines()
+		.filter_map(|l| l.split_whitespace().next())
+		.map(ToOwned::to_owned)
+		.collect())
+}
+
+async fn failed_units_before(host: &ConfigHost) -> BTreeSet<String> {
+	failed_units(host).await.unwrap_or_else(|e| {
+		warn!("failed to list failed units: {e}");
+		BTreeSet::new()
+	})
+}
+
+/// Unit changes are printed to stderr, exit status is reported separately to keep the output on failure
+fn switch_script(system: &Path, action: &str) -> String {
+	format!(
+		"{}/bin/switch-to-configuration {action} 2>&1; echo \"{STATUS_MARKER}$?\"",
+		system.display()
+	)
+}
+
+/// Runs switch-to-configuration, and reports changed units.
+///
+/// Units failed during activation, which weren't failed before it, are reported as failures.
+pub async fn activate(host: &ConfigHost, system: &Path, action: &str) -> Result<ActivationReport> {
+	let failed_before = failed_units_before(host).await;
+	let mut cmd = host.cmd("sh").await?;
+	cmd.arg("-c").arg(switch_script(system, action));
+	let output = cmd.sudo().stream_output().run_string().await?;
+	finish(host, &output, &failed_before, true).await
+}
+
+enum DetachedStatus {
+	Running,
+	/// Activation output, with status marker
+	Finished(String),
+	/// Unit has exited without reporting status
+	Gone,
+}
+
+/// Errors are connection failures, activation failures are reported in status
+async fn poll_detached(host: &ConfigHost, unit: &str, log: &str) -> Result<DetachedStatus> {
+	let mut cmd = host.cmd("sh").await?;
+	// Log is checked again, as the unit might exit between the checks
+	cmd.arg("-c").arg(format!(
+		"if grep -q '{STATUS_MARKER}' {log} 2>/dev/null; then cat {log}; \
+		elif systemctl is-active --quiet {unit}; then :; \
+		elif grep -q '{STATUS_MARKER}' {log} 2>/dev/null; then cat {log}; \
+		else echo {UNIT_GONE}; fi"
+	));
+	let output = cmd.query().run_string().await?;
+	Ok(match output.trim() {
+		"" => DetachedStatus::Running,
+		UNIT_GONE => DetachedStatus::Gone,
+		_ => DetachedStatus::Finished(output),
+	})
+}
+
+/// Same as [`activate`], but switch-to-configuration is executed in a transient unit, and its
+/// completion is polled, reconnecting to the host if the connection was lost.
+///
+/// Polling is stopped after `timeout`, the activation unit is left running on the host.
+///
+/// Returns the host with the new connection, if the old one was replaced.
+pub async fn activate_detached(
+	host: &ConfigHost,
+	system: &Path,
+	action: &str,
+	timeout: Option<Duration>,
+) -> Result<(ActivationReport, Option<ConfigHost>)> {
+	let failed_before = failed_units_before(host).await;
+	let unit = format!("fleet-activation-{}", Utc::now().timestamp_millis());
+	let log = format!("/run/{unit}.log");
+	let mut cmd = host.cmd("systemd-run").await?;
+	cmd.arg("--unit")
+		.arg(&unit)
+		.arg("--collect")
+		.arg("--quiet")
+		.arg("sh")
+		.arg("-c")
+		.arg(format!("({}) > {log}", switch_script(system, action)));
+	cmd.sudo()
+		.run()
+		.await
+		.context("failed to start activation unit")?;
+	info!("activation is started in {unit}");
+
+	let deadline = Instant::now() + timeout.unwrap_or(DETACHED_DEADLINE);
+	let mut last_polled = Instant::now();
+	let mut reconnected: Option<ConfigHost> = None;
+	let output = loop {
+		sleep(POLL_INTERVAL).await;
+		ensure!(
+			Instant::now() < deadline,
+			"activation unit {unit} is still running, giving up waiting for it"
+		);
+		let current = reconnected.as_ref().unwrap_or(host);
+		match poll_detached(current, &unit, &log).await {
+			Ok(DetachedStatus::Running) => last_polled = Instant::now(),
+			Ok(DetachedStatus::Finished(output)) => break output,
+			Ok(DetachedStatus::Gone) => {
+				bail!("activation unit {unit} has exited without reporting status")
+			}
+			Err(e) => {
+				ensure!(
+					last_polled.elapsed() < RECONNECT_DEADLINE,
+					"host is unreachable for {}s, activation status is unknown: {e:#}",
+					RECONNECT_DEADLINE.as_secs()
+				);
+				warn!("failed to poll activation status, reconnecting: {e:#}");
+				// Old host session is dead, every attempt needs the new one
+				match host.config().host(&host.name).await {
+					Ok(host) => reconnected = Some(host),
+					Err(e) => warn!("failed to reconnect: {e:#}"),
+				}
+			}
+		}
+	};
+	let current = reconnected.as_ref().unwrap_or(host);
+	if let Err(e) = current.rm_file(&log, true).await {
+		warn!("failed to remove activation log: {e}");
+	}
+	let report = finish(current, &output, &failed_before, false).await?;
+	Ok((report, reconnected))
+}
+
+/// `streamed` output was already printed, see [`fleet_base::command::MyCommand::stream_output`]
+async fn finish(
+	host: &ConfigHost,
+	output: &str,
+	failed_before: &BTreeSet<String>,
+	streamed: bool,
+) -> Result<ActivationReport> {
+	let (output, status) = output
+		.trim_end()
+		.rsplit_once(STATUS_MARKER)
+		.context("switch-to-configuration was terminated")?;
+	let mut report = ActivationReport::parse(output);
+
+	match failed_units(host).await {
+		Ok(failed_after) => {
+			for unit in failed_after.difference(failed_before) {
+				if !report.failed.contains(unit) {
+					report.failed.push(unit.clone());
+				}
+			}
+		}
+		Err(e) => warn!("failed to list failed units: {e}"),
+	}
+	report.print();
+
+	// Status 4 means some units have failed, which is reported by the caller
+	let status = status.trim();
+	if status != "0" && !(status == "4" && !report.failed.is_empty()) {
+		// Full output is needed to diagnose the failure
+		if !streamed {
+			for line in output.lines().filter(|l| !l.trim().is_empty()) {
+				info!("{line}");
+			}
+		}
+		bail!("switch-to-configuration exited with status {status}");
+	}
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse() {
+		let output = "\
+stopping the following units: nginx.service
+NOT restarting the following changed units: systemd-fsck@dev-sda1.service
+activating the configuration...
+setting up /etc...
+reloading user units for root...
+restarting the following units: postgresql.service, sshd.service
+reloading the following units: dbus.service
+starting the following units: nginx.service
+the following new units were started: app.service
+warning: the following units failed: app.service
+
+× app.service - App
+     Active: failed (Result: exit-code)
+";
+		assert_eq!(
+			ActivationReport::parse(output),
+			ActivationReport {
+				stopped: vec!["nginx.service".to_owned()],
+				started: vec!["nginx.service".to_owned(), "app.service".to_owned()],
+				restarted: vec!["postgresql.service".to_owned(), "sshd.service".to_owned()],
+				reloaded: vec!["dbus.service".to_owned()],
+				not_restarted: vec!["systemd-fsck@dev-sda1.service".to_owned()],
+				failed: vec!["app.service".to_owned()],
+			}
+		);
+	}
+}
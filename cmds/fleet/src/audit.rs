@@ -0,0 +1,203 @@
+//! Append-only audit log of secret operations, stored in [`AUDIT_FILE`] next to fleet data
+//! (`fleet.<fleet>.audit.jsonl` for non-default fleets), so it is versioned together
+//! with the secrets it describes.
+//!
+//! Every entry contains hash of the previous line, thus removal or modification of past entries
+//! breaks the chain. Entries are also signed with the operator ssh key (`ssh-keygen -Y sign`),
+//! key is taken from `FLEET_AUDIT_KEY`, defaulting to `~/.ssh/id_ed25519`, see [`attribution`].
+
+use std::{
+	fs::{self, OpenOptions},
+	io::Write as _,
+	path::PathBuf,
+};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use fleet_base::{attribution, datafile, host::Config};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+pub const AUDIT_FILE: &str = "fleet.audit.jsonl";
+const SIGNATURE_NAMESPACE: &str = "fleet-audit";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOp {
+	/// Secret value was added or replaced by the operator
+	Add,
+	/// Secret was decrypted and shown to the operator
+	Read,
+	/// Secret was encrypted for a different set of hosts
+	Reencrypt,
+	/// Secret was generated by fleet
+	Generate,
+	/// Secret was imported from another secret manager
+	Import,
+	Remove,
+}
+impl AuditOp {
+	pub fn name(&self) -> &'static str {
+		match self {
+			AuditOp::Add => "add",
+			AuditOp::Read => "read",
+			AuditOp::Reencrypt => "reencrypt",
+			AuditOp::Generate => "generate",
+			AuditOp::Import => "import",
+			AuditOp::Remove => "remove",
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+	pub timestamp: DateTime<Utc>,
+	/// `user@machine` of the operator
+	pub actor: String,
+	/// Fingerprint of the operator signing key
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub key: Option<String>,
+	pub op: AuditOp,
+	pub secret: String,
+	/// Hosts secret is encrypted for, or decrypted on
+	pub hosts: Vec<String>,
+	/// Hash of the previous log line, empty for the first entry
+	pub prev: String,
+	/// Armored ssh signature of the entry serialized without this field
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub signature: Option<String>,
+}
+
+fn audit_path(config: &Config) -> PathBuf {
+	config.directory.join(config.fleet_file(AUDIT_FILE))
+}
+
+fn line_hash(line: &str) -> String {
+	STANDARD_NO_PAD.encode(Sha256::digest(line.as_bytes()))
+}
+
+pub fn read_audit(config: &Config) -> Result<Vec<(String, AuditEntry)>> {
+	let path = audit_path(config);
+	let data = match fs::read_to_string(&path) {
+		Ok(data) => data,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(e).context("failed to read audit log"),
+	};
+	data.lines()
+		.enumerate()
+		.map(|(i, line)| {
+			let entry = serde_json::from_str(line)
+				.with_context(|| format!("bad audit log entry at line {}", i + 1))?;
+			Ok((line.to_owned(), entry))
+		})
+		.collect()
+}
+
+/// Appends entry to the audit log, signature failure is not fatal, yet reported
+pub fn record(config: &Config, op: AuditOp, secret: &str, hosts: &[String]) -> Result<()> {
+	let _lock = datafile::lock(&config.directory)?;
+	let prev = read_audit(config)?
+		.last()
+		.map(|(line, _)| line_hash(line))
+		.unwrap_or_default();
+	let mut entry = AuditEntry {
+		timestamp: Utc::now(),
+		actor: attribution::actor(),
+		key: attribution::signing_fingerprint(),
+		op,
+		secret: secret.to_owned(),
+		hosts: hosts.to_vec(),
+		prev,
+		signature: None,
+	};
+	let unsigned = serde_json::to_string(&entry)?;
+	match attribution::sign(SIGNATURE_NAMESPACE, unsigned.as_bytes()) {
+		Ok(signature) => entry.signature = Some(signature),
+		Err(e) => warn!("audit log entry is not signed: {e:#}"),
+	}
+	let mut line = serde_json::to_string(&entry)?;
+	line.push('\n');
+	let mut file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(audit_path(config))
+		.context("failed to open audit log")?;
+	file.write_all(line.as_bytes())
+		.context("failed to write audit log")?;
+	Ok(())
+}
+
+/// Checks hash chain and entry signatures, returns list of problems
+pub fn verify(entries: &[(String, AuditEntry)]) -> Vec<String> {
+	let mut problems = Vec::new();
+	let mut prev = String::new();
+	for (i, (line, entry)) in entries.iter().enumerate() {
+		let n = i + 1;
+		if entry.prev != prev {
+			problems.push(format!("line {n}: hash chain is broken"));
+		}
+		prev = line_hash(line);
+		let Some(signature) = &entry.signature else {
+			problems.push(format!("line {n}: entry is not signed"));
+			continue;
+		};
+		let unsigned = AuditEntry {
+			signature: None,
+			..entry.clone()
+		};
+		let result: Result<()> = try {
+			let unsigned = serde_json::to_string(&unsigned)?;
+			let signer =
+				attribution::check_signature(SIGNATURE_NAMESPACE, unsigned.as_bytes(), signature)?;
+			if let (Some(signer), Some(key)) = (signer, &entry.key) {
+				if signer != *key {
+					Err(anyhow!("signed with {signer}, while the entry names {key}"))?;
+				}
+			}
+		};
+		if let Err(e) = result {
+			problems.push(format!("line {n}: bad signature: {e:#}"));
+		}
+	}
+	problems
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(prev: String) -> AuditEntry {
+		AuditEntry {
+			timestamp: Utc::now(),
+			actor: "admin@laptop".to_owned(),
+			key: None,
+			op: AuditOp::Read,
+			secret: "db-password".to_owned(),
+			hosts: vec!["db1".to_owned()],
+			prev,
+			signature: None,
+		}
+	}
+
+	#[test]
+	fn chain() {
+		let first = entry(String::new());
+		let first_line = serde_json::to_string(&first).unwrap();
+		let second = entry(line_hash(&first_line));
+		let second_line = serde_json::to_string(&second).unwrap();
+		let problems = verify(&[(first_line, first.clone()), (second_line.clone(), second.clone())]);
+		assert!(problems.iter().all(|p| !p.contains("chain")));
+
+		let tampered = serde_json::to_string(&AuditEntry {
+			secret: "other".to_owned(),
+			..first.clone()
+		})
+		.unwrap();
+		let problems = verify(&[(tampered, first), (second_line, second)]);
+		assert!(problems.iter().any(|p| p == "line 2: hash chain is broken"));
+	}
+}
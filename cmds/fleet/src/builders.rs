@@ -0,0 +1,257 @@
+//! Fleet hosts as nix remote builders, enabled with `--builders-from fleet`.
+//!
+//! Nix is pointed to the `.fleet/builders` machines file, which is written after the fleet config
+//! is evaluated, from `fleet.builder` options of the hosts (builder.nix). Nix reads the file
+//! only when a build is started, so the file may be written after the evaluation sessions are spawned.
+
+use std::{
+	ffi::OsString,
+	fs,
+	io::Write as _,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use clap::ValueEnum;
+use fleet_base::host::{Config, Platform, SshTarget};
+use nix_eval::nix_go_json;
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+use tracing::{info, warn};
+
+use crate::cmds::build_systems::resolve_host;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum BuildersFrom {
+	/// Hosts of the fleet, which have `fleet.builder.enable` set
+	Fleet,
+}
+
+/// Tied to builder.nix
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BuilderSettings {
+	enable: bool,
+	systems: Vec<String>,
+	max_jobs: u32,
+	speed_factor: u32,
+	supported_features: Vec<String>,
+	mandatory_features: Vec<String>,
+	ssh_key: Option<String>,
+}
+
+fn builders_path(directory: &Path) -> PathBuf {
+	directory.join(".fleet/builders")
+}
+
+/// Arguments for nix, which make it use builders from the machines file
+pub fn nix_args(directory: &Path) -> Vec<OsString> {
+	let mut spec = OsString::from("@");
+	spec.push(builders_path(directory));
+	vec![
+		"--builders".into(),
+		spec,
+		// Builders are usually closer to the caches than to the deployer
+		"--builders-use-substitutes".into(),
+	]
+}
+
+fn list_or_dash(items: &[String]) -> String {
+	if items.is_empty() {
+		"-".to_owned()
+	} else {
+		items.join(",")
+	}
+}
+
+/// Machines file has room for a single host key
+fn preferred_host_key(keys: &[String]) -> Option<&String> {
+	keys.iter()
+		.find(|k| k.starts_with("ssh-ed25519 "))
+		.or(keys.first())
+}
+
+/// Line of the nix machines file, see `man nix.conf`, `builders`
+///
+/// `ssh_ng` is only supported by builders with nix 2.4 or newer
+fn machine_line(
+	target: &SshTarget,
+	settings: &BuilderSettings,
+	host_key: Option<&str>,
+	ssh_ng: bool,
+) -> String {
+	[
+		format!(
+			"{}://{}",
+			if ssh_ng { "ssh-ng" } else { "ssh" },
+			target.destination()
+		),
+		list_or_dash(&settings.systems),
+		settings.ssh_key.clone().unwrap_or_else(|| "-".to_owned()),
+		settings.max_jobs.to_string(),
+		settings.speed_factor.to_string(),
+		list_or_dash(&settings.supported_features),
+		list_or_dash(&settings.mandatory_features),
+		host_key.map_or_else(|| "-".to_owned(), |k| STANDARD.encode(k)),
+	]
+	.join(" ")
+}
+
+/// Writes the machines file from hosts with `fleet.builder.enable`, the local host is never used,
+/// as it already builds everything it can itself.
+pub async fn write_machines(config: &Config) -> Result<()> {
+	let mut lines = vec![];
+	for host in config.list_hosts().await? {
+		if host.local || host.platform().await? != Platform::Nixos {
+			continue;
+		}
+		let nixos = host.nixos_config().await?;
+		let settings: BuilderSettings = nix_go_json!(nixos.fleet.builder);
+		if !settings.enable {
+			continue;
+		}
+		let target = host.ssh_target().await?;
+		// Machines file has no place for ssh options, those can only be set in the ssh config
+		if target.port.is_some() || !target.jump_hosts.is_empty() {
+			warn!(
+				"host {} is reached with a custom port or jump hosts, which can't be passed to the nix builder, skipping it",
+				host.name
+			);
+			continue;
+		}
+		let keys = config.trusted_host_keys(&host.name);
+		let host_key = preferred_host_key(&keys);
+		if host_key.is_none() {
+			warn!(
+				"host {} has no trusted host keys, builds on it depend on the ssh known hosts of the nix daemon",
+				host.name
+			);
+		}
+		let ssh_ng = match host.remote_nix().await {
+			Ok(remote) => remote.supports_ssh_ng(),
+			Err(e) => {
+				warn!(
+					"failed to probe nix of {}, assuming ssh-ng support: {e:#}",
+					host.name
+				);
+				true
+			}
+		};
+		lines.push(machine_line(
+			&target,
+			&settings,
+			host_key.map(String::as_str),
+			ssh_ng,
+		));
+		info!(
+			"using {} as a builder for {}",
+			host.name,
+			settings.systems.join(", ")
+		);
+	}
+	if lines.is_empty() {
+		warn!("no fleet hosts have fleet.builder.enable set, building locally");
+	}
+	write_file(&config.directory, &lines)
+}
+
+/// Writes the machines file with the only `--build-host` builder. Fleet hosts are used with
+/// their `fleet.builder` settings, even if it is not enabled, other destinations are only
+/// used for the local system.
+pub async fn write_build_host(config: &Config, build_host: &str) -> Result<()> {
+	let line = match resolve_host(config, build_host).await? {
+		Some(host) => {
+			ensure!(
+				!host.local,
+				"build host {} is the local machine, omit --build-host",
+				host.name
+			);
+			let nixos = host.nixos_config().await?;
+			let settings: BuilderSettings = nix_go_json!(nixos.fleet.builder);
+			let target = host.ssh_target().await?;
+			ensure!(
+				target.port.is_none() && target.jump_hosts.is_empty(),
+				"build host {} is reached with a custom port or jump hosts, which can't be passed to nix, configure them in the ssh config instead",
+				host.name
+			);
+			let remote = host.remote_nix().await?;
+			if !remote.has_flakes() {
+				warn!(
+					"flakes are not enabled on build host {}, only derivations evaluated by fleet can be built there",
+					host.name
+				);
+			}
+			let keys = config.trusted_host_keys(&host.name);
+			let host_key = preferred_host_key(&keys);
+			machine_line(
+				&target,
+				&settings,
+				host_key.map(String::as_str),
+				remote.supports_ssh_ng(),
+			)
+		}
+		None => {
+			info!(
+				"{build_host} is not a fleet host, using it to build for {}",
+				config.local_system
+			);
+			format!("ssh-ng://{build_host} {}", config.local_system)
+		}
+	};
+	write_file(&config.directory, &[line])
+}
+
+fn write_file(directory: &Path, lines: &[String]) -> Result<()> {
+	let content: String = lines.iter().map(|l| format!("{l}\n")).collect();
+	let path = builders_path(directory);
+	let dir = path.parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	let mut tmp = NamedTempFile::new_in(dir)?;
+	tmp.write_all(content.as_bytes())?;
+	tmp.persist(path)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn machine_lines() {
+		let target = SshTarget {
+			address: "builder.lan".to_owned(),
+			user: Some("root".to_owned()),
+			port: None,
+			jump_hosts: vec![],
+			transport: Default::default(),
+			compression_level: 3,
+			known_hosts: None,
+		};
+		let mut settings = BuilderSettings {
+			enable: true,
+			systems: vec!["x86_64-linux".to_owned(), "i686-linux".to_owned()],
+			max_jobs: 8,
+			speed_factor: 2,
+			supported_features: vec!["big-parallel".to_owned(), "kvm".to_owned()],
+			mandatory_features: vec![],
+			ssh_key: None,
+		};
+		assert_eq!(
+			machine_line(&target, &settings, None, true),
+			"ssh-ng://root@builder.lan x86_64-linux,i686-linux - 8 2 big-parallel,kvm - -"
+		);
+		assert_eq!(
+			machine_line(&target, &settings, None, false),
+			"ssh://root@builder.lan x86_64-linux,i686-linux - 8 2 big-parallel,kvm - -"
+		);
+		settings.ssh_key = Some("/root/.ssh/builder".to_owned());
+		assert_eq!(
+			machine_line(&target, &settings, Some("ssh-ed25519 AAAA"), true),
+			format!(
+				"ssh-ng://root@builder.lan x86_64-linux,i686-linux /root/.ssh/builder 8 2 big-parallel,kvm - {}",
+				STANDARD.encode("ssh-ed25519 AAAA")
+			)
+		);
+	}
+}
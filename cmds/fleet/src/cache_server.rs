@@ -0,0 +1,83 @@
+//! Temporary binary cache on the deploying machine, see `fleet deploy --serve-cache`.
+//!
+//! Hosts substitute the system closure from it in parallel, instead of the deployer pushing
+//! the closure to every host over ssh. Served paths are signed the same way as for the push,
+//! so hosts only accept them if they trust the signing key.
+
+use std::{
+	net::TcpStream,
+	path::Path,
+	process::{Child, Command, Stdio},
+	time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use fleet_base::host::ConfigHost;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `nix-serve` process, which is killed on drop
+pub struct CacheServer {
+	child: Child,
+	url: String,
+}
+
+impl CacheServer {
+	/// Address is the `host:port`, which is reachable from the deployed hosts,
+	/// server listens on all interfaces on the same port.
+	pub async fn start(address: &str, signing_key: Option<&Path>) -> Result<Self> {
+		let port: u16 = address
+			.rsplit_once(':')
+			.and_then(|(_, port)| port.parse().ok())
+			.context("cache address should be in host:port format")?;
+		let mut cmd = Command::new("nix-serve");
+		cmd.arg("--listen")
+			.arg(format!("0.0.0.0:{port}"))
+			.stdin(Stdio::null())
+			.stdout(Stdio::null());
+		if let Some(key) = signing_key {
+			cmd.env("NIX_SECRET_KEY_FILE", key);
+		}
+		let child = cmd
+			.spawn()
+			.context("failed to start nix-serve, is it installed?")?;
+		let mut server = Self {
+			child,
+			url: format!("http://{address}"),
+		};
+		let started = Instant::now();
+		while TcpStream::connect(("127.0.0.1", port)).is_err() {
+			if let Some(status) = server.child.try_wait()? {
+				bail!("nix-serve has exited: {status}");
+			}
+			if started.elapsed() > START_TIMEOUT {
+				bail!("nix-serve hasn't started in {}s", START_TIMEOUT.as_secs());
+			}
+			sleep(Duration::from_millis(200)).await;
+		}
+		info!("serving binary cache at {}", server.url);
+		Ok(server)
+	}
+	pub fn url(&self) -> &str {
+		&self.url
+	}
+}
+
+impl Drop for CacheServer {
+	fn drop(&mut self) {
+		if let Err(e) = self.child.kill() {
+			warn!("failed to stop nix-serve: {e}");
+		}
+		let _ = self.child.wait();
+	}
+}
+
+/// Makes the host fetch the closure from the deployer cache.
+/// Substitution runs as root, as only trusted users may add substituters.
+pub async fn substitute(host: &ConfigHost, cache_url: &str, path: &Path) -> Result<()> {
+	let mut cmd = host.nix_cmd().await?;
+	cmd.arg("copy").comparg("--from", cache_url).arg(path);
+	cmd.sudo().retry_transient().run_nix().await
+}
@@ -1,26 +1,259 @@
-use std::{env::current_dir, os::unix::fs::symlink, path::PathBuf, time::Duration};
+use std::{
+	cell::RefCell,
+	collections::{BTreeMap, BTreeSet},
+	env::current_dir,
+	ffi::OsString,
+	future::Future,
+	io::{stdin, IsTerminal as _},
+	os::unix::fs::symlink,
+	path::{Path, PathBuf},
+	rc::Rc,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Result};
-use clap::{Parser, ValueEnum};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chrono::Utc;
+use clap::{Parser, Subcommand, ValueEnum};
 use fleet_base::{
-	host::{Config, ConfigHost},
-	opts::FleetOpts,
+	command,
+	host::{Config, ConfigHost, EscalationStrategy, Platform},
+	opts::{FleetOpts, HostItem, HostPattern},
+};
+use futures::{
+	future::{LocalBoxFuture, Shared},
+	stream, FutureExt as _, StreamExt as _,
 };
-use itertools::Itertools as _;
-use nix_eval::nix_go;
-use tokio::{task::LocalSet, time::sleep};
+use nix_eval::{nix_go, nix_go_json, Value};
+use serde::Deserialize;
+use tokio::{
+	select, signal,
+	task::LocalSet,
+	time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, field, info, info_span, warn, Instrument};
 
+use super::{
+	power::{power_off, wait_reachable, wake_if_needed, PowerConfig},
+	probe::{load_probes, seed_host, CopyStrategy, ProbeResult},
+	push::load_pushed,
+};
+use crate::{
+	activation,
+	cache_server::{self, CacheServer},
+	confirm::{Answer, Confirmation},
+	from_cache::FromCache,
+	generations::{current_generation, set_profile},
+	hooks::{run_hooks, HookContext, HookPhase},
+	journal::{append_journal, GenerationRef, JournalEntry},
+	notify::NotifyOpts,
+	policy::{FailureTracker, HostPolicy},
+	run_state::{RunPhase, RunState},
+	schedule::{MaxUnavailable, Schedule},
+	secure_boot::SecureBootSettings,
+	summary::RunSummary,
+	telemetry::{Phase, Telemetry, TelemetryOpts},
+	timeouts::{parse_duration, PhaseTimeouts, TimeoutOpts},
+};
+
+pub(crate) const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+const REBOOT_POLL: Duration = Duration::from_secs(5);
+const SOFT_REBOOT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Full reboot includes firmware initialization, which might take a while on servers
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+/// Toplevel components, change of which is only applied after reboot
+const REBOOT_COMPONENTS: &[&str] = &["kernel", "initrd", "kernel-modules", "systemd"];
+/// Delay before retrying failed activation, see `hosts.<name>.deploy.policy.activationRetries`
+const ACTIVATION_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// Polling interval of the activation job queue, see [`health_check`]
+const HEALTH_CHECK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to wait for the host, powered on by `deploy --wake`
+const WAKE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Delay before the first `deploy --reconcile` retry, doubled every round
+const RECONCILE_INITIAL_DELAY: Duration = Duration::from_secs(30);
+const RECONCILE_MAX_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Rollback watchdog settings, declared in `fleet.rollback` NixOS options.
+/// Tied to rollback.nix
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RollbackSettings {
+	pub(crate) marker_path: String,
+	pub(crate) unit_name: String,
+	pub(crate) timeout: String,
+}
+impl RollbackSettings {
+	pub(crate) async fn for_host(host: &ConfigHost) -> Result<Self> {
+		let nixos_config = host.nixos_config().await?;
+		Ok(nix_go_json!(nixos_config.fleet.rollback))
+	}
+	pub(crate) fn service(&self) -> String {
+		format!("{}.service", self.unit_name)
+	}
+	pub(crate) fn timer(&self) -> String {
+		format!("{}.timer", self.unit_name)
+	}
+	/// Transient unit, created by fleet for the rollback on the current boot
+	pub(crate) fn run_unit(&self) -> String {
+		format!("{}-run", self.unit_name)
+	}
+	pub(crate) fn run_timer(&self) -> String {
+		format!("{}-run.timer", self.unit_name)
+	}
+}
+
 #[derive(Parser)]
 pub struct Deploy {
 	/// Disable automatic rollback
 	#[clap(long)]
 	disable_rollback: bool,
-	/// Action to execute after system is built
-	action: DeployAction,
+	/// Time after which system is rolled back, if deployment wasn't finished,
+	/// in systemd time span format. Overrides `fleet.rollback.timeout` NixOS option,
+	/// defaults to `cli.deploy.rollbackTimeout`
+	#[clap(long)]
+	rollback_timeout: Option<String>,
+	/// Action to execute after system is built, defaults to `cli.deploy.action`
+	action: Option<DeployAction>,
+	/// Upload the system, and only print which units would be stopped, started,
+	/// restarted or reloaded by the switch, without changing anything
+	#[clap(long, conflicts_with = "action")]
+	dry_activate: bool,
+	#[clap(flatten)]
+	telemetry: TelemetryOpts,
+	#[clap(flatten)]
+	notify: NotifyOpts,
+	#[clap(flatten)]
+	timeouts: TimeoutOpts,
+	/// Broadcast message to users logged in on the host before activation,
+	/// and notify them once the activation is finished.
+	///
+	/// Users logging in during the activation see the message in motd.
+	#[clap(long)]
+	broadcast: Option<String>,
+	/// Expected maintenance duration, included in the broadcast message
+	#[clap(long, requires = "broadcast")]
+	broadcast_eta: Option<String>,
+	/// Resume previous deployment run, skipping hosts which have already converged,
+	/// and deploying systems built by the resumed run, without evaluating them again.
+	///
+	/// Hosts, which system was garbage collected since then, are built again.
+	#[clap(long)]
+	resume: Option<String>,
+	/// Only deploy hosts, which have failed in the resumed (or the latest) run
+	#[clap(long)]
+	only_failed: bool,
+	/// Power on unreachable hosts which have `power` configured, and power them off after deployment
+	#[clap(long)]
+	wake: bool,
+	/// Evaluate all hosts first, and build them with a single nix invocation,
+	/// allowing nix to share work between hosts
+	#[clap(long)]
+	batch_build: bool,
+	/// Activate this specialisation of the built system, can be overridden per host
+	/// with `--only host?specialisation=name`
+	#[clap(long)]
+	specialisation: Option<String>,
+	/// How the switch action applies the new system
+	#[clap(long, value_enum, default_value = "activate")]
+	switch_method: SwitchMethod,
+	/// Run activation in a transient unit on the host, and poll for its completion,
+	/// reconnecting if needed, so that flaky connection doesn't interrupt the activation
+	#[clap(long)]
+	detached_activation: bool,
+	#[clap(flatten)]
+	pub(crate) build_log: BuildLogOpts,
+	#[clap(flatten)]
+	pub(crate) rebuild: RebuildOpts,
+	/// After boot/switch, reboot the host if kernel, initrd, kernel modules or systemd
+	/// differ from the booted system, and wait for it to come back.
+	/// Reboot happens inside the host deployment, so ordering and exclusive groups are respected.
+	#[clap(long)]
+	reboot_if_needed: bool,
+	/// Deploy hosts which already run the built system, they are skipped by default
+	#[clap(long)]
+	force: bool,
+	/// Do not consider activation failed (and do not roll it back) if some units
+	/// have failed after activation
+	#[clap(long)]
+	allow_failed_units: bool,
+	/// Before activating each host, show its closure diff and unit changes, and ask
+	/// for confirmation. Enabled by default for switch
+	#[clap(long)]
+	confirm: bool,
+	/// Do not ask for confirmation before switch
+	#[clap(long, conflicts_with = "confirm")]
+	yes: bool,
+	/// Serve built systems to hosts from a temporary binary cache on this machine,
+	/// instead of pushing them to every host. Value is the `address:port`, on which
+	/// this machine is reachable from the hosts. Requires nix-serve
+	#[clap(long)]
+	serve_cache: Option<String>,
+	/// Maximum number of concurrently deployed hosts sharing the same value of the
+	/// `deploy.labels` label, `1 per zone`. May be repeated for different labels
+	#[clap(long, number_of_values = 1)]
+	max_unavailable: Vec<MaxUnavailable>,
+	/// Don't build anything, deploy systems prebuilt elsewhere (i.e by CI) instead,
+	/// hosts realize them from the --substituter cache. Value is either a toplevel store path,
+	/// if a single host is deployed, or a json manifest `{"<host>": "<toplevel store path>"}`
+	#[clap(
+		long,
+		requires = "substituter",
+		conflicts_with_all = ["batch_build", "serve_cache"]
+	)]
+	from_cache: Option<String>,
+	/// Binary cache, containing --from-cache systems
+	#[clap(long, requires = "from_cache")]
+	substituter: Option<String>,
+	/// Remove rollback markers and armed rollback timers, left by interrupted deployments,
+	/// instead of refusing to deploy such hosts
+	#[clap(long)]
+	clear_stale_markers: bool,
+	/// Keep retrying hosts, which have failed to deploy (i.e were offline), with backoff
+	/// for up to this duration, `2h`, until every host has converged.
+	/// Rolled back hosts are not retried, as they were reachable, but rejected the system
+	#[clap(long, value_parser = parse_duration)]
+	reconcile: Option<Duration>,
 }
 
-#[derive(ValueEnum, Clone, Copy)]
+/// Delay before the `round` (starting from 2) of `deploy --reconcile`
+fn reconcile_delay(round: u32) -> Duration {
+	RECONCILE_INITIAL_DELAY
+		.saturating_mul(1 << round.saturating_sub(2).min(16))
+		.min(RECONCILE_MAX_DELAY)
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum SwitchMethod {
+	/// Run switch-to-configuration switch
+	Activate,
+	/// Set new system as the boot default, and restart the userspace with `systemctl soft-reboot`,
+	/// falls back to activation on hosts with systemd older than 254.
+	/// Rollback watchdog is started again after the soft-reboot, same as after the regular boot.
+	SoftReboot,
+}
+
+/// Result of the deployment on a single host
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DeployOutcome {
+	Success,
+	Failed,
+	RolledBack,
+	/// Deployment was interrupted before activation
+	Cancelled,
+}
+impl DeployOutcome {
+	pub(crate) fn name(&self) -> &'static str {
+		match self {
+			DeployOutcome::Success => "success",
+			DeployOutcome::Failed => "failed",
+			DeployOutcome::RolledBack => "rolled_back",
+			DeployOutcome::Cancelled => "cancelled",
+		}
+	}
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
 enum DeployAction {
 	/// Upload derivation, but do not execute the update.
 	Upload,
@@ -63,111 +296,635 @@ pub struct BuildSystems {
 	/// are "sdImage"/"isoImage", and your configuration may include any other build attributes.
 	#[clap(long, default_value = "toplevel")]
 	build_attr: String,
+	/// Evaluate all hosts first, and build them with a single nix invocation,
+	/// allowing nix to share work between hosts
+	#[clap(long)]
+	batch: bool,
+	#[clap(flatten)]
+	pub(crate) build_log: BuildLogOpts,
+	#[clap(flatten)]
+	pub(crate) rebuild: RebuildOpts,
+	#[clap(subcommand)]
+	action: Option<BuildSystemsAction>,
 }
 
-struct Generation {
-	id: u32,
-	current: bool,
-	datetime: String,
-}
-async fn get_current_generation(host: &ConfigHost) -> Result<Generation> {
-	let mut cmd = host.cmd("nix-env").await?;
-	cmd.comparg("--profile", "/nix/var/nix/profiles/system")
-		.arg("--list-generations");
-	// Sudo is required due to --list-generations acquiring lock on the profile.
-	let data = cmd.sudo().run_string().await?;
-	let generations = data
-		.split('\n')
-		.map(|e| e.trim())
-		.filter(|&l| !l.is_empty())
-		.filter_map(|g| {
-			let gen: Option<Generation> = try {
-				let mut parts = g.split_whitespace();
-				let id = parts.next()?;
-				let id: u32 = id.parse().ok()?;
-				let date = parts.next()?;
-				let time = parts.next()?;
-				let current = if let Some(current) = parts.next() {
-					if current == "(current)" {
-						Some(true)
-					} else {
-						None
-					}
+#[derive(Subcommand, Clone)]
+enum BuildSystemsAction {
+	/// Build `buildSystems.<attr>.<host>` packages declared in fleet configuration,
+	/// results are linked to the working directory as `<attr>-<host>`.
+	Package {
+		/// Attribute of `buildSystems`, i.e "vmImage"
+		#[clap(long)]
+		attr: String,
+	},
+}
+
+/// Build output options, shared by the commands which build systems
+#[derive(Parser, Clone)]
+pub struct BuildLogOpts {
+	/// Show full nix stack trace on evaluation errors
+	#[clap(long)]
+	pub(crate) show_trace: bool,
+	/// Print build logs of the specified hosts (all hosts, if none specified) as they are produced,
+	/// by default only the last log line is shown in the progress bar. Build progress is also
+	/// periodically printed for them, with the tree of currently running builds and their phases.
+	/// Accepts the same selectors as --skip, i.e `--verbose-build=web-*,@prod`.
+	///
+	/// Systems built with --batch are not attributed to hosts, and their logs are not streamed.
+	#[clap(long, num_args = 0.., value_delimiter = ',', require_equals = true)]
+	verbose_build: Option<Vec<HostPattern>>,
+}
+impl BuildLogOpts {
+	pub(crate) async fn is_verbose(&self, host: &ConfigHost) -> Result<bool> {
+		let Some(patterns) = &self.verbose_build else {
+			return Ok(false);
+		};
+		if patterns.is_empty() {
+			return Ok(true);
+		}
+		for pattern in patterns {
+			if pattern.matches(host).await? {
+				return Ok(true);
+			}
+		}
+		Ok(false)
+	}
+}
+
+/// Flags of `nixos-rebuild`, mapped onto fleet host selection and builders
+#[derive(Parser, Clone)]
+pub struct RebuildOpts {
+	/// Same as `--only`, but the host is either a fleet host name, or its ssh `[user@]address`,
+	/// as it would be passed to `nixos-rebuild --target-host`
+	#[clap(long)]
+	target_host: Option<String>,
+	/// Build on this `[user@]address` instead of the local machine, using it as the only nix
+	/// remote builder. Fleet hosts are used for the systems and features of their `fleet.builder`
+	/// settings, other machines only for the local system
+	#[clap(long)]
+	pub(crate) build_host: Option<String>,
+	/// Fleet always escalates on the hosts, preferring sudo. With this flag, target host
+	/// is required to have sudo, the same as `nixos-rebuild --use-remote-sudo` would
+	#[clap(long, requires = "target_host")]
+	use_remote_sudo: bool,
+}
+impl RebuildOpts {
+	/// Host selection with `--target-host` applied
+	async fn select(&self, config: &Config, opts: &FleetOpts) -> Result<FleetOpts> {
+		let mut opts = opts.clone();
+		let Some(target_host) = &self.target_host else {
+			return Ok(opts);
+		};
+		ensure!(
+			opts.only.is_empty(),
+			"--target-host can't be combined with --only"
+		);
+		let Some(host) = resolve_host(config, target_host).await? else {
+			bail!("--target-host {target_host} is neither a fleet host name, nor its ssh address");
+		};
+		if self.use_remote_sudo {
+			ensure!(
+				matches!(host.escalation_strategy().await?, EscalationStrategy::Sudo),
+				"--use-remote-sudo is set, yet host {} has no sudo",
+				host.name
+			);
+		}
+		info!("target host {target_host} is {}", host.name);
+		opts.only = vec![HostItem {
+			pattern: HostPattern::Name(host.name.clone()),
+			attrs: BTreeMap::new(),
+		}];
+		Ok(opts)
+	}
+}
+
+/// Fleet host by name or ssh `[user@]address`
+pub(crate) async fn resolve_host(config: &Config, spec: &str) -> Result<Option<ConfigHost>> {
+	let (user, address) = match spec.split_once('@') {
+		Some((user, address)) => (Some(user), address),
+		None => (None, spec),
+	};
+	for host in config.list_hosts().await? {
+		if user.is_none() && host.name == address {
+			return Ok(Some(host));
+		}
+		let target = host.ssh_target_unverified().await?;
+		if target.address == address && (user.is_none() || target.user.as_deref() == user) {
+			return Ok(Some(host));
+		}
+	}
+	Ok(None)
+}
+
+/// Retries remote mutation, which might have been applied even if the command has failed
+/// (i.e ssh connection was dropped after the command was sent).
+///
+/// `applied` is checked before every attempt, and should return true if mutation is already in effect,
+/// or fail if the state is unexpected, and retrying might break something.
+async fn retry_mutation<C, CF, O, OF>(what: &str, applied: C, op: O) -> Result<()>
+where
+	C: Fn() -> CF,
+	CF: Future<Output = Result<bool>>,
+	O: Fn() -> OF,
+	OF: Future<Output = Result<()>>,
+{
+	let mut tries = 0;
+	loop {
+		if applied().await? {
+			if tries != 0 {
+				info!("{what} was applied by the previous attempt");
+			}
+			return Ok(());
+		}
+		match op().await {
+			Ok(()) => return Ok(()),
+			Err(e) if tries < 2 => {
+				tries += 1;
+				warn!("{what} failure ({tries}/3): {e}");
+				sleep(Duration::from_millis(2000)).await;
+			}
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+async fn read_rollback_marker(host: &ConfigHost, marker_path: &str) -> Result<Option<u32>> {
+	let mut cmd = host.cmd("sh").await?;
+	cmd.arg("-c")
+		.arg(format!("if [ -f {marker_path} ]; then cat {marker_path}; fi"));
+	let marker = cmd.sudo().query().run_string().await?;
+	let marker = marker.trim();
+	if marker.is_empty() {
+		return Ok(None);
+	}
+	Ok(Some(marker.parse().context("bad rollback marker")?))
+}
+
+/// Leftovers of an interrupted deployment make rollback marker creation, or rollback
+/// run scheduling (due to the unit name conflict) fail in the middle of the deployment,
+/// so they are detected before anything is built.
+async fn check_stale_rollback(
+	host: &ConfigHost,
+	rollback: &RollbackSettings,
+	clear: bool,
+) -> Result<()> {
+	let marker_path = rollback.marker_path.as_str();
+	let marker = read_rollback_marker(host, marker_path).await?;
+	let run_timer = rollback.run_timer();
+	let armed = is_unit_active(host, &run_timer).await;
+	if marker.is_none() && !armed {
+		return Ok(());
+	}
+	ensure!(
+		!is_unit_active(host, &rollback.service()).await,
+		"rollback watchdog is rolling the host back right now, wait for it to finish"
+	);
+	let mut found = Vec::new();
+	if let Some(generation) = marker {
+		let mut cmd = host.cmd("stat").await?;
+		cmd.comparg("--format", "%y").arg(marker_path);
+		let created = match cmd.sudo().query().run_string().await {
+			Ok(created) => created.trim().to_owned(),
+			Err(_) => "at unknown time".to_owned(),
+		};
+		let current = match current_generation(host, SYSTEM_PROFILE).await {
+			Ok(current) => current.id.to_string(),
+			Err(_) => "unknown".to_owned(),
+		};
+		found.push(format!(
+			"rollback marker {marker_path} for generation {generation} (current generation is {current}), created {created}"
+		));
+	}
+	if armed {
+		found.push(format!("armed rollback timer {run_timer}"));
+	}
+	let found = found.join(", ");
+	if !clear {
+		bail!("found leftovers of a previous deployment: {found}; if no other deployment is in progress, rerun with --clear-stale-markers");
+	}
+	warn!("clearing leftovers of a previous deployment: {found}");
+	if armed {
+		let mut cmd = host.cmd("systemctl").await?;
+		cmd.arg("stop").arg(&run_timer);
+		cmd.sudo()
+			.run()
+			.await
+			.context("failed to stop rollback timer")?;
+	}
+	let mut cmd = host.cmd("rm").await?;
+	cmd.arg("-f").arg(marker_path);
+	cmd.sudo()
+		.run()
+		.await
+		.context("failed to remove rollback marker")
+}
+
+/// Store path current profile generation points to
+pub(crate) async fn profile_target(host: &ConfigHost, profile: &str) -> Result<String> {
+	let mut cmd = host.cmd("readlink").await?;
+	cmd.arg("-f").arg(profile);
+	Ok(cmd.query().run_string().await?.trim().to_owned())
+}
+
+/// Runs `switch-to-configuration dry-activate`, returns its output
+async fn dry_activate(
+	host: &ConfigHost,
+	built: &Path,
+	specialisation: Option<&str>,
+) -> Result<String> {
+	ensure!(
+		host.platform().await? == Platform::Nixos,
+		"dry activation is only supported for nixos hosts"
+	);
+	let mut system = built.to_owned();
+	if let Some(specialisation) = specialisation {
+		system.push("specialisation");
+		system.push(specialisation);
+	}
+	let mut cmd = host.cmd("sh").await?;
+	// Changes are reported to stderr
+	cmd.arg("-c").arg(format!(
+		"{}/bin/switch-to-configuration dry-activate 2>&1",
+		system.display()
+	));
+	// dry-activate only prints what would change
+	cmd.sudo().query().run_string().await
+}
+
+/// Closure diff and unit changes of the pending activation, shown by `--confirm`.
+/// Preview is best-effort, failures are included in the preview instead of failing the deployment.
+async fn activation_preview(
+	host: &ConfigHost,
+	action: DeployAction,
+	current: Option<&Path>,
+	built: &Path,
+	specialisation: Option<&str>,
+) -> String {
+	let action_name = action.name().unwrap_or("upload");
+	let mut preview = format!("Pending {action_name} of {}", host.name);
+	if let Some(current) = current {
+		let diff: Result<String> = try {
+			let mut cmd = host.nix_cmd().await?;
+			cmd.arg("store")
+				.arg("diff-closures")
+				.arg(current)
+				.arg(built);
+			cmd.query().retry_transient().run_nix_string().await?
+		};
+		match diff {
+			Ok(diff) if diff.trim().is_empty() => preview.push_str("\nno closure changes"),
+			Ok(diff) => preview.push_str(&format!("\nclosure changes:\n{}", diff.trim_end())),
+			Err(e) => preview.push_str(&format!("\nfailed to diff closures: {e:#}")),
+		}
+	}
+	if action.should_activate() && host.platform().await.ok() == Some(Platform::Nixos) {
+		match dry_activate(host, built, specialisation).await {
+			Ok(output) => {
+				let changes = dry_activation_changes(&output);
+				if changes.is_empty() {
+					preview.push_str("\nno unit changes");
 				} else {
-					Some(false)
-				};
-				let current = current?;
-				if parts.next().is_some() {
-					warn!("unexpected text after generation: {g}");
-				}
-				Generation {
-					id,
-					current,
-					datetime: format!("{date} {time}"),
+					preview.push_str(&format!("\nunit changes:\n  {}", changes.join("\n  ")));
 				}
-			};
-			if gen.is_none() {
-				warn!("bad generation: {g}")
 			}
-			gen
-		})
-		.collect::<Vec<_>>();
-	let current = generations
-		.into_iter()
-		.filter(|g| g.current)
-		.at_most_one()
-		.map_err(|_e| anyhow!("bad list-generations output"))?
-		.ok_or_else(|| anyhow!("failed to find generation"))?;
-	Ok(current)
+			Err(e) => preview.push_str(&format!("\nfailed to preview unit changes: {e:#}")),
+		}
+	}
+	preview
 }
 
-async fn deploy_task(
+/// Lines of the dry activation output, describing the unit changes
+fn dry_activation_changes(output: &str) -> Vec<&str> {
+	output
+		.lines()
+		.map(str::trim)
+		.filter(|l| l.starts_with("would "))
+		.collect()
+}
+
+/// Whether the host already runs the built system: it is active for switch/test,
+/// and is also the boot default for switch and boot
+async fn is_converged(
+	host: &ConfigHost,
+	action: DeployAction,
+	built: &Path,
+	specialisation: Option<&str>,
+) -> Result<bool> {
+	let built_str = built.to_string_lossy();
+	if matches!(action, DeployAction::Upload) {
+		return Ok(false);
+	}
+	if matches!(action, DeployAction::Boot | DeployAction::Switch)
+		&& profile_target(host, SYSTEM_PROFILE).await? != built_str
+	{
+		return Ok(false);
+	}
+	if matches!(action, DeployAction::Boot) {
+		return Ok(true);
+	}
+	// Specialisation is a link to the separate system, it is resolved on the host
+	let expected = match specialisation {
+		Some(specialisation) => {
+			let mut specialised = built.join("specialisation");
+			specialised.push(specialisation);
+			profile_target(host, &specialised.to_string_lossy()).await?
+		}
+		None => built_str.into_owned(),
+	};
+	Ok(profile_target(host, "/run/current-system").await? == expected)
+}
+
+/// Pushed path might be garbage collected on the host since the push
+pub(crate) async fn is_valid_path(host: &ConfigHost, path: &Path) -> bool {
+	let Ok(mut cmd) = host.cmd("nix-store").await else {
+		return false;
+	};
+	cmd.arg("--check-validity").arg(path);
+	cmd.query().run().await.is_ok()
+}
+
+async fn is_unit_active(host: &ConfigHost, unit: &str) -> bool {
+	let Ok(mut cmd) = host.cmd("systemctl").await else {
+		return false;
+	};
+	cmd.arg("is-active").arg("--quiet").arg(unit);
+	cmd.query().run().await.is_ok()
+}
+
+async fn supports_soft_reboot(host: &ConfigHost) -> bool {
+	let version: Result<u32> = try {
+		let mut cmd = host.cmd("systemctl").await?;
+		cmd.arg("--version");
+		let output = cmd.query().run_string().await?;
+		output
+			.split_whitespace()
+			.nth(1)
+			.and_then(|v| v.parse().ok())
+			.ok_or_else(|| anyhow!("unexpected systemctl --version output"))?
+	};
+	match version {
+		Ok(version) if version >= 254 => true,
+		Ok(version) => {
+			warn!("systemd {version} doesn't support soft-reboot, falling back to activation");
+			false
+		}
+		Err(e) => {
+			warn!("failed to query systemd version, falling back to activation: {e}");
+			false
+		}
+	}
+}
+
+/// Makes system the boot default, soft-reboots into it, and waits until the host is back.
+/// Returns host with the new connection.
+async fn soft_reboot_into(host: &ConfigHost, system: &Path) -> Result<ConfigHost> {
+	let expected = profile_target(host, &system.to_string_lossy()).await?;
+	let mut cmd = host.cmd(system.join("bin/switch-to-configuration")).await?;
+	cmd.arg("boot");
+	cmd.sudo().run().await.context("failed to set boot default")?;
+
+	info!("soft-rebooting");
+	let mut cmd = host.cmd("systemctl").await?;
+	cmd.arg("soft-reboot");
+	if let Err(e) = cmd.sudo().run().await {
+		// Connection is usually closed before systemctl exits
+		info!("soft-reboot command terminated: {e}");
+	}
+	wait_for_system(host, &expected, "soft-reboot", SOFT_REBOOT_TIMEOUT).await
+}
+
+/// Components of the new system, which differ from the booted system, and thus require reboot
+async fn reboot_needed(host: &ConfigHost, system: &Path) -> Result<Vec<&'static str>> {
+	let mut changed = Vec::new();
+	for component in REBOOT_COMPONENTS {
+		let booted = profile_target(host, &format!("/run/booted-system/{component}")).await?;
+		let new = profile_target(host, &system.join(component).to_string_lossy()).await?;
+		if booted != new {
+			changed.push(*component);
+		}
+	}
+	Ok(changed)
+}
+
+/// Reboots host, which already has the system set as the boot default, and waits until it is back.
+/// Returns host with the new connection.
+async fn reboot_into(host: &ConfigHost, system: &Path) -> Result<ConfigHost> {
+	let expected = profile_target(host, &system.to_string_lossy()).await?;
+	info!("rebooting");
+	let mut cmd = host.cmd("systemctl").await?;
+	cmd.arg("reboot");
+	if let Err(e) = cmd.sudo().run().await {
+		// Connection is usually closed before systemctl exits
+		info!("reboot command terminated: {e}");
+	}
+	wait_for_system(host, &expected, "reboot", REBOOT_TIMEOUT).await
+}
+
+/// Waits until the host comes back with the system active,
+/// `expected` is the system path resolved on the host, as specialisations are symlinks
+async fn wait_for_system(
+	host: &ConfigHost,
+	expected: &str,
+	what: &str,
+	wait_timeout: Duration,
+) -> Result<ConfigHost> {
+	let started = Instant::now();
+	loop {
+		sleep(REBOOT_POLL).await;
+		ensure!(
+			started.elapsed() < wait_timeout,
+			"host didn't come back after {what} in {}s",
+			wait_timeout.as_secs()
+		);
+		// Old host session is dead, every attempt needs the new one
+		let reconnected = host.config().host(&host.name).await?;
+		let current = match profile_target(&reconnected, "/run/current-system").await {
+			Ok(current) => current,
+			Err(_) => continue,
+		};
+		if current == expected {
+			info!("host is back");
+			return Ok(reconnected);
+		}
+	}
+}
+
+/// nix-darwin has no switch-to-configuration, and no rollback watchdog, activation
+/// is performed the same way darwin-rebuild does it.
+async fn deploy_task_darwin(
+	action: DeployAction,
+	host: &ConfigHost,
+	built: PathBuf,
+) -> Result<DeployOutcome> {
+	if action.should_switch_profile() {
+		info!("switching generation");
+		if let Err(e) = set_profile(host, SYSTEM_PROFILE, &built.to_string_lossy()).await {
+			error!("failed to switch generation: {e}");
+			return Ok(DeployOutcome::Failed);
+		}
+	}
+	if action.should_activate() {
+		let _span = info_span!("activating").entered();
+		// Removed in newer nix-darwin versions
+		let activate_user = built.join("activate-user");
+		let mut test = host.cmd("test").await?;
+		test.arg("-e").arg(&activate_user);
+		if test.query().run().await.is_ok() {
+			let cmd = host.cmd(&activate_user).in_current_span().await?;
+			if let Err(e) = cmd.run().in_current_span().await {
+				error!("failed to activate user environment: {e}");
+				return Ok(DeployOutcome::Failed);
+			}
+		}
+		let cmd = host.cmd(built.join("activate")).in_current_span().await?;
+		if let Err(e) = cmd.sudo().run().in_current_span().await {
+			error!("failed to activate: {e}");
+			return Ok(DeployOutcome::Failed);
+		}
+	}
+	Ok(DeployOutcome::Success)
+}
+
+/// Home-manager activation script manages its profile by itself, and is executed
+/// as the target user.
+async fn deploy_task_home_manager(
 	action: DeployAction,
 	host: &ConfigHost,
 	built: PathBuf,
+) -> Result<DeployOutcome> {
+	match action {
+		DeployAction::Upload => return Ok(DeployOutcome::Success),
+		DeployAction::Switch => {}
+		DeployAction::Test | DeployAction::Boot => {
+			bail!("home-manager hosts only support upload and switch actions")
+		}
+	}
+	let _span = info_span!("activating").entered();
+	let cmd = host.cmd(built.join("activate")).in_current_span().await?;
+	if let Err(e) = cmd.run().in_current_span().await {
+		error!("failed to activate: {e}");
+		return Ok(DeployOutcome::Failed);
+	}
+	Ok(DeployOutcome::Success)
+}
+
+/// Per-run settings (action, rollback, switch method) are taken from `run`,
+/// `specialisation` and `timeouts` are resolved for the host
+async fn deploy_task(
+	run: &DeployRun,
+	host: &ConfigHost,
+	built: PathBuf,
 	specialisation: Option<String>,
-	disable_rollback: bool,
-) -> Result<()> {
+	timeouts: PhaseTimeouts,
+) -> Result<DeployOutcome> {
+	let action = run.action;
+	let deployment_id = run.run_id.as_str();
+	match host.platform().await? {
+		Platform::Nixos => {}
+		Platform::Darwin => {
+			return with_timeout(
+				"activation",
+				timeouts.activation,
+				deploy_task_darwin(action, host, built),
+			)
+			.await
+		}
+		Platform::HomeManager => {
+			return with_timeout(
+				"activation",
+				timeouts.activation,
+				deploy_task_home_manager(action, host, built),
+			)
+			.await
+		}
+	}
+	if (action.should_switch_profile() || action.should_activate())
+		&& host.has_ephemeral_secrets().await?
+	{
+		// Material should be in place before the activation, or the next boot
+		info!("provisioning secrets");
+		if let Err(e) = host.provision_secrets(Some(&built)).await {
+			error!("{e:#}");
+			return Ok(DeployOutcome::Failed);
+		}
+	}
 	let mut failed = false;
+	let mut rolled_back = false;
+	// Generations before and after the profile switch, recorded to the host journal
+	let mut switch = None;
 	// TODO: Lockfile, to prevent concurrent system switch?
-	// TODO: If rollback target exists - bail, it should be removed. Lockfile will not work in case if rollback
+	// Existing rollback target aborts the deployment. Lockfile will not work in case if rollback
 	// is scheduler on next boot (default behavior). On current boot - rollback activator will fail due to
 	// unit name conflict in systemd-run
+	// Leftovers of interrupted deployments are detected earlier, see check_stale_rollback.
 	// This code is tied to rollback.nix
-	if !disable_rollback && action.should_create_rollback_marker() {
+	let rollback = if action.should_create_rollback_marker() {
+		Some(RollbackSettings::for_host(host).await?)
+	} else {
+		None
+	};
+	let rollback = rollback.as_ref();
+	if let Some(rollback) = rollback.filter(|_| !run.disable_rollback) {
+		let marker_path = rollback.marker_path.as_str();
 		let _span = info_span!("preparing").entered();
 		info!("preparing for rollback");
-		let generation = get_current_generation(host).await?;
+		let generation = current_generation(host, SYSTEM_PROFILE).await?;
 		info!(
 			"rollback target would be {} {}",
 			generation.id, generation.datetime
 		);
+		if let Err(e) = retry_mutation(
+			"rollback marker creation",
+			|| async move {
+				match read_rollback_marker(host, marker_path).await? {
+					None => Ok(false),
+					Some(marker) if marker == generation.id => Ok(true),
+					Some(marker) => bail!("rollback marker for generation {marker} already exists, is another deployment in progress?"),
+				}
+			},
+			|| async move {
+				let mut cmd = host.cmd("sh").await?;
+				let marker_dir = Path::new(marker_path).parent().and_then(|p| p.to_str()).unwrap_or("/");
+				cmd.arg("-c").arg(format!("mark=$(mktemp -p {marker_dir} -t fleet_rollback_marker.XXXXX) && echo -n {} > $mark && mv --no-clobber $mark {marker_path}", generation.id));
+				cmd.sudo().run().await
+			},
+		)
+		.await
 		{
-			let mut cmd = host.cmd("sh").await?;
-			cmd.arg("-c").arg(format!("mark=$(mktemp -p /etc -t fleet_rollback_marker.XXXXX) && echo -n {} > $mark && mv --no-clobber $mark /etc/fleet_rollback_marker", generation.id));
-			if let Err(e) = cmd.sudo().run().await {
-				error!("failed to set rollback marker: {e}");
-				failed = true;
-			}
+			error!("failed to set rollback marker: {e}");
+			failed = true;
 		}
-		// Activation script also starts rollback-watchdog.timer, however, it is possible that it won't be started.
+		// Activation script also starts the watchdog timer, however, it is possible that it won't be started.
 		// Kicking it on manually will work best.
 		//
 		// There wouldn't be conflict, because here we trigger start of the primary service, and systemd will
 		// only allow one instance of it.
 
 		// TODO: We should also watch how this process is going.
-		// After running this command, we have less than 3 minutes to deploy everything,
+		// After running this command, we have less than rollback timeout to deploy everything,
 		// if we fail to perform generation switch in time, then we will still call the activation script, and this may break something.
 		// Anyway, reboot will still help in this case.
-		if action.should_schedule_rollback_run() {
-			let mut cmd = host.cmd("systemd-run").await?;
-			cmd.comparg("--on-active", "3min")
-				.comparg("--unit", "rollback-watchdog-run")
-				.arg("systemctl")
-				.arg("start")
-				.arg("rollback-watchdog.service");
-			if let Err(e) = cmd.sudo().run().await {
+		if action.should_schedule_rollback_run() && !failed {
+			let timeout = run.rollback_timeout.as_deref().unwrap_or(&rollback.timeout);
+			let run_timer = rollback.run_timer();
+			let run_timer = run_timer.as_str();
+			if let Err(e) = retry_mutation(
+				"rollback run scheduling",
+				// Unit name is fixed, thus it can't be armed twice, but systemd-run will fail
+				// on retry if the previous attempt has succeeded.
+				|| async move { Ok(is_unit_active(host, run_timer).await) },
+				|| async move {
+					let mut cmd = host.cmd("systemd-run").await?;
+					cmd.comparg("--on-active", timeout)
+						.comparg("--unit", rollback.run_unit())
+						.arg("systemctl")
+						.arg("start")
+						.arg(rollback.service());
+					cmd.sudo().run().await
+				},
+			)
+			.await
+			{
 				error!("failed to schedule rollback run: {e}");
 				failed = true;
 			}
@@ -176,52 +933,145 @@ async fn deploy_task(
 
 	if action.should_switch_profile() && !failed {
 		info!("switching generation");
-		let mut cmd = host.cmd("nix-env").await?;
-		cmd.comparg("--profile", "/nix/var/nix/profiles/system")
-			.comparg("--set", &built);
-		if let Err(e) = cmd.sudo().run().await {
+		let built_str = built.to_string_lossy();
+		let built_str = built_str.as_ref();
+		let result: Result<()> = try {
+			let before = profile_target(host, SYSTEM_PROFILE).await?;
+			let before_generation = current_generation(host, SYSTEM_PROFILE).await?;
+			let before = before.as_str();
+			retry_mutation(
+				"profile switch",
+				|| async move {
+					let current = profile_target(host, SYSTEM_PROFILE).await?;
+					if current == built_str {
+						return Ok(true);
+					}
+					// Do not clobber generation, which was set by someone else in the meantime.
+					ensure!(
+						current == before,
+						"system profile was concurrently switched to {current}"
+					);
+					Ok(false)
+				},
+				|| async move { set_profile(host, SYSTEM_PROFILE, built_str).await },
+			)
+			.await?;
+			let after_generation = current_generation(host, SYSTEM_PROFILE).await?;
+			switch = Some((
+				GenerationRef {
+					id: before_generation.id,
+					path: before.to_owned(),
+				},
+				GenerationRef {
+					id: after_generation.id,
+					path: built_str.to_owned(),
+				},
+			));
+		};
+		if let Err(e) = result {
 			error!("failed to switch generation: {e}");
 			failed = true;
 		}
 	}
 
-	// FIXME: Connection might be disconnected after activation run
-
+	// Connection to the host doesn't survive soft-reboot, it is replaced with the new one
+	let mut reconnected = None;
+	// Detached activation might reconnect to the host, if the connection was lost
+	let mut session_replaced = None;
 	if action.should_activate() && !failed {
 		let _span = info_span!("activating").entered();
 		info!("executing activation script");
-		let specialised = if let Some(specialisation) = specialisation {
+		let specialised = if let Some(specialisation) = &specialisation {
 			let mut specialised = built.join("specialisation");
 			specialised.push(specialisation);
 			specialised
 		} else {
 			built.clone()
 		};
-		let switch_script = specialised.join("bin/switch-to-configuration");
-		let mut cmd = host.cmd(switch_script).in_current_span().await?;
-		cmd.arg(action.name().expect("upload.should_activate == false"));
-		if let Err(e) = cmd.sudo().run().in_current_span().await {
+		let soft_reboot = matches!(action, DeployAction::Switch)
+			&& run.switch_method == SwitchMethod::SoftReboot
+			&& supports_soft_reboot(host).await;
+		let result = if soft_reboot {
+			with_timeout(
+				"activation",
+				timeouts.activation,
+				soft_reboot_into(host, &specialised),
+			)
+			.in_current_span()
+			.await
+			.map(|host| reconnected = Some(host))
+		} else {
+			let action = action.name().expect("upload.should_activate == false");
+			// On timeout, switch-to-configuration is left running on the host,
+			// rollback is serialized with it by the switch-to-configuration lock.
+			let activation = async {
+				if run.detached_activation {
+					let (report, replaced) = activation::activate_detached(
+						host,
+						&specialised,
+						action,
+						timeouts.activation,
+					)
+					.await?;
+					session_replaced = replaced;
+					Ok(report)
+				} else {
+					activation::activate(host, &specialised, action).await
+				}
+			};
+			with_timeout("activation", timeouts.activation, activation)
+				.in_current_span()
+				.await
+				.and_then(|report| {
+					ensure!(
+						run.allow_failed_units || report.failed.is_empty(),
+						"units have failed after activation: {}",
+						report.failed.join(", ")
+					);
+					Ok(())
+				})
+		};
+		if let Err(e) = result {
 			error!("failed to activate: {e}");
 			failed = true;
 		}
 	}
-	if action.should_create_rollback_marker() {
-		if !disable_rollback {
+	let host = reconnected
+		.as_ref()
+		.or(session_replaced.as_ref())
+		.unwrap_or(host);
+	if action.should_activate() && !failed {
+		let result = with_timeout(
+			"health check",
+			timeouts.health_check,
+			health_check(host, run.allow_failed_units),
+		)
+		.instrument(info_span!("health check"))
+		.await;
+		if let Err(e) = result {
+			error!("health check failed: {e}");
+			failed = true;
+		}
+	}
+	if let Some(rollback) = rollback {
+		if !run.disable_rollback {
 			if failed {
 				if action.should_schedule_rollback_run() {
 					info!("executing rollback");
 					if let Err(e) = host
-						.systemctl_start("rollback-watchdog.service")
+						.systemctl_start(&rollback.service())
 						.instrument(info_span!("rollback"))
 						.await
 					{
 						error!("failed to trigger rollback: {e}")
+					} else {
+						rolled_back = true;
 					}
 				}
 			} else {
 				info!("trying to mark upgrade as successful");
 				if let Err(e) = host
-					.rm_file("/etc/fleet_rollback_marker", true)
+					.rm_file(&rollback.marker_path, true)
 					.in_current_span()
 					.await
 				{
@@ -229,180 +1079,1460 @@ async fn deploy_task(
 				}
 			}
 			info!("disarming watchdog, just in case");
-			if let Err(_e) = host.systemctl_stop("rollback-watchdog.timer").await {
+			if let Err(_e) = host.systemctl_stop(&rollback.timer()).await {
 				// It is ok, if there was no reboot - then timer might not be running.
 			}
-			if action.should_schedule_rollback_run() {
-				if let Err(e) = host.systemctl_stop("rollback-watchdog-run.timer").await {
+			// Transient rollback run doesn't survive soft-reboot
+			if action.should_schedule_rollback_run() && reconnected.is_none() {
+				if let Err(e) = host.systemctl_stop(&rollback.run_timer()).await {
 					error!("failed to disarm rollback run: {e}");
 				}
 			}
 		} else if let Err(_e) = host
-			.rm_file("/etc/fleet_rollback_marker", true)
+			.rm_file(&rollback.marker_path, true)
 			.in_current_span()
 			.await
 		{
 			// Marker might not exist, yet better try to remove it.
 		}
 	}
-	Ok(())
+	let outcome = if !failed {
+		DeployOutcome::Success
+	} else if rolled_back {
+		DeployOutcome::RolledBack
+	} else {
+		DeployOutcome::Failed
+	};
+	if let Some((before, after)) = switch {
+		let entry = JournalEntry {
+			deployment_id: deployment_id.to_owned(),
+			timestamp: Utc::now(),
+			action: action.name().unwrap_or("upload").to_owned(),
+			before,
+			after,
+			outcome: outcome.name().to_owned(),
+			specialisation,
+		};
+		if let Err(e) = append_journal(host, entry).await {
+			warn!("failed to record profile switch to the host journal: {e}");
+		}
+	}
+	Ok(outcome)
 }
 
-async fn build_task(config: Config, host: String, build_attr: &str) -> Result<PathBuf> {
+/// Specialisations of the built system, taken from the evaluated config, as the system
+/// might be built remotely, or be prebuilt, and thus be missing from the local store
+async fn specialisations(host: &ConfigHost) -> Result<Vec<String>> {
+	let nixos = host.nixos_config().await?;
+	Ok(nix_go!(nixos.specialisation).list_fields().await?)
+}
+
+/// Sends message to all logged in users using wall
+pub(crate) async fn broadcast(host: &ConfigHost, message: &str) {
+	let cmd = match host.cmd("wall").await {
+		Ok(cmd) => cmd,
+		Err(e) => {
+			warn!("failed to broadcast message: {e}");
+			return;
+		}
+	};
+	let mut cmd = cmd.sudo();
+	cmd.arg(message);
+	if let Err(e) = cmd.run().await {
+		warn!("failed to broadcast message: {e}");
+	}
+}
+
+/// Read by pam_motd on login
+const MOTD_NOTICE: &str = "/run/motd.d/fleet-deployment";
+
+/// Shows the message to users logging in, or removes it with `None`
+async fn set_motd(host: &ConfigHost, message: Option<&str>) {
+	let result: Result<()> = try {
+		let mut cmd = host.cmd("sh").await?;
+		cmd.arg("-c");
+		match message {
+			Some(message) => {
+				cmd.arg(format!(
+					"mkdir -p /run/motd.d && printf '%s\\n' \"$1\" > {MOTD_NOTICE}"
+				))
+				.arg("sh")
+				.arg(message);
+			}
+			None => {
+				cmd.arg(format!("rm -f {MOTD_NOTICE}"));
+			}
+		}
+		cmd.sudo().run().await?
+	};
+	if let Err(e) = result {
+		warn!("failed to update motd: {e}");
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NixBuildResult {
+	drv_path: String,
+	outputs: BTreeMap<String, PathBuf>,
+}
+
+/// Repl session can't have per-build arguments, derivation is built with separate `nix build` instead.
+///
+/// Separate process is also used for streaming build logs, as repl output is not attributed to the host span.
+async fn build_with_args(
+	config: &Config,
+	drv: &Value,
+	extra_args: Vec<OsString>,
+	verbose: bool,
+) -> Result<BTreeMap<String, PathBuf>> {
+	let drv_path: String = nix_go_json!(drv.drvPath);
+	let mut cmd = config.local_host().cmd("nix").await?;
+	cmd.args(&config.nix_args)
+		.args(&config.build_nix_args)
+		.args(extra_args)
+		.arg("build")
+		.arg("--no-link")
+		.arg("--json")
+		.arg(format!("{drv_path}^*"));
+	let cmd = if verbose { cmd.stream_build_logs() } else { cmd };
+	let output = cmd.retry_transient().run_nix_string().await?;
+	if command::is_dry_run() {
+		// Build was only printed, outputs are known from the evaluation
+		let out_path: PathBuf = nix_go_json!(drv.outPath);
+		return Ok(BTreeMap::from([("out".to_owned(), out_path)]));
+	}
+	let mut results: Vec<NixBuildResult> =
+		serde_json::from_str(&output).context("failed to parse nix build output")?;
+	ensure!(results.len() == 1, "expected single build result");
+	Ok(results.remove(0).outputs)
+}
+
+/// Waits for the jobs queued by activation to finish, and checks that no units have failed
+async fn health_check(host: &ConfigHost, allow_failed_units: bool) -> Result<()> {
+	loop {
+		let mut cmd = host.cmd("systemctl").await?;
+		cmd.arg("list-jobs").arg("--no-legend");
+		if cmd.query().run_string().await?.trim().is_empty() {
+			break;
+		}
+		sleep(HEALTH_CHECK_POLL_INTERVAL).await;
+	}
+	let mut cmd = host.cmd("sh").await?;
+	// is-system-running exits with non-zero code for every state other than running
+	cmd.arg("-c").arg("systemctl is-system-running || true");
+	match cmd.query().run_string().await?.trim() {
+		"running" => Ok(()),
+		"degraded" if allow_failed_units => {
+			warn!("system is degraded");
+			Ok(())
+		}
+		state => bail!("system is {state}"),
+	}
+}
+
+async fn with_timeout<T>(
+	phase: &str,
+	duration: Option<Duration>,
+	fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+	let Some(duration) = duration else {
+		return fut.await;
+	};
+	timeout(duration, fut)
+		.await
+		.unwrap_or_else(|_| bail!("{phase} timed out after {}s", duration.as_secs()))
+}
+
+/// `verbose` enables build log streaming, see [`BuildLogOpts`]
+pub(crate) async fn build_task(
+	config: Config,
+	host: String,
+	build_attr: &str,
+	verbose: bool,
+) -> Result<PathBuf> {
 	info!("building");
+	// Evaluation closure borrows config, scheduler is borrowed from it too
+	let config = &config;
+	config
+		.eval
+		.run(|config_field| async move {
+			let host = config.host_on(&config_field, &host).await?;
+			// let action = Action::from(self.subcommand.clone());
+			let drv = host.system_attr(build_attr).await?;
+			let extra_args = host.extra_nix_args().await?;
+			let built: Result<BTreeMap<String, PathBuf>> = if extra_args.is_empty()
+				&& config.build_nix_args.is_empty()
+				&& !verbose
+			{
+				drv.build()
+					.await
+					.map(|outputs| outputs.into_iter().collect())
+					.map_err(anyhow::Error::from)
+			} else {
+				build_with_args(&config, &drv, extra_args, verbose).await
+			};
+			let outputs = built.inspect_err(|_| {
+					if build_attr == "sdImage" {
+						info!("sd-image build failed");
+						info!("Make sure you have imported modulesPath/installer/sd-card/sd-image-<arch>[-installer].nix (For installer, you may want to check config)");
+					}
+				})?;
+			let out_output = outputs
+				.get("out")
+				.ok_or_else(|| anyhow!("system build should produce \"out\" output"))?;
+
+			check_closure_size(&config, &host, out_output).await?;
+
+			Ok(out_output.clone())
+		})
+		.await
+}
+
+/// Systems built by [`batch_build`], shared between the host tasks
+type BatchBuild = Shared<LocalBoxFuture<'static, Arc<BTreeMap<String, PathBuf>>>>;
+
+/// Evaluates derivations of all hosts, and builds them with a single `nix build`.
+///
+/// Batch is started by the first host task awaiting it, and every task awaits it within its own
+/// build timeout and cancellation. Resolves to systems, which were built successfully, hosts with
+/// per-host nix arguments are not batched. Failures are not fatal, missing hosts should be built
+/// with [`build_task`], which will reuse everything that was built in the batch, and report errors per host.
+fn batch_build(config: Config, hosts: Vec<String>, build_attr: String) -> BatchBuild {
+	let span = info_span!("batch build");
+	async move {
+		let config = &config;
+		let build_attr = build_attr.as_str();
+		let evaluated = futures::future::join_all(hosts.into_iter().map(|name| async move {
+			let result: Result<Option<(String, PathBuf)>> = config
+				.eval
+				.run(|config_field| async move {
+					let host = config.host_on(&config_field, &name).await?;
+					if !host.extra_nix_args().await?.is_empty() {
+						return Ok(None);
+					}
+					let drv = host.system_attr(build_attr).await?;
+					Ok(Some((nix_go_json!(drv.drvPath), nix_go_json!(drv.outPath))))
+				})
+				.await;
+			(name, result)
+		}))
+		.await;
+		let mut outputs = BTreeMap::new();
+		for (name, result) in evaluated {
+			match result {
+				Ok(Some(output)) => {
+					outputs.insert(name, output);
+				}
+				Ok(None) => info!("{name} has extra nix args, it will be built separately"),
+				Err(e) => warn!("failed to evaluate {name}: {e:#}"),
+			}
+		}
+		if outputs.is_empty() {
+			return Arc::new(BTreeMap::new());
+		}
+		info!("building {} hosts", outputs.len());
+		let result: Result<()> = try {
+			let mut cmd = config.local_host().cmd("nix").await?;
+			cmd.args(&config.nix_args)
+				.args(&config.build_nix_args)
+				.arg("build")
+				.arg("--no-link")
+				.arg("--keep-going")
+				.args(outputs.values().map(|(drv, _)| format!("{drv}^out")));
+			cmd.retry_transient().run_nix().await?
+		};
+		let failed = match result {
+			Ok(()) => false,
+			Err(e) => {
+				warn!("batch build failed, failed hosts will be built separately: {e:#}");
+				true
+			}
+		};
+		let local_host = config.local_host();
+		let mut built = BTreeMap::new();
+		for (name, (_, out)) in outputs {
+			// With --keep-going, systems of other hosts are still built
+			if !failed || is_valid_path(&local_host, &out).await {
+				built.insert(name, out);
+			}
+		}
+		Arc::new(built)
+	}
+	.instrument(span)
+	.boxed_local()
+	.shared()
+}
+
+/// Uses system built by [`batch_build`], falling back to [`build_task`]
+async fn build_or_prebuilt(
+	config: Config,
+	host: String,
+	build_attr: &str,
+	prebuilt: Option<&BatchBuild>,
+	verbose: bool,
+) -> Result<PathBuf> {
+	let built = match prebuilt {
+		Some(batch) => batch.clone().await.get(&host).cloned(),
+		None => None,
+	};
+	let Some(built) = built else {
+		return build_task(config, host, build_attr, verbose).await;
+	};
 	let host = config.host(&host).await?;
-	// let action = Action::from(self.subcommand.clone());
-	let nixos = host.nixos_config().await?;
-	let drv = nix_go!(nixos.system.build[{ build_attr }]);
-	let outputs = drv.build().await.inspect_err(|_| {
-			if build_attr == "sdImage" {
-				info!("sd-image build failed");
-				info!("Make sure you have imported modulesPath/installer/sd-card/sd-image-<arch>[-installer].nix (For installer, you may want to check config)");
+	check_closure_size(&config, &host, &built).await?;
+	Ok(built)
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum ClosureSizeExceeded {
+	Fail,
+	Warn,
+}
+
+async fn check_closure_size(config: &Config, host: &ConfigHost, built: &PathBuf) -> Result<()> {
+	let deploy = host.deploy_options().await?;
+	let max_size: Option<u64> = nix_go_json!(deploy.maxClosureSize);
+	let Some(max_size) = max_size else {
+		return Ok(());
+	};
+	let size = config.local_host().closure_size(built).await?;
+	if size <= max_size {
+		return Ok(());
+	}
+	let exceeded: ClosureSizeExceeded = nix_go_json!(deploy.closureSizeExceeded);
+	let message = format!("built closure size {size} bytes exceeds budget of {max_size} bytes");
+	match exceeded {
+		ClosureSizeExceeded::Fail => bail!("{message}"),
+		ClosureSizeExceeded::Warn => warn!("{message}"),
+	}
+	Ok(())
+}
+
+/// Builds `buildSystems.<attr>.<host>`, returns `None` if the package is not declared for the host
+async fn build_package(
+	config: Config,
+	host: String,
+	attr: &str,
+	verbose: bool,
+) -> Result<Option<BTreeMap<String, PathBuf>>> {
+	let config = &config;
+	config
+		.eval
+		.run(|config_field| async move {
+			let packages = nix_go!(config_field.buildSystems[{ attr }]);
+			if !packages.has_field(&host).await? {
+				return Ok(None);
 			}
-		})?;
-	let out_output = outputs
-		.get("out")
-		.ok_or_else(|| anyhow!("system build should produce \"out\" output"))?;
+			info!("building");
+			let drv = nix_go!(packages[{ host }]);
+			let host = config.host_on(&config_field, &host).await?;
+			let extra_args = host.extra_nix_args().await?;
+			let outputs = if extra_args.is_empty() && config.build_nix_args.is_empty() && !verbose {
+				drv.build().await?.into_iter().collect()
+			} else {
+				build_with_args(config, &drv, extra_args, verbose).await?
+			};
+			Ok(Some(outputs))
+		})
+		.await
+}
 
-	Ok(out_output.clone())
+/// Links build output to the working directory, replacing link from the previous build
+pub(crate) fn link_result(name: &str, path: &Path) -> Result<()> {
+	let out = current_dir()?.join(name);
+	if out.is_symlink() {
+		std::fs::remove_file(&out)?;
+	}
+	info!("linking {name} to {path:?}");
+	symlink(path, &out).with_context(|| format!("failed to symlink {out:?}"))
 }
 
 impl BuildSystems {
+	async fn run_package(
+		self,
+		config: &Config,
+		hosts: Vec<ConfigHost>,
+		attr: String,
+	) -> Result<()> {
+		let config_field = &config.config_field;
+		let packages = nix_go!(config_field.buildSystems);
+		let known = packages.list_fields().await?;
+		ensure!(
+			known.contains(&attr),
+			"buildSystems.{attr} is not declared, known packages: {}",
+			known.join(", ")
+		);
+		let set = LocalSet::new();
+		let config = config.clone();
+		let build_log = self.build_log.clone();
+		let failed = Rc::new(RefCell::new(Vec::new()));
+		let task_failed = failed.clone();
+		Schedule::new(hosts).await?.spawn(&set, move |host| {
+			let config = config.clone();
+			let span = info_span!("package", host = field::display(&host.name));
+			let attr = attr.clone();
+			let build_log = build_log.clone();
+			let failed = task_failed.clone();
+			async move {
+				let result: Result<bool> = try {
+					let verbose = build_log.is_verbose(&host).await?;
+					match build_package(config, host.name.clone(), &attr, verbose).await? {
+						Some(outputs) => {
+							for (output, path) in outputs {
+								let name = if output == "out" {
+									format!("{attr}-{}", host.name)
+								} else {
+									format!("{attr}-{}-{output}", host.name)
+								};
+								link_result(&name, &path)?;
+							}
+						}
+						None => info!("buildSystems.{attr} is not declared for this host, skipping"),
+					}
+					true
+				};
+				result.unwrap_or_else(|e| {
+					error!("failed to build package: {e:#}");
+					failed.borrow_mut().push(host.name.clone());
+					false
+				})
+			}
+			.instrument(span)
+		});
+		set.await;
+		let failed = failed.borrow();
+		if !failed.is_empty() {
+			bail!("failed to build package for {}", failed.join(", "));
+		}
+		Ok(())
+	}
+
 	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
-		let hosts = config.list_hosts().await?;
+		let opts = &self.rebuild.select(config, opts).await?;
+		let mut hosts = Vec::new();
+		for host in config.list_hosts().await? {
+			if !opts.should_skip(&host).await? {
+				hosts.push(host);
+			}
+		}
+		if let Some(BuildSystemsAction::Package { attr }) = self.action.clone() {
+			return self.run_package(config, hosts, attr).await;
+		}
 		let set = LocalSet::new();
 		let build_attr = self.build_attr.clone();
-		for host in hosts.into_iter() {
-			if opts.should_skip(&host).await? {
-				continue;
-			}
+		let prebuilt = self.batch.then(|| {
+			let names = hosts.iter().map(|h| h.name.clone()).collect();
+			batch_build(config.clone(), names, build_attr.clone())
+		});
+		let config = config.clone();
+		let build_log = self.build_log.clone();
+		let summary = RunSummary::default();
+		let task_summary = summary.clone();
+		Schedule::new(hosts).await?.spawn(&set, move |host| {
 			let config = config.clone();
 			let span = info_span!("build", host = field::display(&host.name));
-			let hostname = host.name;
+			let hostname = host.name.clone();
 			let build_attr = build_attr.clone();
-			// FIXME: Since the introduction of better-nix-eval,
-			// due to single repl used for builds, hosts are waiting for each other to build,
-			// instead of building concurrently.
-			//
-			// Open multiple repls?
-			//
-			// Create build batcher, which will behave similar to golangs
-			// WaitGroup, and start executing once all the build tasks are scheduled?
-			// This also allows to cleanup build output, as there will be no longer
-			// "waiting for remote machine" messages in the cases when one package is needed for
-			// multiple hosts.
-			set.spawn_local(
-				(async move {
-					let built = match build_task(config, hostname.clone(), &build_attr).await {
-						Ok(path) => path,
-						Err(e) => {
-							error!("failed to deploy host: {}", e);
-							return;
-						}
-					};
-					// TODO: Handle error
-					let mut out = current_dir().expect("cwd exists");
-					out.push(format!("built-{}", hostname));
-
-					info!("linking iso image to {:?}", out);
-					if let Err(e) = symlink(built, out) {
-						error!("failed to symlink: {e}")
+			let prebuilt = prebuilt.clone();
+			let build_log = build_log.clone();
+			let summary = task_summary.clone();
+			// Without --batch, builds are only concurrent with --eval-jobs > 1, as a single nix repl
+			// evaluates and builds one host at a time.
+			async move {
+				let started = Instant::now();
+				let verbose = match build_log.is_verbose(&host).await {
+					Ok(verbose) => verbose,
+					Err(e) => {
+						error!("failed to match host: {e}");
+						summary.record_outcome(&hostname, "failed", started);
+						return false;
 					}
-				})
-				.instrument(span),
-			);
-		}
+				};
+				let built = match build_or_prebuilt(
+					config,
+					hostname.clone(),
+					&build_attr,
+					prebuilt.as_ref(),
+					verbose,
+				)
+				.await
+				{
+					Ok(path) => path,
+					Err(e) => {
+						error!("failed to deploy host: {}", e);
+						summary.record_outcome(&hostname, "failed", started);
+						return false;
+					}
+				};
+				summary.record_phase(&hostname, RunPhase::Built.name());
+				summary.record_new(&hostname, built.clone());
+				summary.record_outcome(&hostname, "success", started);
+				// TODO: Handle error
+				let mut out = current_dir().expect("cwd exists");
+				out.push(format!("built-{}", hostname));
+
+				info!("linking iso image to {:?}", out);
+				if let Err(e) = symlink(built, out) {
+					error!("failed to symlink: {e}")
+				}
+				true
+			}
+			.instrument(span)
+		});
 		set.await;
+		summary.print();
+		let failed = summary.failed();
+		if !failed.is_empty() {
+			bail!("failed to build {}", failed.join(", "));
+		}
 		Ok(())
 	}
 }
 
+/// State shared between host deployment tasks of a single run
+#[derive(Clone)]
+struct DeployRun {
+	config: Config,
+	opts: FleetOpts,
+	action: DeployAction,
+	disable_rollback: bool,
+	rollback_timeout: Option<String>,
+	timeouts: TimeoutOpts,
+	/// Default specialisation, see [`Deploy::specialisation`]
+	specialisation: Option<String>,
+	switch_method: SwitchMethod,
+	detached_activation: bool,
+	reboot_if_needed: bool,
+	force: bool,
+	allow_failed_units: bool,
+	dry_activate: bool,
+	/// Output of `--dry-activate`, per host
+	dry_activations: Arc<Mutex<BTreeMap<String, String>>>,
+	run_id: String,
+	broadcast_message: Option<String>,
+	telemetry: Telemetry,
+	summary: RunSummary,
+	notify: NotifyOpts,
+	state: RunState,
+	/// Results of `fleet probe`, used to pick copy strategy and timeouts
+	probes: Arc<BTreeMap<String, ProbeResult>>,
+	/// Systems uploaded by `fleet push`, which don't need to be uploaded again
+	pushed: Arc<BTreeMap<String, PathBuf>>,
+	/// Cancelled on Ctrl-C, hosts which haven't started activation yet are not deployed
+	cancel: CancellationToken,
+	wake: bool,
+	/// Systems built by `--batch-build`
+	prebuilt: Option<BatchBuild>,
+	build_log: BuildLogOpts,
+	/// Set with `--confirm`, or for switch without `--yes`
+	confirmation: Option<Confirmation>,
+	/// Url of the cache, started by `--serve-cache`
+	cache_url: Option<String>,
+	/// Systems prebuilt elsewhere, see [`Deploy::from_cache`]
+	from_cache: Option<Arc<FromCache>>,
+	clear_stale_markers: bool,
+}
+
+impl DeployRun {
+	fn hook_context<'a>(
+		&'a self,
+		host: &'a str,
+		phase: HookPhase,
+		toplevel: &'a Path,
+	) -> HookContext<'a> {
+		HookContext {
+			host,
+			phase,
+			deployment_id: &self.run_id,
+			action: self.action.name().unwrap_or("upload"),
+			toplevel: Some(toplevel),
+			previous_generation: None,
+			outcome: None,
+		}
+	}
+	async fn check_stale_rollback(&self, host: &ConfigHost) -> Result<()> {
+		if !self.action.should_create_rollback_marker()
+			|| self.disable_rollback
+			|| host.platform().await? != Platform::Nixos
+		{
+			return Ok(());
+		}
+		let rollback = RollbackSettings::for_host(host).await?;
+		check_stale_rollback(host, &rollback, self.clear_stale_markers).await
+	}
+	/// Last phase, after which host is considered converged
+	fn target_phase(&self) -> RunPhase {
+		match self.action {
+			DeployAction::Upload => RunPhase::Uploaded,
+			_ => RunPhase::Activated,
+		}
+	}
+}
+
+pub(crate) async fn upload_task(
+	local_host: &ConfigHost,
+	host: &ConfigHost,
+	built: &PathBuf,
+	probes: &BTreeMap<String, ProbeResult>,
+	closure_size: Option<u64>,
+	copy_timeout: Option<Duration>,
+	cache_url: Option<&str>,
+) -> Result<()> {
+	let probe = probes.get(&host.name);
+	let seed = seed_host(host).await?;
+	let strategy = probe.map_or(CopyStrategy::Direct, |p| p.copy_strategy(seed.is_some()));
+	let copy_timeout =
+		copy_timeout.or_else(|| probe.zip(closure_size).map(|(p, s)| p.copy_timeout(s)));
+	info!(
+		"uploading system closure (strategy: {strategy:?}, timeout: {})",
+		copy_timeout.map_or("none".to_owned(), |t| format!("{}s", t.as_secs()))
+	);
+	let config = host.config();
+	let config_field = &config.config_field;
+	let fleet_public_key: Option<String> = nix_go_json!(config_field.nixSigning.publicKey);
+	{
+		// TODO: Move to remote_derivation method.
+		// Alternatively, nix store make-content-addressed can be used,
+		// at least for the first deployment, to provide trusted store key.
+		//
+		// It is much slower, yet doesn't require root on the deployer machine.
+		let mut sign = local_host
+			.cmd("nix")
+			.await
+			.context("failed to setup local")?;
+		let key_file = if fleet_public_key.is_some() {
+			config.signing_key.clone().context(
+				"nixSigning.publicKey is configured, but signing key is not found, pass --signing-key or run `fleet keys generate-signing-key`",
+			)?
+		} else {
+			// Private key for host machine is registered in nix-sign.nix
+			PathBuf::from("/etc/nix/private-key")
+		};
+		sign.arg("store")
+			.arg("sign")
+			.comparg("--key-file", key_file)
+			.arg("-r")
+			.arg(built);
+		if let Err(e) = sign.sudo().retry_transient().run_nix().await {
+			if config.features.require_signatures || fleet_public_key.is_some() {
+				bail!("failed to sign store paths: {e}");
+			}
+			warn!("failed to sign store paths: {e}");
+		};
+	}
+	if let Some(cache_url) = cache_url {
+		info!("substituting from the deployer cache");
+		match cache_server::substitute(host, cache_url, built).await {
+			Ok(()) => return Ok(()),
+			Err(e) => {
+				warn!("failed to substitute from the deployer cache, falling back to copy: {e:#}")
+			}
+		}
+	}
+	// Transient copy failures are retried by the nix commands themselves
+	let copy = copy_closure(host, seed.as_ref(), probes, strategy, built);
+	match copy_timeout {
+		Some(t) => timeout(t, copy)
+			.await
+			.unwrap_or_else(|_| Err(anyhow!("copy timed out after {}s", t.as_secs()))),
+		None => copy.await,
+	}
+	.context("upload failed")?;
+	// Paths built on the host are trusted without signatures
+	if fleet_public_key.is_some() && !host.local && strategy != CopyStrategy::BuildOnTarget {
+		verify_signatures(host, built).await?;
+	}
+	Ok(())
+}
+
+/// Copies the closure to the host, using the strategy picked from the link probe
+async fn copy_closure(
+	host: &ConfigHost,
+	seed: Option<&ConfigHost>,
+	probes: &BTreeMap<String, ProbeResult>,
+	strategy: CopyStrategy,
+	built: &PathBuf,
+) -> Result<()> {
+	match strategy {
+		CopyStrategy::Direct | CopyStrategy::Compressed => {
+			let remote = host
+				.remote_derivation(built, strategy == CopyStrategy::Compressed)
+				.await?;
+			assert!(remote == *built, "CA derivations aren't implemented");
+		}
+		CopyStrategy::Relay => {
+			let seed = seed.expect("relay is only picked for hosts with seed host");
+			let compress = probes.get(&seed.name).is_some_and(ProbeResult::is_slow);
+			info!("uploading to the seed host {}", seed.name);
+			let remote = seed
+				.remote_derivation(built, compress)
+				.await
+				.context("upload to the seed host")?;
+			assert!(remote == *built, "CA derivations aren't implemented");
+			let target = seed.ssh_target().await?;
+			let mut copy = host.nix_cmd().await?;
+			copy.arg("copy")
+				.comparg("--from", format!("ssh-ng://{}", target.destination()))
+				.arg(built);
+			copy.sudo()
+				.retry_transient()
+				.run_nix()
+				.await
+				.context("copy from the seed host")?;
+		}
+		CopyStrategy::BuildOnTarget => build_on_target(host, built).await?,
+	}
+	Ok(())
+}
+
+/// Uploads only the derivation closure, and builds the system on the host,
+/// so that the dependencies are fetched from the substituters of the host instead
+async fn build_on_target(host: &ConfigHost, built: &PathBuf) -> Result<()> {
+	let mut deriver = host.config().local_host().cmd("nix-store").await?;
+	deriver.arg("--query").arg("--deriver").arg(built);
+	let drv = PathBuf::from(deriver.query().run_string().await?.trim());
+	// Deriver is unknown for paths added to the store directly, i.e signed bootables
+	if drv.extension().map_or(true, |e| e != "drv") || !drv.exists() {
+		warn!("derivation of {built:?} is not available, uploading it instead of building on the host");
+		host.remote_derivation(built, true).await?;
+		return Ok(());
+	}
+	info!("building on the host");
+	host.remote_derivation(&drv, true)
+		.await
+		.context("derivation upload")?;
+	let mut realise = host.cmd("nix-store").await?;
+	realise.arg("--realise").arg(&drv);
+	let outputs = realise.run_string().await.context("build on the host")?;
+	ensure!(
+		outputs.lines().any(|l| Path::new(l.trim()) == built),
+		"build on the host produced unexpected outputs: {}",
+		outputs.trim(),
+	);
+	Ok(())
+}
+
+/// Checks that the uploaded closure is signed by a key trusted by the host,
+/// which is the fleet key, if `nixSigning` is configured
+async fn verify_signatures(host: &ConfigHost, built: &Path) -> Result<()> {
+	let mut verify = host.nix_cmd().await?;
+	verify
+		.arg("store")
+		.arg("verify")
+		.arg("--no-contents")
+		.arg("-r")
+		.arg(built);
+	verify
+		.retry_transient()
+		.run_nix()
+		.await
+		.context("uploaded closure is not signed by a key trusted by the host")
+}
+
+/// Probes nix of the hosts before anything is built, so that hosts with older nix are reported,
+/// and the deployment is adapted to them up front, instead of failing halfway through it.
+///
+/// At most `max_parallel` hosts are connected to at once, same as for the deployment itself.
+async fn probe_remote_nix(hosts: &[ConfigHost], max_parallel: Option<usize>) {
+	let probes = hosts.iter().filter(|h| !h.local).map(|host| {
+		let span = info_span!("probe", host = field::display(&host.name));
+		async move { (host, host.remote_nix().await) }.instrument(span)
+	});
+	let results = stream::iter(probes)
+		.buffer_unordered(max_parallel.unwrap_or(usize::MAX).max(1))
+		.collect::<Vec<_>>()
+		.await;
+	for (host, result) in results {
+		if let Err(e) = result {
+			warn!(
+				"failed to probe nix of {}, it will be probed again on use: {e:#}",
+				host.name
+			);
+		}
+	}
+}
+
+async fn deploy_host(
+	run: &DeployRun,
+	host: &ConfigHost,
+	policy: &HostPolicy,
+) -> Result<DeployOutcome> {
+	let woken = run.wake && !host.local && wake_if_needed(host).await?;
+	let outcome = deploy_woken_host(run, host, policy, woken).await;
+	if woken {
+		info!("powering host back off");
+		let result: Result<()> = try {
+			let power = PowerConfig::for_host(host).await?;
+			power_off(host, &power).await?
+		};
+		if let Err(e) = result {
+			warn!("failed to power host off: {e:#}");
+		}
+	}
+	outcome
+}
+
+async fn deploy_woken_host(
+	run: &DeployRun,
+	host: &ConfigHost,
+	policy: &HostPolicy,
+	woken: bool,
+) -> Result<DeployOutcome> {
+	let hostname = &host.name;
+	let local_host = run.config.local_host();
+	let previous = run.state.host(hostname);
+	let timeouts = run.timeouts.for_host(host).await?;
+	let verbose = run.build_log.is_verbose(host).await?;
+
+	// Woken hosts are still booting, they are checked once they are reachable
+	if !woken {
+		run.check_stale_rollback(host).await?;
+	}
+
+	let started = Instant::now();
+	// Toplevel, recorded in .fleet/runs/<id>.json by the resumed run, or by the previous --reconcile round
+	let reused = previous.as_ref().and_then(|p| p.built.clone());
+	let built = if let Some(from_cache) = &run.from_cache {
+		from_cache.system(hostname)?
+	} else if let Some(built) = reused.filter(|built| built.exists()) {
+		info!("deploying system built in the resumed run: {built:?}");
+		built
+	} else {
+		select! {
+			built = with_timeout(
+				"build",
+				timeouts.build,
+				build_or_prebuilt(
+					run.config.clone(),
+					hostname.clone(),
+					"toplevel",
+					run.prebuilt.as_ref(),
+					verbose,
+				),
+			) => built?,
+			() = run.cancel.cancelled() => return Ok(DeployOutcome::Cancelled),
+		}
+	};
+	let uploaded = previous.as_ref().is_some_and(|p| {
+		p.built.as_ref() == Some(&built) && p.phase >= Some(RunPhase::Uploaded)
+	});
+	run.telemetry.record_phase(hostname, Phase::Build, started);
+	run.state.record(hostname, RunPhase::Built, &built);
+	let closure_size = match local_host.closure_size(&built).await {
+		Ok(size) => {
+			run.telemetry.record_closure_size(hostname, size);
+			Some(size)
+		}
+		// Prebuilt system is not in the local store
+		Err(_) if run.from_cache.is_some() => None,
+		Err(e) => {
+			warn!("failed to query closure size: {e}");
+			None
+		}
+	};
+	run_hooks(
+		host,
+		&run.hook_context(hostname, HookPhase::PostBuild, &built),
+	)
+	.await?;
+
+	if woken {
+		// Host was booting while the system was built
+		wait_reachable(host, WAKE_TIMEOUT).await?;
+		run.check_stale_rollback(host).await?;
+	}
+	let specialisation = run
+		.opts
+		.action_attr(host, "specialisation")
+		.await
+		.context("failed to get specialization")?
+		.or_else(|| run.specialisation.clone());
+	if let Some(specialisation) = &specialisation {
+		let available = specialisations(host).await?;
+		ensure!(
+			available.contains(specialisation),
+			"built system has no specialisation {specialisation}, available: {}",
+			available.join(", ")
+		);
+	}
+	if !run.force && host.platform().await?.has_system_profile() {
+		match is_converged(host, run.action, &built, specialisation.as_deref()).await {
+			Ok(true) => {
+				info!("already converged");
+				run.state.record(hostname, run.target_phase(), &built);
+				return Ok(DeployOutcome::Success);
+			}
+			Ok(false) => {}
+			Err(e) => warn!("failed to query current system: {e}"),
+		}
+	}
+	// Mutating commands only pretend to succeed in dry-run, with empty output, the rest of
+	// the deployment would act on it, i.e roll back the host after failed activation checks
+	if command::is_dry_run() {
+		match run.action.name() {
+			Some(action) => info!("dry-run, would upload and {action} {}", built.display()),
+			None => info!("dry-run, would upload {}", built.display()),
+		}
+		return Ok(DeployOutcome::Success);
+	}
+	// Signed bootables are only installed together with the bootloader, on profile switch
+	let secure_boot = match SecureBootSettings::for_host(host).await? {
+		Some(settings) if run.action.should_switch_profile() => Some(settings),
+		_ => None,
+	};
+	let signed = match &secure_boot {
+		Some(settings) => settings
+			.sign(host)
+			.await
+			.context("secure boot signing failed")?,
+		None => None,
+	};
+	if let Some(from_cache) = run.from_cache.as_ref().filter(|_| !uploaded) {
+		info!("realizing system from {}", from_cache.substituter);
+		let started = Instant::now();
+		select! {
+			realized = cache_server::substitute(host, &from_cache.substituter, &built) => {
+				realized.context("failed to realize prebuilt system")?
+			},
+			() = run.cancel.cancelled() => return Ok(DeployOutcome::Cancelled),
+		}
+		run.telemetry.record_phase(hostname, Phase::Copy, started);
+	} else if host.local {
+		info!("deploying to the local machine, upload is not needed");
+	} else if run.pushed.get(hostname) == Some(&built) && is_valid_path(host, &built).await {
+		info!("system was pushed ahead of time, upload is not needed");
+	} else if !uploaded {
+		let started = Instant::now();
+		select! {
+			uploaded = upload_task(
+				&local_host,
+				host,
+				&built,
+				&run.probes,
+				closure_size,
+				timeouts.copy,
+				run.cache_url.as_deref(),
+			) => uploaded?,
+			() = run.cancel.cancelled() => return Ok(DeployOutcome::Cancelled),
+		}
+		run.telemetry.record_phase(hostname, Phase::Copy, started);
+	}
+	if let (Some(settings), Some(signed)) = (&secure_boot, &signed) {
+		if !host.local {
+			info!("uploading signed bootables");
+			upload_task(
+				&local_host,
+				host,
+				signed,
+				&run.probes,
+				None,
+				timeouts.copy,
+				None,
+			)
+			.await?;
+		}
+		settings.register(host, &built, signed).await?;
+	}
+	run.state.record(hostname, RunPhase::Uploaded, &built);
+	run_hooks(
+		host,
+		&run.hook_context(hostname, HookPhase::PostUpload, &built),
+	)
+	.await?;
+	if run.dry_activate {
+		let output = dry_activate(host, &built, specialisation.as_deref())
+			.await
+			.context("dry activation failed")?;
+		run.dry_activations
+			.lock()
+			.unwrap()
+			.insert(hostname.clone(), output);
+	}
+	if run.target_phase() == RunPhase::Uploaded {
+		return Ok(DeployOutcome::Success);
+	}
+	// Last point where deployment can be cancelled, once activation is started,
+	// it is left to finish or to be rolled back by the armed rollback timer.
+	if run.cancel.is_cancelled() {
+		return Ok(DeployOutcome::Cancelled);
+	}
+
+	let has_system_profile = host.platform().await?.has_system_profile();
+	let current_system = if has_system_profile {
+		match profile_target(host, "/run/current-system").await {
+			Ok(current) => Some(PathBuf::from(current)),
+			Err(e) => {
+				warn!("failed to query current system: {e}");
+				None
+			}
+		}
+	} else {
+		None
+	};
+	if let Some(current) = &current_system {
+		run.summary.record_old(hostname, current.clone());
+	}
+	let previous_generation = if has_system_profile {
+		match current_generation(host, SYSTEM_PROFILE).await {
+			Ok(generation) => Some(generation.id),
+			Err(e) => {
+				warn!("failed to query current generation: {e}");
+				None
+			}
+		}
+	} else {
+		None
+	};
+	if let Some(confirmation) = &run.confirmation {
+		let preview = activation_preview(
+			host,
+			run.action,
+			current_system.as_deref(),
+			&built,
+			specialisation.as_deref(),
+		)
+		.await;
+		match confirmation.ask(hostname, preview).await? {
+			Answer::Yes => {}
+			Answer::No => {
+				warn!("activation was declined");
+				return Ok(DeployOutcome::Cancelled);
+			}
+			Answer::Quit => {
+				warn!("deployment was stopped");
+				run.cancel.cancel();
+				return Ok(DeployOutcome::Cancelled);
+			}
+		}
+	}
+	run_hooks(
+		host,
+		&HookContext {
+			previous_generation,
+			..run.hook_context(hostname, HookPhase::PreActivate, &built)
+		},
+	)
+	.await?;
+
+	let broadcast_message = run
+		.broadcast_message
+		.as_ref()
+		.filter(|_| run.action.should_activate());
+	if let Some(message) = broadcast_message {
+		broadcast(host, message).await;
+		set_motd(host, Some(message)).await;
+	}
+	let started = Instant::now();
+	let mut attempt = 0;
+	let mut outcome = loop {
+		let outcome =
+			match deploy_task(run, host, built.clone(), specialisation.clone(), timeouts).await {
+				Ok(outcome) => outcome,
+				Err(e) => {
+					error!("activation failed: {e}");
+					DeployOutcome::Failed
+				}
+			};
+		if outcome == DeployOutcome::Success
+			|| attempt >= policy.activation_retries
+			|| run.cancel.is_cancelled()
+		{
+			break outcome;
+		}
+		attempt += 1;
+		warn!(
+			"retrying activation ({attempt}/{})",
+			policy.activation_retries
+		);
+		sleep(ACTIVATION_RETRY_DELAY).await;
+	};
+	run.telemetry.record_phase(hostname, Phase::Activate, started);
+	if broadcast_message.is_some() {
+		set_motd(host, None).await;
+		broadcast(
+			host,
+			&format!(
+				"fleet deployment {} finished: {}",
+				run.run_id,
+				outcome.name()
+			),
+		)
+		.await;
+	}
+	// Connection to the host doesn't survive reboot, it is replaced with the new one
+	let mut rebooted = None;
+	if outcome == DeployOutcome::Success
+		&& run.reboot_if_needed
+		&& host.platform().await? == Platform::Nixos
+	{
+		let started = Instant::now();
+		let _span = info_span!("reboot").entered();
+		let activated = match &specialisation {
+			Some(specialisation) => built.join("specialisation").join(specialisation),
+			None => built.clone(),
+		};
+		let result: Result<()> = try {
+			let changed = reboot_needed(host, &activated).in_current_span().await?;
+			if changed.is_empty() {
+				info!("reboot is not needed");
+			} else {
+				info!("reboot is needed, changed: {}", changed.join(", "));
+				// Boot entry of the generation is the system itself, not its specialisation
+				let reconnected = reboot_into(host, &built).in_current_span().await?;
+				if activated != built {
+					info!("activating specialisation after reboot");
+					let mut cmd = reconnected
+						.cmd(activated.join("bin/switch-to-configuration"))
+						.await?;
+					cmd.arg("test");
+					cmd.sudo().run().await?;
+				}
+				rebooted = Some(reconnected);
+			}
+		};
+		if let Err(e) = result {
+			error!("reboot failed: {e:#}");
+			outcome = DeployOutcome::Failed;
+		}
+		run.telemetry.record_phase(hostname, Phase::Reboot, started);
+	}
+	let host = rebooted.as_ref().unwrap_or(host);
+	if outcome == DeployOutcome::Success {
+		run.state.record(hostname, RunPhase::Activated, &built);
+	}
+	if let Err(e) = run_hooks(
+		host,
+		&HookContext {
+			previous_generation,
+			outcome: Some(outcome.name()),
+			..run.hook_context(hostname, HookPhase::PostActivate, &built)
+		},
+	)
+	.await
+	{
+		warn!("post-activation hook failed: {e:#}");
+	}
+	Ok(outcome)
+}
+
+/// First Ctrl-C cancels the deployment, second one aborts fleet immediately
+async fn handle_interrupt(cancel: CancellationToken) {
+	if signal::ctrl_c().await.is_err() {
+		return;
+	}
+	warn!("interrupted, waiting for in-flight activations to finish, press Ctrl-C again to abort");
+	cancel.cancel();
+	if signal::ctrl_c().await.is_err() {
+		return;
+	}
+	error!("aborted, hosts in the middle of activation will be rolled back by the armed rollback timer");
+	std::process::exit(130);
+}
+
+fn print_dry_activations(activations: &BTreeMap<String, String>) {
+	for (host, output) in activations {
+		let changes = dry_activation_changes(output);
+		if changes.is_empty() {
+			info!("{host}: no unit changes");
+		} else {
+			info!("{host}:\n  {}", changes.join("\n  "));
+		}
+	}
+}
+
 impl Deploy {
 	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		let opts = &self.rebuild.select(config, opts).await?;
+		let defaults = &config.cli_defaults.deploy;
+		let action = match (self.action, &defaults.action) {
+			(Some(action), _) => action,
+			// Dry activation only needs the system to be uploaded
+			(None, _) if self.dry_activate => DeployAction::Upload,
+			(None, Some(action)) => DeployAction::from_str(action, false)
+				.map_err(|e| anyhow!("bad cli.deploy.action: {e}"))?,
+			(None, None) => bail!("no action specified, and cli.deploy.action is not set"),
+		};
+		ensure!(
+			!self.reboot_if_needed || action.should_switch_profile(),
+			"--reboot-if-needed is only supported for boot and switch actions"
+		);
+		let confirm =
+			!command::is_dry_run() && (self.confirm || action == DeployAction::Switch && !self.yes);
+		if confirm {
+			ensure!(
+				stdin().is_terminal(),
+				"stdin is not a tty, pass --yes to deploy without confirmation"
+			);
+		}
 		let hosts = config.list_hosts().await?;
-		let set = LocalSet::new();
+		let resume = if let Some(run_id) = &self.resume {
+			Some(run_id.clone())
+		} else if self.only_failed {
+			Some(RunState::latest_id(&config.directory)?)
+		} else {
+			None
+		};
+		let (run_id, state) = if let Some(run_id) = resume {
+			info!("resuming deployment {run_id}");
+			let state = RunState::load(&config.directory, &run_id)?;
+			(run_id, state)
+		} else {
+			let run_id = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+			info!("starting deployment {run_id}");
+			let state = RunState::new(&config.directory, &run_id);
+			(run_id, state)
+		};
+		let cache = if let Some(address) = &self.serve_cache {
+			Some(CacheServer::start(address, config.signing_key.as_deref()).await?)
+		} else {
+			None
+		};
+		let broadcast_message = self.broadcast.as_ref().map(|message| {
+			let mut message = format!("{message}\n(fleet deployment {run_id}");
+			if let Some(eta) = &self.broadcast_eta {
+				message.push_str(&format!(", expected duration: {eta}"));
+			}
+			message.push(')');
+			message
+		});
+		let run = DeployRun {
+			config: config.clone(),
+			opts: opts.clone(),
+			action,
+			disable_rollback: self.disable_rollback,
+			rollback_timeout: self
+				.rollback_timeout
+				.clone()
+				.or_else(|| defaults.rollback_timeout.clone()),
+			timeouts: self.timeouts.clone(),
+			specialisation: self.specialisation.clone(),
+			switch_method: self.switch_method,
+			detached_activation: self.detached_activation,
+			reboot_if_needed: self.reboot_if_needed,
+			force: self.force,
+			allow_failed_units: self.allow_failed_units,
+			dry_activate: self.dry_activate,
+			dry_activations: Default::default(),
+			build_log: self.build_log.clone(),
+			run_id,
+			broadcast_message,
+			telemetry: Telemetry::default(),
+			summary: RunSummary::default(),
+			notify: self.notify.clone(),
+			state,
+			probes: Arc::new(load_probes(&config.directory)),
+			pushed: Arc::new(load_pushed(&config.directory)),
+			cancel: CancellationToken::new(),
+			wake: self.wake,
+			prebuilt: Default::default(),
+			confirmation: confirm.then(Confirmation::default),
+			cache_url: cache.as_ref().map(|c| c.url().to_owned()),
+			from_cache: None,
+			clear_stale_markers: self.clear_stale_markers,
+		};
+		let mut selected = Vec::new();
 		for host in hosts.into_iter() {
 			if opts.should_skip(&host).await? {
 				continue;
 			}
-			let config = config.clone();
-			let span = info_span!("deploy", host = field::display(&host.name));
-			let hostname = host.name.clone();
-			let local_host = config.local_host();
-			let opts = opts.clone();
-			// FIXME: Fix repl concurrency (see build-systems)
-			set.spawn_local(
-				(async move {
-					let built = match build_task(config.clone(), hostname.clone(), "toplevel").await
-					{
-						Ok(path) => path,
-						Err(e) => {
-							error!("failed to deploy host: {}", e);
-							return;
-						}
-					};
-					if !opts.is_local(&hostname) {
-						info!("uploading system closure");
-						{
-							// TODO: Move to remote_derivation method.
-							// Alternatively, nix store make-content-addressed can be used,
-							// at least for the first deployment, to provide trusted store key.
-							//
-							// It is much slower, yet doesn't require root on the deployer machine.
-							let Ok(mut sign) = local_host.cmd("nix").await else {
-								error!("failed to setup local");
-								return;
-							};
-							// Private key for host machine is registered in nix-sign.nix
-							sign.arg("store")
-								.arg("sign")
-								.comparg("--key-file", "/etc/nix/private-key")
-								.arg("-r")
-								.arg(&built);
-							if let Err(e) = sign.sudo().run_nix().await {
-								warn!("failed to sign store paths: {e}");
-							};
-						}
-						let mut tries = 0;
-						loop {
-							match host.remote_derivation(&built).await {
-								Ok(remote) => {
-									assert!(remote == built, "CA derivations aren't implemented");
-									break;
-								}
-								Err(e) if tries < 3 => {
-									tries += 1;
-									warn!("copy failure ({}/3): {}", tries, e);
-									sleep(Duration::from_millis(5000)).await;
-								}
+			if let Some(previous) = run.state.host(&host.name) {
+				if self.only_failed && !previous.failed {
+					continue;
+				}
+				if !previous.failed && previous.phase >= Some(run.target_phase()) {
+					info!("host {} is already converged in this run, skipping", host.name);
+					continue;
+				}
+			} else if self.only_failed {
+				continue;
+			}
+			selected.push(host);
+		}
+		let run = if let Some(source) = &self.from_cache {
+			let substituter = self.substituter.clone().expect("required by clap");
+			let from_cache = FromCache::load(source, substituter)?;
+			let names = selected.iter().map(|h| h.name.clone()).collect::<Vec<_>>();
+			from_cache.ensure_hosts(&names)?;
+			from_cache.verify(config, &names).await?;
+			DeployRun {
+				from_cache: Some(Arc::new(from_cache)),
+				..run
+			}
+		} else if self.batch_build {
+			let names = selected.iter().map(|h| h.name.clone()).collect();
+			DeployRun {
+				prebuilt: Some(batch_build(config.clone(), names, "toplevel".to_owned())),
+				..run
+			}
+		} else {
+			run
+		};
+		// Sleeping hosts are unreachable, they are probed once woken
+		if !self.wake {
+			probe_remote_nix(&selected, defaults.max_parallel).await;
+		}
+		let halted = Rc::new(RefCell::new(None));
+		let deadline = self.reconcile.map(|d| Instant::now() + d);
+		let interrupt = tokio::spawn(handle_interrupt(run.cancel.clone()));
+		// Outcomes of the hosts, which will be retried by --reconcile, reported once they are final
+		let pending = Rc::new(RefCell::new(BTreeMap::new()));
+		let mut round = 1;
+		loop {
+			let set = LocalSet::new();
+			let task_run = run.clone();
+			// Failure domain limits apply to every round separately, as hosts fail again and again
+			// while they are offline
+			let failures = Rc::new(FailureTracker::new(config).await?);
+			let task_halted = halted.clone();
+			// Hosts, which shouldn't be retried by --reconcile
+			let settled = Rc::new(RefCell::new(BTreeSet::new()));
+			let task_settled = settled.clone();
+			let task_pending = pending.clone();
+			let names = selected.iter().map(|h| h.name.clone()).collect::<Vec<_>>();
+			Schedule::new(selected)
+				.await?
+				.max_parallel(defaults.max_parallel)
+				.max_unavailable(&self.max_unavailable)
+				.spawn(&set, move |host| {
+					let span = info_span!("deploy", host = field::display(&host.name));
+					let run = task_run.clone();
+					let failures = failures.clone();
+					let halted = task_halted.clone();
+					let settled = task_settled.clone();
+					let pending = task_pending.clone();
+					async move {
+						let started = Instant::now();
+						let policy = match HostPolicy::for_host(&host).await {
+							Ok(policy) => policy,
+							Err(e) => {
+								error!("failed to read deploy policy: {e:#}");
+								HostPolicy::default()
+							}
+						};
+						let outcome = if run.cancel.is_cancelled() {
+							DeployOutcome::Cancelled
+						} else {
+							match deploy_host(&run, &host, &policy).await {
+								Ok(outcome) => outcome,
 								Err(e) => {
-									error!("upload failed: {e}");
-									return;
+									error!("failed to deploy host: {e:#}");
+									DeployOutcome::Failed
 								}
 							}
+						};
+						if outcome == DeployOutcome::Success {
+							run.state.clear_failure(&host.name);
+						} else {
+							run.state.record_failure(&host.name);
 						}
-					}
-					if let Err(e) = deploy_task(
-						self.action,
-						&host,
-						built,
-						if let Ok(v) = opts.action_attr(&host, "specialisation").await {
-							v
+						if matches!(outcome, DeployOutcome::Failed | DeployOutcome::RolledBack) {
+							if let Some(reason) = failures.record_failure(&host.name, &policy) {
+								error!("halting deployment: {reason}");
+								halted.borrow_mut().get_or_insert(reason);
+								run.cancel.cancel();
+							}
+						}
+						let is_settled =
+							matches!(outcome, DeployOutcome::Success | DeployOutcome::RolledBack);
+						if is_settled || deadline.is_none() {
+							pending.borrow_mut().remove(&host.name);
+							run.telemetry.record_outcome(&host.name, outcome.name());
+							run.notify
+								.host_outcome(&run.config, &run.run_id, &host.name, outcome.name())
+								.await;
 						} else {
-							error!("unreachable? failed to get specialization");
-							return;
-						},
-						self.disable_rollback,
-					)
-					.await
-					{
-						error!("activation failed: {e}");
+							pending.borrow_mut().insert(host.name.clone(), outcome);
+						}
+						run.summary
+							.record_outcome(&host.name, outcome.name(), started);
+						if let Some(state) = run.state.host(&host.name) {
+							if let Some(phase) = state.phase {
+								run.summary.record_phase(&host.name, phase.name());
+							}
+							if let Some(built) = state.built {
+								run.summary.record_new(&host.name, built);
+							}
+						}
+						if is_settled {
+							settled.borrow_mut().insert(host.name.clone());
+						}
+						outcome == DeployOutcome::Success
 					}
-				})
-				.instrument(span),
+					.instrument(span)
+				});
+			set.await;
+
+			let Some(deadline) = deadline else {
+				break;
+			};
+			if run.cancel.is_cancelled() || halted.borrow().is_some() {
+				break;
+			}
+			let remaining = names
+				.into_iter()
+				.filter(|h| !settled.borrow().contains(h))
+				.collect::<Vec<_>>();
+			if remaining.is_empty() {
+				info!("all hosts have converged");
+				break;
+			}
+			round += 1;
+			let delay = reconcile_delay(round);
+			let left = deadline.saturating_duration_since(Instant::now());
+			if left <= delay {
+				warn!(
+					"reconcile deadline has passed, {} hosts have not converged: {}",
+					remaining.len(),
+					remaining.join(", ")
+				);
+				break;
+			}
+			info!(
+				"{} hosts remaining: {}, retrying in {}s (round {round}, {}m left)",
+				remaining.len(),
+				remaining.join(", "),
+				delay.as_secs(),
+				left.as_secs() / 60
 			);
+			select! {
+				_ = sleep(delay) => {}
+				_ = run.cancel.cancelled() => break,
+			}
+			selected = config
+				.list_hosts()
+				.await?
+				.into_iter()
+				.filter(|h| remaining.contains(&h.name))
+				.collect();
+		}
+		for (host, outcome) in pending.take() {
+			run.telemetry.record_outcome(&host, outcome.name());
+			run.notify
+				.host_outcome(config, &run.run_id, &host, outcome.name())
+				.await;
+		}
+		interrupt.abort();
+		drop(cache);
+		if run.cancel.is_cancelled() {
+			warn!("deployment {} was cancelled", run.run_id);
+		}
+		run.summary.print();
+		run.notify.finished(config, &run.run_id, &run.summary).await;
+		if run.dry_activate {
+			print_dry_activations(&run.dry_activations.lock().unwrap());
+		}
+		if let Err(e) = run.telemetry.push(config, &self.telemetry).await {
+			warn!("failed to push deployment metrics: {e}");
+		}
+		if let Some(reason) = halted.borrow_mut().take() {
+			bail!("deployment was halted: {reason}");
+		}
+		let failed = run.summary.failed();
+		if !failed.is_empty() {
+			bail!("failed to deploy {}", failed.join(", "));
 		}
-		set.await;
 		Ok(())
 	}
 }
@@ -1,23 +1,327 @@
-use std::io::stdout;
+//! Shell completions.
+//!
+//! Static part is generated by clap, host, tag and secret names are completed by shell helpers,
+//! calling hidden `fleet complete <values>` command. As evaluating fleet config is slow, values are
+//! read from `.fleet/completions/<fleet>.json`, refreshed after successful commands, if fleet data
+//! or fleet.nix have changed, or the cache has expired.
 
-use clap::{Command, Parser};
+use std::{
+	collections::BTreeSet,
+	env::current_dir,
+	fs,
+	io::{stdout, Write as _},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use clap::{Command, Parser, ValueEnum};
 use clap_complete::Shell;
+use fleet_base::{datafile, host::Config, keys::IdentityStore, opts::FleetOpts, sealed};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tempfile::NamedTempFile;
+
+/// Cache is refreshed at least once per this duration, as hosts might be declared in the files
+/// imported by fleet.nix
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompletionValues {
+	Hosts,
+	Tags,
+	/// Host names and `@tag` selectors, accepted by --only/--skip
+	HostPatterns,
+	Secrets,
+}
+
+/// Arguments completed with dynamic values, keyed by the words preceding the completed one
+const DYNAMIC: &[(&str, CompletionValues)] = &[
+	("--only", CompletionValues::HostPatterns),
+	("--skip", CompletionValues::HostPatterns),
+	("ssh", CompletionValues::Hosts),
+	("test-vm", CompletionValues::Hosts),
+	("host rename", CompletionValues::Hosts),
+	("host remove", CompletionValues::Hosts),
+	("host trust", CompletionValues::Hosts),
+	("disko apply", CompletionValues::Hosts),
+	("secret reprovision", CompletionValues::Hosts),
+	("secret read-shared", CompletionValues::Secrets),
+	("secret update-shared", CompletionValues::Secrets),
+	("secret info", CompletionValues::Secrets),
+];
+
+#[derive(Serialize, Deserialize, Default)]
+struct CompletionCache {
+	hosts: Vec<String>,
+	tags: Vec<String>,
+	secrets: Vec<String>,
+	/// See [`source_hash`]
+	#[serde(default)]
+	source_hash: String,
+}
+
+fn cache_path(directory: &Path, fleet: &str) -> PathBuf {
+	directory.join(format!(".fleet/completions/{fleet}.json"))
+}
+
+fn load_cache(directory: &Path, fleet: &str) -> Option<CompletionCache> {
+	let data = fs::read(cache_path(directory, fleet)).ok()?;
+	serde_json::from_slice(&data).ok()
+}
+
+/// Without cache, hosts and secrets are read from the fleet data file, unless it is sealed.
+fn load_uncached(directory: &Path, fleet: &str) -> Result<CompletionCache> {
+	let is_sealed = sealed::is_sealed(directory, fleet);
+	if is_sealed {
+		return Ok(CompletionCache::default());
+	}
+	let raw = datafile::read_raw(directory, fleet, is_sealed)?;
+	let data = datafile::parse_value(&raw, is_sealed, &IdentityStore::new(None, None))?;
+	let names = |field: &str| -> Vec<String> {
+		data.get(field)
+			.and_then(|v| v.as_object())
+			.map(|o| o.keys().cloned().collect())
+			.unwrap_or_default()
+	};
+	Ok(CompletionCache {
+		hosts: names("hosts"),
+		tags: vec![],
+		secrets: names("sharedSecrets"),
+		source_hash: String::new(),
+	})
+}
+
+/// Hash of fleet data and fleet.nix contents. Data file is rewritten after every command,
+/// so its modification time can't be used.
+fn source_hash(directory: &Path, fleet: &str) -> String {
+	let is_sealed = sealed::is_sealed(directory, fleet);
+	let mut hasher = Sha256::new();
+	for path in [
+		datafile::data_path(directory, fleet, is_sealed),
+		directory.join("fleet.nix"),
+	] {
+		hasher.update(fs::read(path).unwrap_or_default());
+	}
+	format!("{:x}", hasher.finalize())
+}
+
+fn is_stale(directory: &Path, fleet: &str, source_hash: &str) -> bool {
+	let Ok(cached_at) = fs::metadata(cache_path(directory, fleet)).and_then(|m| m.modified())
+	else {
+		return true;
+	};
+	if SystemTime::now()
+		.duration_since(cached_at)
+		.map_or(true, |age| age > CACHE_TTL)
+	{
+		return true;
+	}
+	load_cache(directory, fleet).map_or(true, |cache| cache.source_hash != source_hash)
+}
+
+/// Updates completion cache, if it is outdated
+pub async fn refresh_cache(config: &Config) -> Result<()> {
+	let source_hash = source_hash(&config.directory, &config.fleet);
+	if !is_stale(&config.directory, &config.fleet, &source_hash) {
+		return Ok(());
+	}
+	let mut hosts = Vec::new();
+	let mut tags = BTreeSet::new();
+	for host in config.list_hosts().await? {
+		tags.extend(host.tags().await?);
+		hosts.push(host.name);
+	}
+	let mut secrets: BTreeSet<String> =
+		config.list_configured_shared().await?.into_iter().collect();
+	secrets.extend(config.list_shared());
+	{
+		let data = config.data();
+		for host_secrets in data.host_secrets.values() {
+			secrets.extend(host_secrets.keys().cloned());
+		}
+	}
+	let cache = CompletionCache {
+		hosts,
+		tags: tags.into_iter().collect(),
+		secrets: secrets.into_iter().collect(),
+		source_hash,
+	};
+
+	let path = cache_path(&config.directory, &config.fleet);
+	let dir = path.parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	let tmp = NamedTempFile::new_in(dir)?;
+	serde_json::to_writer(&tmp, &cache)?;
+	tmp.persist(path)?;
+	Ok(())
+}
 
+/// Prints dynamic values for shell helpers, one per line
 #[derive(Parser)]
 pub struct Complete {
-	/// For which shell to generate the completions
-	#[arg(long, short)]
-	shell: Shell,
+	#[clap(required_unless_present = "shell")]
+	values: Option<CompletionValues>,
+	/// Generate completions for the shell, alias of `fleet completions <shell>`,
+	/// kept for the existing packaging scripts
+	#[arg(long, short, conflicts_with = "values")]
+	shell: Option<Shell>,
 }
 
 impl Complete {
-	pub fn run(&self, mut command: Command) {
-		let Self { shell } = self;
+	pub fn run(&self, opts: &FleetOpts, command: Command) -> Result<()> {
+		if let Some(shell) = self.shell {
+			return Completions { shell }.run(command);
+		}
+		let values = self.values.expect("required unless --shell is set");
+		let directory = current_dir()?;
+		let cache = match load_cache(&directory, &opts.fleet) {
+			Some(cache) => cache,
+			None => load_uncached(&directory, &opts.fleet)?,
+		};
+		let values: Vec<String> = match values {
+			CompletionValues::Hosts => cache.hosts,
+			CompletionValues::Tags => cache.tags,
+			CompletionValues::HostPatterns => cache
+				.hosts
+				.into_iter()
+				.chain(cache.tags.iter().map(|t| format!("@{t}")))
+				.collect(),
+			CompletionValues::Secrets => cache.secrets,
+		};
+		let mut out = stdout().lock();
+		for value in values {
+			writeln!(out, "{value}")?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Parser)]
+pub struct Completions {
+	/// For which shell to generate the completions
+	shell: Shell,
+}
 
+impl Completions {
+	pub fn run(&self, mut command: Command) -> Result<()> {
 		let bin_name = command
 			.get_bin_name()
 			.unwrap_or_else(|| command.get_name())
 			.to_owned();
-		clap_complete::generate(*shell, &mut command, &bin_name, &mut stdout());
+		let mut script = Vec::new();
+		clap_complete::generate(self.shell, &mut command, &bin_name, &mut script);
+		let script = String::from_utf8(script).context("completion script is not utf-8")?;
+		let script = with_dynamic(self.shell, &bin_name, script);
+		stdout().lock().write_all(script.as_bytes())?;
+		Ok(())
+	}
+}
+
+fn value_name(values: CompletionValues) -> String {
+	values
+		.to_possible_value()
+		.expect("no skipped values")
+		.get_name()
+		.to_owned()
+}
+
+/// Shell `case` arms for the words preceding completed one, grouped by word count
+fn dynamic_arms(words: usize) -> Vec<(String, String)> {
+	DYNAMIC
+		.iter()
+		.filter(|(path, _)| path.split(' ').count() == words)
+		.map(|(path, values)| (path.to_string(), value_name(*values)))
+		.collect()
+}
+
+/// Adds dynamic value helpers to the clap-generated script
+fn with_dynamic(shell: Shell, bin: &str, script: String) -> String {
+	match shell {
+		Shell::Bash => {
+			let mut helper = format!(
+				"\n_{bin}_dynamic() {{\n\tlocal cur=\"${{COMP_WORDS[COMP_CWORD]}}\" values=\"\"\n\tif (( COMP_CWORD >= 2 )); then\n\t\tcase \"${{COMP_WORDS[COMP_CWORD-2]}} ${{COMP_WORDS[COMP_CWORD-1]}}\" in\n"
+			);
+			for (path, values) in dynamic_arms(2) {
+				helper.push_str(&format!("\t\t\t\"{path}\") values={values} ;;\n"));
+			}
+			helper.push_str("\t\tesac\n\tfi\n\tif [[ -z \"$values\" ]]; then\n\t\tcase \"${COMP_WORDS[COMP_CWORD-1]}\" in\n");
+			for (path, values) in dynamic_arms(1) {
+				helper.push_str(&format!("\t\t\t\"{path}\") values={values} ;;\n"));
+			}
+			helper.push_str(&format!(
+				"\t\tesac\n\tfi\n\tif [[ -n \"$values\" ]]; then\n\t\tCOMPREPLY=($(compgen -W \"$({bin} complete \"$values\" 2>/dev/null)\" -- \"$cur\"))\n\t\treturn 0\n\tfi\n\t_{bin} \"$@\"\n}}\ncomplete -F _{bin}_dynamic -o nosort -o bashdefault -o default {bin}\n"
+			));
+			script + &helper
+		}
+		Shell::Zsh => {
+			let mut helper = format!(
+				"_{bin}_dynamic() {{\n\tlocal values\n\tcase \"${{words[CURRENT-2]}} ${{words[CURRENT-1]}}\" in\n"
+			);
+			for (path, values) in dynamic_arms(2) {
+				helper.push_str(&format!("\t\t\"{path}\") values={values} ;;\n"));
+			}
+			helper.push_str(
+				"\tesac\n\tif [[ -z $values ]]; then\n\t\tcase \"${words[CURRENT-1]}\" in\n",
+			);
+			for (path, values) in dynamic_arms(1) {
+				helper.push_str(&format!("\t\t\t\"{path}\") values={values} ;;\n"));
+			}
+			helper.push_str(&format!(
+				"\t\tesac\n\tfi\n\tif [[ -n $values ]]; then\n\t\tlocal -a candidates=(${{(f)\"$({bin} complete $values 2>/dev/null)\"}})\n\t\tcompadd -a candidates\n\t\treturn\n\tfi\n\t_{bin} \"$@\"\n}}\n\n"
+			));
+			// Generated script ends with dispatch to the completion function, which should
+			// be replaced with the dynamic one, defined before the dispatch
+			let dispatch = format!("if [ \"$funcstack[1]\" = \"_{bin}\" ]; then");
+			let Some(at) = script.rfind(&dispatch) else {
+				return script;
+			};
+			let (head, tail) = script.split_at(at);
+			let tail = tail
+				.replace(&format!("_{bin} \"$@\""), &format!("_{bin}_dynamic \"$@\""))
+				.replace(
+					&format!("compdef _{bin} {bin}"),
+					&format!("compdef _{bin}_dynamic {bin}"),
+				);
+			format!("{head}{helper}{tail}")
+		}
+		Shell::Fish => {
+			let mut helper = format!(
+				"\nfunction __{bin}_dynamic_values\n\tset -l words (commandline -opc)\n\tswitch \"$words[-2] $words[-1]\"\n"
+			);
+			for (path, values) in dynamic_arms(2) {
+				helper.push_str(&format!(
+					"\t\tcase \"{path}\"\n\t\t\techo {values}; return 0\n"
+				));
+			}
+			helper.push_str("\tend\n\tswitch \"$words[-1]\"\n");
+			for (path, values) in dynamic_arms(1) {
+				helper.push_str(&format!(
+					"\t\tcase \"{path}\"\n\t\t\techo {values}; return 0\n"
+				));
+			}
+			helper.push_str(&format!(
+				"\tend\n\treturn 1\nend\ncomplete -c {bin} -f -n '__{bin}_dynamic_values >/dev/null' -a '({bin} complete (__{bin}_dynamic_values) 2>/dev/null)'\n"
+			));
+			script + &helper
+		}
+		_ => script,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn zsh_dispatch() {
+		let script = "#compdef fleet\n_fleet() {\n}\n\nif [ \"$funcstack[1]\" = \"_fleet\" ]; then\n    _fleet \"$@\"\nelse\n    compdef _fleet fleet\nfi\n";
+		let out = with_dynamic(Shell::Zsh, "fleet", script.to_owned());
+		let dispatch = out.rfind("if [ \"$funcstack").unwrap();
+		assert!(out.find("_fleet_dynamic() {").unwrap() < dispatch);
+		assert!(out
+			.ends_with("    _fleet_dynamic \"$@\"\nelse\n    compdef _fleet_dynamic fleet\nfi\n"));
+		assert!(out.contains("\t\t\"host trust\") values=hosts ;;\n"));
+		assert!(out.contains("\t\t\t\"--only\") values=host-patterns ;;\n"));
 	}
 }
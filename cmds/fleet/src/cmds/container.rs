@@ -0,0 +1,199 @@
+//! Container targets: images are built from `buildSystems.ociImage.<name>`, pushed to the registry
+//! and optionally rolled out on a fleet host running docker/podman. See containers.nix
+
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use fleet_base::host::Config;
+use nix_eval::{nix_go, nix_go_json};
+use serde::Deserialize;
+use tracing::{error, field, info, info_span, Instrument as _};
+
+use super::build_systems::link_result;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum PushTool {
+	Skopeo,
+	Crane,
+}
+
+#[derive(Deserialize)]
+struct Rollout {
+	host: String,
+	command: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContainerTarget {
+	repository: String,
+	tag: Option<String>,
+	push_tool: PushTool,
+	rollout: Option<Rollout>,
+}
+
+#[derive(Parser)]
+pub enum Container {
+	/// Build container images, and link them to the working directory as `oci-<name>`
+	Build {
+		/// Containers to build, all if not specified
+		names: Vec<String>,
+	},
+	/// Build container images, push them to the registry, and roll them out
+	Deploy {
+		/// Containers to deploy, all if not specified
+		names: Vec<String>,
+		/// Only push images, without running rollout commands
+		#[clap(long)]
+		no_rollout: bool,
+	},
+}
+
+async fn load_targets(
+	config: &Config,
+	names: &[String],
+) -> Result<BTreeMap<String, ContainerTarget>> {
+	let config_field = &config.config_field;
+	let mut targets: BTreeMap<String, ContainerTarget> = nix_go_json!(config_field.containers);
+	if names.is_empty() {
+		return Ok(targets);
+	}
+	let mut selected = BTreeMap::new();
+	for name in names {
+		let Some(target) = targets.remove(name) else {
+			bail!("unknown container {name}, it should be declared in `containers`");
+		};
+		selected.insert(name.clone(), target);
+	}
+	Ok(selected)
+}
+
+async fn build_image(config: &Config, name: &str) -> Result<PathBuf> {
+	let config = &config;
+	config
+		.eval
+		.run(|config_field| async move {
+			info!("building");
+			let drv = nix_go!(config_field.buildSystems.ociImage[{ name }]);
+			let outputs = drv.build().await?;
+			outputs
+				.get("out")
+				.cloned()
+				.context("image derivation has no out output")
+		})
+		.await
+}
+
+/// Image tag, derived from the store path hash, unless declared
+fn image_tag(target: &ContainerTarget, image: &Path) -> Result<String> {
+	if let Some(tag) = &target.tag {
+		return Ok(tag.clone());
+	}
+	let name = image
+		.file_name()
+		.and_then(|n| n.to_str())
+		.context("bad image path")?;
+	let (hash, _) = name.split_once('-').context("image is not a store path")?;
+	Ok(hash.to_owned())
+}
+
+/// `buildImage`/`buildLayeredImage` produce image archive, `streamLayeredImage` produces
+/// a script, which writes it to stdout
+fn is_archive(image: &Path) -> bool {
+	image
+		.file_name()
+		.and_then(|n| n.to_str())
+		.is_some_and(|n| n.ends_with(".tar") || n.ends_with(".tar.gz"))
+}
+
+async fn push_image(
+	config: &Config,
+	target: &ContainerTarget,
+	image: &Path,
+	reference: &str,
+) -> Result<()> {
+	let dir = tempfile::tempdir()?;
+	let archive = if is_archive(image) {
+		image.to_owned()
+	} else {
+		let archive = dir.path().join("image.tar");
+		let mut cmd = config.local_host().cmd("sh").await?;
+		cmd.arg("-c").arg(format!(
+			"{} > {}",
+			shlex::try_quote(image.to_str().context("non-utf8 image path")?)?,
+			shlex::try_quote(archive.to_str().context("non-utf8 temp dir")?)?,
+		));
+		cmd.run().await.context("failed to stream image")?;
+		archive
+	};
+	info!("pushing {reference}");
+	match target.push_tool {
+		PushTool::Skopeo => {
+			let mut cmd = config.local_host().cmd("skopeo").await?;
+			cmd.arg("copy")
+				.arg(format!("docker-archive:{}", archive.display()))
+				.arg(format!("docker://{reference}"));
+			cmd.run().await
+		}
+		PushTool::Crane => {
+			let mut cmd = config.local_host().cmd("crane").await?;
+			cmd.arg("push").arg(&archive).arg(reference);
+			cmd.run().await
+		}
+	}
+	.context("failed to push image")
+}
+
+async fn rollout(config: &Config, rollout: &Rollout, reference: &str) -> Result<()> {
+	let host = config.host(&rollout.host).await?;
+	info!("rolling out on {}", rollout.host);
+	let mut cmd = host.cmd("sh").await?;
+	cmd.arg("-c").arg(&rollout.command).env("IMAGE", reference);
+	cmd.sudo().run().await.context("rollout command failed")
+}
+
+impl Container {
+	pub async fn run(self, config: &Config) -> Result<()> {
+		match self {
+			Container::Build { names } => {
+				for name in load_targets(config, &names).await?.into_keys() {
+					let span = info_span!("container", name = field::display(&name));
+					let image = build_image(config, &name).instrument(span).await?;
+					link_result(&format!("oci-{name}"), &image)?;
+				}
+			}
+			Container::Deploy { names, no_rollout } => {
+				let mut failed = Vec::new();
+				for (name, target) in load_targets(config, &names).await? {
+					let span = info_span!("container", name = field::display(&name));
+					let result: Result<()> = async {
+						let image = build_image(config, &name).await?;
+						let reference =
+							format!("{}:{}", target.repository, image_tag(&target, &image)?);
+						push_image(config, &target, &image, &reference).await?;
+						if let Some(r) = target.rollout.as_ref().filter(|_| !no_rollout) {
+							rollout(config, r, &reference).await?;
+						}
+						info!("deployed {reference}");
+						Ok(())
+					}
+					.instrument(span)
+					.await;
+					if let Err(e) = result {
+						error!("failed to deploy container {name}: {e:#}");
+						failed.push(name);
+					}
+				}
+				if !failed.is_empty() {
+					bail!("failed to deploy containers: {}", failed.join(", "));
+				}
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,133 @@
+use std::env::current_dir;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use fleet_base::{
+	datafile,
+	history::{self, Kind, Revision},
+	keys::IdentityStore,
+	opts::FleetOpts,
+	sealed,
+};
+use serde_json::Value;
+use tracing::{info, warn};
+
+#[derive(Parser)]
+pub enum Data {
+	/// Revisions of fleet data, with changes made in each of them, newest first
+	Log {
+		/// How many revisions to show
+		#[clap(long, default_value = "20")]
+		limit: usize,
+	},
+	/// Restore fleet data to the previous, or to the specified revision,
+	/// restore is recorded as a new revision, so it can be undone too
+	Undo {
+		/// Revision id, as shown by `fleet data log`
+		#[clap(long)]
+		to: Option<u64>,
+	},
+}
+
+/// Changes between the revisions, as much as can be decrypted
+fn describe(old: Option<&Value>, new: Option<&Value>) -> Vec<String> {
+	match (old, new) {
+		(_, None) => vec!["<can't be read>".to_owned()],
+		(None, Some(_)) => vec!["initial revision".to_owned()],
+		(Some(old), Some(new)) => {
+			let changes = history::diff(old, new);
+			if changes.is_empty() {
+				vec!["formatting only".to_owned()]
+			} else {
+				changes
+			}
+		}
+	}
+}
+
+fn parse(revision: &Revision, identities: &IdentityStore) -> Result<Value> {
+	let raw = revision.read()?;
+	datafile::parse_value(&raw, revision.kind.is_sealed(), identities)
+}
+
+impl Data {
+	/// Runs without evaluating fleet configuration, as evaluation saves fleet data on exit
+	pub fn run(&self, opts: &FleetOpts) -> Result<()> {
+		let directory = current_dir()?;
+		let identities = IdentityStore::new(opts.identity.clone(), opts.keyring.clone());
+		match self {
+			Data::Log { limit } => {
+				let revisions = history::list(&directory, &opts.fleet)?;
+				if revisions.is_empty() {
+					info!("fleet data history is empty, it is recorded on every change");
+					return Ok(());
+				}
+				let skip = revisions.len().saturating_sub(*limit + 1);
+				let mut previous = None;
+				let mut entries = Vec::new();
+				for (i, revision) in revisions.iter().enumerate().skip(skip) {
+					let value = match parse(revision, &identities) {
+						Ok(v) => Some(v),
+						Err(e) => {
+							warn!("failed to read revision {}: {e:#}", revision.id);
+							None
+						}
+					};
+					// The oldest shown revision is only used as the base for the next one
+					if i != skip || skip == 0 {
+						let changes = describe(previous.as_ref(), value.as_ref());
+						entries.push((revision, changes));
+					}
+					previous = value;
+				}
+				for (revision, changes) in entries.into_iter().rev() {
+					println!(
+						"{} {} ({:?})",
+						revision.id,
+						revision.time.format("%Y-%m-%d %H:%M:%S"),
+						revision.kind
+					);
+					for change in changes {
+						println!("  {change}");
+					}
+				}
+			}
+			Data::Undo { to } => {
+				let _lock = datafile::lock(&directory)?;
+				let is_sealed = sealed::is_sealed(&directory, &opts.fleet);
+				let current = datafile::read_raw(&directory, &opts.fleet, is_sealed)?;
+				// Records the current state, if it was changed outside of fleet
+				history::record(&directory, &opts.fleet, is_sealed, &current, &current)?;
+
+				let revisions = history::list(&directory, &opts.fleet)?;
+				let target = history::undo_target(&revisions, *to)?;
+				let kind = Kind::of(&current, is_sealed);
+				if target.kind != kind {
+					bail!(
+						"revision {} is stored as {:?}, while fleet data is currently stored as {kind:?}, switch the storage mode first",
+						target.id,
+						target.kind
+					);
+				}
+				let raw = target.read()?;
+				if raw == current {
+					info!("fleet data is already at revision {}", target.id);
+					return Ok(());
+				}
+				let changes = describe(
+					datafile::parse_value(&current, is_sealed, &identities)
+						.ok()
+						.as_ref(),
+					parse(target, &identities).ok().as_ref(),
+				);
+				datafile::write(&directory, &opts.fleet, is_sealed, &raw)?;
+				history::record(&directory, &opts.fleet, is_sealed, &current, &raw)?;
+				info!("fleet data is restored to revision {}", target.id);
+				for change in changes {
+					info!("  {change}");
+				}
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,110 @@
+//! Disk layout management with [disko](https://github.com/nix-community/disko).
+//!
+//! Partitioning script is built from the host configuration, copied to the host and executed
+//! there, i.e from the installer, before `fleet init-host` or a manual install.
+
+use std::io::{self, stdin, BufRead as _, IsTerminal as _, Write as _};
+
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use fleet_base::host::{Config, Platform};
+use nix_eval::{nix_go, nix_go_json};
+use tracing::{info, info_span, warn, Instrument as _};
+
+use super::build_systems::build_task;
+
+#[derive(Parser)]
+pub enum Disko {
+	/// Destroy, partition, format and mount disks of the host, according to its disko configuration.
+	///
+	/// Without --destructive, only builds the script and lists disks which would be wiped.
+	Apply {
+		host: String,
+		/// Actually run the script, all data on the listed disks will be lost
+		#[clap(long)]
+		destructive: bool,
+		/// Do not ask for confirmation
+		#[clap(long)]
+		yes: bool,
+	},
+}
+
+fn confirm(host: &str, disks: &[String]) -> Result<()> {
+	ensure!(
+		stdin().is_terminal(),
+		"stdin is not a tty, pass --yes to apply without confirmation"
+	);
+	eprintln!(
+		"All data on {} of host {host} will be destroyed.",
+		disks.join(", ")
+	);
+	eprint!("Type the host name again to continue: ");
+	io::stderr().flush()?;
+	let mut line = String::new();
+	stdin().lock().read_line(&mut line)?;
+	ensure!(line.trim() == host, "confirmation mismatch, aborting");
+	Ok(())
+}
+
+impl Disko {
+	pub async fn run(self, config: &Config) -> Result<()> {
+		match self {
+			Disko::Apply {
+				host: name,
+				destructive,
+				yes,
+			} => {
+				let host = config.host(&name).await?;
+				ensure!(
+					host.platform().await? == Platform::Nixos,
+					"only nixos hosts have disko configuration"
+				);
+				let nixos = host.nixos_config().await?;
+				let disk_field = nix_go!(nixos.disko.devices.disk);
+				let mut disks = Vec::new();
+				for disk in disk_field
+					.list_fields()
+					.await
+					.context("failed to evaluate disko devices, is disko module imported?")?
+				{
+					let device: String = nix_go_json!(disk_field[{ disk }].device);
+					disks.push(device);
+				}
+				ensure!(
+					!disks.is_empty(),
+					"host {name} has no disko disks configured"
+				);
+
+				let script = build_task(config.clone(), name.clone(), "diskoScript", false)
+					.instrument(info_span!("disko"))
+					.await
+					.context("failed to build disko script")?;
+				if !destructive {
+					info!("disko script: {}", script.display());
+					for disk in &disks {
+						info!("would wipe: {disk}");
+					}
+					warn!("pass --destructive to run the script");
+					return Ok(());
+				}
+				if !yes {
+					confirm(&name, &disks)?;
+				}
+
+				let script = host
+					.remote_derivation(&script, false)
+					.instrument(info_span!("copy"))
+					.await?;
+				info!("partitioning");
+				let cmd = host.cmd(&script).await?;
+				cmd.sudo()
+					.run()
+					.instrument(info_span!("partition"))
+					.await
+					.context("disko script failed")?;
+				info!("disks of {name} are partitioned and mounted at /mnt");
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,325 @@
+//! Diagnostics of the local environment and fleet configuration, every problem is reported
+//! together with the suggested fix.
+//!
+//! Environment is checked before the config is evaluated, so that evaluation failures caused
+//! by the environment are still explained.
+
+use std::{ffi::OsString, path::Path, process::Command, time::Duration};
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use clap::Parser;
+use fleet_base::{
+	attribution::{self, AllowedSigners, Verification},
+	fleetdata::FleetData,
+	host::{Config, ConfigHost},
+	keys::IdentityStore,
+	opts::FleetOpts,
+	transport::parse_nix_version,
+};
+use futures::future::join_all;
+use tracing::{error, info, warn};
+
+/// Older versions are not supported
+const MIN_NIX_VERSION: (u32, u32) = (2, 20);
+const REQUIRED_FEATURES: &[&str] = &["nix-command", "flakes"];
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Parser)]
+pub struct Doctor {
+	/// Don't check host reachability
+	#[clap(long)]
+	offline: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Severity {
+	Warning,
+	Error,
+}
+
+struct Finding {
+	severity: Severity,
+	problem: String,
+	fix: String,
+}
+
+#[derive(Default)]
+struct Report {
+	findings: Vec<Finding>,
+}
+
+impl Report {
+	fn warning(&mut self, problem: impl Into<String>, fix: impl Into<String>) {
+		self.findings.push(Finding {
+			severity: Severity::Warning,
+			problem: problem.into(),
+			fix: fix.into(),
+		});
+	}
+	fn error(&mut self, problem: impl Into<String>, fix: impl Into<String>) {
+		self.findings.push(Finding {
+			severity: Severity::Error,
+			problem: problem.into(),
+			fix: fix.into(),
+		});
+	}
+
+	fn finish(self) -> Result<()> {
+		let errors = self
+			.findings
+			.iter()
+			.filter(|f| f.severity == Severity::Error)
+			.count();
+		for finding in &self.findings {
+			match finding.severity {
+				Severity::Warning => warn!("{}\n  fix: {}", finding.problem, finding.fix),
+				Severity::Error => error!("{}\n  fix: {}", finding.problem, finding.fix),
+			}
+		}
+		if errors != 0 {
+			bail!("{errors} problems found");
+		}
+		if self.findings.is_empty() {
+			info!("no problems found");
+		} else {
+			info!("{} warnings, no errors found", self.findings.len());
+		}
+		Ok(())
+	}
+}
+
+fn output(cmd: &str, args: &[&str]) -> Option<std::process::Output> {
+	Command::new(cmd).args(args).output().ok()
+}
+
+fn check_nix(report: &mut Report) {
+	let Some(version) = output("nix", &["--version"]) else {
+		report.error(
+			"nix is not installed, or not in PATH",
+			"install nix, see https://nixos.org/download",
+		);
+		return;
+	};
+	let version = String::from_utf8_lossy(&version.stdout);
+	match parse_nix_version(&version) {
+		Some(parsed) if parsed < MIN_NIX_VERSION => report.error(
+			format!("nix {} is too old", version.trim()),
+			format!(
+				"upgrade nix to {}.{} or newer",
+				MIN_NIX_VERSION.0, MIN_NIX_VERSION.1
+			),
+		),
+		Some(_) => {}
+		None => report.warning(
+			format!("failed to parse nix version: {version:?}"),
+			"make sure `nix` is the nix cli, and not a wrapper",
+		),
+	}
+
+	// Fails without nix-command
+	let enabled = output("nix", &["config", "show", "experimental-features"])
+		.filter(|o| o.status.success())
+		.map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+		.unwrap_or_default();
+	let enabled: Vec<&str> = enabled.split_whitespace().collect();
+	let missing: Vec<&str> = REQUIRED_FEATURES
+		.iter()
+		.copied()
+		.filter(|f| !enabled.contains(f))
+		.collect();
+	if !missing.is_empty() {
+		report.error(
+			format!(
+				"required nix experimental features are not enabled: {}",
+				missing.join(", ")
+			),
+			format!(
+				"add `extra-experimental-features = {}` to ~/.config/nix/nix.conf",
+				REQUIRED_FEATURES.join(" ")
+			),
+		);
+	}
+}
+
+fn check_ssh_agent(report: &mut Report) {
+	if std::env::var_os("SSH_AUTH_SOCK").is_none() {
+		report.warning(
+			"ssh agent is not running, ssh keys will be asked for passphrase on every connection",
+			"start ssh-agent, and add your key with `ssh-add`",
+		);
+		return;
+	}
+	// 1 means agent has no identities, 2 means it is unreachable
+	match output("ssh-add", &["-l"]).map(|o| o.status.code()) {
+		Some(Some(0)) => {}
+		Some(Some(1)) => report.warning(
+			"ssh agent has no keys",
+			"add the key used to access hosts with `ssh-add`",
+		),
+		_ => report.warning(
+			"ssh agent is not reachable",
+			"check SSH_AUTH_SOCK, or restart ssh-agent",
+		),
+	}
+}
+
+fn check_identity(report: &mut Report, identities: &IdentityStore) {
+	if let Err(e) = identities.identities() {
+		report.warning(
+			format!("admin identity is not available: {e:#}"),
+			"pass --identity/FLEET_IDENTITY, or create a keyring with `age -p` at ~/.config/fleet/keyring.age",
+		);
+	}
+}
+
+async fn check_reachable(host: &ConfigHost) -> Result<()> {
+	let cmd = host.cmd("true").await?;
+	match tokio::time::timeout(CONNECT_TIMEOUT, cmd.run()).await {
+		Ok(result) => result,
+		Err(_) => bail!("connection timed out"),
+	}
+}
+
+/// Entries, which were modified bypassing fleet, see [`attribution`]
+fn check_signatures(report: &mut Report, data: &FleetData, allowed_signers: &AllowedSigners) {
+	let verified = attribution::verify_data(data, allowed_signers, None);
+	let mut unsigned = 0;
+	for (entry, verification) in verified {
+		match verification {
+			Verification::Valid => {}
+			Verification::Unsigned => unsigned += 1,
+			Verification::Invalid(e) => report.error(
+				format!("{entry} has invalid signature, it was modified bypassing fleet: {e}"),
+				"review the change in `fleet data log`, restore it with `fleet data undo`",
+			),
+		}
+	}
+	if unsigned != 0 {
+		report.warning(
+			format!("{unsigned} fleet data entries are not signed by their authors"),
+			"set FLEET_AUDIT_KEY, entries are signed when they are modified",
+		);
+	}
+}
+
+async fn check_config(
+	report: &mut Report,
+	config: &Config,
+	opts: &FleetOpts,
+	offline: bool,
+) -> Result<()> {
+	let mut hosts = Vec::new();
+	for host in config.list_hosts().await? {
+		if !opts.should_skip(&host).await? {
+			hosts.push(host);
+		}
+	}
+
+	for host in &hosts {
+		if host.local {
+			continue;
+		}
+		if config.cached_key(&host.name).is_none() {
+			report.warning(
+				format!("host {} has no encryption key in fleet data", host.name),
+				"run `fleet secret force-keys`",
+			);
+		}
+		if config.trusted_host_keys(&host.name).is_empty() {
+			report.warning(
+				format!("host {} has no trusted ssh host keys", host.name),
+				format!("run `fleet host trust {}`", host.name),
+			);
+		}
+	}
+
+	let now = Utc::now();
+	{
+		let data = config.data();
+		for (name, secret) in &data.shared_secrets {
+			if secret.owners.is_empty() {
+				report.warning(
+					format!("shared secret {name} has no owners"),
+					"set its owners in the fleet config and run `fleet secret regenerate`, or remove it from fleet data",
+				);
+			}
+			if secret.secret.expires_at.is_some_and(|e| e < now) {
+				report.error(
+					format!("shared secret {name} has expired"),
+					"run `fleet secret regenerate`",
+				);
+			}
+		}
+		for (host, secrets) in &data.host_secrets {
+			for (name, secret) in secrets {
+				if secret.expires_at.is_some_and(|e| e < now) {
+					report.error(
+						format!("secret {name} of host {host} has expired"),
+						"run `fleet secret regenerate`",
+					);
+				}
+			}
+		}
+		check_signatures(report, &data, &config.allowed_signers);
+	}
+
+	if !offline {
+		let checks = hosts
+			.iter()
+			.filter(|h| !h.local)
+			.map(|h| async move { (h, check_reachable(h).await) });
+		for (host, result) in join_all(checks).await {
+			if let Err(e) = result {
+				report.error(
+					format!("host {} is unreachable: {e:#}", host.name),
+					format!(
+						"check the network and `hosts.{0}.ssh` settings, `fleet ssh {0}` shows the ssh error",
+						host.name
+					),
+				);
+			}
+		}
+	}
+	Ok(())
+}
+
+impl Doctor {
+	/// Config is built here, as its evaluation failure is a finding too
+	pub async fn run(&self, opts: &FleetOpts, nix_args: Vec<OsString>) -> Result<()> {
+		let mut report = Report::default();
+		if !Path::new("flake.nix").exists() {
+			report.error(
+				"no flake.nix in the current directory",
+				"run fleet from the root of the fleet project",
+			);
+			return report.finish();
+		}
+		check_nix(&mut report);
+		check_ssh_agent(&mut report);
+
+		match opts.build(nix_args, vec![]).await {
+			Ok(config) => {
+				check_identity(&mut report, &config.identities);
+				// Host listing fails on evaluation errors, everything found before it is still reported
+				if let Err(e) = check_config(&mut report, &config, opts, self.offline).await {
+					report.error(
+						format!("failed to check fleet config: {e:#}"),
+						"fix the evaluation error, `NIX_ARGS=--show-trace` shows the full trace",
+					);
+				}
+			}
+			Err(e) => {
+				check_identity(
+					&mut report,
+					&IdentityStore::new(opts.identity.clone(), opts.keyring.clone()),
+				);
+				report.error(
+					format!("failed to evaluate fleet config: {e:#}"),
+					"fix the evaluation error, `NIX_ARGS=--show-trace` shows the full trace",
+				);
+			}
+		}
+		report.finish()
+	}
+}
@@ -0,0 +1,271 @@
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, stdin, BufRead, IsTerminal, Read, Write},
+	os::unix::fs::FileTypeExt,
+	path::{Path, PathBuf},
+	process::{Child, Command, Stdio},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::Parser;
+use fleet_base::host::Config;
+use sha2::{Digest, Sha256};
+use tracing::{info, info_span, warn};
+
+use super::build_systems::build_task;
+
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const PROGRESS_EVERY: u64 = 256 * 1024 * 1024;
+
+#[derive(Parser)]
+pub struct Flash {
+	/// Host, which image should be written
+	host: String,
+	/// Block device to write image to, i.e /dev/sdb
+	device: PathBuf,
+	/// Image attribute to build, "sdImage" or "isoImage"
+	#[clap(long, default_value = "sdImage")]
+	build_attr: String,
+	/// Do not ask for confirmation before overwriting the device
+	#[clap(long)]
+	yes: bool,
+	/// Skip reading image back after write
+	#[clap(long)]
+	no_verify: bool,
+}
+
+struct DeviceInfo {
+	size: u64,
+	model: String,
+	removable: bool,
+}
+
+fn sysfs_read(name: &str, file: &str) -> Option<String> {
+	fs::read_to_string(format!("/sys/class/block/{name}/{file}"))
+		.ok()
+		.map(|v| v.trim().to_owned())
+}
+
+fn device_info(device: &Path) -> Result<DeviceInfo> {
+	let meta = fs::metadata(device).with_context(|| format!("failed to stat {device:?}"))?;
+	ensure!(
+		meta.file_type().is_block_device(),
+		"{device:?} is not a block device"
+	);
+	let canonical = fs::canonicalize(device)?;
+	let name = canonical
+		.file_name()
+		.and_then(|n| n.to_str())
+		.ok_or_else(|| anyhow!("bad device name"))?;
+	ensure!(
+		sysfs_read(name, "partition").is_none(),
+		"{device:?} is a partition, whole device should be specified"
+	);
+	let sectors: u64 = sysfs_read(name, "size")
+		.ok_or_else(|| anyhow!("failed to read device size"))?
+		.parse()
+		.context("bad device size")?;
+	Ok(DeviceInfo {
+		// sysfs size is always in 512 byte sectors
+		size: sectors * 512,
+		model: sysfs_read(name, "device/model").unwrap_or_else(|| "<unknown model>".to_owned()),
+		removable: sysfs_read(name, "removable").as_deref() == Some("1"),
+	})
+}
+
+fn ensure_not_mounted(device: &Path) -> Result<()> {
+	let canonical = fs::canonicalize(device)?;
+	let canonical = canonical.to_string_lossy();
+	let mounts = fs::read_to_string("/proc/mounts").context("failed to read mounts")?;
+	for line in mounts.lines() {
+		let Some(source) = line.split_whitespace().next() else {
+			continue;
+		};
+		if source.starts_with(canonical.as_ref()) {
+			bail!("{source} is mounted, unmount it before flashing");
+		}
+	}
+	Ok(())
+}
+
+/// Finds image file in the output of sdImage/isoImage build
+pub(crate) fn find_image(built: &Path) -> Result<PathBuf> {
+	for dir in ["sd-image", "iso"] {
+		let dir = built.join(dir);
+		let Ok(entries) = fs::read_dir(&dir) else {
+			continue;
+		};
+		for entry in entries {
+			let path = entry?.path();
+			let name = path.to_string_lossy();
+			if name.ends_with(".img") || name.ends_with(".img.zst") || name.ends_with(".iso") {
+				return Ok(path);
+			}
+		}
+	}
+	bail!("no image found in {built:?}")
+}
+
+fn confirm(device: &Path, info: &DeviceInfo) -> Result<()> {
+	ensure!(
+		stdin().is_terminal(),
+		"stdin is not a tty, pass --yes to flash without confirmation"
+	);
+	eprintln!(
+		"All data on {} ({}, {} bytes{}) will be destroyed.",
+		device.display(),
+		info.model,
+		info.size,
+		if info.removable {
+			", removable"
+		} else {
+			", NOT REMOVABLE"
+		}
+	);
+	eprint!("Type the device path again to continue: ");
+	io::stderr().flush()?;
+	let mut line = String::new();
+	stdin().lock().read_line(&mut line)?;
+	ensure!(
+		Path::new(line.trim()) == device,
+		"confirmation mismatch, aborting"
+	);
+	Ok(())
+}
+
+struct Image {
+	reader: Box<dyn Read + Send>,
+	/// Size is unknown for compressed images
+	size: Option<u64>,
+	decompressor: Option<Child>,
+}
+
+fn open_image(image: &Path) -> Result<Image> {
+	if image.extension().is_some_and(|e| e == "zst") {
+		let mut child = Command::new("zstd")
+			.arg("-dc")
+			.arg(image)
+			.stdout(Stdio::piped())
+			.spawn()
+			.context("failed to spawn zstd for image decompression")?;
+		let stdout = child.stdout.take().expect("piped");
+		Ok(Image {
+			reader: Box::new(stdout),
+			size: None,
+			decompressor: Some(child),
+		})
+	} else {
+		let file = File::open(image)?;
+		let size = file.metadata()?.len();
+		Ok(Image {
+			reader: Box::new(file),
+			size: Some(size),
+			decompressor: None,
+		})
+	}
+}
+
+/// Returns written byte count and its hash
+fn write_image(mut input: impl Read, device: &Path, device_size: u64) -> Result<(u64, Vec<u8>)> {
+	let mut out = OpenOptions::new()
+		.write(true)
+		.open(device)
+		.with_context(|| format!("failed to open {device:?} for writing, do you have access?"))?;
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0; CHUNK_SIZE];
+	let mut written = 0u64;
+	let mut next_report = PROGRESS_EVERY;
+	loop {
+		let read = input.read(&mut buf)?;
+		if read == 0 {
+			break;
+		}
+		ensure!(
+			written + read as u64 <= device_size,
+			"image doesn't fit on the device"
+		);
+		out.write_all(&buf[..read])?;
+		hasher.update(&buf[..read]);
+		written += read as u64;
+		if written >= next_report {
+			info!("written {} MiB", written / 1024 / 1024);
+			next_report += PROGRESS_EVERY;
+		}
+	}
+	out.sync_all().context("failed to flush device")?;
+	Ok((written, hasher.finalize().to_vec()))
+}
+
+fn verify_image(device: &Path, len: u64, expected: &[u8]) -> Result<()> {
+	let input = File::open(device)?;
+	let mut input = input.take(len);
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0; CHUNK_SIZE];
+	loop {
+		let read = input.read(&mut buf)?;
+		if read == 0 {
+			break;
+		}
+		hasher.update(&buf[..read]);
+	}
+	ensure!(
+		hasher.finalize().as_slice() == expected,
+		"verification failed, data read back differs from the image"
+	);
+	Ok(())
+}
+
+impl Flash {
+	pub async fn run(self, config: &Config) -> Result<()> {
+		let info = device_info(&self.device)?;
+		ensure_not_mounted(&self.device)?;
+		if !info.removable {
+			warn!("{:?} is not a removable device", self.device);
+		}
+
+		let built = build_task(config.clone(), self.host.clone(), &self.build_attr, false)
+			.await
+			.context("failed to build image")?;
+		let image = find_image(&built)?;
+		info!("flashing {image:?}");
+
+		let Image {
+			reader,
+			size,
+			decompressor,
+		} = open_image(&image)?;
+		if let Some(image_size) = size {
+			ensure!(
+				image_size <= info.size,
+				"image size {image_size} is larger than device size {}",
+				info.size
+			);
+		}
+		if !self.yes {
+			confirm(&self.device, &info)?;
+		}
+
+		let device = self.device.clone();
+		let (written, hash) = tokio::task::spawn_blocking(move || {
+			let _span = info_span!("writing").entered();
+			write_image(reader, &device, info.size)
+		})
+		.await??;
+		if let Some(mut decompressor) = decompressor {
+			let status = decompressor.wait()?;
+			ensure!(status.success(), "image decompression failed: {status}");
+		}
+		info!("written {written} bytes");
+
+		if !self.no_verify {
+			let device = self.device.clone();
+			tokio::task::spawn_blocking(move || {
+				let _span = info_span!("verifying").entered();
+				verify_image(&device, written, &hash)
+			})
+			.await??;
+			info!("verification succeeded");
+		}
+		Ok(())
+	}
+}
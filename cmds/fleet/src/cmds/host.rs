@@ -0,0 +1,581 @@
+use std::{
+	fs,
+	io::{stdin, stdout, Write as _},
+	path::PathBuf,
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+use clap::{Parser, ValueEnum};
+use fleet_base::{
+	attribution::{self, Entry},
+	host::Config,
+};
+use tracing::{error, info, info_span, warn, Instrument as _};
+
+use super::{info::key_fingerprint, init_host::generate_ssh_key};
+use crate::audit::{self, AuditOp};
+
+#[derive(Parser)]
+pub enum Host {
+	/// Rename host in fleet data: its key, secrets and shared secret ownership.
+	/// Host should also be renamed in the fleet configuration.
+	Rename { old: String, new: String },
+	/// Remove host and its secrets from fleet data, and remove it from shared secret owners
+	Remove {
+		name: String,
+		/// Reencrypt shared secrets for the remaining owners, so that the removed host key
+		/// can't be used to decrypt them anymore.
+		/// Requires one of the remaining owners to be reachable.
+		#[clap(long)]
+		reencrypt: bool,
+		/// Which hosts should be preferred for reencryption
+		#[clap(long)]
+		prefer_identities: Vec<String>,
+	},
+	/// Add a new host: writes its fleet module, registers its keys in fleet data.
+	///
+	/// Values, which are not passed as options, are asked interactively.
+	Add(AddHost),
+	/// Trust ssh host keys of the host, connections to it will then fail if it presents any other key.
+	///
+	/// Keys are scanned with ssh-keyscan, and confirmed interactively, unless --fingerprint is specified.
+	Trust {
+		name: String,
+		/// Trust this key (in openssh public key format) instead of scanning the host
+		#[clap(long)]
+		key: Vec<String>,
+		/// Only trust scanned keys with this fingerprint (`SHA256:...`), without confirmation
+		#[clap(long)]
+		fingerprint: Option<String>,
+	},
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeySource {
+	/// Scan ssh host keys of the running host, its ed25519 key is used to encrypt secrets
+	Scan,
+	/// Generate a new ssh host key locally, it should be placed on the host
+	/// as /etc/ssh/ssh_host_ed25519_key
+	Generate,
+	/// Use the existing key: ssh public key, or age recipient
+	Import,
+	/// Don't register the key, it is fetched from the host on the first deploy,
+	/// or generated by `fleet init-host`
+	Later,
+}
+
+#[derive(Parser)]
+pub struct AddHost {
+	/// Host name, also used as the hostname
+	name: String,
+	/// Ssh address, defaults to the host name
+	#[clap(long)]
+	address: Option<String>,
+	/// Ssh user, empty for the ssh config default
+	#[clap(long)]
+	user: Option<String>,
+	/// Ssh port, ssh config default if not set
+	#[clap(long)]
+	port: Option<u16>,
+	/// Nix system of the host
+	#[clap(long)]
+	system: Option<String>,
+	/// How to obtain the host encryption key
+	#[clap(long, value_enum)]
+	key_source: Option<KeySource>,
+	/// Key to import, implies `--key-source import`
+	#[clap(long)]
+	encryption_key: Option<String>,
+	/// Where to write the private part of the generated host key
+	#[clap(long)]
+	host_key_output: Option<PathBuf>,
+	/// Directory of the host module, relative to the fleet root, `hosts/<name>` by default
+	#[clap(long)]
+	dir: Option<PathBuf>,
+	/// Don't ask anything, use defaults for values, which are not passed
+	#[clap(long)]
+	defaults: bool,
+}
+
+const DEFAULT_SYSTEM: &str = "x86_64-linux";
+const CONFIGURATION_TEMPLATE: &str = "\
+# NixOS configuration of the host.
+#
+# Hardware configuration (file systems, boot loader, kernel modules) should be added here, i.e from
+# `nixos-generate-config --show-hardware-config` output, ran on the host.
+{...}: {
+  # system.stateVersion = \"<NixOS release, the host was installed with>\";
+}
+";
+
+/// Returns scanned keys, without the host name
+async fn keyscan_address(config: &Config, address: &str, port: Option<u16>) -> Result<Vec<String>> {
+	let mut cmd = config.local_host().cmd("ssh-keyscan").await?;
+	if let Some(port) = port {
+		cmd.arg("-p").arg(port.to_string());
+	}
+	cmd.arg(address);
+	let out = cmd
+		.query()
+		.run_string()
+		.await
+		.context("ssh-keyscan failed")?;
+	let keys = out
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty() && !l.starts_with('#'))
+		.filter_map(|l| l.split_once(' ').map(|(_, key)| key.to_owned()))
+		.collect::<Vec<_>>();
+	ensure!(!keys.is_empty(), "no host keys were scanned from {address}");
+	Ok(keys)
+}
+
+async fn keyscan(config: &Config, name: &str) -> Result<Vec<String>> {
+	let host = config.host(name).await?;
+	let target = host.ssh_target_unverified().await?;
+	ensure!(
+		target.jump_hosts.is_empty(),
+		"host is only reachable via jump hosts, which ssh-keyscan doesn't support, pass --key instead"
+	);
+	keyscan_address(config, &target.address, target.port).await
+}
+
+/// Shows fingerprints of the scanned keys, and asks whether they should be trusted
+fn confirm_keys(name: &str, scanned: &[String]) -> Result<()> {
+	let mut message = format!("host {name} presented keys:\n");
+	for key in scanned {
+		let kind = key.split_whitespace().next().unwrap_or_default();
+		let fingerprint = key_fingerprint(key).unwrap_or_else(|| "<invalid>".to_owned());
+		message.push_str(&format!("  {kind} {fingerprint}\n"));
+	}
+	info!("{}", message.trim_end());
+	print!("trust these keys? [y/N] ");
+	stdout().flush()?;
+	let mut answer = String::new();
+	stdin().read_line(&mut answer)?;
+	if !matches!(answer.trim(), "y" | "Y" | "yes") {
+		bail!("host keys are not trusted");
+	}
+	Ok(())
+}
+
+async fn trust(
+	config: &Config,
+	name: &str,
+	keys: Vec<String>,
+	fingerprint: Option<String>,
+) -> Result<()> {
+	let hosts = config.list_hosts().await?;
+	ensure!(hosts.iter().any(|h| h.name == name), "unknown host: {name}");
+
+	let keys = if !keys.is_empty() {
+		for key in &keys {
+			ensure!(key_fingerprint(key).is_some(), "invalid ssh public key: {key:?}");
+		}
+		keys
+	} else {
+		let scanned = keyscan(config, name).await?;
+		if let Some(fingerprint) = fingerprint {
+			let matching = scanned
+				.into_iter()
+				.filter(|k| key_fingerprint(k).as_ref() == Some(&fingerprint))
+				.collect::<Vec<_>>();
+			ensure!(
+				!matching.is_empty(),
+				"host presented no key with fingerprint {fingerprint}"
+			);
+			matching
+		} else {
+			confirm_keys(name, &scanned)?;
+			scanned
+		}
+	};
+
+	let previous = config.trusted_host_keys(name);
+	if !previous.is_empty() && previous != keys {
+		warn!("replacing previously trusted keys of {name}");
+	}
+	config.trust_host_keys(name, keys);
+	info!("host keys of {name} are trusted");
+	Ok(())
+}
+
+fn rename(config: &Config, old: &str, new: &str) -> Result<()> {
+	let mut data = config.data_mut();
+	ensure!(
+		!data.hosts.contains_key(new) && !data.host_secrets.contains_key(new),
+		"host {new} already exists in fleet data"
+	);
+	let key = data.hosts.remove(old);
+	let secrets = data.host_secrets.remove(old);
+	let mut shared = 0;
+	for (name, secret) in &mut data.shared_secrets {
+		let mut renamed = false;
+		for owner in secret.owners.iter_mut().filter(|o| o.as_str() == old) {
+			*owner = new.to_owned();
+			renamed = true;
+		}
+		if renamed {
+			attribution::stamp(Entry::SharedSecret(name), secret);
+			shared += 1;
+		}
+	}
+	if key.is_none() && secrets.is_none() && shared == 0 {
+		bail!("host {old} is not mentioned in fleet data");
+	}
+	let secrets_count = secrets.as_ref().map_or(0, |s| s.len());
+	// Entry names are signed, so moved entries are signed again
+	if let Some(mut key) = key {
+		attribution::stamp(Entry::Host(new), &mut key);
+		data.hosts.insert(new.to_owned(), key);
+	}
+	if let Some(mut secrets) = secrets {
+		for (name, secret) in &mut secrets {
+			attribution::stamp(
+				Entry::HostSecret {
+					host: new,
+					secret: name,
+				},
+				secret,
+			);
+		}
+		data.host_secrets.insert(new.to_owned(), secrets);
+	}
+	info!("renamed {old} to {new}: {secrets_count} host secrets, {shared} shared secrets");
+	Ok(())
+}
+
+async fn remove(
+	config: &Config,
+	name: &str,
+	reencrypt: bool,
+	prefer_identities: &[String],
+) -> Result<()> {
+	let (secrets, affected) = {
+		let mut data = config.data_mut();
+		data.hosts.remove(name);
+		let secrets = data.host_secrets.remove(name).map_or(0, |s| s.len());
+		let affected = data
+			.shared_secrets
+			.iter()
+			.filter(|(_, s)| s.owners.iter().any(|o| o == name))
+			.map(|(n, _)| n.clone())
+			.collect::<Vec<_>>();
+		(secrets, affected)
+	};
+	info!("removed {secrets} host secrets");
+
+	let mut not_migrated = Vec::new();
+	for secret_name in affected {
+		let mut secret = config.shared_secret(&secret_name)?;
+		secret.owners.retain(|o| o != name);
+		if secret.owners.is_empty() {
+			warn!("shared secret {secret_name} has no owners left, removing it");
+			audit::record(config, AuditOp::Remove, &secret_name, &[name.to_owned()])?;
+			config.remove_shared(&secret_name);
+			continue;
+		}
+		if reencrypt {
+			let holder = prefer_identities
+				.iter()
+				.find(|i| secret.owners.contains(i))
+				.unwrap_or(&secret.owners[0])
+				.clone();
+			let result: Result<()> = try {
+				let host = config.host(&holder).await?;
+				for part in secret.secret.parts.values_mut() {
+					if !part.raw.encrypted {
+						continue;
+					}
+					part.raw = host
+						.reencrypt(part.raw.clone(), secret.owners.clone(), &secret.readers)
+						.instrument(info_span!("reencrypt", secret = secret_name))
+						.await?;
+				}
+			};
+			if let Err(e) = result {
+				error!("failed to reencrypt {secret_name} on {holder}: {e:#}");
+				not_migrated.push(secret_name);
+				continue;
+			}
+			audit::record(config, AuditOp::Reencrypt, &secret_name, &secret.owners)?;
+		} else {
+			// Removed host is still able to decrypt the stored data
+			not_migrated.push(secret_name.clone());
+		}
+		config.replace_shared(secret_name, secret);
+	}
+	if !not_migrated.is_empty() {
+		warn!(
+			"shared secrets, which are still encrypted for {name}, reencrypt or regenerate them:\n{}",
+			not_migrated.join("\n")
+		);
+	}
+	info!("host {name} removed from fleet data, remove it from the fleet configuration too");
+	Ok(())
+}
+
+/// Asks for the value, empty answer selects the default
+fn ask(defaults: bool, question: &str, default: &str) -> Result<String> {
+	if defaults {
+		return Ok(default.to_owned());
+	}
+	if default.is_empty() {
+		print!("{question}: ");
+	} else {
+		print!("{question} [{default}]: ");
+	}
+	stdout().flush()?;
+	let mut answer = String::new();
+	if stdin().read_line(&mut answer)? == 0 {
+		bail!("stdin is closed, pass the values as options, or use --defaults");
+	}
+	let answer = answer.trim();
+	Ok(if answer.is_empty() {
+		default.to_owned()
+	} else {
+		answer.to_owned()
+	})
+}
+
+fn check_host_name(name: &str) -> Result<()> {
+	ensure!(
+		!name.is_empty()
+			&& !name.starts_with('-')
+			&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+		"host name {name:?} should only consist of letters, digits and -, as it is used as the hostname"
+	);
+	Ok(())
+}
+
+/// Fleet module, which declares the host, see `modules/hosts.nix`
+fn host_module(
+	name: &str,
+	system: &str,
+	address: &str,
+	user: Option<&str>,
+	port: Option<u16>,
+) -> String {
+	let mut ssh = Vec::new();
+	if address != name {
+		ssh.push(format!("address = {};", nixlike::escape_string(address)));
+	}
+	if let Some(user) = user {
+		ssh.push(format!("user = {};", nixlike::escape_string(user)));
+	}
+	if let Some(port) = port {
+		ssh.push(format!("port = {port};"));
+	}
+	let mut out = format!(
+		"# Host {name}, added by `fleet host add`, should be imported by the fleet configuration.\n{{\n  hosts.{} = {{\n    system = {};\n",
+		nixlike::format_identifier(name),
+		nixlike::escape_string(system),
+	);
+	if !ssh.is_empty() {
+		out.push_str("    ssh = {\n");
+		for line in ssh {
+			out.push_str(&format!("      {line}\n"));
+		}
+		out.push_str("    };\n");
+	}
+	out.push_str("    nixos.imports = [./configuration.nix];\n  };\n}\n");
+	out
+}
+
+async fn add(config: &Config, opts: AddHost) -> Result<()> {
+	let AddHost {
+		name,
+		address,
+		user,
+		port,
+		system,
+		key_source,
+		encryption_key,
+		host_key_output,
+		dir,
+		defaults,
+	} = opts;
+	check_host_name(&name)?;
+	let hosts = config.list_hosts().await?;
+	ensure!(
+		!hosts.iter().any(|h| h.name == name),
+		"host {name} is already configured"
+	);
+	ensure!(
+		!config.data().hosts.contains_key(&name),
+		"host {name} is already registered in fleet data, remove it with `fleet host remove` first"
+	);
+	let dir = dir.unwrap_or_else(|| PathBuf::from("hosts").join(&name));
+	let module_dir = config.directory.join(&dir);
+	let module = module_dir.join("default.nix");
+	ensure!(!module.exists(), "{} already exists", module.display());
+
+	let address = match address {
+		Some(address) => address,
+		None => ask(defaults, "ssh address", &name)?,
+	};
+	let user = match user {
+		Some(user) => user,
+		None => ask(defaults, "ssh user, empty for the ssh config default", "")?,
+	};
+	let user = (!user.is_empty()).then_some(user);
+	let port = match port {
+		Some(port) => Some(port),
+		None => {
+			let port = ask(defaults, "ssh port, empty for the ssh config default", "")?;
+			if port.is_empty() {
+				None
+			} else {
+				Some(
+					port.parse()
+						.with_context(|| format!("invalid port: {port}"))?,
+				)
+			}
+		}
+	};
+	let system = match system {
+		Some(system) => system,
+		None => ask(defaults, "nix system", DEFAULT_SYSTEM)?,
+	};
+
+	let key_source = match (key_source, &encryption_key) {
+		(Some(source), _) => source,
+		(None, Some(_)) => KeySource::Import,
+		(None, None) if defaults => KeySource::Later,
+		(None, None) => loop {
+			let answer = ask(
+				defaults,
+				"encryption key: [s]can the running host, [g]enerate host key, [i]mport, [l]ater",
+				"s",
+			)?;
+			match answer.as_str() {
+				"s" | "scan" => break KeySource::Scan,
+				"g" | "generate" => break KeySource::Generate,
+				"i" | "import" => break KeySource::Import,
+				"l" | "later" => break KeySource::Later,
+				_ => continue,
+			}
+		},
+	};
+	let (encryption_key, trusted) = match key_source {
+		KeySource::Scan => {
+			let scanned = keyscan_address(config, &address, port).await?;
+			confirm_keys(&name, &scanned)?;
+			let key = scanned
+				.iter()
+				.find(|k| k.starts_with("ssh-ed25519 "))
+				.cloned()
+				.context("host has no ed25519 host key, which is used to encrypt secrets, import the key instead")?;
+			(Some(key), scanned)
+		}
+		KeySource::Generate => {
+			let output = match host_key_output {
+				Some(output) => output,
+				None => PathBuf::from(ask(
+					defaults,
+					"where to write the private host key",
+					&format!("{name}_ssh_host_ed25519_key"),
+				)?),
+			};
+			let key = generate_ssh_key(config, &output).await?;
+			warn!(
+				"private host key is written to {}, place it on the host as /etc/ssh/ssh_host_ed25519_key, and remove the local copy",
+				output.display()
+			);
+			(Some(key.clone()), vec![key])
+		}
+		KeySource::Import => {
+			let key = match encryption_key {
+				Some(key) => key,
+				None => ask(
+					defaults,
+					"encryption key, ssh public key or age recipient",
+					"",
+				)?,
+			};
+			let key = key.trim().to_owned();
+			if key.starts_with("age1") {
+				(Some(key), vec![])
+			} else {
+				ensure!(
+					key_fingerprint(&key).is_some(),
+					"invalid encryption key: {key:?}"
+				);
+				// Host decrypts secrets with its ssh host key
+				(Some(key.clone()), vec![key])
+			}
+		}
+		KeySource::Later => (None, vec![]),
+	};
+
+	fs::create_dir_all(&module_dir)
+		.with_context(|| format!("failed to create {}", module_dir.display()))?;
+	fs::write(
+		&module,
+		host_module(&name, &system, &address, user.as_deref(), port),
+	)?;
+	let configuration = module_dir.join("configuration.nix");
+	if !configuration.exists() {
+		fs::write(&configuration, CONFIGURATION_TEMPLATE)?;
+	}
+
+	if !trusted.is_empty() {
+		config.trust_host_keys(&name, trusted);
+	}
+	if let Some(key) = encryption_key {
+		config.update_key(&name, key);
+		info!("host key is registered in fleet data");
+	}
+	info!(
+		"host {name} is added, next steps:\n  add ./{0} to the fleet configuration imports\n  add hardware configuration to {0}/configuration.nix\n  install it with `fleet init-host {name}`, or deploy it with `fleet deploy --only {name}`",
+		dir.display()
+	);
+	Ok(())
+}
+
+impl Host {
+	pub async fn run(self, config: &Config) -> Result<()> {
+		match self {
+			Host::Rename { old, new } => rename(config, &old, &new),
+			Host::Remove {
+				name,
+				reencrypt,
+				prefer_identities,
+			} => remove(config, &name, reencrypt, &prefer_identities).await,
+			Host::Trust {
+				name,
+				key,
+				fingerprint,
+			} => trust(config, &name, key, fingerprint).await,
+			Host::Add(opts) => add(config, opts).await,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn module() {
+		assert_eq!(
+			host_module("web-1", "aarch64-linux", "10.0.0.5", Some("admin"), None),
+			"\
+# Host web-1, added by `fleet host add`, should be imported by the fleet configuration.
+{
+  hosts.web-1 = {
+    system = \"aarch64-linux\";
+    ssh = {
+      address = \"10.0.0.5\";
+      user = \"admin\";
+    };
+    nixos.imports = [./configuration.nix];
+  };
+}
+"
+		);
+		assert!(check_host_name("web-1").is_ok());
+		assert!(check_host_name("-web").is_err());
+		assert!(check_host_name("web.example.com").is_err());
+	}
+}
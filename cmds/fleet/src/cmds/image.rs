@@ -0,0 +1,227 @@
+//! Release images of multiple hosts, collected to a single directory together with
+//! `SHA256SUMS` and `manifest.json`.
+//!
+//! Manifest is updated after every exported image, and images which are already present
+//! in it with the same store path are not exported again, so interrupted runs can be resumed.
+
+use std::{
+	cell::RefCell,
+	collections::BTreeMap,
+	fmt::Write as _,
+	fs::{self, File, Permissions},
+	io,
+	os::unix::fs::PermissionsExt as _,
+	path::{Path, PathBuf},
+	process::Command,
+	rc::Rc,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use fleet_base::{host::Config, opts::FleetOpts};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::task::LocalSet;
+use tracing::{error, field, info, info_span, Instrument as _};
+
+use super::{
+	build_systems::{build_task, BuildLogOpts},
+	flash::find_image,
+};
+use crate::schedule::Schedule;
+
+#[derive(Parser)]
+pub struct Image {
+	/// Directory to write images, checksums and manifest to
+	#[clap(long)]
+	out_dir: PathBuf,
+	/// Image attribute to build, "sdImage" or "isoImage"
+	#[clap(long, default_value = "sdImage")]
+	build_attr: String,
+	/// Compress images with zstd, already compressed images are copied as-is
+	#[clap(long)]
+	compress: bool,
+	#[clap(flatten)]
+	build_log: BuildLogOpts,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ManifestEntry {
+	attr: String,
+	/// Store path of the built image
+	source: PathBuf,
+	/// File name in the output directory
+	file: String,
+	size: u64,
+	sha256: String,
+	built_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+	images: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+	fn load(out_dir: &Path) -> Result<Self> {
+		let Ok(data) = fs::read(out_dir.join("manifest.json")) else {
+			return Ok(Self::default());
+		};
+		serde_json::from_slice(&data).context("failed to parse image manifest")
+	}
+	fn save(&self, out_dir: &Path) -> Result<()> {
+		let tmp = NamedTempFile::new_in(out_dir)?;
+		serde_json::to_writer_pretty(&tmp, self)?;
+		tmp.persist(out_dir.join("manifest.json"))?;
+
+		let mut sums = String::new();
+		for entry in self.images.values() {
+			writeln!(sums, "{}  {}", entry.sha256, entry.file)?;
+		}
+		let tmp = NamedTempFile::new_in(out_dir)?;
+		fs::write(tmp.path(), sums)?;
+		tmp.persist(out_dir.join("SHA256SUMS"))?;
+		Ok(())
+	}
+	/// Whether the image was already exported by the previous run
+	fn is_exported(&self, out_dir: &Path, host: &str, attr: &str, source: &Path) -> bool {
+		self.images
+			.get(host)
+			.is_some_and(|e| e.attr == attr && e.source == source && out_dir.join(&e.file).exists())
+	}
+}
+
+/// Copies or compresses image to the output directory, returns the file name, size and hash
+fn export(
+	image: &Path,
+	out_dir: &Path,
+	host: &str,
+	compress: bool,
+) -> Result<(String, u64, String)> {
+	let name = image
+		.file_name()
+		.expect("image is a file")
+		.to_string_lossy();
+	let compress = compress && !name.ends_with(".zst");
+	let file = if compress {
+		format!("{host}-{name}.zst")
+	} else {
+		format!("{host}-{name}")
+	};
+
+	let tmp = NamedTempFile::new_in(out_dir)?;
+	if compress {
+		let status = Command::new("zstd")
+			.arg("-q")
+			.arg("-T0")
+			.arg("-c")
+			.arg(image)
+			.stdout(tmp.reopen()?)
+			.status()
+			.context("failed to spawn zstd for image compression")?;
+		ensure!(status.success(), "image compression failed: {status}");
+	} else {
+		io::copy(&mut File::open(image)?, &mut tmp.as_file())?;
+	}
+	let mut hasher = Sha256::new();
+	io::copy(&mut File::open(tmp.path())?, &mut hasher)?;
+	let size = tmp.as_file().metadata()?.len();
+	tmp.as_file()
+		.set_permissions(Permissions::from_mode(0o644))?;
+	tmp.persist(out_dir.join(&file))?;
+	Ok((file, size, format!("{:x}", hasher.finalize())))
+}
+
+impl Image {
+	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		fs::create_dir_all(&self.out_dir)
+			.with_context(|| format!("failed to create {:?}", self.out_dir))?;
+		let mut hosts = Vec::new();
+		for host in config.list_hosts().await? {
+			if !opts.should_skip(&host).await? {
+				hosts.push(host);
+			}
+		}
+		let manifest = Rc::new(RefCell::new(Manifest::load(&self.out_dir)?));
+		let failed = Rc::new(RefCell::new(Vec::new()));
+
+		let set = LocalSet::new();
+		let config = config.clone();
+		let this = Rc::new(self);
+		{
+			let manifest = manifest.clone();
+			let failed = failed.clone();
+			let this = this.clone();
+			Schedule::new(hosts).await?.spawn(&set, move |host| {
+				let config = config.clone();
+				let span = info_span!("image", host = field::display(&host.name));
+				let manifest = manifest.clone();
+				let failed = failed.clone();
+				let this = this.clone();
+				async move {
+					let result: Result<()> = try {
+						let verbose = this.build_log.is_verbose(&host).await?;
+						let built =
+							build_task(config, host.name.clone(), &this.build_attr, verbose)
+								.await?;
+						let image = find_image(&built)?;
+						if manifest.borrow().is_exported(
+							&this.out_dir,
+							&host.name,
+							&this.build_attr,
+							&image,
+						) {
+							info!("image is up to date");
+						} else {
+							info!("exporting {image:?}");
+							let (out_dir, name, compress) =
+								(this.out_dir.clone(), host.name.clone(), this.compress);
+							let source = image.clone();
+							let (file, size, sha256) = tokio::task::spawn_blocking(move || {
+								export(&source, &out_dir, &name, compress)
+							})
+							.await??;
+							let mut manifest = manifest.borrow_mut();
+							manifest.images.insert(
+								host.name.clone(),
+								ManifestEntry {
+									attr: this.build_attr.clone(),
+									source: image,
+									file,
+									size,
+									sha256,
+									built_at: Utc::now(),
+								},
+							);
+							manifest.save(&this.out_dir)?;
+						}
+					};
+					match result {
+						Ok(()) => true,
+						Err(e) => {
+							error!("failed to build image: {e:#}");
+							failed.borrow_mut().push(host.name.clone());
+							false
+						}
+					}
+				}
+				.instrument(span)
+			});
+		}
+		set.await;
+
+		manifest.borrow().save(&this.out_dir)?;
+		let failed = failed.borrow();
+		if !failed.is_empty() {
+			bail!(
+				"failed to build images of {}, rerun to resume",
+				failed.join(", ")
+			);
+		}
+		info!("images are written to {:?}", this.out_dir);
+		Ok(())
+	}
+}
@@ -1,9 +1,358 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
-use anyhow::{ensure, Result};
+use anyhow::{bail, ensure, Result};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
 use clap::Parser;
-use fleet_base::host::Config;
-use nix_eval::nix_go_json;
+use fleet_base::{
+	fleetdata::FleetSecret,
+	host::{Config, ConfigHost, Platform},
+};
+use nix_eval::{nix_go, nix_go_json, Index, Value};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tabled::{Table, Tabled};
+
+use crate::{
+	graph::{DeployGraph, GraphFormat},
+	policy::HostPolicy,
+	schedule::Schedule,
+};
+
+/// Version of the json output format, should be bumped on incompatible changes
+/// to any of serialized structures below.
+const INVENTORY_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Inventory<T> {
+	version: u32,
+	#[serde(flatten)]
+	data: T,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HostsInventory {
+	hosts: Vec<HostSummary>,
+}
+
+#[derive(Serialize, Tabled, Clone)]
+#[serde(rename_all = "camelCase")]
+struct HostSummary {
+	#[tabled(rename = "Name")]
+	name: String,
+	#[tabled(rename = "System")]
+	system: String,
+	#[tabled(rename = "Tags", display_with = "display_list")]
+	tags: Vec<String>,
+	/// SHA256 fingerprint of host ssh key, in the same format as `ssh-keygen -l` uses
+	#[tabled(rename = "Key fingerprint", display_with = "display_opt")]
+	key_fingerprint: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SecretsInventory {
+	secrets: Vec<SecretSummary>,
+}
+
+#[derive(Serialize, Tabled, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SecretSummary {
+	#[tabled(rename = "Name")]
+	name: String,
+	#[tabled(rename = "Shared")]
+	shared: bool,
+	#[tabled(rename = "Owners", display_with = "display_list")]
+	owners: Vec<String>,
+	#[tabled(rename = "Parts", display_with = "display_list")]
+	parts: Vec<String>,
+	#[tabled(rename = "Created at")]
+	created_at: DateTime<Utc>,
+	#[tabled(rename = "Expires at", display_with = "display_opt")]
+	expires_at: Option<DateTime<Utc>>,
+}
+impl SecretSummary {
+	fn new(name: String, shared: bool, owners: Vec<String>, secret: &FleetSecret) -> Self {
+		Self {
+			name,
+			shared,
+			owners,
+			parts: secret.parts.keys().cloned().collect(),
+			created_at: secret.created_at,
+			expires_at: secret.expires_at,
+		}
+	}
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HostDetails {
+	#[serde(flatten)]
+	summary: HostSummary,
+	encryption_key: Option<String>,
+	internal_ips: Vec<String>,
+	external_ips: Vec<String>,
+	/// Specialisations of the host system, which can be activated with `fleet deploy --specialisation`
+	specialisations: Vec<String>,
+	secrets: Vec<SecretSummary>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OptionDetails {
+	/// Path of the option itself, prefix of the queried path, if it points inside of the option value
+	option: String,
+	#[serde(rename = "type")]
+	ty: Option<String>,
+	description: Option<String>,
+	value: serde_json::Value,
+	declarations: Vec<String>,
+	definitions: Vec<OptionDefinition>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OptionDefinition {
+	file: String,
+	value: serde_json::Value,
+}
+
+fn display_list(v: &[String]) -> String {
+	v.join(", ")
+}
+fn display_opt<T: ToString>(v: &Option<T>) -> String {
+	v.as_ref().map(ToString::to_string).unwrap_or_default()
+}
+
+/// Computes `SHA256:...` fingerprint of openssh public key
+pub(crate) fn key_fingerprint(key: &str) -> Option<String> {
+	let blob = key.split_whitespace().nth(1)?;
+	let blob = base64::engine::general_purpose::STANDARD
+		.decode(blob)
+		.ok()?;
+	let hash = Sha256::digest(blob);
+	Some(format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+}
+
+async fn host_summary(config: &Config, host: &ConfigHost) -> Result<HostSummary> {
+	let host_config = host
+		.host_config
+		.as_ref()
+		.expect("listed hosts have config");
+	let key = config.cached_key(&host.name);
+	Ok(HostSummary {
+		name: host.name.clone(),
+		system: nix_go_json!(host_config.system),
+		tags: host.tags().await?,
+		key_fingerprint: key.as_deref().and_then(key_fingerprint),
+	})
+}
+
+/// Entries of the `specialisation` directory of the host toplevel
+async fn host_specialisations(host: &ConfigHost) -> Result<Vec<String>> {
+	if host.platform().await? != Platform::Nixos {
+		return Ok(vec![]);
+	}
+	let nixos = host.nixos_config().await?;
+	Ok(nix_go!(nixos.specialisation).list_fields().await?)
+}
+
+/// Splits `boot.kernel.sysctl."net.ipv4.ip_forward"` into attribute names
+fn parse_option_path(path: &str) -> Result<Vec<String>> {
+	let mut out = Vec::new();
+	let mut chars = path.chars().peekable();
+	loop {
+		let mut segment = String::new();
+		if chars.peek() == Some(&'"') {
+			chars.next();
+			loop {
+				match chars.next() {
+					Some('"') => break,
+					Some('\\') => segment.extend(chars.next()),
+					Some(c) => segment.push(c),
+					None => bail!("unterminated quote in option path {path}"),
+				}
+			}
+		} else {
+			while let Some(c) = chars.next_if(|c| *c != '.') {
+				segment.push(c);
+			}
+		}
+		ensure!(!segment.is_empty(), "empty attribute in option path {path}");
+		out.push(segment);
+		match chars.next() {
+			Some('.') => {}
+			None => return Ok(out),
+			Some(c) => bail!("unexpected {c:?} after quoted attribute in option path {path}"),
+		}
+	}
+}
+
+fn format_option_path(path: &[String]) -> String {
+	path.iter()
+		.map(|s| nixlike::format_identifier(s))
+		.collect::<Vec<_>>()
+		.join(".")
+}
+
+async fn is_option(value: &Value) -> Result<bool> {
+	if value.type_of().await? != "set" || !value.has_field("_type").await? {
+		return Ok(false);
+	}
+	let ty: serde_json::Value = nix_go_json!(value._type);
+	Ok(ty == "option")
+}
+
+/// Values, which can't be represented as json (functions, derivations with cyclic references),
+/// are replaced with their type
+async fn value_json(value: &Value) -> Result<serde_json::Value> {
+	match value.as_json().await {
+		Ok(v) => Ok(v),
+		Err(_) => Ok(format!("<{}>", value.type_of().await?).into()),
+	}
+}
+
+/// Follows attribute path inside of the option value, `None` if the value has no such attribute
+async fn select_in_value(value: Value, path: &[String]) -> Result<Option<Value>> {
+	let mut value = value;
+	for name in path {
+		if value.type_of().await? != "set" || !value.has_field(name).await? {
+			return Ok(None);
+		}
+		value = value.select([Index::attr(name)]).await?;
+	}
+	Ok(Some(value))
+}
+
+async fn option_details(host: &ConfigHost, path: &str) -> Result<OptionDetails> {
+	if host.platform().await? != Platform::Nixos {
+		bail!("{} is not a nixos host", host.name);
+	}
+	let Some(host_config) = &host.host_config else {
+		bail!("local host has no nixos options");
+	};
+	let path = parse_option_path(path)?;
+
+	let mut option = nix_go!(host_config.nixos.options);
+	let mut option_len = None;
+	for (i, name) in path.iter().enumerate() {
+		if option.type_of().await? != "set" || !option.has_field(name).await? {
+			bail!("option {} doesn't exist", format_option_path(&path[..=i]));
+		}
+		option = option.select([Index::attr(name)]).await?;
+		if is_option(&option).await? {
+			option_len = Some(i + 1);
+			break;
+		}
+	}
+	let Some(option_len) = option_len else {
+		let children = option.list_fields().await?;
+		bail!(
+			"{} is a set of options, not an option, it contains: {}",
+			format_option_path(&path),
+			children.join(", ")
+		);
+	};
+	let rest = &path[option_len..];
+
+	let Some(value) = select_in_value(nix_go!(option.value), rest).await? else {
+		bail!("option value has no {} attribute", format_option_path(rest));
+	};
+	let value = value_json(&value).await?;
+
+	let pkgs = host.pkgs().await?;
+	let lib = nix_go!(pkgs.lib);
+	let definitions = nix_go!(option.definitionsWithLocations);
+	let files: Vec<String> = nix_go_json!(lib.catAttrs("file")(definitions));
+	let values = nix_go!(lib.catAttrs("value")(definitions));
+	let mut defined = Vec::new();
+	for (i, file) in files.into_iter().enumerate() {
+		let value = nix_go!(lib.elemAt(values)({ i }));
+		if let Some(value) = select_in_value(value, rest).await? {
+			defined.push(OptionDefinition {
+				file,
+				value: value_json(&value).await?,
+			});
+		}
+	}
+
+	let description: Option<serde_json::Value> = if option.has_field("description").await? {
+		nix_go_json!(option.description)
+	} else {
+		None
+	};
+	let description = match description {
+		Some(serde_json::Value::String(text)) => Some(text),
+		// `lib.mdDoc` and `lib.literalMD` wrappers
+		Some(serde_json::Value::Object(mut doc)) => match doc.remove("text") {
+			Some(serde_json::Value::String(text)) => Some(text),
+			_ => None,
+		},
+		_ => None,
+	};
+	let ty = if option.has_field("type").await? {
+		Some(nix_go_json!(option[{ "type" }].description))
+	} else {
+		None
+	};
+
+	Ok(OptionDetails {
+		option: format_option_path(&path[..option_len]),
+		ty,
+		description,
+		value,
+		declarations: nix_go_json!(option.declarations),
+		definitions: defined,
+	})
+}
+
+fn print_option_value(value: &serde_json::Value) -> String {
+	match nixlike::serialize(value) {
+		Ok(v) => nixlike::format_nix(&v).trim_end().to_owned(),
+		Err(_) => value.to_string(),
+	}
+}
+
+fn host_secrets(config: &Config, host: &str) -> Result<Vec<SecretSummary>> {
+	let mut out = Vec::new();
+	for name in config.list_secrets(host) {
+		let secret = config.host_secret(host, &name)?;
+		out.push(SecretSummary::new(
+			name,
+			false,
+			vec![host.to_owned()],
+			&secret,
+		));
+	}
+	for name in config.list_shared() {
+		let secret = config.shared_secret(&name)?;
+		if !secret.owners.iter().any(|o| o == host) {
+			continue;
+		}
+		out.push(SecretSummary::new(
+			name,
+			true,
+			secret.owners.clone(),
+			&secret.secret,
+		));
+	}
+	Ok(out)
+}
+
+fn print_inventory<T: Serialize, R: Tabled>(json: bool, data: T, rows: Vec<R>) -> Result<()> {
+	if json {
+		let v = serde_json::to_string_pretty(&Inventory {
+			version: INVENTORY_VERSION,
+			data,
+		})?;
+		println!("{v}");
+	} else {
+		println!("{}", Table::new(rows));
+	}
+	Ok(())
+}
 
 #[derive(Parser)]
 pub struct Info {
@@ -15,6 +364,31 @@ pub struct Info {
 
 #[derive(Parser)]
 pub enum InfoCmd {
+	/// Hosts inventory: names, tags, systems and key fingerprints
+	Hosts {
+		#[clap(long)]
+		tagged: Vec<String>,
+	},
+	/// Full resolved metadata of a single host
+	Host { name: String },
+	/// Secrets inventory: names, owners and expiration dates
+	Secrets {
+		/// Only list secrets owned by this host
+		#[clap(long)]
+		host: Option<String>,
+	},
+	/// Evaluated value of a nixos option of the host, with files, where it was declared and defined
+	Option {
+		host: String,
+		/// Option path, attributes containing dots should be quoted: `boot.kernel.sysctl."net.ipv4.ip_forward"`
+		path: String,
+	},
+	/// Deployment plan: host dependencies with deployment waves, failure domains,
+	/// and owners of shared secrets
+	Graph {
+		#[clap(long, value_enum, default_value = "dot")]
+		format: GraphFormat,
+	},
 	/// List hosts
 	ListHosts {
 		#[clap(long)]
@@ -34,6 +408,124 @@ impl Info {
 	pub async fn run(self, config: &Config) -> Result<()> {
 		let mut data = Vec::new();
 		match self.cmd {
+			InfoCmd::Hosts { ref tagged } => {
+				let mut hosts = Vec::new();
+				for host in config.list_hosts().await? {
+					let summary = host_summary(config, &host).await?;
+					if tagged.iter().all(|t| summary.tags.contains(t)) {
+						hosts.push(summary);
+					}
+				}
+				let rows = hosts.clone();
+				return print_inventory(self.json, HostsInventory { hosts }, rows);
+			}
+			InfoCmd::Host { ref name } => {
+				let host = config.host(name).await?;
+				let host_config = host
+					.host_config
+					.as_ref()
+					.expect("configured host has config");
+				let details = HostDetails {
+					summary: host_summary(config, &host).await?,
+					encryption_key: config.cached_key(name),
+					internal_ips: nix_go_json!(host_config.network.internalIps),
+					external_ips: nix_go_json!(host_config.network.externalIps),
+					specialisations: host_specialisations(&host).await?,
+					secrets: host_secrets(config, name)?,
+				};
+				if self.json {
+					let rows = vec![details.summary.clone()];
+					return print_inventory(true, details, rows);
+				}
+				println!("{}", Table::new([details.summary]));
+				println!("Internal ips: {}", details.internal_ips.join(", "));
+				println!("External ips: {}", details.external_ips.join(", "));
+				println!("Specialisations: {}", details.specialisations.join(", "));
+				println!("{}", Table::new(details.secrets));
+				return Ok(());
+			}
+			InfoCmd::Secrets { ref host } => {
+				let secrets = if let Some(host) = host {
+					host_secrets(config, host)?
+				} else {
+					let mut secrets = Vec::new();
+					for name in config.list_shared() {
+						let secret = config.shared_secret(&name)?;
+						secrets.push(SecretSummary::new(
+							name,
+							true,
+							secret.owners.clone(),
+							&secret.secret,
+						));
+					}
+					for host in config.list_hosts().await? {
+						for name in config.list_secrets(&host.name) {
+							let secret = config.host_secret(&host.name, &name)?;
+							secrets.push(SecretSummary::new(
+								name,
+								false,
+								vec![host.name.clone()],
+								&secret,
+							));
+						}
+					}
+					secrets
+				};
+				let rows = secrets.clone();
+				return print_inventory(self.json, SecretsInventory { secrets }, rows);
+			}
+			InfoCmd::Option { ref host, ref path } => {
+				let host = config.host(host).await?;
+				let details = option_details(&host, path).await?;
+				if self.json {
+					let v = serde_json::to_string_pretty(&Inventory {
+						version: INVENTORY_VERSION,
+						data: details,
+					})?;
+					println!("{v}");
+					return Ok(());
+				}
+				println!("Option: {}", details.option);
+				if let Some(ty) = &details.ty {
+					println!("Type: {ty}");
+				}
+				if let Some(description) = &details.description {
+					println!("Description: {}", description.trim_end());
+				}
+				println!("Value: {}", print_option_value(&details.value));
+				println!("Declared in: {}", details.declarations.join(", "));
+				println!("Defined in:");
+				for definition in &details.definitions {
+					println!(
+						"  {}: {}",
+						definition.file,
+						print_option_value(&definition.value)
+					);
+				}
+				return Ok(());
+			}
+			InfoCmd::Graph { format } => {
+				ensure!(!self.json, "graph has no json output, use --format");
+				let hosts = config.list_hosts().await?;
+				let mut domains = BTreeMap::new();
+				for host in &hosts {
+					if let Some(domain) = HostPolicy::for_host(host).await?.failure_domain {
+						domains.insert(host.name.clone(), domain);
+					}
+				}
+				let mut secrets = BTreeMap::new();
+				for name in config.list_shared() {
+					let owners = config.shared_secret(&name)?.owners;
+					secrets.insert(name, owners);
+				}
+				let graph = DeployGraph {
+					hosts: Schedule::new(hosts).await?.plan(),
+					domains,
+					secrets,
+				};
+				print!("{}", graph.render(format));
+				return Ok(());
+			}
 			InfoCmd::ListHosts { ref tagged } => {
 				'host: for host in config.list_hosts().await? {
 					if !tagged.is_empty() {
@@ -84,3 +576,22 @@ impl Info {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::parse_option_path;
+
+	#[test]
+	fn option_paths() {
+		assert_eq!(
+			parse_option_path(r#"boot.kernel.sysctl."net.ipv4.ip_forward""#).unwrap(),
+			["boot", "kernel", "sysctl", "net.ipv4.ip_forward"]
+		);
+		assert_eq!(
+			parse_option_path(r#"users.users."a\"b".name"#).unwrap(),
+			["users", "users", "a\"b", "name"]
+		);
+		assert!(parse_option_path("boot..kernel").is_err());
+		assert!(parse_option_path(r#"boot."kernel"x"#).is_err());
+	}
+}
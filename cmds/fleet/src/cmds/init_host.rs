@@ -0,0 +1,266 @@
+//! Provisioning of a fresh machine into the fleet host.
+//!
+//! Heavy lifting (kexec into the installer, partitioning, installation) is done by
+//! [nixos-anywhere](https://github.com/nix-community/nixos-anywhere), fleet only prepares
+//! everything it needs: built system, disko script and the host key, which is registered in
+//! fleet data, so secrets can be encrypted for the host before it even booted.
+//!
+//! Secrets needed during the installation itself (`secrets.<name>.bootstrap` NixOS option) are
+//! generated for the new host key, and decrypted locally into the installer files.
+
+use std::{
+	collections::BTreeMap,
+	fs::{self, OpenOptions},
+	io::Write as _,
+	os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _},
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::Utc;
+use clap::Parser;
+use fleet_base::{
+	fleetdata::decrypt_secret_data_async,
+	host::{Config, ConfigHost, Platform},
+	sealed::{parse_identities, BoxedIdentity},
+};
+use nix_eval::nix_go_json;
+use serde::Deserialize;
+use tempfile::TempDir;
+use tracing::{info, info_span, warn, Instrument as _};
+
+use super::{build_systems::build_task, secrets::generate};
+
+const HOST_KEY: &str = "etc/ssh/ssh_host_ed25519_key";
+
+#[derive(Parser)]
+pub struct InitHost {
+	/// Host, which configuration should be installed
+	host: String,
+	/// Ssh destination of the machine, if it differs from the configured one,
+	/// i.e because the machine is not yet reachable by its final address.
+	/// Any linux with ssh and root access (directly or via sudo) is supported.
+	#[clap(long)]
+	target: Option<String>,
+	/// Kexec tarball url, nixos-anywhere default is used if not specified
+	#[clap(long)]
+	kexec: Option<String>,
+	/// Do not reboot machine after installation
+	#[clap(long)]
+	no_reboot: bool,
+	/// Replace host key, even if the host already has one registered in fleet data.
+	/// Secrets encrypted for the old key will need to be regenerated.
+	#[clap(long)]
+	new_key: bool,
+}
+
+/// Generates host key in the nixos-anywhere extra files directory, returns public key
+async fn generate_host_key(config: &Config, extra_files: &TempDir) -> Result<String> {
+	let key = extra_files.path().join(HOST_KEY);
+	let dir = key.parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	fs::set_permissions(dir, fs::Permissions::from_mode(0o755))?;
+	generate_ssh_key(config, &key).await
+}
+
+/// Generates ed25519 ssh key without passphrase at the path, public key is written next to it
+pub(crate) async fn generate_ssh_key(config: &Config, key: &Path) -> Result<String> {
+	ensure!(!key.exists(), "{} already exists", key.display());
+	let mut cmd = config.local_host().cmd("ssh-keygen").await?;
+	cmd.arg("-q")
+		.arg("-t")
+		.arg("ed25519")
+		.arg("-N")
+		.arg("")
+		.arg("-C")
+		.arg("")
+		.arg("-f")
+		.arg(key);
+	cmd.run().await.context("failed to generate host key")?;
+	let public = fs::read_to_string(key.with_extension("pub"))?;
+	Ok(public.trim().to_owned())
+}
+
+/// Placement of the bootstrap secret part, see `fleet.secrets.bootstrap` NixOS option
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BootstrapPart {
+	target: Option<String>,
+	installer_path: Option<String>,
+	mode: String,
+}
+
+#[derive(Default)]
+struct Bootstrap {
+	secrets: Vec<String>,
+	/// `(installer path, local file)`, passed as nixos-anywhere `--disk-encryption-keys`
+	disk_keys: Vec<(String, PathBuf)>,
+}
+
+fn write_secret(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+	if let Some(dir) = path.parent() {
+		fs::create_dir_all(dir)?;
+	}
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(mode)
+		.open(path)
+		.with_context(|| format!("failed to create {}", path.display()))?;
+	file.write_all(data)?;
+	Ok(())
+}
+
+/// Generates bootstrap secrets for the new host key, and places their parts into the installer files
+async fn bootstrap_secrets(
+	config: &Config,
+	host: &ConfigHost,
+	key: &str,
+	extra_files: &TempDir,
+	disk_keys: &TempDir,
+) -> Result<Bootstrap> {
+	let nixos_config = host.nixos_config().await?;
+	let bootstrap: BTreeMap<String, BTreeMap<String, BootstrapPart>> =
+		nix_go_json!(nixos_config.fleet.secrets.bootstrap);
+	let mut out = Bootstrap::default();
+	if bootstrap.is_empty() {
+		return Ok(out);
+	}
+	let identities: Arc<[BoxedIdentity]> = parse_identities(
+		&fs::read(extra_files.path().join(HOST_KEY))?,
+		"new host key",
+	)?
+	.into();
+	for (name, parts) in bootstrap {
+		info!("generating bootstrap secret {name}");
+		let field = host.secret_field(&name).await?;
+		// Host key is not registered until the installation succeeds, so it is passed as a plain recipient
+		let secret = generate(config, &name, field, &[], &[key.to_owned()])
+			.await
+			.with_context(|| format!("failed to generate bootstrap secret {name}"))?;
+		for (part_name, placement) in &parts {
+			if placement.target.is_none() && placement.installer_path.is_none() {
+				continue;
+			}
+			let Some(part) = secret.parts.get(part_name) else {
+				bail!("generated secret {name} has no part {part_name}");
+			};
+			let data = if part.raw.encrypted {
+				decrypt_secret_data_async(identities.clone(), part.raw.data.clone()).await?
+			} else {
+				part.raw.data.clone().into()
+			};
+			if let Some(target) = &placement.target {
+				let mode = u32::from_str_radix(&placement.mode, 8)
+					.with_context(|| format!("bad mode of secret {name} part {part_name}"))?;
+				write_secret(
+					&extra_files.path().join(target.trim_start_matches('/')),
+					data.expose(),
+					mode,
+				)?;
+			}
+			if let Some(installer_path) = &placement.installer_path {
+				let local = disk_keys.path().join(format!("{name}-{part_name}"));
+				write_secret(&local, data.expose(), 0o600)?;
+				out.disk_keys.push((installer_path.clone(), local));
+			}
+		}
+		// Should be in fleet data before the system is built, as the system embeds it
+		config.insert_secret(&host.name, name.clone(), secret);
+		out.secrets.push(name);
+	}
+	Ok(out)
+}
+
+impl InitHost {
+	pub async fn run(self, config: &Config) -> Result<()> {
+		let hosts = config.list_hosts().await?;
+		ensure!(
+			hosts.iter().any(|h| h.name == self.host),
+			"unknown host: {}",
+			self.host
+		);
+		let host = config.host(&self.host).await?;
+		ensure!(
+			host.platform().await? == Platform::Nixos,
+			"only nixos hosts can be provisioned"
+		);
+		if config.cached_key(&self.host).is_some() && !self.new_key {
+			bail!(
+				"host {} already has a key registered, pass --new-key to reinstall it anyway",
+				self.host
+			);
+		}
+		// Installer has an ephemeral host key, host key is only trusted after installation
+		let target = host.ssh_target_unverified().await?;
+
+		let extra_files = TempDir::new()?;
+		let key = generate_host_key(config, &extra_files).await?;
+		let disk_keys = TempDir::new()?;
+		let bootstrap = bootstrap_secrets(config, &host, &key, &extra_files, &disk_keys).await?;
+
+		let (disko, system) = futures::try_join!(
+			build_task(config.clone(), self.host.clone(), "diskoScript", false)
+				.instrument(info_span!("disko")),
+			build_task(config.clone(), self.host.clone(), "toplevel", false)
+				.instrument(info_span!("system")),
+		)
+		.context("failed to build host, is disko module imported and configured?")?;
+
+		let mut cmd = config.local_host().cmd("nixos-anywhere").await?;
+		cmd.arg("--store-paths")
+			.arg(&disko)
+			.arg(&system)
+			.arg("--extra-files")
+			.arg(extra_files.path());
+		for (installer_path, local) in &bootstrap.disk_keys {
+			cmd.arg("--disk-encryption-keys")
+				.arg(installer_path)
+				.arg(local);
+		}
+		if let Some(port) = target.port {
+			cmd.arg("--ssh-port").arg(port.to_string());
+		}
+		if !target.jump_hosts.is_empty() {
+			cmd.arg("--ssh-option")
+				.arg(format!("ProxyJump={}", target.jump_hosts.join(",")));
+		}
+		if let Some(kexec) = &self.kexec {
+			cmd.arg("--kexec").arg(kexec);
+		}
+		if self.no_reboot {
+			cmd.arg("--no-reboot");
+		}
+		cmd.arg(
+			self.target
+				.clone()
+				.unwrap_or_else(|| format!("root@{}", target.address)),
+		);
+		info!("installing");
+		cmd.run()
+			.instrument(info_span!("nixos-anywhere"))
+			.await
+			.context("installation failed")?;
+
+		if config.cached_key(&self.host).is_some() {
+			warn!("host key was replaced, secrets encrypted for the old key need to be regenerated");
+		}
+		config.trust_host_keys(&self.host, vec![key.clone()]);
+		config.update_key(&self.host, key);
+		let delivered_at = Utc::now();
+		for name in &bootstrap.secrets {
+			let mut secret = config.host_secret(&self.host, name)?;
+			secret.bootstrapped_at = Some(delivered_at);
+			config.insert_secret(&self.host, name.clone(), secret);
+		}
+		if !bootstrap.secrets.is_empty() {
+			info!(
+				"delivered bootstrap secrets: {}",
+				bootstrap.secrets.join(", ")
+			);
+		}
+		info!("host {} is installed, its key is registered in fleet data", self.host);
+		Ok(())
+	}
+}
@@ -0,0 +1,203 @@
+use std::{
+	fs::{self, OpenOptions},
+	io::Write as _,
+	os::unix::fs::OpenOptionsExt as _,
+	path::PathBuf,
+	process::{Command, Stdio},
+};
+
+use age::secrecy::ExposeSecret as _;
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use fleet_base::{host::Config, keys::default_signing_key};
+use tracing::{error, info, info_span, warn, Instrument as _};
+
+use crate::audit::{self, AuditOp};
+
+#[derive(Parser)]
+pub enum Keys {
+	/// Generate nix signing key, which is used to sign deployed closures,
+	/// its public part should be set as `nixSigning.publicKey`
+	GenerateSigningKey {
+		/// Key name, embedded in signatures
+		#[clap(long, default_value = "fleet-1")]
+		name: String,
+		/// Where to write the secret key, defaults to ~/.config/fleet/signing-key
+		#[clap(long)]
+		output: Option<PathBuf>,
+	},
+	/// Replace host encryption key with the key sealed to the host TPM, and reencrypt host secrets for it.
+	///
+	/// Key is generated on the host, and is unsealed by the host on every secrets installation,
+	/// so secrets can't be decrypted from the disk contents alone.
+	EnrollTpm {
+		host: String,
+		/// TPM PCRs the key is bound to, in systemd-cryptenroll format
+		#[clap(long, default_value = "7")]
+		pcrs: String,
+	},
+	/// Generate deployer token: restricted age identity for CI, which can deploy hosts and read
+	/// shared secrets with `ci = true`, but can't read or modify other secrets
+	IssueDeployer {
+		/// Deployer name, used as the `deployers` attribute
+		name: String,
+		/// Where to write the token, its path should be passed to CI as `FLEET_IDENTITY`
+		#[clap(long)]
+		output: PathBuf,
+	},
+}
+
+fn nix_key(args: &[&str], stdin: Option<&[u8]>) -> Result<String> {
+	let mut child = Command::new("nix")
+		.arg("key")
+		.args(args)
+		.stdin(if stdin.is_some() {
+			Stdio::piped()
+		} else {
+			Stdio::null()
+		})
+		.stdout(Stdio::piped())
+		.spawn()
+		.context("failed to run nix")?;
+	if let Some(stdin) = stdin {
+		child.stdin.take().expect("piped").write_all(stdin)?;
+	}
+	let output = child.wait_with_output()?;
+	ensure!(output.status.success(), "nix key {} failed", args[0]);
+	Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Secrets encrypted for the old key are reencrypted on the host, which is able to decrypt them
+/// with both keys until the ssh host key is gone
+async fn enroll_tpm(config: &Config, name: &str, pcrs: &str) -> Result<()> {
+	let host = config.host(name).await?;
+	let mut cmd = host.cmd("fleet-install-secrets").await?;
+	cmd.arg("enroll-tpm").eqarg("--pcrs", pcrs);
+	let key = cmd
+		.sudo()
+		.run_string()
+		.await
+		.context("failed to enroll TPM key, does the host have TPM2 and up to date system?")?;
+	let key = key.trim().to_owned();
+	ensure!(
+		key.starts_with("age1"),
+		"unexpected enrollment output: {key:?}"
+	);
+	config.update_key(name, key);
+	info!("host key is sealed to the TPM");
+
+	let mut failed = Vec::new();
+	for secret_name in config.list_secrets(name) {
+		let mut secret = config.host_secret(name, &secret_name)?;
+		let result: Result<()> = try {
+			for part in secret.parts.values_mut() {
+				if part.raw.encrypted {
+					part.raw = host
+						.reencrypt(part.raw.clone(), vec![name.to_owned()], &[])
+						.instrument(info_span!("reencrypt", secret = secret_name))
+						.await?;
+				}
+			}
+		};
+		if let Err(e) = result {
+			error!("failed to reencrypt {secret_name}: {e:#}");
+			failed.push(secret_name);
+			continue;
+		}
+		audit::record(config, AuditOp::Reencrypt, &secret_name, &[name.to_owned()])?;
+		config.insert_secret(name, secret_name, secret);
+	}
+	for secret_name in config.list_shared() {
+		let mut secret = config.shared_secret(&secret_name)?;
+		if !secret.owners.iter().any(|o| o == name) {
+			continue;
+		}
+		let result: Result<()> = try {
+			for part in secret.secret.parts.values_mut() {
+				if part.raw.encrypted {
+					part.raw = host
+						.reencrypt(part.raw.clone(), secret.owners.clone(), &secret.readers)
+						.instrument(info_span!("reencrypt", secret = secret_name))
+						.await?;
+				}
+			}
+		};
+		if let Err(e) = result {
+			error!("failed to reencrypt {secret_name}: {e:#}");
+			failed.push(secret_name);
+			continue;
+		}
+		audit::record(config, AuditOp::Reencrypt, &secret_name, &secret.owners)?;
+		config.replace_shared(secret_name, secret);
+	}
+	if !failed.is_empty() {
+		warn!(
+			"secrets, which are still encrypted for the ssh host key, regenerate them:\n{}",
+			failed.join("\n")
+		);
+	}
+	info!("deploy the host to install secrets encrypted for the new key");
+	Ok(())
+}
+
+impl Keys {
+	/// Whether the fleet configuration should be evaluated for this command
+	pub fn needs_config(&self) -> bool {
+		matches!(self, Keys::EnrollTpm { .. })
+	}
+	pub async fn run_with_config(&self, config: &Config) -> Result<()> {
+		match self {
+			Keys::EnrollTpm { host, pcrs } => enroll_tpm(config, host, pcrs).await,
+			_ => self.run(),
+		}
+	}
+	/// Runs without evaluating fleet configuration, as the key is needed to configure it
+	pub fn run(&self) -> Result<()> {
+		match self {
+			Keys::GenerateSigningKey { name, output } => {
+				let output = output
+					.clone()
+					.or_else(default_signing_key)
+					.context("failed to determine config directory, pass --output")?;
+				ensure!(
+					!output.exists(),
+					"{output:?} already exists, remove it first to generate a new key"
+				);
+				let secret = nix_key(&["generate-secret", "--key-name", name], None)?;
+				let public = nix_key(&["convert-secret-to-public"], Some(secret.as_bytes()))?;
+
+				if let Some(dir) = output.parent() {
+					fs::create_dir_all(dir)?;
+				}
+				let mut file = OpenOptions::new()
+					.write(true)
+					.create_new(true)
+					.mode(0o600)
+					.open(&output)
+					.with_context(|| format!("failed to create {output:?}"))?;
+				file.write_all(secret.as_bytes())?;
+
+				info!("secret key is written to {output:?}");
+				info!("add it to the fleet configuration: nixSigning.publicKey = \"{public}\";");
+			}
+			Keys::IssueDeployer { name, output } => {
+				let identity = age::x25519::Identity::generate();
+				let public = identity.to_public().to_string();
+				let mut file = OpenOptions::new()
+					.write(true)
+					.create_new(true)
+					.mode(0o600)
+					.open(output)
+					.with_context(|| format!("failed to create {output:?}"))?;
+				writeln!(file, "# fleet deployer token {name}, public key: {public}")?;
+				writeln!(file, "{}", identity.to_string().expose_secret())?;
+
+				info!("deployer token is written to {output:?}");
+				info!("add it to the fleet configuration: deployers.{name} = \"{public}\";");
+				info!("then set `ci = true` for shared secrets needed by CI, and run `fleet secret regenerate` for them");
+			}
+			Keys::EnrollTpm { .. } => unreachable!("needs config"),
+		}
+		Ok(())
+	}
+}
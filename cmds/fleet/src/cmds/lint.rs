@@ -0,0 +1,298 @@
+//! Static checks of the evaluated fleet config, for suspicious, yet evaluating patterns.
+//!
+//! Unlike `fleet doctor`, nothing is checked on the hosts or in the local environment,
+//! so lint results only depend on the fleet config and fleet data, and are suitable for CI.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use fleet_base::{
+	host::{Config, ConfigHost, Platform},
+	opts::FleetOpts,
+};
+use nix_eval::{nix_go, nix_go_json, Value};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+const LINT_VERSION: u32 = 1;
+
+#[derive(Parser)]
+pub struct Lint {
+	/// Print findings as json, `{"version": 1, "findings": [{"lint", "severity", "host", "message"}]}`
+	#[clap(long)]
+	json: bool,
+	/// Fail on warnings too, not only on errors
+	#[clap(long)]
+	deny_warnings: bool,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum Severity {
+	Warning,
+	Error,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Finding {
+	/// Stable lint name, which can be matched in CI
+	lint: &'static str,
+	severity: Severity,
+	host: Option<String>,
+	message: String,
+}
+
+#[derive(Serialize)]
+struct Report {
+	version: u32,
+	findings: Vec<Finding>,
+}
+impl Report {
+	fn push(
+		&mut self,
+		lint: &'static str,
+		severity: Severity,
+		host: Option<&str>,
+		message: impl Into<String>,
+	) {
+		self.findings.push(Finding {
+			lint,
+			severity,
+			host: host.map(ToOwned::to_owned),
+			message: message.into(),
+		});
+	}
+}
+
+/// Secret mode grants read access to others
+fn is_world_readable(mode: &str) -> bool {
+	u32::from_str_radix(mode, 8).is_ok_and(|m| m & 0o004 != 0)
+}
+
+/// NixOS warnings, which are caused by renamed or removed options
+fn is_deprecation(warning: &str) -> bool {
+	let warning = warning.to_ascii_lowercase();
+	[
+		"deprecated",
+		"has been renamed",
+		"obsolete",
+		"no longer has any effect",
+	]
+	.iter()
+	.any(|w| warning.contains(w))
+}
+
+fn duplicates(items: &[String]) -> BTreeSet<&String> {
+	let mut seen = BTreeSet::new();
+	items.iter().filter(|i| !seen.insert(*i)).collect()
+}
+
+/// `hosts` are the selected hosts, `all` are all hosts of the fleet
+fn lint_data(report: &mut Report, config: &Config, hosts: &[String], all: &[String]) {
+	let data = config.data();
+	for host in hosts {
+		if data
+			.hosts
+			.get(host)
+			.map_or(true, |h| h.encryption_key.is_empty())
+		{
+			report.push(
+				"missing-encryption-key",
+				Severity::Error,
+				Some(host),
+				"host has no encryption key in fleet data, its secrets can't be encrypted",
+			);
+		}
+	}
+	for (name, secret) in &data.shared_secrets {
+		for owner in &secret.owners {
+			if !all.contains(owner) {
+				report.push(
+					"unknown-secret-owner",
+					Severity::Error,
+					None,
+					format!("shared secret {name} is owned by {owner}, which is not a host of the fleet"),
+				);
+			}
+		}
+	}
+	for (host, secrets) in &data.host_secrets {
+		if !all.contains(host) && !secrets.is_empty() {
+			report.push(
+				"unknown-secret-owner",
+				Severity::Error,
+				None,
+				format!(
+					"fleet data has {} secrets of {host}, which is not a host of the fleet",
+					secrets.len()
+				),
+			);
+		}
+	}
+}
+
+async fn lint_secrets(report: &mut Report, host: &ConfigHost, nixos: &Value) -> Result<()> {
+	let secrets = nix_go!(nixos.secrets);
+	// Attributes of `secrets.<name>`, which are not parts
+	let secret_options: Vec<String> = nix_go_json!(nixos.fleet.secrets.optionNames);
+	for name in secrets.list_fields().await? {
+		let secret = nix_go!(secrets[{ name }]);
+		let mode: String = nix_go_json!(secret.mode);
+		if is_world_readable(&mode) {
+			report.push(
+				"world-readable-secret",
+				Severity::Error,
+				Some(&host.name),
+				format!("secret {name} has world-readable mode {mode}"),
+			);
+		}
+		for part_name in secret.list_fields().await? {
+			if secret_options.contains(&part_name) {
+				continue;
+			}
+			let part = nix_go!(secret[{ part_name }]);
+			let mode: Option<String> = nix_go_json!(part.mode);
+			if let Some(mode) = mode.filter(|m| is_world_readable(m)) {
+				report.push(
+					"world-readable-secret",
+					Severity::Error,
+					Some(&host.name),
+					format!("part {part_name} of secret {name} has world-readable mode {mode}"),
+				);
+			}
+			let target: Option<String> = nix_go_json!(part.target);
+			if let Some(target) = target.filter(|t| t.starts_with("/nix/store")) {
+				report.push(
+					"world-readable-secret",
+					Severity::Error,
+					Some(&host.name),
+					format!("part {part_name} of secret {name} targets {target}, which is world-readable"),
+				);
+			}
+		}
+	}
+	Ok(())
+}
+
+async fn lint_host(report: &mut Report, host: &ConfigHost, hosts: &[String]) -> Result<()> {
+	let tags = host.tags().await?;
+	for tag in duplicates(&tags) {
+		report.push(
+			"duplicate-tag",
+			Severity::Warning,
+			Some(&host.name),
+			format!("tag {tag} is listed multiple times"),
+		);
+	}
+	for tag in tags.iter().filter(|t| hosts.contains(t)) {
+		report.push(
+			"tag-shadows-host",
+			Severity::Warning,
+			Some(&host.name),
+			format!("tag {tag} is also a host name, `--only {tag}` selects the host, not the tag"),
+		);
+	}
+
+	if host.platform().await? != Platform::Nixos {
+		return Ok(());
+	}
+	let nixos = host.nixos_config().await?;
+	if !nixos.has_field("fleet").await? {
+		report.push(
+			"missing-fleet-module",
+			Severity::Error,
+			Some(&host.name),
+			"nixos system has no fleet module, it was not built with fleet nixosModules",
+		);
+		return Ok(());
+	}
+	let warnings: Vec<String> = nix_go_json!(nixos.warnings);
+	for warning in warnings.into_iter().filter(|w| is_deprecation(w)) {
+		report.push(
+			"deprecated-option",
+			Severity::Warning,
+			Some(&host.name),
+			warning,
+		);
+	}
+	lint_secrets(report, host, &nixos).await
+}
+
+impl Lint {
+	pub async fn run(&self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		let mut report = Report {
+			version: LINT_VERSION,
+			findings: vec![],
+		};
+		let all = config.list_hosts().await?;
+		let names: Vec<String> = all.iter().map(|h| h.name.clone()).collect();
+		let mut selected = Vec::new();
+		for host in all {
+			if !opts.should_skip(&host).await? {
+				selected.push(host);
+			}
+		}
+		lint_data(
+			&mut report,
+			config,
+			&selected.iter().map(|h| h.name.clone()).collect::<Vec<_>>(),
+			&names,
+		);
+		for host in &selected {
+			lint_host(&mut report, host, &names).await?;
+		}
+
+		let errors = report
+			.findings
+			.iter()
+			.filter(|f| f.severity == Severity::Error || self.deny_warnings)
+			.count();
+		if self.json {
+			println!("{}", serde_json::to_string_pretty(&report)?);
+		} else {
+			for finding in &report.findings {
+				let location = finding
+					.host
+					.as_ref()
+					.map(|h| format!("{h}: "))
+					.unwrap_or_default();
+				match finding.severity {
+					Severity::Warning => {
+						warn!("[{}] {location}{}", finding.lint, finding.message)
+					}
+					Severity::Error => {
+						error!("[{}] {location}{}", finding.lint, finding.message)
+					}
+				}
+			}
+			if report.findings.is_empty() {
+				info!("no problems found");
+			}
+		}
+		if errors != 0 {
+			bail!("{errors} lint findings");
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn checks() {
+		assert!(is_world_readable("0444"));
+		assert!(is_world_readable("644"));
+		assert!(!is_world_readable("0440"));
+		assert!(!is_world_readable("bad"));
+		assert!(is_deprecation(
+			"The option `services.foo.bar' defined in `x.nix' has been renamed to `services.foo.baz'."
+		));
+		assert!(!is_deprecation("host has no swap"));
+		let tags = ["a", "b", "a"].map(ToOwned::to_owned);
+		assert_eq!(duplicates(&tags).into_iter().collect::<Vec<_>>(), ["a"]);
+	}
+}
@@ -0,0 +1,65 @@
+use std::env::current_dir;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use fleet_base::{
+	datafile::{self, Layout},
+	keys::IdentityStore,
+	migrate,
+	opts::FleetOpts,
+};
+use tracing::info;
+
+#[derive(Parser)]
+pub struct Migrate {
+	/// Only print the changes, which would be made, fail if fleet data is outdated
+	#[clap(long)]
+	check: bool,
+}
+
+impl Migrate {
+	/// Runs without evaluating fleet configuration, as evaluation upgrades data by itself
+	pub fn run(&self, opts: &FleetOpts) -> Result<()> {
+		let directory = current_dir()?;
+		let identities = IdentityStore::new(opts.identity.clone(), opts.keyring.clone());
+		let changes = migrate::upgrade_file(&directory, &opts.fleet, &identities, self.check)?;
+		if changes.is_empty() {
+			info!("fleet data is up to date");
+			return Ok(());
+		}
+		for change in &changes {
+			info!("{change}");
+		}
+		if self.check {
+			bail!("fleet data is outdated, run `fleet migrate` to upgrade it");
+		}
+		info!("fleet data is upgraded to {}", migrate::CURRENT_VERSION);
+		Ok(())
+	}
+}
+
+#[derive(Parser)]
+pub struct MigrateStorage {
+	/// Target layout of fleet data
+	#[clap(value_enum)]
+	layout: Layout,
+}
+
+impl MigrateStorage {
+	pub fn run(&self, opts: &FleetOpts) -> Result<()> {
+		let directory = current_dir()?;
+		let identities = IdentityStore::new(opts.identity.clone(), opts.keyring.clone());
+		for change in migrate::upgrade_file(&directory, &opts.fleet, &identities, false)? {
+			info!("migrated fleet data: {change}");
+		}
+		if !datafile::convert(&directory, &opts.fleet, &identities, self.layout)? {
+			info!(
+				"fleet data is already stored in the {:?} layout",
+				self.layout
+			);
+			return Ok(());
+		}
+		info!("fleet data is converted to the {:?} layout", self.layout);
+		Ok(())
+	}
+}
@@ -1,5 +1,24 @@
 pub mod build_systems;
 pub mod complete;
+pub mod container;
+pub mod data;
+pub mod disko;
+pub mod doctor;
+pub mod flash;
+pub mod host;
+pub mod image;
 pub mod info;
+pub mod init_host;
+pub mod keys;
+pub mod lint;
+pub mod migrate;
+pub mod probe;
+pub mod power;
+pub mod push;
+pub mod rollback;
 pub mod secrets;
+pub mod ssh;
+pub mod test_vm;
 pub mod tf;
+pub mod verify;
+pub mod watch;
@@ -0,0 +1,291 @@
+use std::{
+	fs,
+	net::UdpSocket,
+	process::Stdio,
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::{Parser, ValueEnum};
+use fleet_base::{
+	host::{Config, ConfigHost},
+	opts::FleetOpts,
+};
+use nix_eval::nix_go_json;
+use serde::Deserialize;
+use tokio::{io::AsyncWriteExt as _, process::Command, time::sleep};
+use tracing::{error, info, info_span, warn, Instrument as _};
+
+const WOL_PORT: u16 = 9;
+const REACHABLE_POLL: Duration = Duration::from_secs(5);
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum PowerAction {
+	On,
+	Off,
+	Cycle,
+}
+
+#[derive(Parser)]
+pub struct Power {
+	action: PowerAction,
+	/// Hosts to manage, all hosts (respecting --only/--skip) if not specified
+	hosts: Vec<String>,
+	/// After powering host on, wait until it is reachable over ssh
+	#[clap(long)]
+	wait: bool,
+	/// Maximum time to wait for host to become reachable, in seconds
+	#[clap(long, default_value_t = 300)]
+	wait_timeout: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum BmcProtocol {
+	Ipmi,
+	Redfish,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Bmc {
+	protocol: BmcProtocol,
+	address: String,
+	user: String,
+	password_file: String,
+	redfish_system: String,
+}
+
+/// Power management options of the host, declared in `hosts.<name>.power`
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PowerConfig {
+	mac: Option<String>,
+	broadcast: String,
+	bmc: Option<Bmc>,
+}
+
+impl PowerConfig {
+	pub(crate) async fn for_host(host: &ConfigHost) -> Result<Self> {
+		let Some(host_config) = &host.host_config else {
+			bail!("local host has no power options");
+		};
+		Ok(nix_go_json!(host_config.power))
+	}
+	/// Whether fleet is able to power the host on
+	pub(crate) fn can_wake(&self) -> bool {
+		self.mac.is_some() || self.bmc.is_some()
+	}
+}
+
+fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+	let bytes = mac
+		.split([':', '-'])
+		.map(|b| u8::from_str_radix(b, 16))
+		.collect::<Result<Vec<_>, _>>()
+		.with_context(|| format!("invalid mac address: {mac}"))?;
+	bytes
+		.try_into()
+		.map_err(|_| anyhow!("invalid mac address: {mac}"))
+}
+
+fn send_wol(mac: &str, broadcast: &str) -> Result<()> {
+	let mac = parse_mac(mac)?;
+	// Magic packet: 6 bytes of 0xff, followed by 16 repetitions of the target mac
+	let mut packet = vec![0xff; 6];
+	for _ in 0..16 {
+		packet.extend_from_slice(&mac);
+	}
+	let socket = UdpSocket::bind("0.0.0.0:0")?;
+	socket.set_broadcast(true)?;
+	socket
+		.send_to(&packet, (broadcast, WOL_PORT))
+		.context("failed to send wake-on-lan packet")?;
+	Ok(())
+}
+
+async fn ipmi(bmc: &Bmc, command: &str) -> Result<()> {
+	let output = Command::new("ipmitool")
+		.args(["-I", "lanplus", "-H", &bmc.address, "-U", &bmc.user])
+		.args(["-f", &bmc.password_file])
+		.args(["chassis", "power", command])
+		.output()
+		.await
+		.context("failed to run ipmitool")?;
+	ensure!(
+		output.status.success(),
+		"ipmitool failed: {}",
+		String::from_utf8_lossy(&output.stderr).trim()
+	);
+	Ok(())
+}
+
+/// Quoted curl config values support backslash escapes
+pub(crate) fn curl_config_escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+async fn redfish_reset(bmc: &Bmc, reset_type: &str) -> Result<()> {
+	let password = fs::read_to_string(&bmc.password_file)
+		.with_context(|| format!("failed to read bmc password from {}", bmc.password_file))?;
+	let url = format!(
+		"https://{}{}/Actions/ComputerSystem.Reset",
+		bmc.address, bmc.redfish_system
+	);
+	// Credentials are passed in the curl config on stdin, arguments are visible to other users
+	let credentials = format!(
+		"user = \"{}\"\n",
+		curl_config_escape(&format!("{}:{}", bmc.user, password.trim_end()))
+	);
+	// BMCs mostly use self-signed certificates
+	let mut child = Command::new("curl")
+		.args(["--silent", "--show-error", "--fail", "--insecure"])
+		.args(["--config", "-"])
+		.args(["--header", "Content-Type: application/json"])
+		.args(["--data", &format!("{{\"ResetType\":\"{reset_type}\"}}")])
+		.arg(url)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.context("failed to run curl")?;
+	let mut stdin = child.stdin.take().expect("piped");
+	stdin.write_all(credentials.as_bytes()).await?;
+	drop(stdin);
+	let output = child
+		.wait_with_output()
+		.await
+		.context("failed to run curl")?;
+	ensure!(
+		output.status.success(),
+		"redfish request failed: {}",
+		String::from_utf8_lossy(&output.stderr).trim()
+	);
+	Ok(())
+}
+
+pub(crate) async fn power_on(power: &PowerConfig) -> Result<()> {
+	match (&power.bmc, &power.mac) {
+		(Some(bmc), _) if bmc.protocol == BmcProtocol::Ipmi => ipmi(bmc, "on").await,
+		(Some(bmc), _) => redfish_reset(bmc, "On").await,
+		(None, Some(mac)) => send_wol(mac, &power.broadcast),
+		(None, None) => bail!("neither power.mac nor power.bmc is configured"),
+	}
+}
+
+pub(crate) async fn power_off(host: &ConfigHost, power: &PowerConfig) -> Result<()> {
+	match &power.bmc {
+		Some(bmc) if bmc.protocol == BmcProtocol::Ipmi => ipmi(bmc, "soft").await,
+		Some(bmc) => redfish_reset(bmc, "GracefulShutdown").await,
+		None => ssh_systemctl(host, "poweroff").await,
+	}
+}
+
+async fn power_cycle(host: &ConfigHost, power: &PowerConfig) -> Result<()> {
+	match &power.bmc {
+		Some(bmc) if bmc.protocol == BmcProtocol::Ipmi => ipmi(bmc, "cycle").await,
+		Some(bmc) => redfish_reset(bmc, "PowerCycle").await,
+		None => ssh_systemctl(host, "reboot").await,
+	}
+}
+
+async fn ssh_systemctl(host: &ConfigHost, verb: &str) -> Result<()> {
+	let mut cmd = host.cmd("systemctl").await?;
+	// Connection is dropped by the host, do not wait for the job to finish
+	cmd.arg("--no-block").arg(verb);
+	cmd.sudo().run().await
+}
+
+/// Checks reachability with plain ssh, fleet session to the host is cached, and can't be
+/// reestablished, if the host wasn't reachable initially
+pub(crate) async fn is_reachable(host: &ConfigHost) -> Result<bool> {
+	let target = host.ssh_target().await?;
+	let status = Command::new("ssh")
+		.args(["-o", "BatchMode=yes", "-o", "ConnectTimeout=5"])
+		.args(target.ssh_args())
+		.arg(target.destination())
+		.arg("true")
+		.kill_on_drop(true)
+		.output()
+		.await
+		.context("failed to run ssh")?
+		.status;
+	Ok(status.success())
+}
+
+/// Powers host on, if it is not reachable, returns whether it was powered on
+pub(crate) async fn wake_if_needed(host: &ConfigHost) -> Result<bool> {
+	let power = PowerConfig::for_host(host).await?;
+	if !power.can_wake() || is_reachable(host).await? {
+		return Ok(false);
+	}
+	info!("host is not reachable, powering on");
+	power_on(&power).await?;
+	Ok(true)
+}
+
+pub(crate) async fn wait_reachable(host: &ConfigHost, timeout: Duration) -> Result<()> {
+	let started = Instant::now();
+	while !is_reachable(host).await? {
+		ensure!(
+			started.elapsed() < timeout,
+			"host is not reachable after {}s",
+			timeout.as_secs()
+		);
+		sleep(REACHABLE_POLL).await;
+	}
+	Ok(())
+}
+
+impl Power {
+	async fn run_host(&self, host: &ConfigHost) -> Result<()> {
+		let power = PowerConfig::for_host(host).await?;
+		match self.action {
+			PowerAction::On => {
+				info!("powering on");
+				power_on(&power).await?;
+			}
+			PowerAction::Off => {
+				info!("powering off");
+				power_off(host, &power).await?;
+			}
+			PowerAction::Cycle => {
+				info!("power cycling");
+				power_cycle(host, &power).await?;
+			}
+		}
+		if self.wait && self.action != PowerAction::Off {
+			if self.action == PowerAction::Cycle {
+				// Give host some time to actually go down
+				sleep(REACHABLE_POLL * 2).await;
+			}
+			wait_reachable(host, Duration::from_secs(self.wait_timeout)).await?;
+			info!("host is up");
+		}
+		Ok(())
+	}
+
+	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		let mut failed = false;
+		for host in config.list_hosts().await? {
+			if self.hosts.is_empty() {
+				if opts.should_skip(&host).await? {
+					continue;
+				}
+			} else if !self.hosts.contains(&host.name) {
+				continue;
+			}
+			if host.local {
+				warn!("skipping local host {}", host.name);
+				continue;
+			}
+			let span = info_span!("power", host = host.name);
+			if let Err(e) = self.run_host(&host).instrument(span).await {
+				error!("{}: {e:#}", host.name);
+				failed = true;
+			}
+		}
+		ensure!(!failed, "power management failed for some hosts");
+		Ok(())
+	}
+}
@@ -0,0 +1,195 @@
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+	time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use fleet_base::{
+	host::{Config, ConfigHost},
+	opts::FleetOpts,
+};
+use nix_eval::nix_go_json;
+use serde::{Deserialize, Serialize};
+use tabled::{Table, Tabled};
+use tempfile::NamedTempFile;
+use tracing::{error, info, info_span, Instrument};
+
+const RTT_SAMPLES: u32 = 5;
+const THROUGHPUT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Links slower than this are copied with ssh compression enabled
+const COMPRESS_BELOW_BYTES_PER_SEC: u64 = 4 * 1024 * 1024;
+/// Through links slower than this only derivations are uploaded, and the system is built on the host
+const BUILD_ON_TARGET_BELOW_BYTES_PER_SEC: u64 = 256 * 1024;
+/// Copy timeout is never lower than this value
+const MIN_COPY_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Parser)]
+pub struct Probe {
+	/// Hosts to probe, all hosts (respecting --only/--skip) if not specified
+	hosts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Tabled)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeResult {
+	#[tabled(rename = "RTT (ms)")]
+	pub rtt_ms: u64,
+	#[tabled(rename = "Upload (bytes/s)")]
+	pub upload_bytes_per_sec: u64,
+	#[tabled(rename = "Probed at")]
+	pub probed_at: DateTime<Utc>,
+}
+
+/// Strategy for uploading closure to the host
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CopyStrategy {
+	Direct,
+	/// Enable ssh compression, useful for slow links
+	Compressed,
+	/// Upload to the seed host once, the host copies the closure from it, see `deploy.seedHost`
+	Relay,
+	/// Only derivations are uploaded, the system is built on the host
+	BuildOnTarget,
+}
+
+impl ProbeResult {
+	/// Whether ssh compression is beneficial for the link
+	pub fn is_slow(&self) -> bool {
+		self.upload_bytes_per_sec < COMPRESS_BELOW_BYTES_PER_SEC
+	}
+	pub fn copy_strategy(&self, has_seed: bool) -> CopyStrategy {
+		if !self.is_slow() {
+			CopyStrategy::Direct
+		} else if has_seed {
+			CopyStrategy::Relay
+		} else if self.upload_bytes_per_sec < BUILD_ON_TARGET_BELOW_BYTES_PER_SEC {
+			CopyStrategy::BuildOnTarget
+		} else {
+			CopyStrategy::Compressed
+		}
+	}
+	/// Realistic timeout for copying closure of specified size, with a generous margin,
+	/// as closure might be partially present on the target, or be substituted.
+	pub fn copy_timeout(&self, closure_size: u64) -> Duration {
+		let expected = closure_size / self.upload_bytes_per_sec.max(1);
+		MIN_COPY_TIMEOUT.max(Duration::from_secs(expected * 3))
+	}
+}
+
+/// `deploy.seedHost` of the host, if it is not the host itself
+pub async fn seed_host(host: &ConfigHost) -> Result<Option<ConfigHost>> {
+	if host.local {
+		return Ok(None);
+	}
+	let deploy = host.deploy_options().await?;
+	let seed: Option<String> = nix_go_json!(deploy.seedHost);
+	match seed {
+		Some(seed) if seed != host.name => Ok(Some(host.config().host(&seed).await?)),
+		_ => Ok(None),
+	}
+}
+
+fn probes_path(directory: &Path) -> PathBuf {
+	directory.join(".fleet/probe.json")
+}
+
+pub fn load_probes(directory: &Path) -> BTreeMap<String, ProbeResult> {
+	let Ok(data) = fs::read(probes_path(directory)) else {
+		return BTreeMap::new();
+	};
+	serde_json::from_slice(&data).unwrap_or_default()
+}
+
+fn save_probes(directory: &Path, probes: &BTreeMap<String, ProbeResult>) -> Result<()> {
+	let path = probes_path(directory);
+	let dir = path.parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	let tmp = NamedTempFile::new_in(dir)?;
+	serde_json::to_writer_pretty(&tmp, probes)?;
+	tmp.persist(path)?;
+	Ok(())
+}
+
+async fn probe_host(config: &Config, host: &ConfigHost) -> Result<ProbeResult> {
+	if host.local {
+		bail!("local host can't be probed");
+	}
+	// First command establishes the connection, it is not counted.
+	host.cmd("true").await?.run().await?;
+
+	let mut total = Duration::ZERO;
+	for _ in 0..RTT_SAMPLES {
+		let cmd = host.cmd("true").await?;
+		let started = Instant::now();
+		cmd.run().await?;
+		total += started.elapsed();
+	}
+	let rtt = total / RTT_SAMPLES;
+
+	// MyCommand has no stdin support, so the data is piped into plain ssh,
+	// connected with the same parameters as fleet.
+	let target = host.ssh_target().await?;
+	let mut cmd = config.local_host().cmd("sh").await?;
+	cmd.arg("-c").arg(format!(
+		"head -c {THROUGHPUT_BYTES} /dev/urandom | ssh {} {} 'cat > /dev/null'",
+		target.ssh_args().join(" "),
+		target.destination(),
+	));
+	let started = Instant::now();
+	cmd.run().await.context("throughput test")?;
+	let elapsed = started.elapsed().saturating_sub(rtt);
+	let upload_bytes_per_sec = (THROUGHPUT_BYTES as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+
+	Ok(ProbeResult {
+		rtt_ms: rtt.as_millis() as u64,
+		upload_bytes_per_sec,
+		probed_at: Utc::now(),
+	})
+}
+
+#[derive(Tabled)]
+struct ProbeDisplay {
+	#[tabled(rename = "Host")]
+	host: String,
+	#[tabled(inline)]
+	result: ProbeResult,
+	#[tabled(rename = "Strategy")]
+	strategy: String,
+}
+
+impl Probe {
+	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		let mut probes = load_probes(&config.directory);
+		let mut table = Vec::new();
+		for host in config.list_hosts().await? {
+			if self.hosts.is_empty() {
+				if opts.should_skip(&host).await? || host.local {
+					continue;
+				}
+			} else if !self.hosts.contains(&host.name) {
+				continue;
+			}
+			let span = info_span!("probe", host = host.name);
+			match probe_host(config, &host).instrument(span).await {
+				Ok(result) => {
+					let has_seed = seed_host(&host).await?.is_some();
+					table.push(ProbeDisplay {
+						host: host.name.clone(),
+						strategy: format!("{:?}", result.copy_strategy(has_seed)),
+						result: result.clone(),
+					});
+					probes.insert(host.name.clone(), result);
+				}
+				Err(e) => error!("failed to probe {}: {e}", host.name),
+			}
+		}
+		save_probes(&config.directory, &probes)?;
+		info!("probed\n{}", Table::new(table));
+		Ok(())
+	}
+}
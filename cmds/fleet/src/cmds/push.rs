@@ -0,0 +1,115 @@
+//! Uploads built systems ahead of time, so that the following deploy doesn't need to wait
+//! for the (potentially slow) copy.
+
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use fleet_base::{host::Config, opts::FleetOpts};
+use tempfile::NamedTempFile;
+use tokio::task::LocalSet;
+use tracing::{error, field, info, info_span, Instrument};
+
+use super::{
+	build_systems::{build_task, upload_task},
+	probe::load_probes,
+};
+
+#[derive(Parser)]
+pub struct Push {
+	/// Also copy closures to the binary cache, i.e s3://bucket or file:///path
+	#[clap(long)]
+	to_cache: Option<String>,
+	/// Only copy closures to the binary cache, without uploading them to hosts
+	#[clap(long, requires = "to_cache")]
+	cache_only: bool,
+}
+
+fn pushed_path(directory: &Path) -> PathBuf {
+	directory.join(".fleet/pushed.json")
+}
+
+/// Systems, which were pushed to the hosts by `fleet push`
+pub fn load_pushed(directory: &Path) -> BTreeMap<String, PathBuf> {
+	let Ok(data) = fs::read(pushed_path(directory)) else {
+		return BTreeMap::new();
+	};
+	serde_json::from_slice(&data).unwrap_or_default()
+}
+
+fn save_pushed(directory: &Path, pushed: &BTreeMap<String, PathBuf>) -> Result<()> {
+	let path = pushed_path(directory);
+	let dir = path.parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	let tmp = NamedTempFile::new_in(dir)?;
+	serde_json::to_writer_pretty(&tmp, pushed)?;
+	tmp.persist(path)?;
+	Ok(())
+}
+
+async fn copy_to_cache(config: &Config, cache: &str, built: &Path) -> Result<()> {
+	let mut cmd = config.local_host().cmd("nix").await?;
+	cmd.arg("copy").comparg("--to", cache).arg(built);
+	cmd.retry_transient()
+		.run_nix()
+		.await
+		.context("failed to copy to binary cache")
+}
+
+impl Push {
+	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		let set = LocalSet::new();
+		let probes = Arc::new(load_probes(&config.directory));
+		let pushed = Arc::new(Mutex::new(load_pushed(&config.directory)));
+		let to_cache = Arc::new(self.to_cache);
+		for host in config.list_hosts().await? {
+			if opts.should_skip(&host).await? {
+				continue;
+			}
+			let config = config.clone();
+			let probes = probes.clone();
+			let pushed = pushed.clone();
+			let to_cache = to_cache.clone();
+			let cache_only = self.cache_only;
+			let span = info_span!("push", host = field::display(&host.name));
+			set.spawn_local(
+				(async move {
+					let result: Result<()> = try {
+						let built = build_task(config.clone(), host.name.clone(), "toplevel", false).await?;
+						if let Some(cache) = to_cache.as_deref() {
+							info!("copying to binary cache");
+							copy_to_cache(&config, cache, &built).await?;
+						}
+						if !cache_only && !host.local {
+							let local_host = config.local_host();
+							let closure_size = local_host.closure_size(&built).await.ok();
+							upload_task(
+								&local_host,
+								&host,
+								&built,
+								&probes,
+								closure_size,
+								None,
+								None,
+							)
+							.await?;
+							pushed.lock().unwrap().insert(host.name.clone(), built);
+						}
+					};
+					if let Err(e) = result {
+						error!("failed to push: {e:#}");
+					}
+				})
+				.instrument(span),
+			);
+		}
+		set.await;
+		let pushed = pushed.lock().unwrap();
+		save_pushed(&config.directory, &pushed)
+	}
+}
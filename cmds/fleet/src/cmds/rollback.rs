@@ -0,0 +1,131 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use clap::Parser;
+use fleet_base::host::{Config, Platform};
+use tracing::{info, info_span, warn, Instrument as _};
+
+use super::build_systems::{profile_target, RollbackSettings, SYSTEM_PROFILE};
+use crate::{
+	generations::{current_generation, switch_generation},
+	journal::{append_journal, read_journal, GenerationRef, JournalEntry},
+};
+
+#[derive(Parser)]
+pub struct Rollback {
+	/// Host to roll back
+	host: String,
+	/// Generation to switch to
+	#[clap(long, conflicts_with = "to_previous")]
+	to_generation: Option<u32>,
+	/// Switch to the last known-good generation deployed by fleet before the current one,
+	/// failing if the host journal has none.
+	///
+	/// Without either flag, the known-good generation is used if there is one,
+	/// otherwise the generation preceding the current one.
+	#[clap(long)]
+	to_previous: bool,
+}
+
+impl Rollback {
+	pub async fn run(self, config: &Config) -> Result<()> {
+		let host = config.host(&self.host).await?;
+		if host.platform().await? != Platform::Nixos {
+			bail!("rollback is only supported for nixos hosts");
+		}
+		let current = current_generation(&host, SYSTEM_PROFILE).await?;
+		let before_path = profile_target(&host, SYSTEM_PROFILE).await?;
+		let journal = read_journal(&host).await?;
+		let previous = journal
+			.iter()
+			.rev()
+			.find(|e| e.outcome == "success" && e.after.id != current.id)
+			.map(|e| e.after.id);
+		let target = match (self.to_generation, self.to_previous) {
+			(Some(id), false) => id,
+			(Some(_), true) => unreachable!("--to-generation conflicts with --to-previous"),
+			(None, true) => previous.ok_or_else(|| {
+				anyhow!("no known-good generation found in the host journal, use --to-generation")
+			})?,
+			// Hosts, which weren't deployed by fleet yet, have no journal
+			(None, false) => match previous {
+				Some(id) => id,
+				None => {
+					warn!("no known-good generation found in the host journal, rolling back to the preceding generation");
+					current
+						.id
+						.checked_sub(1)
+						.filter(|id| *id > 0)
+						.ok_or_else(|| anyhow!("generation {} is the first one", current.id))?
+				}
+			},
+		};
+		// Generation is activated with the specialisation it was last activated with
+		let specialisation = journal
+			.iter()
+			.rev()
+			.find(|e| e.after.id == target)
+			.and_then(|e| e.specialisation.clone());
+		if target == current.id {
+			bail!("generation {target} is already current");
+		}
+		info!(
+			"rolling back from generation {} ({}) to {target}",
+			current.id, current.datetime
+		);
+
+		switch_generation(&host, SYSTEM_PROFILE, target)
+			.await
+			.with_context(|| format!("failed to switch to generation {target}"))?;
+
+		let after_path = profile_target(&host, SYSTEM_PROFILE).await?;
+		let specialised = match &specialisation {
+			Some(specialisation) => {
+				info!("activating specialisation {specialisation}");
+				format!("{after_path}/specialisation/{specialisation}")
+			}
+			None => after_path.clone(),
+		};
+		let mut cmd = host
+			.cmd(format!("{specialised}/bin/switch-to-configuration"))
+			.await?;
+		cmd.arg("switch");
+		let activation = cmd.sudo().run().instrument(info_span!("activating")).await;
+
+		// Pending automatic rollback would undo the explicit one.
+		let rollback = RollbackSettings::for_host(&host).await?;
+		if let Err(e) = host.rm_file(&rollback.marker_path, true).await {
+			warn!("failed to remove rollback marker: {e}");
+		}
+		for timer in [rollback.timer(), rollback.run_timer()] {
+			// Timers are not running if there is no deployment in progress.
+			let _ = host.systemctl_stop(&timer).await;
+		}
+
+		let entry = JournalEntry {
+			deployment_id: format!("rollback-{}", Utc::now().format("%Y%m%dT%H%M%SZ")),
+			timestamp: Utc::now(),
+			action: "rollback".to_owned(),
+			before: GenerationRef {
+				id: current.id,
+				path: before_path,
+			},
+			after: GenerationRef {
+				id: target,
+				path: after_path,
+			},
+			outcome: if activation.is_ok() {
+				"success"
+			} else {
+				"failed"
+			}
+			.to_owned(),
+			specialisation,
+		};
+		if let Err(e) = append_journal(&host, entry).await {
+			warn!("failed to record rollback to the host journal: {e}");
+		}
+		activation.context("failed to activate rolled back generation")?;
+		info!("rolled back to generation {target}");
+		Ok(())
+	}
+}
@@ -0,0 +1,280 @@
+//! Migration of secrets from sops-nix and agenix repositories.
+//!
+//! Secrets are decrypted on the deployer machine, and reencrypted for their fleet owners.
+
+use std::{
+	collections::BTreeMap,
+	fs,
+	io::Read as _,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use age::{armor::ArmoredReader, Decryptor, Identity};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chrono::Utc;
+use clap::{Parser, ValueEnum};
+use fleet_base::{
+	fleetdata::{
+		encrypt_secret_data_async, spawn_crypto, FleetSecret, FleetSecretPart, FleetSharedSecret,
+	},
+	host::Config,
+	sealed::{parse_identities, BoxedIdentity},
+};
+use futures::future::try_join_all;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use tracing::{info, info_span, warn, Instrument as _};
+
+use crate::audit::{self, AuditOp};
+
+#[derive(ValueEnum, Clone, Copy)]
+enum ImportFormat {
+	/// agenix `secrets.nix`, secret owners are detected from the listed public keys
+	Agenix,
+	/// sops encrypted file, decrypted with the `sops` binary
+	Sops,
+}
+
+#[derive(Parser)]
+pub struct Import {
+	/// Format of the imported secrets
+	#[clap(long, value_enum)]
+	format: ImportFormat,
+	/// Path to agenix `secrets.nix`, or to sops encrypted file
+	path: PathBuf,
+	/// Identity used for decryption, admin identity is used by default.
+	/// For sops, it is passed as SOPS_AGE_KEY_FILE, and sops key discovery is used if not set
+	#[clap(long)]
+	identity: Option<PathBuf>,
+	/// Secret owners. Required for sops, overrides detected owners for agenix
+	#[clap(long = "owner", short = 'm')]
+	owners: Vec<String>,
+	/// Prefix for imported secret names
+	#[clap(long, default_value = "")]
+	prefix: String,
+	/// How to name private secret part
+	#[clap(short = 's', long, default_value = "secret")]
+	part: String,
+	/// Replace secrets, which are already defined
+	#[clap(long)]
+	replace: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AgenixSecret {
+	#[serde(default)]
+	public_keys: Vec<String>,
+}
+
+/// Key type and key data, without the comment
+fn normalize_key(key: &str) -> String {
+	key.split_whitespace().take(2).collect::<Vec<_>>().join(" ")
+}
+
+fn decrypt_age(data: &[u8], identities: &[BoxedIdentity]) -> Result<Vec<u8>> {
+	let decryptor = Decryptor::new(ArmoredReader::new(data)).context("failed to init decryptor")?;
+	let Decryptor::Recipients(decryptor) = decryptor else {
+		bail!("passphrase encrypted secrets are not supported");
+	};
+	let mut reader = decryptor
+		.decrypt(identities.iter().map(|i| i.as_ref() as &dyn Identity))
+		.context("failed to decrypt, is your identity in the publicKeys list?")?;
+	let mut out = vec![];
+	reader.read_to_end(&mut out)?;
+	Ok(out)
+}
+
+/// sops-nix refers nested values by path joined with `/`
+fn flatten_sops(prefix: &str, value: JsonValue, out: &mut BTreeMap<String, Vec<u8>>) {
+	let join = |key: &str| {
+		if prefix.is_empty() {
+			key.to_owned()
+		} else {
+			format!("{prefix}/{key}")
+		}
+	};
+	match value {
+		JsonValue::Object(map) => {
+			for (key, value) in map {
+				if prefix.is_empty() && key == "sops" {
+					continue;
+				}
+				flatten_sops(&join(&key), value, out);
+			}
+		}
+		JsonValue::String(s) => {
+			out.insert(prefix.to_owned(), s.into_bytes());
+		}
+		JsonValue::Null => {}
+		other => {
+			out.insert(prefix.to_owned(), other.to_string().into_bytes());
+		}
+	}
+}
+
+impl Import {
+	/// Returns secret name to (owners, data)
+	async fn read_agenix(
+		&self,
+		config: &Config,
+	) -> Result<BTreeMap<String, (Vec<String>, Vec<u8>)>> {
+		let mut cmd = config.local_host().cmd("nix").await?;
+		cmd.args(&config.nix_args)
+			.arg("eval")
+			.arg("--json")
+			.arg("--file")
+			.arg(&self.path);
+		let listed: BTreeMap<String, AgenixSecret> =
+			serde_json::from_str(&cmd.query().retry_transient().run_nix_string().await?)
+				.context("failed to parse agenix secrets.nix")?;
+		let base = self.path.parent().unwrap_or(Path::new("."));
+
+		let mut host_keys = BTreeMap::new();
+		for host in config.list_hosts().await? {
+			if let Some(key) = config.cached_key(&host.name) {
+				host_keys.insert(normalize_key(&key), host.name);
+			}
+		}
+
+		let identities: Arc<[BoxedIdentity]> = match &self.identity {
+			Some(path) => {
+				let data =
+					fs::read(path).with_context(|| format!("failed to read identity {path:?}"))?;
+				parse_identities(&data, &path.display().to_string())?.into()
+			}
+			None => config.identities.shared_identities()?,
+		};
+		let mut decrypting = Vec::new();
+		for (file, secret) in listed {
+			let _span = info_span!("agenix", file).entered();
+			let owners = if self.owners.is_empty() {
+				secret
+					.public_keys
+					.iter()
+					.filter_map(|k| host_keys.get(&normalize_key(k)).cloned())
+					.collect::<Vec<_>>()
+			} else {
+				self.owners.clone()
+			};
+			if owners.is_empty() {
+				warn!("none of the secret recipients is a known fleet host, skipping");
+				continue;
+			}
+			let data =
+				fs::read(base.join(&file)).with_context(|| format!("failed to read {file}"))?;
+			let identities = identities.clone();
+			decrypting.push(async move {
+				let data = spawn_crypto(move || decrypt_age(&data, &identities))
+					.await
+					.with_context(|| format!("failed to decrypt {file}"))?;
+				let name = file.strip_suffix(".age").unwrap_or(&file).replace('/', "-");
+				Ok::<_, anyhow::Error>((name, (owners, data)))
+			});
+		}
+		Ok(try_join_all(decrypting).await?.into_iter().collect())
+	}
+
+	async fn read_sops(&self, config: &Config) -> Result<BTreeMap<String, (Vec<String>, Vec<u8>)>> {
+		ensure!(
+			!self.owners.is_empty(),
+			"--owner is required for sops import"
+		);
+		let structured = self
+			.path
+			.extension()
+			.is_some_and(|e| e == "yaml" || e == "yml" || e == "json");
+
+		let mut cmd = config.local_host().cmd("sops").await?;
+		if let Some(identity) = &self.identity {
+			cmd.env("SOPS_AGE_KEY_FILE", identity.to_string_lossy());
+		}
+		cmd.arg("--decrypt");
+		if structured {
+			cmd.comparg("--output-type", "json");
+		}
+		cmd.arg(&self.path);
+		let decrypted = cmd
+			.query()
+			.run_bytes()
+			.await
+			.context("sops decryption failed")?;
+
+		let mut values = BTreeMap::new();
+		if structured {
+			let value: JsonValue =
+				serde_json::from_slice(&decrypted).context("failed to parse sops output")?;
+			flatten_sops("", value, &mut values);
+		} else {
+			let name = self
+				.path
+				.file_stem()
+				.and_then(|s| s.to_str())
+				.ok_or_else(|| anyhow!("bad sops file name"))?;
+			values.insert(name.to_owned(), decrypted);
+		}
+		Ok(values
+			.into_iter()
+			.map(|(name, data)| (name.replace('/', "-"), (self.owners.clone(), data)))
+			.collect())
+	}
+
+	pub async fn run(self, config: &Config) -> Result<()> {
+		let secrets = match self.format {
+			ImportFormat::Agenix => self.read_agenix(config).await?,
+			ImportFormat::Sops => self.read_sops(config).await?,
+		};
+		let encrypted = try_join_all(secrets.into_iter().map(|(name, (owners, data))| {
+			let span = info_span!("import", secret = name);
+			async move {
+				let recipients = config.recipients(owners.clone()).await?;
+				let encrypted = encrypt_secret_data_async(recipients, data)
+					.await
+					.ok_or_else(|| anyhow!("no recipients provided"))?;
+				Ok::<_, anyhow::Error>((name, owners, encrypted))
+			}
+			.instrument(span)
+		}))
+		.await?;
+		let mut imported = 0;
+		for (name, owners, encrypted) in encrypted {
+			let name = format!("{}{name}", self.prefix);
+			let secret = FleetSecret {
+				created_at: Utc::now(),
+				expires_at: None,
+				bootstrapped_at: None,
+				attribution: None,
+				parts: [(self.part.clone(), FleetSecretPart { raw: encrypted })]
+					.into_iter()
+					.collect(),
+			};
+			if let [owner] = owners.as_slice() {
+				if config.has_secret(owner, &name) && !self.replace {
+					warn!("secret {name} is already defined for {owner}, skipping");
+					continue;
+				}
+				audit::record(config, AuditOp::Import, &name, &owners)?;
+				config.insert_secret(owner, name.clone(), secret);
+			} else {
+				if config.has_shared(&name) && !self.replace {
+					warn!("shared secret {name} is already defined, skipping");
+					continue;
+				}
+				audit::record(config, AuditOp::Import, &name, &owners)?;
+				// Encrypted to owners only, `fleet secret regenerate` reencrypts it for the configured readers
+				config.replace_shared(
+					name.clone(),
+					FleetSharedSecret {
+						owners,
+						readers: vec![],
+						secret,
+					},
+				);
+			}
+			imported += 1;
+		}
+		info!("imported {imported} secrets, declare them in fleet configuration to use");
+		Ok(())
+	}
+}
@@ -0,0 +1,226 @@
+//! Generator inputs, parts of other secrets referenced with `inputs` of `mkImpureSecretGenerator`.
+//!
+//! Referenced host secrets, which are not generated yet, are generated first. Encrypted parts
+//! are decrypted the same way `fleet secret read` and `fleet secret read-shared` do, and are only
+//! passed to generators running on the local machine.
+
+use std::{
+	collections::BTreeMap,
+	fs::{self, Permissions},
+	os::unix::fs::PermissionsExt as _,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use fleet_base::{
+	host::{Config, ConfigHost},
+	secret_bytes::SecretBytes,
+};
+use serde::Deserialize;
+use tempfile::TempDir;
+use tracing::{info, warn};
+
+use super::{generate_inner, shared_decryptor};
+use crate::audit::{self, AuditOp};
+
+fn default_part() -> String {
+	"secret".to_owned()
+}
+
+/// `{ host = "peer"; secret = "wireguard"; part = "public"; }` or `{ shared = "ca"; part = "cert"; }`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SecretInput {
+	host: Option<String>,
+	secret: Option<String>,
+	shared: Option<String>,
+	#[serde(default = "default_part")]
+	part: String,
+}
+
+enum Source<'i> {
+	Host { host: &'i str, secret: &'i str },
+	Shared { name: &'i str },
+}
+
+impl SecretInput {
+	pub fn host(host: String, secret: String, part: String) -> Self {
+		Self {
+			host: Some(host),
+			secret: Some(secret),
+			shared: None,
+			part,
+		}
+	}
+	pub fn shared(name: String, part: String) -> Self {
+		Self {
+			host: None,
+			secret: None,
+			shared: Some(name),
+			part,
+		}
+	}
+	fn source(&self) -> Result<Source<'_>> {
+		Ok(match (&self.host, &self.secret, &self.shared) {
+			(Some(host), Some(secret), None) => Source::Host { host, secret },
+			(None, None, Some(name)) => Source::Shared { name },
+			_ => bail!("secret input should have either host and secret, or shared attribute set"),
+		})
+	}
+}
+
+/// Input values, `true` if the value was encrypted
+pub struct Inputs(BTreeMap<String, (SecretBytes, bool)>);
+
+/// Host secret, which is referenced, but not generated yet
+async fn generate_dependency(
+	config: &Config,
+	host: &str,
+	secret: &str,
+	chain: &[String],
+) -> Result<()> {
+	let key = format!("{host}/{secret}");
+	if chain.contains(&key) {
+		bail!(
+			"secret inputs form a cycle: {} -> {key}",
+			chain.join(" -> ")
+		);
+	}
+	let config_host = config.host(host).await?;
+	ensure!(
+		config_host
+			.list_configured_secrets()
+			.await?
+			.iter()
+			.any(|s| s == secret),
+		"secret {secret} is not configured for {host}"
+	);
+	info!("generating referenced secret {secret} of {host}");
+	let field = config_host.secret_field(secret).await?;
+	let mut chain = chain.to_vec();
+	chain.push(key);
+	let owners = [host.to_owned()];
+	let generated = Box::pin(generate_inner(config, secret, field, &owners, &[], &chain)).await?;
+	audit::record(config, AuditOp::Generate, secret, &owners)?;
+	config.insert_secret(host, secret.to_owned(), generated);
+	Ok(())
+}
+
+pub async fn resolve(
+	config: &Config,
+	dependent: &str,
+	inputs: BTreeMap<String, SecretInput>,
+	chain: &[String],
+) -> Result<Inputs> {
+	let mut out = BTreeMap::new();
+	for (name, input) in inputs {
+		ensure!(
+			!name.is_empty() && !name.starts_with('.') && !name.contains('/'),
+			"input name {name:?} can't be used as a file name"
+		);
+		let value = match input.source()? {
+			Source::Host { host, secret } => {
+				if !config.has_secret(host, secret) {
+					generate_dependency(config, host, secret, chain).await?;
+				}
+				let stored = config.host_secret(host, secret)?;
+				let part = stored.parts.get(&input.part).with_context(|| {
+					format!("secret {secret} of {host} has no part {}", input.part)
+				})?;
+				if part.raw.encrypted {
+					audit::record(config, AuditOp::Read, secret, &[host.to_owned()])?;
+					let host = config.host(host).await?;
+					(host.decrypt(part.raw.clone()).await?, true)
+				} else {
+					(part.raw.data.clone().into(), false)
+				}
+			}
+			Source::Shared { name: shared } => {
+				ensure!(
+					config.has_shared(shared),
+					"shared secret {shared}, referenced by {dependent}, is not generated yet, run `fleet secret regenerate` first"
+				);
+				let stored = config.shared_secret(shared)?;
+				let part = stored.secret.parts.get(&input.part).with_context(|| {
+					format!("shared secret {shared} has no part {}", input.part)
+				})?;
+				if part.raw.encrypted {
+					audit::record(config, AuditOp::Read, shared, &stored.owners)?;
+					let data = match shared_decryptor(config, shared, &stored).await? {
+						Some(host) => host.decrypt(part.raw.clone()).await?,
+						None => config.decrypt_as_reader(shared, &part.raw).await?,
+					};
+					(data, true)
+				} else {
+					(part.raw.data.clone().into(), false)
+				}
+			}
+		};
+		out.insert(name, value);
+	}
+	Ok(Inputs(out))
+}
+
+impl Inputs {
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// Decrypted values, by input name
+	pub fn into_values(self) -> impl Iterator<Item = (String, SecretBytes)> {
+		self.0.into_iter().map(|(name, (data, _))| (name, data))
+	}
+
+	/// Input directory on the local machine, files are only readable by the current user
+	pub fn write_local(&self) -> Result<TempDir> {
+		let dir = TempDir::new()?;
+		fs::set_permissions(dir.path(), Permissions::from_mode(0o700))?;
+		for (name, (data, _)) in &self.0 {
+			let path = dir.path().join(name);
+			fs::write(&path, data.expose())
+				.with_context(|| format!("failed to write input {name}"))?;
+			fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+		}
+		Ok(dir)
+	}
+
+	/// Input directory on the generator host, only unencrypted inputs may be passed,
+	/// values decrypted with the admin identity never leave the local machine.
+	///
+	/// Directory should be removed with [`remove_remote`] once the generator is finished.
+	pub async fn write_remote(&self, host: &ConfigHost) -> Result<String> {
+		if let Some((name, _)) = self.0.iter().find(|(_, (_, encrypted))| *encrypted) {
+			bail!("input {name} is encrypted, such inputs are only passed to generators without impureOn");
+		}
+		let dir = host.mktemp_dir().await?;
+		let written: Result<()> = try {
+			for (name, (data, _)) in &self.0 {
+				let mut cmd = host.cmd("sh").await?;
+				cmd.arg("-c")
+					.arg(r#"cat > "$1""#)
+					.arg("sh")
+					.arg(format!("{dir}/{name}"))
+					.stdin(data.clone());
+				cmd.run()
+					.await
+					.with_context(|| format!("failed to write input {name}"))?;
+			}
+		};
+		if let Err(e) = written {
+			remove_remote(host, &dir).await;
+			return Err(e);
+		}
+		Ok(dir)
+	}
+}
+
+/// Removes input directory, written by [`Inputs::write_remote`]
+pub async fn remove_remote(host: &ConfigHost, dir: &str) {
+	let removed: Result<()> = try {
+		let mut cmd = host.cmd("rm").await?;
+		cmd.arg("-rf").arg(dir);
+		cmd.run().await?
+	};
+	if let Err(e) = removed {
+		warn!("failed to remove generator inputs: {e:#}");
+	}
+}
@@ -1,16 +1,29 @@
+mod import;
+pub(crate) mod inputs;
+mod output;
+
 use std::{
 	collections::{BTreeMap, BTreeSet, HashSet},
-	io::{self, stdin, stdout, Read, Write},
+	env::{args_os, current_exe},
+	ffi::OsString,
+	io::{self, stdin, Write},
 	path::PathBuf,
+	process::Command,
+	time::Duration,
 };
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use chrono::{DateTime, Utc};
 use clap::Parser;
 use fleet_base::{
-	fleetdata::{encrypt_secret_data, FleetSecret, FleetSecretPart, FleetSharedSecret},
-	host::Config,
+	attribution::{self, Entry, Verification},
+	command,
+	fleetdata::{
+		encrypt_secret_data_async, FleetSecret, FleetSecretPart, FleetSharedSecret,
+	},
+	host::{Config, ConfigHost},
 	opts::FleetOpts,
+	secret_bytes::SecretBytes,
 };
 use fleet_shared::SecretData;
 use nix_eval::{nix_go, nix_go_json, Value};
@@ -20,6 +33,12 @@ use tabled::{Table, Tabled};
 use tokio::fs::read;
 use tracing::{error, info, info_span, warn, Instrument};
 
+use super::info::key_fingerprint;
+use crate::{
+	audit::{self, AuditOp},
+	timeouts::parse_duration,
+};
+
 #[derive(Parser)]
 pub enum Secret {
 	/// Force load host keys for all defined hosts
@@ -89,9 +108,36 @@ pub enum Secret {
 		#[clap(short = 'm', long)]
 		machine: String,
 
-		/// Which private secret part to read
-		#[clap(short = 'p', long, default_value = "secret")]
-		part: String,
+		#[clap(flatten)]
+		output: output::ReadOpts,
+	},
+	/// Read shared secret. Secrets with admins or readers are decrypted locally with the admin identity,
+	/// others are decrypted on one of the owners, which requires sudo there
+	ReadShared {
+		name: String,
+
+		#[clap(flatten)]
+		output: output::ReadOpts,
+	},
+	/// Show owners, team access lists, parts and authors of the shared secret
+	Info { name: String },
+	/// Add owner to the shared secret, reencrypting it without regeneration, unless the secret
+	/// has `regenerateOnOwnerAdded` set
+	ShareWith {
+		name: String,
+		host: String,
+		/// Which host should we use to decrypt, if the secret has no admins or readers
+		#[clap(long)]
+		prefer_identities: Vec<String>,
+	},
+	/// Remove owner from the shared secret, reencrypting it without regeneration, unless the secret
+	/// has `regenerateOnOwnerRemoved` set
+	Unshare {
+		name: String,
+		host: String,
+		/// Which host should we use to decrypt, if the secret has no admins or readers
+		#[clap(long)]
+		prefer_identities: Vec<String>,
 	},
 	UpdateShared {
 		name: String,
@@ -115,6 +161,45 @@ pub enum Secret {
 		prefer_identities: Vec<String>,
 	},
 	List {},
+	/// Report secrets which are about to expire, and renew them using their generators,
+	/// i.e `fleet.mkAcmeCertificate` certificates
+	Check {
+		/// Secrets expiring within this period are renewed
+		#[clap(long, default_value = "30d", value_parser = parse_duration)]
+		renew_before: Duration,
+		/// Only report expiring secrets, implied by the global `--dry-run`
+		#[clap(long)]
+		report_only: bool,
+		/// Do not run `fleet deploy switch` for owners of renewed secrets
+		#[clap(long)]
+		no_deploy: bool,
+	},
+	/// Push encrypted material of ephemeral secrets (`fleet.secrets.ephemeral`) to the host,
+	/// and reinstall them, without redeploying the system
+	Reprovision { host: String },
+	/// Import secrets from sops-nix or agenix repository
+	Import(import::Import),
+	/// Show log of secret operations, recorded in fleet.audit.jsonl
+	Audit {
+		/// Only show operations on this secret
+		#[clap(long)]
+		secret: Option<String>,
+		/// Only show operations affecting this host
+		#[clap(long)]
+		host: Option<String>,
+		/// Only show operations performed by this `user@machine`
+		#[clap(long)]
+		actor: Option<String>,
+		/// Only show operations of this kind
+		#[clap(long, value_enum)]
+		op: Option<AuditOp>,
+		/// Only show operations performed after this time
+		#[clap(long)]
+		since: Option<DateTime<Utc>>,
+		/// Check hash chain and signatures of the whole log
+		#[clap(long)]
+		verify: bool,
+	},
 	Edit {
 		name: String,
 		#[clap(short = 'm', long)]
@@ -143,10 +228,14 @@ async fn update_owner_set(
 	let set = original_set.iter().collect::<BTreeSet<_>>();
 	let expected_set = updated_set.iter().collect::<BTreeSet<_>>();
 
-	if set == expected_set {
+	let access = config.shared_secret_access(secret_name).await?;
+	let readers = access.recipients();
+
+	if set == expected_set && secret.readers == readers {
 		info!("no need to update owner list, it is already correct");
 		return Ok(secret);
 	}
+	config.ensure_secret_admin(secret_name, &access)?;
 
 	let should_regenerate = if set.difference(&expected_set).next().is_some() {
 		// TODO: Remove this warning for revokable secrets.
@@ -170,27 +259,98 @@ async fn update_owner_set(
 		} else {
 			secret.owners.first()
 		};
-		let Some(identity_holder) = identity_holder else {
-			bail!("no available holder found");
-		};
 
 		for (part_name, part) in secret.secret.parts.iter_mut() {
 			let _span = info_span!("part reencryption", part_name);
 			if !part.raw.encrypted {
 				continue;
 			}
-			let host = config.host(identity_holder).await?;
-			let encrypted = host
-				.reencrypt(part.raw.clone(), updated_set.to_vec())
-				.await?;
-			part.raw = encrypted;
+			// Team members are recipients too, then the admin identity can decrypt the secret locally,
+			// without sudo on the owner host
+			let local = if secret.readers.is_empty() {
+				None
+			} else {
+				match config.decrypt_as_reader(secret_name, &part.raw).await {
+					Ok(data) => Some(data),
+					Err(e) => {
+						warn!("{e:#}, reencrypting on the owner host");
+						None
+					}
+				}
+			};
+			part.raw = if let Some(data) = local {
+				let recipients = config
+					.shared_secret_recipients(updated_set, &access)
+					.await?;
+				encrypt_secret_data_async(recipients, data)
+					.await
+					.context("no recipients")?
+			} else {
+				let Some(identity_holder) = identity_holder else {
+					bail!("no available holder found");
+				};
+				let host = config.host(identity_holder).await?;
+				host.reencrypt(part.raw.clone(), updated_set.to_vec(), &readers)
+					.await?
+			};
 		}
 
 		secret.owners = updated_set.to_vec();
+		secret.readers = readers;
 		Ok(secret)
 	}
 }
 
+/// Changes owners of the shared secret, and writes it back to fleet data
+async fn update_shared(
+	config: &Config,
+	name: String,
+	machine: Option<Vec<String>>,
+	add_machine: Vec<String>,
+	remove_machine: Vec<String>,
+	prefer_identities: &[String],
+) -> Result<()> {
+	// TODO: Forbid updating secrets with set expectedOwners (= not user-managed).
+
+	let secret = config.shared_secret(&name)?;
+	if secret.secret.parts.values().all(|v| !v.raw.encrypted) {
+		bail!("no secret");
+	}
+
+	let initial_machines = secret.owners.clone();
+	let target_machines = parse_machines(
+		initial_machines.clone(),
+		machine,
+		add_machine,
+		remove_machine,
+	)?;
+
+	if target_machines.is_empty() {
+		info!("no machines left for secret, removing it");
+		audit::record(config, AuditOp::Remove, &name, &initial_machines)?;
+		config.remove_shared(&name);
+		return Ok(());
+	}
+
+	let config_field = &config.config_field;
+	let field = nix_go!(config_field.sharedSecrets[{ name }]);
+
+	let updated = update_owner_set(
+		&name,
+		config,
+		secret,
+		field,
+		&target_machines,
+		prefer_identities,
+	)
+	.await?;
+	if updated.owners != initial_machines {
+		audit::record(config, AuditOp::Reencrypt, &name, &updated.owners)?;
+	}
+	config.replace_shared(name, updated);
+	Ok(())
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 enum GeneratorKind {
@@ -204,18 +364,28 @@ async fn generate_pure(
 	_secret: Value,
 	_default_generator: Value,
 	_owners: &[String],
+	_readers: &[String],
 ) -> Result<FleetSecret> {
 	bail!("pure generators are broken for now")
 }
 async fn generate_impure(
 	config: &Config,
-	_display_name: &str,
+	display_name: &str,
 	secret: Value,
 	default_generator: Value,
 	owners: &[String],
+	readers: &[String],
+	chain: &[String],
 ) -> Result<FleetSecret> {
 	let generator = nix_go!(secret.generator);
 	let on: Option<String> = nix_go_json!(default_generator.impureOn);
+	let inputs: BTreeMap<String, inputs::SecretInput> =
+		if default_generator.has_field("inputs").await? {
+			nix_go_json!(default_generator.inputs)
+		} else {
+			BTreeMap::new()
+		};
+	let inputs = inputs::resolve(config, display_name, inputs, chain).await?;
 
 	let host = if let Some(on) = &on {
 		config.host(on).await?
@@ -231,6 +401,7 @@ async fn generate_impure(
 		let key = config.key(owner).await?;
 		recipients.push(key);
 	}
+	recipients.extend(readers.iter().cloned());
 	let generators = nix_go!(mk_secret_generators(Obj {
 		recipients: { recipients },
 	}));
@@ -241,13 +412,33 @@ async fn generate_impure(
 	let generator = generator
 		.get("out")
 		.ok_or_else(|| anyhow!("missing generateImpure out"))?;
-	let generator = host.remote_derivation(generator).await?;
+	let generator = host.remote_derivation(generator, false).await?;
 
 	let out_parent = host.mktemp_dir().await?;
 	let out = format!("{out_parent}/out");
 
 	let mut gen = host.cmd(generator).await?;
 	gen.env("out", &out);
+	// Removed once the generator is finished
+	let mut _local_inputs = None;
+	let mut remote_inputs = None;
+	if !inputs.is_empty() {
+		let dir = if on.is_none() {
+			let dir = inputs.write_local()?;
+			let path = dir
+				.path()
+				.to_str()
+				.context("temp dir is not utf-8")?
+				.to_owned();
+			_local_inputs = Some(dir);
+			path
+		} else {
+			let dir = inputs.write_remote(&host).await?;
+			remote_inputs = Some(dir.clone());
+			dir
+		};
+		gen.env("inputs", dir);
+	}
 	if on.is_none() {
 		// This path is local, thus we can feed `OsString` directly to env var... But I don't think that's necessary to handle.
 		let project_path: String = config
@@ -258,7 +449,11 @@ async fn generate_impure(
 			.map_err(|s| anyhow!("fleet project path is not utf-8: {s:?}"))?;
 		gen.env("FLEET_PROJECT", project_path);
 	}
-	gen.run().await.context("impure generator")?;
+	let generated = gen.run().await.context("impure generator");
+	if let Some(dir) = &remote_inputs {
+		inputs::remove_remote(&host, dir).await;
+	}
+	generated?;
 
 	{
 		let marker = host.read_file_text(format!("{out}/marker")).await?;
@@ -267,7 +462,7 @@ async fn generate_impure(
 
 	let mut parts = BTreeMap::new();
 	for part in host.read_dir(&out).await? {
-		if part == "created_at" || part == "expired_at" || part == "marker" {
+		if part == "created_at" || part == "expires_at" || part == "marker" {
 			continue;
 		}
 		let contents: SecretData = host
@@ -284,14 +479,30 @@ async fn generate_impure(
 	Ok(FleetSecret {
 		created_at,
 		expires_at,
+		bootstrapped_at: None,
+		attribution: None,
 		parts,
 	})
 }
-async fn generate(
+/// `readers` are keys of team members, see [`fleet_base::access::SecretAccess`]
+pub(crate) async fn generate(
 	config: &Config,
 	display_name: &str,
 	secret: Value,
 	owners: &[String],
+	readers: &[String],
+) -> Result<FleetSecret> {
+	generate_inner(config, display_name, secret, owners, readers, &[]).await
+}
+/// `chain` is the list of `<host>/<secret>` being generated as inputs of other secrets,
+/// see [`inputs::resolve`]
+async fn generate_inner(
+	config: &Config,
+	display_name: &str,
+	secret: Value,
+	owners: &[String],
+	readers: &[String],
+	chain: &[String],
 ) -> Result<FleetSecret> {
 	let generator = nix_go!(secret.generator);
 	// Can't properly check on nix module system level
@@ -324,10 +535,19 @@ async fn generate(
 
 	match kind {
 		GeneratorKind::Impure => {
-			generate_impure(config, display_name, secret, default_generator, owners).await
+			generate_impure(
+				config,
+				display_name,
+				secret,
+				default_generator,
+				owners,
+				readers,
+				chain,
+			)
+			.await
 		}
 		GeneratorKind::Pure => {
-			generate_pure(config, display_name, secret, default_generator, owners).await
+			generate_pure(config, display_name, secret, default_generator, owners, readers).await
 		}
 	}
 }
@@ -338,9 +558,13 @@ async fn generate_shared(
 	expected_owners: Vec<String>,
 ) -> Result<FleetSharedSecret> {
 	// let owners: Vec<String> = nix_go_json!(secret.expectedOwners);
+	let access = config.shared_secret_access(display_name).await?;
+	config.ensure_secret_admin(display_name, &access)?;
+	let readers = access.recipients();
 	Ok(FleetSharedSecret {
-		secret: generate(config, display_name, secret, &expected_owners).await?,
+		secret: generate(config, display_name, secret, &expected_owners, &readers).await?,
 		owners: expected_owners,
+		readers,
 	})
 }
 
@@ -364,9 +588,8 @@ async fn parse_public(
 	})
 }
 
-async fn parse_secret() -> Result<Option<Vec<u8>>> {
-	let mut input = vec![];
-	stdin().read_to_end(&mut input)?;
+async fn parse_secret() -> Result<Option<SecretBytes>> {
+	let input = SecretBytes::read_from(stdin())?;
 	if input.is_empty() {
 		Ok(None)
 	} else {
@@ -428,8 +651,98 @@ fn parse_machines(
 	}
 	Ok(target_machines)
 }
+/// Global options of this invocation except host selection, passed to the nested deploy
+fn global_args() -> Vec<OsString> {
+	let mut out = Vec::new();
+	let mut args = args_os().skip(1).take_while(|a| *a != "secret");
+	while let Some(arg) = args.next() {
+		if arg == "--only" || arg == "--skip" {
+			args.next();
+			continue;
+		}
+		if arg
+			.to_str()
+			.is_some_and(|a| a.starts_with("--only=") || a.starts_with("--skip="))
+		{
+			continue;
+		}
+		out.push(arg);
+	}
+	out
+}
+
+/// Deploys renewed secrets with a nested `fleet deploy`, as the running invocation has
+/// evaluated the configuration with the old fleet data
+fn deploy_renewed(config: &Config, hosts: &BTreeSet<String>) -> Result<()> {
+	config.save()?;
+	let mut cmd = Command::new(current_exe()?);
+	cmd.args(global_args());
+	for host in hosts {
+		cmd.arg("--only").arg(host);
+	}
+	let status = cmd
+		.arg("deploy")
+		.arg("--yes")
+		.arg("switch")
+		.status()
+		.context("failed to run deploy")?;
+	ensure!(
+		status.success(),
+		"deployment of renewed secrets failed: {status}"
+	);
+	Ok(())
+}
+
+/// Host to decrypt the shared secret on, `None` if it should be decrypted locally
+/// with the admin identity, see [`Config::decrypt_as_reader`]
+async fn shared_decryptor(
+	config: &Config,
+	name: &str,
+	secret: &FleetSharedSecret,
+) -> Result<Option<ConfigHost>> {
+	let access = config.shared_secret_access(name).await?;
+	// Deployer keys alone don't make the admin identity a reader
+	let has_readers = secret.readers.iter().any(|r| !access.deployers.contains(r));
+	if access.is_restricted() || has_readers || config.is_deployer().await? {
+		return Ok(None);
+	}
+	let Some(owner) = secret.owners.first() else {
+		bail!("secret has no owners");
+	};
+	Ok(Some(config.host(owner).await?))
+}
+
+/// Single part, or all parts if `part` is `None`
+fn select_parts<'s>(
+	name: &str,
+	parts: &'s BTreeMap<String, FleetSecretPart>,
+	part: Option<&str>,
+) -> Result<BTreeMap<&'s String, &'s FleetSecretPart>> {
+	let Some(part) = part else {
+		return Ok(parts.iter().collect());
+	};
+	let Some((part_name, data)) = parts.get_key_value(part) else {
+		bail!("no part {part} in secret {name}");
+	};
+	Ok([(part_name, data)].into_iter().collect())
+}
+
 impl Secret {
 	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		if !matches!(
+			self,
+			Secret::ForceKeys
+				| Secret::ReadShared { .. }
+				| Secret::Info { .. }
+				| Secret::List {}
+				| Secret::Check { .. }
+				| Secret::Reprovision { .. }
+				| Secret::Audit { .. }
+		) {
+			config
+				.ensure_not_deployer("modify secrets or read host secrets")
+				.await?;
+		}
 		match self {
 			Secret::ForceKeys => {
 				for host in config.list_hosts().await? {
@@ -468,15 +781,17 @@ impl Secret {
 					machines = shared.owners;
 				}
 
-				let recipients = config.recipients(machines.clone()).await?;
+				let access = config.shared_secret_access(&name).await?;
+				config.ensure_secret_admin(&name, &access)?;
+				let recipients = config.shared_secret_recipients(&machines, &access).await?;
 
 				let mut parts = BTreeMap::new();
 
-				let mut input = vec![];
-				io::stdin().read_to_end(&mut input)?;
+				let input = SecretBytes::read_from(io::stdin())?;
 
 				if !input.is_empty() {
-					let encrypted = encrypt_secret_data(recipients, input)
+					let encrypted = encrypt_secret_data_async(recipients, input)
+						.await
 						.ok_or_else(|| anyhow!("no recipients provided"))?;
 					parts.insert(part_name, FleetSecretPart { raw: encrypted });
 				}
@@ -485,13 +800,17 @@ impl Secret {
 					parts.insert(public_name, FleetSecretPart { raw: public });
 				}
 
+				audit::record(config, AuditOp::Add, &name, &machines)?;
 				config.replace_shared(
 					name,
 					FleetSharedSecret {
 						owners: machines,
+						readers: access.recipients(),
 						secret: FleetSecret {
 							created_at: Utc::now(),
 							expires_at,
+							bootstrapped_at: None,
+							attribution: None,
 							parts,
 						},
 					},
@@ -519,14 +838,17 @@ impl Secret {
 					FleetSecret {
 						created_at: Utc::now(),
 						expires_at: None,
+						bootstrapped_at: None,
+						attribution: None,
 						parts: BTreeMap::new(),
 					}
 				};
 
 				if let Some(secret) = parse_secret().await? {
 					let recipient = config.recipient(&machine).await?;
-					let encrypted =
-						encrypt_secret_data(vec![recipient], secret).expect("recipient provided");
+					let encrypted = encrypt_secret_data_async(vec![recipient], secret)
+						.await
+						.expect("recipient provided");
 					if out
 						.parts
 						.insert(part_name.clone(), FleetSecretPart { raw: encrypted })
@@ -546,26 +868,125 @@ impl Secret {
 					}
 				};
 
+				audit::record(config, AuditOp::Add, &name, &[machine.clone()])?;
 				config.insert_secret(&machine, name, out);
 			}
 			#[allow(clippy::await_holding_refcell_ref)]
 			Secret::Read {
 				name,
 				machine,
-				part: part_name,
+				output,
 			} => {
 				let secret = config.host_secret(&machine, &name)?;
-				let Some(secret) = secret.parts.get(&part_name) else {
-					bail!("no part {part_name} in secret {name}");
+				let parts = select_parts(&name, &secret.parts, output.parts())?;
+				if parts.values().any(|p| p.raw.encrypted) {
+					// Recorded before decryption, access attempt is worth logging too
+					audit::record(config, AuditOp::Read, &name, &[machine.clone()])?;
+				}
+				let host = config.host(&machine).await?;
+				let mut data = BTreeMap::new();
+				for (part_name, part) in parts {
+					let part = if part.raw.encrypted {
+						host.decrypt(part.raw.clone()).await?
+					} else {
+						part.raw.data.clone().into()
+					};
+					data.insert(part_name.clone(), part);
+				}
+				output.write(&name, data)?;
+			}
+			Secret::ReadShared { name, output } => {
+				let secret = config.shared_secret(&name)?;
+				let parts = select_parts(&name, &secret.secret.parts, output.parts())?;
+				let mut decrypt_on = None;
+				if parts.values().any(|p| p.raw.encrypted) {
+					audit::record(config, AuditOp::Read, &name, &secret.owners)?;
+					decrypt_on = shared_decryptor(config, &name, &secret).await?;
+				}
+				let mut data = BTreeMap::new();
+				for (part_name, part) in parts {
+					let part = if !part.raw.encrypted {
+						part.raw.data.clone().into()
+					} else if let Some(host) = &decrypt_on {
+						host.decrypt(part.raw.clone()).await?
+					} else {
+						config.decrypt_as_reader(&name, &part.raw).await?
+					};
+					data.insert(part_name.clone(), part);
+				}
+				output.write(&name, data)?;
+			}
+			Secret::Info { name } => {
+				let secret = config.shared_secret(&name)?;
+				let access = config.shared_secret_access(&name).await?;
+				let display_keys = |keys: &[String]| {
+					if keys.is_empty() {
+						return "<none>".to_owned();
+					}
+					keys.iter()
+						.map(|k| key_fingerprint(k).unwrap_or_else(|| k.clone()))
+						.collect::<Vec<_>>()
+						.join(", ")
 				};
-				let data = if secret.raw.encrypted {
-					let host = config.host(&machine).await?;
-					host.decrypt(secret.raw.clone()).await?
+				println!("Owners: {}", secret.owners.join(", "));
+				println!("Admins: {}", display_keys(&access.admins));
+				println!("Readers: {}", display_keys(&access.readers));
+				if secret.readers != access.recipients() {
+					println!(
+						"{}",
+						"Secret is not encrypted for the configured admins/readers, run `fleet secret regenerate`"
+							.yellow()
+					);
+				}
+				let parts = secret
+					.secret
+					.parts
+					.iter()
+					.map(|(name, part)| {
+						if part.raw.encrypted {
+							format!("{name} (encrypted)")
+						} else {
+							name.clone()
+						}
+					})
+					.collect::<Vec<_>>();
+				println!("Parts: {}", parts.join(", "));
+				println!("Created at: {}", secret.secret.created_at);
+				if let Some(expires_at) = secret.secret.expires_at {
+					println!("Expires at: {expires_at}");
+				}
+				if let Some(attribution) = &secret.secret.attribution {
+					println!("Created by: {}", attribution.created_by);
+					println!("Modified by: {}", attribution.modified_by);
+				}
+				let verification = attribution::verify(
+					Entry::SharedSecret(&name),
+					&secret,
+					&config.allowed_signers,
+				);
+				if matches!(verification, Verification::Invalid(_)) {
+					println!(
+						"{}",
+						format!("Signature: {verification}, secret was modified bypassing fleet")
+							.red()
+					);
 				} else {
-					secret.raw.data.clone()
-				};
-
-				stdout().write_all(&data)?;
+					println!("Signature: {verification}");
+				}
+			}
+			Secret::ShareWith {
+				name,
+				host,
+				prefer_identities,
+			} => {
+				update_shared(config, name, None, vec![host], vec![], &prefer_identities).await?;
+			}
+			Secret::Unshare {
+				name,
+				host,
+				prefer_identities,
+			} => {
+				update_shared(config, name, None, vec![], vec![host], &prefer_identities).await?;
 			}
 			Secret::UpdateShared {
 				name,
@@ -574,40 +995,15 @@ impl Secret {
 				remove_machine,
 				prefer_identities,
 			} => {
-				// TODO: Forbid updating secrets with set expectedOwners (= not user-managed).
-
-				let secret = config.shared_secret(&name)?;
-				if secret.secret.parts.values().all(|v| !v.raw.encrypted) {
-					bail!("no secret");
-				}
-
-				let initial_machines = secret.owners.clone();
-				let target_machines = parse_machines(
-					initial_machines.clone(),
+				update_shared(
+					config,
+					name,
 					machine,
 					add_machine,
 					remove_machine,
-				)?;
-
-				if target_machines.is_empty() {
-					info!("no machines left for secret, removing it");
-					config.remove_shared(&name);
-					return Ok(());
-				}
-
-				let config_field = &config.config_field;
-				let field = nix_go!(config_field.sharedSecrets[{ name }]);
-
-				let updated = update_owner_set(
-					&name,
-					config,
-					secret,
-					field,
-					&target_machines,
 					&prefer_identities,
 				)
 				.await?;
-				config.replace_shared(name, updated);
 			}
 			Secret::Regenerate { prefer_identities } => {
 				info!("checking for secrets to regenerate");
@@ -632,6 +1028,7 @@ impl Secret {
 						let shared = generate_shared(config, missing, secret, expected_owners)
 							.in_current_span()
 							.await?;
+						audit::record(config, AuditOp::Generate, missing, &shared.owners)?;
 						config.replace_shared(missing.to_string(), shared)
 					}
 				}
@@ -655,7 +1052,7 @@ impl Secret {
 						info!("generating secret: {missing}");
 						let secret = host.secret_field(missing).in_current_span().await?;
 						let generated =
-							match generate(config, missing, secret, &[host.name.clone()])
+							match generate(config, missing, secret, &[host.name.clone()], &[])
 								.in_current_span()
 								.await
 							{
@@ -665,6 +1062,7 @@ impl Secret {
 									continue;
 								}
 							};
+						audit::record(config, AuditOp::Generate, missing, &[host.name.clone()])?;
 						config.insert_secret(&host.name, missing.to_string(), generated)
 					}
 				}
@@ -682,23 +1080,167 @@ impl Secret {
 					}
 
 					let secret = nix_go!(config_field.sharedSecrets[{ name }]);
-					config.replace_shared(
-						name.to_owned(),
-						update_owner_set(
-							name,
-							config,
-							data,
-							secret,
-							&expected_owners,
-							&prefer_identities,
-						)
-						.await?,
-					);
+					let owners = data.owners.clone();
+					let updated = update_owner_set(
+						name,
+						config,
+						data,
+						secret,
+						&expected_owners,
+						&prefer_identities,
+					)
+					.await?;
+					if updated.owners != owners {
+						audit::record(config, AuditOp::Reencrypt, name, &updated.owners)?;
+					}
+					config.replace_shared(name.to_owned(), updated);
 				}
 				for k in to_remove {
+					let owners = config.shared_secret(&k)?.owners;
+					audit::record(config, AuditOp::Remove, &k, &owners)?;
 					config.remove_shared(&k);
 				}
 			}
+			Secret::Check {
+				renew_before,
+				report_only,
+				no_deploy,
+			} => {
+				let report_only = report_only || command::is_dry_run();
+				let threshold = Utc::now() + chrono::Duration::from_std(renew_before)?;
+				let mut renewed = BTreeSet::new();
+				for host in config.list_hosts().await? {
+					if opts.should_skip(&host).await? {
+						continue;
+					}
+					let _span = info_span!("host", host = host.name).entered();
+					for name in config.list_secrets(&host.name) {
+						let secret = config.host_secret(&host.name, &name)?;
+						let Some(expires_at) = secret.expires_at.filter(|e| *e <= threshold) else {
+							continue;
+						};
+						warn!("secret {name} expires at {expires_at}");
+						if report_only {
+							continue;
+						}
+						info!("renewing secret: {name}");
+						let field = host.secret_field(&name).in_current_span().await?;
+						let generated =
+							match generate(config, &name, field, &[host.name.clone()], &[])
+								.in_current_span()
+								.await
+							{
+								Ok(v) => v,
+								Err(e) => {
+									error!("failed to renew {name}: {e:?}");
+									continue;
+								}
+							};
+						audit::record(config, AuditOp::Generate, &name, &[host.name.clone()])?;
+						config.insert_secret(&host.name, name, generated);
+						renewed.insert(host.name.clone());
+					}
+				}
+				for name in &config.list_shared() {
+					let data = config.shared_secret(name)?;
+					let Some(expires_at) = data.secret.expires_at.filter(|e| *e <= threshold)
+					else {
+						continue;
+					};
+					warn!("shared secret {name} expires at {expires_at}");
+					if report_only {
+						continue;
+					}
+					info!("renewing shared secret: {name}");
+					let config_field = &config.config_field;
+					let secret = nix_go!(config_field.sharedSecrets[{ name }]);
+					let shared =
+						match generate_shared(config, name, secret, data.owners.clone()).await {
+							Ok(v) => v,
+							Err(e) => {
+								error!("failed to renew {name}: {e:?}");
+								continue;
+							}
+						};
+					audit::record(config, AuditOp::Generate, name, &shared.owners)?;
+					renewed.extend(shared.owners.iter().cloned());
+					config.replace_shared(name.clone(), shared);
+				}
+				if renewed.is_empty() {
+					info!("no secrets were renewed");
+				} else if no_deploy {
+					info!(
+						"secrets were renewed, run `fleet deploy` for {}",
+						renewed.iter().cloned().collect::<Vec<_>>().join(", ")
+					);
+				} else {
+					deploy_renewed(config, &renewed)?;
+				}
+			}
+			Secret::Reprovision { host } => {
+				let host = config.host(&host).await?;
+				ensure!(
+					host.has_ephemeral_secrets().await?,
+					"secrets of {} are not ephemeral, they are deployed with the system",
+					host.name
+				);
+				host.provision_secrets(None).await?;
+				info!("secrets reprovisioned");
+			}
+			Secret::Import(import) => import.run(config).await?,
+			Secret::Audit {
+				secret,
+				host,
+				actor,
+				op,
+				since,
+				verify,
+			} => {
+				let entries = audit::read_audit(config)?;
+				if verify {
+					let problems = audit::verify(&entries);
+					if !problems.is_empty() {
+						bail!("audit log verification failed:\n{}", problems.join("\n"));
+					}
+					info!("audit log is intact, {} entries", entries.len());
+				}
+				#[derive(Tabled)]
+				struct AuditDisplay {
+					#[tabled(rename = "Time")]
+					timestamp: DateTime<Utc>,
+					#[tabled(rename = "Actor")]
+					actor: String,
+					#[tabled(rename = "Key")]
+					key: String,
+					#[tabled(rename = "Operation")]
+					op: &'static str,
+					#[tabled(rename = "Secret")]
+					secret: String,
+					#[tabled(rename = "Hosts")]
+					hosts: String,
+					#[tabled(rename = "Signed")]
+					signed: bool,
+				}
+				let table = entries
+					.into_iter()
+					.map(|(_, e)| e)
+					.filter(|e| secret.as_ref().map_or(true, |s| *s == e.secret))
+					.filter(|e| host.as_ref().map_or(true, |h| e.hosts.contains(h)))
+					.filter(|e| actor.as_ref().map_or(true, |a| *a == e.actor))
+					.filter(|e| op.map_or(true, |o| o == e.op))
+					.filter(|e| since.map_or(true, |s| e.timestamp >= s))
+					.map(|e| AuditDisplay {
+						timestamp: e.timestamp,
+						actor: e.actor,
+						key: e.key.unwrap_or_else(|| "-".to_owned()),
+						op: e.op.name(),
+						secret: e.secret,
+						hosts: e.hosts.join(", "),
+						signed: e.signature.is_some(),
+					})
+					.collect::<Vec<_>>();
+				info!("audit log\n{}", Table::new(table));
+			}
 			Secret::List {} => {
 				let _span = info_span!("loading secrets").entered();
 				let configured = config.list_configured_shared().await?;
@@ -740,11 +1282,14 @@ impl Secret {
 			} => {
 				let secret = config.host_secret(&machine, &name)?;
 				if let Some(data) = secret.parts.get(&part) {
+					audit::record(config, AuditOp::Read, &name, &[machine.clone()])?;
 					let host = config.host(&machine).await?;
 					let secret = host.decrypt(data.raw.clone()).await?;
-					String::from_utf8(secret).context("secret is not utf8")?
+					// Editor only handles text
+					secret.expose_str()?;
+					secret
 				} else if add {
-					String::new()
+					SecretBytes::new()
 				} else {
 					bail!("part {part} not found in secret {name}. Did you mean to `--add` it?");
 				};
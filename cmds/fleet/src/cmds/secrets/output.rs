@@ -0,0 +1,174 @@
+//! Output of `fleet secret read` and `fleet secret read-shared`.
+//!
+//! Secret parts may contain arbitrary bytes, so every format either passes them as-is,
+//! or encodes them without loss.
+
+use std::{
+	collections::BTreeMap,
+	fs::{self, Permissions},
+	io::{stdout, Write as _},
+	os::unix::fs::PermissionsExt as _,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use clap::{Parser, ValueEnum};
+use fleet_base::secret_bytes::SecretBytes;
+use serde_json::json;
+use tempfile::NamedTempFile;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFormat {
+	/// Part data as-is
+	Raw,
+	/// Part data in standard base64 encoding
+	Base64,
+	/// `{"<part>": {"encoding": "utf8" | "base64", "data": "..."}}`
+	Json,
+	/// `<SECRET>_<PART>='...'` lines, which can be sourced by the shell.
+	/// Parts should be valid utf-8 without NUL bytes
+	Env,
+}
+
+#[derive(Parser)]
+pub struct ReadOpts {
+	/// Which secret part to read, `raw` and `base64` formats read the `secret` part by default,
+	/// `json` and `env` include all parts by default
+	#[clap(short = 'p', long)]
+	part: Option<String>,
+	#[clap(long, value_enum, default_value = "raw")]
+	format: ReadFormat,
+	/// Write to this file instead of stdout
+	#[clap(short = 'o', long)]
+	output: Option<PathBuf>,
+	/// Permissions of the --output file, in octal
+	#[clap(long, default_value = "600", value_parser = parse_mode, requires = "output")]
+	mode: u32,
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+	let mode = u32::from_str_radix(mode, 8).context("mode should be an octal number")?;
+	ensure!(mode <= 0o777, "mode should be at most 777");
+	Ok(mode)
+}
+
+impl ReadOpts {
+	/// Parts to decrypt, `None` means all parts
+	pub fn parts(&self) -> Option<&str> {
+		match (&self.part, self.format) {
+			(Some(part), _) => Some(part),
+			(None, ReadFormat::Raw | ReadFormat::Base64) => Some("secret"),
+			(None, ReadFormat::Json | ReadFormat::Env) => None,
+		}
+	}
+
+	/// Output is assembled in [`SecretBytes`] too, so no copies of the secret are left behind
+	pub fn write(&self, secret: &str, parts: BTreeMap<String, SecretBytes>) -> Result<()> {
+		let out = match self.format {
+			ReadFormat::Raw | ReadFormat::Base64 => {
+				let mut parts = parts.into_values();
+				let (Some(data), None) = (parts.next(), parts.next()) else {
+					bail!("raw and base64 formats can only output a single part");
+				};
+				if self.format == ReadFormat::Raw {
+					data
+				} else {
+					let mut encoded = SecretBytes::from(STANDARD.encode(data.expose()));
+					encoded.extend_from_slice(b"\n");
+					encoded
+				}
+			}
+			ReadFormat::Json => {
+				let parts: serde_json::Map<_, _> = parts
+					.into_iter()
+					.map(|(name, data)| {
+						let value = match data.expose_str() {
+							Ok(text) => json!({"encoding": "utf8", "data": text}),
+							Err(_) => {
+								json!({"encoding": "base64", "data": STANDARD.encode(data.expose())})
+							}
+						};
+						(name, value)
+					})
+					.collect();
+				let mut out = SecretBytes::new();
+				serde_json::to_writer_pretty(&mut out, &parts)?;
+				out.extend_from_slice(b"\n");
+				out
+			}
+			ReadFormat::Env => {
+				let mut out = SecretBytes::new();
+				for (name, data) in parts {
+					let value = data
+						.expose_str()
+						.ok()
+						.filter(|v| !v.contains('\0'))
+						.with_context(|| {
+							format!("part {name} is binary, use json or base64 format instead")
+						})?;
+					writeln!(
+						out,
+						"{}={}",
+						env_name(&format!("{secret}_{name}")),
+						shlex::try_quote(value)?
+					)?;
+				}
+				out
+			}
+		};
+		match &self.output {
+			Some(path) => write_file(path, out.expose(), self.mode),
+			None => {
+				let mut stdout = stdout().lock();
+				stdout.write_all(out.expose())?;
+				stdout.flush()?;
+				Ok(())
+			}
+		}
+	}
+}
+
+/// Permissions are set before the data is written, so the secret is never readable by others
+fn write_file(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+	let dir = match path.parent() {
+		Some(dir) if !dir.as_os_str().is_empty() => dir,
+		_ => Path::new("."),
+	};
+	let mut file = NamedTempFile::new_in(dir)?;
+	fs::set_permissions(file.path(), Permissions::from_mode(mode))?;
+	file.write_all(data)?;
+	file.as_file().sync_all()?;
+	file.persist(path)
+		.with_context(|| format!("failed to write {}", path.display()))?;
+	Ok(())
+}
+
+/// Shell variable name, `my-secret_secret` becomes `MY_SECRET_SECRET`
+fn env_name(name: &str) -> String {
+	let mut out: String = name
+		.chars()
+		.map(|c| {
+			if c.is_ascii_alphanumeric() {
+				c.to_ascii_uppercase()
+			} else {
+				'_'
+			}
+		})
+		.collect();
+	if out.starts_with(|c: char| c.is_ascii_digit()) {
+		out.insert(0, '_');
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::env_name;
+
+	#[test]
+	fn env_names() {
+		assert_eq!(env_name("my-secret_secret"), "MY_SECRET_SECRET");
+		assert_eq!(env_name("1password.key"), "_1PASSWORD_KEY");
+	}
+}
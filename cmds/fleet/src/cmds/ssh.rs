@@ -0,0 +1,36 @@
+use std::{os::unix::process::CommandExt as _, process::Command};
+
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use fleet_base::host::Config;
+
+#[derive(Parser)]
+pub struct Ssh {
+	/// Host to connect to
+	host: String,
+	/// Command to run instead of the interactive shell
+	#[clap(last = true)]
+	command: Vec<String>,
+}
+
+impl Ssh {
+	pub async fn run(self, config: &Config) -> Result<()> {
+		let hosts = config.list_hosts().await?;
+		ensure!(
+			hosts.iter().any(|h| h.name == self.host),
+			"unknown host: {}",
+			self.host
+		);
+		let host = config.host(&self.host).await?;
+		let target = host.ssh_target().await?;
+
+		let mut ssh = Command::new("ssh");
+		ssh.args(target.ssh_args())
+			.arg(target.destination())
+			.args(&self.command);
+		// Only returns on failure, otherwise the process is replaced with ssh,
+		// so that interactive session gets terminal and signals directly.
+		let err = ssh.exec();
+		Err(err).context("failed to execute ssh")
+	}
+}
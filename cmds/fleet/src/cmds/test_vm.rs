@@ -0,0 +1,165 @@
+//! Pre-deploy smoke test: host system is booted in a local headless QEMU VM
+//! (`system.build.vm`, same as `nixos-rebuild build-vm`), with encrypted secrets replaced by
+//! placeholders, and `fleet.healthChecks` are executed inside. See nixos/test-vm.nix
+
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::Parser;
+use fleet_base::host::{Config, Platform};
+use tokio::time::timeout;
+use tracing::{error, field, info, info_span, Instrument as _};
+
+use super::build_systems::build_task;
+use crate::timeouts::parse_duration;
+
+/// Prefix of the VM console lines, produced by fleet-test-vm service
+const MARKER: &str = "fleet-test-vm: ";
+/// Kernel parameter which enables fleet-test-vm service
+const KERNEL_PARAM: &str = "fleet.test-vm";
+
+#[derive(Parser)]
+pub struct TestVm {
+	/// Host to test
+	host: String,
+	/// Maximum time for the VM to boot and run health checks
+	#[clap(long, default_value = "10m", value_parser = parse_duration)]
+	timeout: Duration,
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct Report {
+	passed: Vec<String>,
+	failed: Vec<String>,
+	failed_units: Vec<String>,
+	/// Checks service has finished, VM output was not truncated
+	done: bool,
+}
+impl Report {
+	fn success(&self) -> bool {
+		self.done && self.failed.is_empty() && self.failed_units.is_empty()
+	}
+}
+
+fn parse_report(output: &str) -> Report {
+	let mut report = Report::default();
+	for line in output.lines() {
+		// Console lines might be interleaved with kernel messages, and end with \r
+		let Some((_, message)) = line.split_once(MARKER) else {
+			continue;
+		};
+		let message = message.trim();
+		if message == "done" {
+			report.done = true;
+		} else if let Some(check) = message.strip_prefix("check ") {
+			if let Some(name) = check.strip_suffix(" passed") {
+				report.passed.push(name.to_owned());
+			} else if let Some(name) = check.strip_suffix(" failed") {
+				report.failed.push(name.to_owned());
+			}
+		} else if let Some(unit) = message
+			.strip_prefix("unit ")
+			.and_then(|u| u.strip_suffix(" failed"))
+		{
+			report.failed_units.push(unit.to_owned());
+		}
+	}
+	report
+}
+
+/// VM output contains `bin/run-<hostname>-vm` script
+fn find_runner(vm: &Path) -> Result<PathBuf> {
+	let bin = vm.join("bin");
+	for entry in fs::read_dir(&bin).with_context(|| format!("failed to list {bin:?}"))? {
+		let entry = entry?;
+		let name = entry.file_name();
+		let name = name.to_string_lossy();
+		if name.starts_with("run-") && name.ends_with("-vm") {
+			return Ok(entry.path());
+		}
+	}
+	bail!("vm runner not found in {bin:?}")
+}
+
+impl TestVm {
+	pub async fn run(self, config: &Config) -> Result<()> {
+		let host = config.host(&self.host).await?;
+		ensure!(
+			host.platform().await? == Platform::Nixos,
+			"test-vm is only supported for nixos hosts"
+		);
+		let span = info_span!("test-vm", host = field::display(&self.host));
+		let report = async {
+			let vm = build_task(config.clone(), self.host.clone(), "vm", false).await?;
+			let runner = find_runner(&vm)?;
+
+			// Fresh disk image for every run, VM state from the previous runs should not affect checks
+			let dir = tempfile::tempdir()?;
+			let dir_path = dir.path().to_str().context("non-utf8 temp dir")?;
+			let mut cmd = config.local_host().cmd(runner).await?;
+			cmd.env("NIX_DISK_IMAGE", format!("{dir_path}/disk.qcow2"))
+				.env("TMPDIR", dir_path)
+				.env("QEMU_OPTS", "-nographic")
+				.env("QEMU_KERNEL_PARAMS", KERNEL_PARAM);
+			info!("booting vm");
+			let output = timeout(self.timeout, cmd.run_string())
+				.await
+				.map_err(|_| {
+					anyhow!(
+						"vm didn't finish health checks in {}s",
+						self.timeout.as_secs()
+					)
+				})?
+				.context("vm failed")?;
+			Ok::<_, anyhow::Error>(parse_report(&output))
+		}
+		.instrument(span)
+		.await?;
+
+		for check in &report.passed {
+			info!("check {check}: pass");
+		}
+		for check in &report.failed {
+			error!("check {check}: fail");
+		}
+		for unit in &report.failed_units {
+			error!("unit {unit} has failed");
+		}
+		if !report.done {
+			bail!("vm has stopped before health checks were finished");
+		}
+		ensure!(report.success(), "{} failed vm tests", self.host);
+		info!("{} passed vm tests", self.host);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn report() {
+		let output = "\
+[   10.1] systemd[1]: Starting Fleet VM health checks...\r
+fleet-test-vm: check nginx passed\r
+fleet-test-vm: check db failed\r
+[   10.3] random kernel line fleet-test-vm: unit postgresql.service failed\r
+fleet-test-vm: done\r
+";
+		assert_eq!(
+			parse_report(output),
+			Report {
+				passed: vec!["nginx".to_owned()],
+				failed: vec!["db".to_owned()],
+				failed_units: vec!["postgresql.service".to_owned()],
+				done: true,
+			}
+		);
+		assert!(!parse_report("fleet-test-vm: check nginx passed").success());
+	}
+}
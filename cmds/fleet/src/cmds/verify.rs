@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+
+use anyhow::{ensure, Result};
+use clap::Parser;
+use fleet_base::{
+	host::{Config, ConfigHost, Platform},
+	opts::FleetOpts,
+};
+use nix_eval::{nix_go, nix_go_json};
+use tabled::{Table, Tabled};
+use tracing::{info, info_span, Instrument as _};
+
+use super::build_systems::{profile_target, RollbackSettings};
+
+#[derive(Parser)]
+pub struct Verify {
+	/// Hosts to verify, all hosts (respecting --only/--skip) if not specified
+	hosts: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Status {
+	Pass,
+	Drift,
+	Error,
+}
+
+#[derive(Tabled)]
+struct VerifyDisplay {
+	#[tabled(rename = "Host")]
+	host: String,
+	#[tabled(rename = "Status")]
+	status: String,
+	#[tabled(rename = "Details")]
+	details: String,
+}
+
+/// Expected on-host state of the secret part files
+struct ExpectedPart {
+	/// Path incorporating the data hash, always has the current version of the secret
+	path: String,
+	/// Copies, which should have the same contents as `path`
+	copies: Vec<String>,
+	mode: String,
+	owner: String,
+	group: String,
+}
+
+/// On-host state of the secret part file
+struct ActualFile {
+	mode: String,
+	owner: String,
+	group: String,
+	sha256: String,
+}
+
+fn normalize_mode(mode: &str) -> &str {
+	let mode = mode.trim_start_matches('0');
+	if mode.is_empty() {
+		"0"
+	} else {
+		mode
+	}
+}
+
+async fn expected_parts(host: &ConfigHost) -> Result<BTreeMap<String, ExpectedPart>> {
+	let nixos = host.nixos_config().await?;
+	let secrets = nix_go!(nixos.secrets);
+	// Secret attributes, which are not parts
+	let secret_options: Vec<String> = nix_go_json!(nixos.fleet.secrets.optionNames);
+	let mut out = BTreeMap::new();
+	for name in secrets.list_fields().await? {
+		let secret = nix_go!(secrets[{ name }]);
+		let mode: String = nix_go_json!(secret.mode);
+		let owner: String = nix_go_json!(secret.owner);
+		let group: String = nix_go_json!(secret.group);
+		for part_name in secret.list_fields().await? {
+			if secret_options.contains(&part_name) {
+				continue;
+			}
+			let part = nix_go!(secret[{ part_name }]);
+			let part_mode: Option<String> = nix_go_json!(part.mode);
+			let part_owner: Option<String> = nix_go_json!(part.owner);
+			let part_group: Option<String> = nix_go_json!(part.group);
+			let stable_path: String = nix_go_json!(part.stablePath);
+			let target: Option<String> = nix_go_json!(part.target);
+			out.insert(
+				format!("{name}/{part_name}"),
+				ExpectedPart {
+					path: nix_go_json!(part.path),
+					copies: [stable_path].into_iter().chain(target).collect(),
+					mode: part_mode.unwrap_or_else(|| mode.clone()),
+					owner: part_owner.unwrap_or_else(|| owner.clone()),
+					group: part_group.unwrap_or_else(|| group.clone()),
+				},
+			);
+		}
+	}
+	Ok(out)
+}
+
+fn verify_file(
+	name: &str,
+	path: &str,
+	part: &ExpectedPart,
+	file: &ActualFile,
+	drift: &mut Vec<String>,
+) {
+	if normalize_mode(&file.mode) != normalize_mode(&part.mode) {
+		drift.push(format!(
+			"secret {name}: {path} has mode {}, expected {}",
+			file.mode, part.mode
+		));
+	}
+	if file.owner != part.owner || file.group != part.group {
+		drift.push(format!(
+			"secret {name}: {path} is owned by {}:{}, expected {}:{}",
+			file.owner, file.group, part.owner, part.group
+		));
+	}
+}
+
+/// Returns list of drift descriptions
+async fn verify_secrets(host: &ConfigHost) -> Result<Vec<String>> {
+	let expected = expected_parts(host).await?;
+	if expected.is_empty() {
+		return Ok(vec![]);
+	}
+	let mut cmd = host.cmd("sh").await?;
+	cmd.arg("-c")
+		.arg(r#"for p; do if s=$(stat -c '%a %U %G' "$p" 2>/dev/null); then echo "$s $(sha256sum < "$p" | cut -d' ' -f1) $p"; else echo "missing - - - $p"; fi; done"#)
+		.arg("sh")
+		.args(
+			expected
+				.values()
+				.flat_map(|p| [&p.path].into_iter().chain(&p.copies))
+				.map(String::as_str),
+		);
+	let output = cmd.sudo().query().run_string().await?;
+	let mut actual = BTreeMap::new();
+	for line in output.lines() {
+		let mut fields = line.splitn(5, ' ');
+		let (Some(mode), Some(owner), Some(group), Some(sha256), Some(path)) = (
+			fields.next(),
+			fields.next(),
+			fields.next(),
+			fields.next(),
+			fields.next(),
+		) else {
+			continue;
+		};
+		if mode == "missing" {
+			continue;
+		}
+		actual.insert(
+			path.to_owned(),
+			ActualFile {
+				mode: mode.to_owned(),
+				owner: owner.to_owned(),
+				group: group.to_owned(),
+				sha256: sha256.to_owned(),
+			},
+		);
+	}
+	let mut drift = Vec::new();
+	for (name, part) in expected {
+		// Hashed path only exists for the current version of the secret
+		let Some(current) = actual.get(&part.path) else {
+			drift.push(format!("secret {name}: missing or outdated"));
+			continue;
+		};
+		verify_file(&name, &part.path, &part, current, &mut drift);
+		for copy in &part.copies {
+			match actual.get(copy) {
+				None => drift.push(format!("secret {name}: {copy} is missing")),
+				Some(file) if file.sha256 != current.sha256 => drift.push(format!(
+					"secret {name}: {copy} differs from the current version"
+				)),
+				Some(file) => verify_file(&name, copy, &part, file, &mut drift),
+			}
+		}
+	}
+	Ok(drift)
+}
+
+async fn verify_rollback_units(host: &ConfigHost) -> Result<Vec<String>> {
+	let rollback = RollbackSettings::for_host(host).await?;
+	let mut drift = Vec::new();
+	for unit in [rollback.service(), rollback.timer()] {
+		let mut cmd = host.cmd("systemctl").await?;
+		cmd.arg("cat").arg("--no-pager").arg(&unit);
+		if cmd.query().run_string().await.is_err() {
+			drift.push(format!("rollback unit {unit} is missing"));
+		}
+	}
+	Ok(drift)
+}
+
+async fn verify_host(host: &ConfigHost) -> Result<Vec<String>> {
+	let platform = host.platform().await?;
+	ensure!(
+		platform != Platform::HomeManager,
+		"home-manager hosts can't be verified"
+	);
+	let mut drift = Vec::new();
+
+	let toplevel = host.system_attr("toplevel").await?;
+	let expected: String = nix_go_json!(toplevel.outPath);
+	let current = profile_target(host, "/run/current-system").await?;
+	if current != expected {
+		drift.push(format!("active system is {current}, expected {expected}"));
+	}
+	if platform == Platform::Nixos {
+		drift.extend(verify_secrets(host).await?);
+		drift.extend(verify_rollback_units(host).await?);
+	}
+	Ok(drift)
+}
+
+impl Verify {
+	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		let mut table = Vec::new();
+		let mut all_passed = true;
+		for host in config.list_hosts().await? {
+			if self.hosts.is_empty() {
+				if opts.should_skip(&host).await? {
+					continue;
+				}
+			} else if !self.hosts.contains(&host.name) {
+				continue;
+			}
+			let span = info_span!("verify", host = host.name);
+			let (status, details) = match verify_host(&host).instrument(span).await {
+				Ok(drift) if drift.is_empty() => (Status::Pass, String::new()),
+				Ok(drift) => (Status::Drift, drift.join("\n")),
+				Err(e) => (Status::Error, format!("{e:#}")),
+			};
+			all_passed &= status == Status::Pass;
+			table.push(VerifyDisplay {
+				host: host.name.clone(),
+				status: format!("{status:?}").to_uppercase(),
+				details,
+			});
+		}
+		info!("verified\n{}", Table::new(table));
+		ensure!(all_passed, "some hosts have drifted from the configuration");
+		Ok(())
+	}
+}
@@ -0,0 +1,218 @@
+//! GitOps mode: remote branch is polled, and every new commit is checked out and deployed
+//! by a nested `fleet deploy` invocation, so that every run evaluates the updated configuration.
+//!
+//! Runs are recorded to `.fleet/watch.json`. Working tree is switched to the deployed commit,
+//! and changes made to it by deploys (i.e generated secrets written to `fleet.nix`) are discarded,
+//! so watcher should be run in a dedicated checkout.
+
+use std::{
+	env::{args_os, current_dir, current_exe},
+	ffi::OsString,
+	fs,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::{process::Command, time::sleep};
+use tracing::{error, info, info_span, warn, Instrument as _};
+
+use crate::{timeouts::parse_duration, Opts, RootOpts};
+
+/// Older runs are dropped from the state file
+const MAX_RUNS: usize = 100;
+
+#[derive(Parser)]
+pub struct Watch {
+	/// Git remote to poll
+	#[clap(long, default_value = "origin")]
+	remote: String,
+	/// Branch to deploy
+	#[clap(long, default_value = "main")]
+	branch: String,
+	/// How often to poll the remote
+	#[clap(long, default_value = "1m", value_parser = parse_duration)]
+	interval: Duration,
+	/// `fleet deploy` arguments, i.e `fleet watch -- switch --wake`
+	#[clap(last = true, required = true)]
+	deploy: Vec<OsString>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct WatchRun {
+	commit: String,
+	started_at: DateTime<Utc>,
+	finished_at: DateTime<Utc>,
+	success: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct WatchState {
+	runs: Vec<WatchRun>,
+}
+
+fn state_path(directory: &Path) -> PathBuf {
+	directory.join(".fleet/watch.json")
+}
+
+fn load_state(directory: &Path) -> Result<WatchState> {
+	let Ok(data) = fs::read(state_path(directory)) else {
+		return Ok(WatchState::default());
+	};
+	serde_json::from_slice(&data).context("failed to parse watch state")
+}
+
+fn save_state(directory: &Path, state: &WatchState) -> Result<()> {
+	let path = state_path(directory);
+	let dir = path.parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	let tmp = NamedTempFile::new_in(dir)?;
+	serde_json::to_writer_pretty(&tmp, state)?;
+	tmp.persist(path)?;
+	Ok(())
+}
+
+async fn git(directory: &Path, args: &[&str]) -> Result<String> {
+	let output = Command::new("git")
+		.arg("-C")
+		.arg(directory)
+		.args(args)
+		.output()
+		.await
+		.context("failed to run git")?;
+	ensure!(
+		output.status.success(),
+		"git {} failed: {}",
+		args.join(" "),
+		String::from_utf8_lossy(&output.stderr).trim()
+	);
+	Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Global options of this invocation (`--only`, `--fleet`, ...), passed to the nested deploy
+///
+/// `watch` is located by clap, as the same word may also be a value of some global option
+fn global_args() -> Result<Vec<OsString>> {
+	let args: Vec<OsString> = args_os().collect();
+	for (i, arg) in args.iter().enumerate().skip(1) {
+		if arg != "watch" {
+			continue;
+		}
+		let probe = args[..=i].iter().cloned().chain(["--".into(), "_".into()]);
+		if RootOpts::try_parse_from(probe).is_ok_and(|o| matches!(o.command, Opts::Watch(_))) {
+			return Ok(args[1..i].to_vec());
+		}
+	}
+	bail!("failed to locate watch subcommand in the command line")
+}
+
+/// Tracked files changed in the working tree, space separated
+async fn dirty_files(directory: &Path) -> Result<String> {
+	Ok(git(directory, &["diff", "HEAD", "--name-only"])
+		.await?
+		.replace('\n', " "))
+}
+
+impl Watch {
+	/// Runs without evaluating fleet configuration, as it is evaluated by every nested deploy
+	pub async fn run(&self) -> Result<()> {
+		let directory = current_dir()?;
+		// Once the watcher has deployed something, the checkout is owned by it
+		if !state_path(&directory).exists() {
+			ensure!(
+				dirty_files(&directory).await?.is_empty(),
+				"working tree has uncommitted changes, watcher should be run in a dedicated checkout"
+			);
+		}
+		let global_args = global_args()?;
+		info!(
+			"watching {}/{}, polling every {}s",
+			self.remote,
+			self.branch,
+			self.interval.as_secs()
+		);
+		loop {
+			if let Err(e) = self.poll(&directory, &global_args).await {
+				error!("{e:#}");
+			}
+			sleep(self.interval).await;
+		}
+	}
+
+	async fn poll(&self, directory: &Path, global_args: &[OsString]) -> Result<()> {
+		git(directory, &["fetch", "--quiet", &self.remote, &self.branch]).await?;
+		let commit = git(directory, &["rev-parse", "FETCH_HEAD"]).await?;
+		let state = load_state(directory)?;
+		// Failed commits are not retried, the next commit is expected to fix them
+		if state.runs.last().is_some_and(|r| r.commit == commit) {
+			return Ok(());
+		}
+		let span = info_span!("watch", commit = &commit[..12.min(commit.len())]);
+		self.deploy_commit(directory, global_args, state, commit)
+			.instrument(span)
+			.await
+	}
+
+	async fn deploy_commit(
+		&self,
+		directory: &Path,
+		global_args: &[OsString],
+		mut state: WatchState,
+		commit: String,
+	) -> Result<()> {
+		info!("deploying new commit");
+		let dirty = dirty_files(directory).await?;
+		if !dirty.is_empty() {
+			warn!("discarding changes left by the previous deploy: {dirty}");
+		}
+		git(
+			directory,
+			&[
+				"-c",
+				"advice.detachedHead=false",
+				"checkout",
+				"--quiet",
+				"--force",
+				"--detach",
+				&commit,
+			],
+		)
+		.await?;
+
+		let started_at = Utc::now();
+		let status = Command::new(current_exe()?)
+			.args(global_args)
+			.arg("deploy")
+			// Nobody is there to answer
+			.arg("--yes")
+			.args(&self.deploy)
+			.status()
+			.await
+			.context("failed to run deploy")?;
+		let success = status.success();
+		if success {
+			info!("deployment succeeded");
+		} else {
+			warn!("deployment failed: {status}");
+		}
+		state.runs.push(WatchRun {
+			commit,
+			started_at,
+			finished_at: Utc::now(),
+			success,
+		});
+		if state.runs.len() > MAX_RUNS {
+			state.runs.drain(..state.runs.len() - MAX_RUNS);
+		}
+		save_state(directory, &state)?;
+		if !success {
+			bail!("deployment of the new commit has failed");
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,56 @@
+//! Interactive confirmation of host activation, see `fleet deploy --confirm`.
+//!
+//! Hosts are asked for one by one, as they become ready for activation, prompts of
+//! concurrently deployed hosts are serialized.
+
+use std::{
+	io::{self, stdin, BufRead as _, Write as _},
+	sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+	Yes,
+	No,
+	/// Stop the whole deployment
+	Quit,
+}
+
+/// Shared between host tasks, remembers "all" answer
+#[derive(Clone, Default)]
+pub struct Confirmation(Arc<Mutex<bool>>);
+
+impl Confirmation {
+	pub async fn ask(&self, host: &str, preview: String) -> Result<Answer> {
+		let mut approved_all = self.0.lock().await;
+		if *approved_all {
+			return Ok(Answer::Yes);
+		}
+		let host = host.to_owned();
+		let (answer, all) = tokio::task::spawn_blocking(move || prompt(&host, &preview)).await??;
+		*approved_all = all;
+		Ok(answer)
+	}
+}
+
+fn prompt(host: &str, preview: &str) -> Result<(Answer, bool)> {
+	eprintln!("{preview}");
+	loop {
+		eprint!("Activate {host}? [y]es, [n]o, [a]ll remaining hosts, [q]uit: ");
+		io::stderr().flush()?;
+		let mut line = String::new();
+		if stdin().lock().read_line(&mut line)? == 0 {
+			bail!("stdin is closed, pass --yes to deploy without confirmation");
+		}
+		return Ok(match line.trim() {
+			"y" | "yes" => (Answer::Yes, false),
+			"n" | "no" => (Answer::No, false),
+			"a" | "all" => (Answer::Yes, true),
+			"q" | "quit" => (Answer::Quit, false),
+			_ => continue,
+		});
+	}
+}
@@ -0,0 +1,111 @@
+//! Deployment of systems built elsewhere, i.e by CI, see `fleet deploy --from-cache`.
+//!
+//! Nothing is built locally, hosts realize the prebuilt toplevels from the binary cache.
+//! Signatures are verified before any host is touched, if `nixSigning.publicKey` is configured,
+//! otherwise hosts only accept paths signed by the keys they trust.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use fleet_base::host::Config;
+use nix_eval::nix_go_json;
+use tracing::{info, warn};
+
+pub struct FromCache {
+	pub substituter: String,
+	/// Only allowed for a single selected host
+	single: Option<PathBuf>,
+	/// `{"<host>": "/nix/store/...-nixos-system-..."}` manifest
+	systems: BTreeMap<String, PathBuf>,
+}
+
+impl FromCache {
+	/// Source is either a store path, or a path to the manifest
+	pub fn load(source: &str, substituter: String) -> Result<Self> {
+		if source.starts_with("/nix/store/") {
+			return Ok(Self {
+				substituter,
+				single: Some(PathBuf::from(source)),
+				systems: BTreeMap::new(),
+			});
+		}
+		let manifest = fs::read(source).with_context(|| format!("failed to read {source}"))?;
+		let systems = serde_json::from_slice(&manifest).with_context(|| {
+			format!("failed to parse {source}, expected {{\"<host>\": \"<store path>\"}}")
+		})?;
+		Ok(Self {
+			substituter,
+			single: None,
+			systems,
+		})
+	}
+	pub fn ensure_hosts(&self, hosts: &[String]) -> Result<()> {
+		if self.single.is_some() {
+			if hosts.len() > 1 {
+				bail!(
+					"single store path can only be deployed to a single host, selected: {}",
+					hosts.join(", ")
+				);
+			}
+			return Ok(());
+		}
+		let missing: Vec<&str> = hosts
+			.iter()
+			.filter(|h| !self.systems.contains_key(*h))
+			.map(String::as_str)
+			.collect();
+		if !missing.is_empty() {
+			bail!("manifest has no systems for {}", missing.join(", "));
+		}
+		Ok(())
+	}
+	pub fn system(&self, host: &str) -> Result<PathBuf> {
+		self.single
+			.clone()
+			.or_else(|| self.systems.get(host).cloned())
+			.with_context(|| format!("no prebuilt system for {host}"))
+	}
+	/// Checks that the whole closures of the host systems are signed by the fleet key
+	pub async fn verify(&self, config: &Config, hosts: &[String]) -> Result<()> {
+		let config_field = &config.config_field;
+		let fleet_public_key: Option<String> = nix_go_json!(config_field.nixSigning.publicKey);
+		let Some(key) = fleet_public_key else {
+			warn!("nixSigning.publicKey is not configured, hosts will only check signatures against the keys they trust");
+			return Ok(());
+		};
+		let systems = hosts
+			.iter()
+			.map(|h| self.system(h))
+			.collect::<Result<BTreeSet<_>>>()?;
+		for system in systems {
+			info!("verifying signatures of {}", system.display());
+			verify_signatures(config, &self.substituter, &key, &system).await?;
+		}
+		Ok(())
+	}
+}
+
+async fn verify_signatures(
+	config: &Config,
+	substituter: &str,
+	key: &str,
+	path: &Path,
+) -> Result<()> {
+	let mut cmd = config.local_host().cmd("nix").await?;
+	cmd.arg("store")
+		.arg("verify")
+		.comparg("--store", substituter)
+		.comparg("--option", "trusted-public-keys")
+		.arg(key)
+		.arg("--no-contents")
+		.arg("-r")
+		.arg(path);
+	cmd.retry_transient()
+		.run_nix()
+		.await
+		.with_context(|| format!("{} is not signed by the fleet key", path.display()))
+}
@@ -0,0 +1,318 @@
+//! Generations of nix profiles on the hosts.
+//!
+//! Current generation is read from the profile symlink, which points to `<profile>-<id>-link`
+//! next to it, with the link mtime being the generation creation time, the same data `nix-env`
+//! uses itself. Parsing of `nix-env --list-generations` is only a fallback, for profiles
+//! which are not managed the usual way.
+//!
+//! Profiles are switched either with `nix-env`, or with the `nix profile` CLI, see [`ProfileTool`].
+//! Both use the same generation links, so only the commands differ.
+
+use std::{ffi::OsString, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::DateTime;
+use fleet_base::host::ConfigHost;
+use itertools::Itertools as _;
+use nix_eval::nix_go_json;
+use serde::Deserialize;
+use tracing::debug;
+
+/// Tied to `hosts.<name>.deploy.profileTool` in deploy.nix
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum ProfileToolOption {
+	Auto,
+	NixEnv,
+	NixProfile,
+}
+
+/// CLI, which manages generations of the profile
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum ProfileTool {
+	NixEnv,
+	NixProfile,
+}
+impl ProfileTool {
+	/// Fleet profiles always point to a single store path, which both tools handle the same,
+	/// so `nix profile` is only used if the host has no `nix-env` at all.
+	///
+	/// Detection is a part of the nix probe, see [`ConfigHost::remote_nix`], which is done once per host.
+	pub(crate) async fn for_host(host: &ConfigHost) -> Result<Self> {
+		let deploy = host.deploy_options().await?;
+		let option: ProfileToolOption = nix_go_json!(deploy.profileTool);
+		let tool = match option {
+			ProfileToolOption::NixEnv => Self::NixEnv,
+			ProfileToolOption::NixProfile => Self::NixProfile,
+			ProfileToolOption::Auto if host.remote_nix().await?.has_nix_env => Self::NixEnv,
+			ProfileToolOption::Auto => Self::NixProfile,
+		};
+		debug!("profiles are managed by {tool:?}");
+		Ok(tool)
+	}
+
+	/// Command line, which adds a generation pointing to `path` and makes it current
+	fn set_args(self, profile: &str, path: &str) -> Vec<OsString> {
+		let args: &[&str] = match self {
+			Self::NixEnv => &["nix-env", "--profile", profile, "--set", path],
+			// nix profile has no way to set the profile to an arbitrary store path,
+			// yet nix build can do it for already built paths
+			Self::NixProfile => &[
+				"nix",
+				"--extra-experimental-features",
+				"nix-command",
+				"build",
+				"--no-link",
+				"--profile",
+				profile,
+				path,
+			],
+		};
+		args.iter().map(OsString::from).collect()
+	}
+
+	/// Command line, which makes an existing generation current
+	fn switch_args(self, profile: &str, id: u32) -> Vec<OsString> {
+		let id = id.to_string();
+		let args: &[&str] = match self {
+			Self::NixEnv => &[
+				"nix-env",
+				"--profile",
+				profile,
+				"--switch-generation",
+				id.as_str(),
+			],
+			Self::NixProfile => &[
+				"nix",
+				"--extra-experimental-features",
+				"nix-command",
+				"profile",
+				"rollback",
+				"--profile",
+				profile,
+				"--to",
+				id.as_str(),
+			],
+		};
+		args.iter().map(OsString::from).collect()
+	}
+
+	async fn run(host: &ConfigHost, args: Vec<OsString>) -> Result<()> {
+		let (program, args) = args.split_first().expect("command is not empty");
+		let mut cmd = host.cmd(program).await?;
+		cmd.args(args);
+		cmd.sudo().run().await
+	}
+}
+
+/// Adds a generation of the profile, pointing to `path`, and makes it current
+pub(crate) async fn set_profile(host: &ConfigHost, profile: &str, path: &str) -> Result<()> {
+	let tool = ProfileTool::for_host(host).await?;
+	ProfileTool::run(host, tool.set_args(profile, path)).await
+}
+
+/// Makes generation `id` of the profile current
+pub(crate) async fn switch_generation(host: &ConfigHost, profile: &str, id: u32) -> Result<()> {
+	let tool = ProfileTool::for_host(host).await?;
+	ProfileTool::run(host, tool.switch_args(profile, id)).await
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Generation {
+	pub(crate) id: u32,
+	pub(crate) current: bool,
+	/// `YYYY-MM-DD HH:MM:SS`, in UTC for generations read from the profile link,
+	/// and in the host timezone for `nix-env` output
+	pub(crate) datetime: String,
+}
+
+/// Generation id from the profile link target, `system-42-link` for the `system` profile
+fn parse_link(profile: &str, target: &str) -> Option<u32> {
+	let profile = Path::new(profile).file_name()?.to_str()?;
+	let target = Path::new(target).file_name()?.to_str()?;
+	let id = target
+		.strip_prefix(profile)?
+		.strip_prefix('-')?
+		.strip_suffix("-link")?;
+	id.parse().ok()
+}
+
+/// Output of the profile link query, see [`current_generation`]
+fn parse_link_query(profile: &str, output: &str) -> Result<Generation> {
+	let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+	let (Some(target), Some(mtime), None) = (lines.next(), lines.next(), lines.next()) else {
+		bail!("unexpected profile link query output: {output:?}");
+	};
+	let id = parse_link(profile, target).with_context(|| {
+		format!("profile {profile} points to {target}, which is not a generation link")
+	})?;
+	let mtime: i64 = mtime
+		.parse()
+		.with_context(|| format!("bad generation link mtime: {mtime:?}"))?;
+	let datetime =
+		DateTime::from_timestamp(mtime, 0).context("generation link mtime is out of range")?;
+	Ok(Generation {
+		id,
+		current: true,
+		datetime: datetime.format("%Y-%m-%d %H:%M:%S").to_string(),
+	})
+}
+
+/// Parses `nix-env --list-generations` output, lines are `<id> <date> <time> [(current)]`.
+///
+/// Malformed lines are reported as errors instead of being skipped, as skipping the current one
+/// would make the whole listing useless.
+fn parse_list_generations(output: &str) -> Result<Vec<Generation>> {
+	output
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty())
+		.map(|line| {
+			let mut parts = line.split_whitespace().collect::<Vec<_>>();
+			let current = parts.last() == Some(&"(current)");
+			if current {
+				parts.pop();
+			}
+			let Some((id, date)) = parts.split_first() else {
+				bail!("bad generation line: {line:?}");
+			};
+			let id = id
+				.parse()
+				.map_err(|_| anyhow!("bad generation line, expected id first: {line:?}"))?;
+			if date.is_empty() {
+				bail!("bad generation line, no creation time: {line:?}");
+			}
+			Ok(Generation {
+				id,
+				current,
+				datetime: date.join(" "),
+			})
+		})
+		.collect()
+}
+
+fn find_current(generations: Vec<Generation>) -> Result<Generation> {
+	generations
+		.into_iter()
+		.filter(|g| g.current)
+		.at_most_one()
+		.map_err(|_| anyhow!("multiple generations are marked as current"))?
+		.context("no generation is marked as current")
+}
+
+pub(crate) async fn current_generation(host: &ConfigHost, profile: &str) -> Result<Generation> {
+	let mut cmd = host.cmd("sh").await?;
+	cmd.arg("-c")
+		.arg(r#"target=$(readlink "$1") && echo "$target" && stat -c %Y "$(dirname "$1")/$(basename "$target")""#)
+		.arg("sh")
+		.arg(profile);
+	let link = match cmd.query().run_string().await {
+		Ok(output) => parse_link_query(profile, &output),
+		Err(e) => Err(e),
+	};
+	let e = match link {
+		Ok(generation) => return Ok(generation),
+		Err(e) => e,
+	};
+	// nix profile history doesn't mark the current generation, the link is the only source
+	if ProfileTool::for_host(host).await? == ProfileTool::NixProfile {
+		return Err(e.context(format!(
+			"failed to read current generation of {profile}, which is managed by nix profile"
+		)));
+	}
+	debug!("failed to read generation from the profile link, falling back to nix-env: {e:#}");
+
+	let mut cmd = host.cmd("nix-env").await?;
+	cmd.comparg("--profile", profile)
+		.arg("--list-generations")
+		// Output is not localized now, yet it should stay parseable if it ever will be
+		.env("LC_ALL", "C");
+	// Sudo is required due to --list-generations acquiring lock on the profile.
+	let data = cmd.sudo().query().run_string().await?;
+	find_current(parse_list_generations(&data)?)
+		.with_context(|| format!("failed to find current generation of {profile}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn link_targets() {
+		let profile = "/nix/var/nix/profiles/system";
+		assert_eq!(parse_link(profile, "system-42-link"), Some(42));
+		assert_eq!(
+			parse_link(profile, "/nix/var/nix/profiles/system-7-link"),
+			Some(7)
+		);
+		assert_eq!(
+			parse_link("/nix/var/nix/profiles/system-profiles/web", "web-3-link"),
+			Some(3)
+		);
+		assert_eq!(parse_link(profile, "system-profiles-3-link"), None);
+		assert_eq!(parse_link(profile, "/nix/store/abc-nixos-system"), None);
+
+		let generation = parse_link_query(profile, "system-42-link\n1700000000\n").unwrap();
+		assert_eq!(
+			generation,
+			Generation {
+				id: 42,
+				current: true,
+				datetime: "2023-11-14 22:13:20".to_owned(),
+			}
+		);
+		assert!(parse_link_query(profile, "system-42-link\n").is_err());
+	}
+
+	#[test]
+	fn profile_tools() {
+		let profile = "/nix/var/nix/profiles/system";
+		assert_eq!(
+			ProfileTool::NixEnv.set_args(profile, "/nix/store/abc-nixos-system"),
+			[
+				"nix-env",
+				"--profile",
+				profile,
+				"--set",
+				"/nix/store/abc-nixos-system"
+			]
+		);
+		assert_eq!(
+			ProfileTool::NixProfile.switch_args(profile, 41),
+			[
+				"nix",
+				"--extra-experimental-features",
+				"nix-command",
+				"profile",
+				"rollback",
+				"--profile",
+				profile,
+				"--to",
+				"41"
+			]
+		);
+	}
+
+	#[test]
+	fn list_generations() {
+		let output = "
+  40   2024-01-02 10:00:00
+  41   2024-01-03 11:30:12
+  42   2024-01-04 09:15:00   (current)
+";
+		let generations = parse_list_generations(output).unwrap();
+		assert_eq!(generations.len(), 3);
+		assert_eq!(
+			find_current(generations).unwrap(),
+			Generation {
+				id: 42,
+				current: true,
+				datetime: "2024-01-04 09:15:00".to_owned(),
+			}
+		);
+
+		assert!(parse_list_generations("  42   (current)\n").is_err());
+		assert!(parse_list_generations("generation 42 2024-01-04 09:15:00\n").is_err());
+		assert!(find_current(parse_list_generations("1 2024-01-04 09:15:00\n").unwrap()).is_err());
+	}
+}
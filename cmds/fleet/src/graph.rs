@@ -0,0 +1,221 @@
+//! Deployment plan as a graph, for `fleet info graph`.
+//!
+//! Hosts are grouped by failure domain, edges go from the host to its dependents, and from shared
+//! secrets to their owners. Deployment waves are only shown in host labels, as the scheduler
+//! starts hosts as soon as their dependencies are deployed, not wave by wave.
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use clap::ValueEnum;
+
+use crate::schedule::PlannedHost;
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum GraphFormat {
+	/// Graphviz DOT
+	Dot,
+	/// Mermaid flowchart
+	Mermaid,
+}
+
+pub struct DeployGraph {
+	pub hosts: Vec<PlannedHost>,
+	/// Failure domain of the host, `hosts.<name>.deploy.policy.failureDomain`
+	pub domains: BTreeMap<String, String>,
+	/// Owners of the shared secret
+	pub secrets: BTreeMap<String, Vec<String>>,
+}
+
+impl DeployGraph {
+	pub fn render(&self, format: GraphFormat) -> String {
+		match format {
+			GraphFormat::Dot => self.dot(),
+			GraphFormat::Mermaid => self.mermaid(),
+		}
+	}
+
+	fn host_lines(host: &PlannedHost) -> Vec<String> {
+		let mut lines = vec![host.name.clone(), format!("wave {}", host.wave)];
+		if !host.groups.is_empty() {
+			lines.push(format!("groups: {}", host.groups.join(", ")));
+		}
+		for (label, value) in &host.labels {
+			lines.push(format!("{label}={value}"));
+		}
+		lines
+	}
+
+	/// Hosts by failure domain, `None` for hosts without a domain
+	fn by_domain(&self) -> BTreeMap<Option<&str>, Vec<&PlannedHost>> {
+		let mut out: BTreeMap<_, Vec<_>> = BTreeMap::new();
+		for host in &self.hosts {
+			let domain = self.domains.get(&host.name).map(String::as_str);
+			out.entry(domain).or_default().push(host);
+		}
+		out
+	}
+
+	fn dot(&self) -> String {
+		let mut out = String::from("digraph fleet {\n\trankdir=LR;\n");
+		for (i, (domain, hosts)) in self.by_domain().into_iter().enumerate() {
+			let indent = if let Some(domain) = domain {
+				let _ = writeln!(
+					out,
+					"\tsubgraph cluster_{i} {{\n\t\tlabel={};",
+					dot_quote(&format!("failure domain {domain}"))
+				);
+				"\t\t"
+			} else {
+				"\t"
+			};
+			for host in hosts {
+				let _ = writeln!(
+					out,
+					"{indent}{} [shape=box, label={}];",
+					dot_quote(&host.name),
+					dot_quote(&Self::host_lines(host).join("\n"))
+				);
+			}
+			if domain.is_some() {
+				out.push_str("\t}\n");
+			}
+		}
+		for host in &self.hosts {
+			for dep in &host.after {
+				let _ = writeln!(out, "\t{} -> {};", dot_quote(dep), dot_quote(&host.name));
+			}
+		}
+		for (secret, owners) in &self.secrets {
+			let id = dot_quote(&format!("secret:{secret}"));
+			let _ = writeln!(out, "\t{id} [shape=note, label={}];", dot_quote(secret));
+			for owner in owners {
+				let _ = writeln!(out, "\t{id} -> {} [style=dashed];", dot_quote(owner));
+			}
+		}
+		out.push_str("}\n");
+		out
+	}
+
+	fn mermaid(&self) -> String {
+		// Host names might contain characters, which are not allowed in mermaid ids
+		let ids: BTreeMap<&str, String> = self
+			.hosts
+			.iter()
+			.enumerate()
+			.map(|(i, h)| (h.name.as_str(), format!("h{i}")))
+			.collect();
+		let mut out = String::from("flowchart LR\n");
+		for (i, (domain, hosts)) in self.by_domain().into_iter().enumerate() {
+			let indent = if let Some(domain) = domain {
+				let _ = writeln!(
+					out,
+					"  subgraph d{i} [{}]",
+					mermaid_quote(&format!("failure domain {domain}"))
+				);
+				"    "
+			} else {
+				"  "
+			};
+			for host in hosts {
+				let _ = writeln!(
+					out,
+					"{indent}{}[{}]",
+					ids[host.name.as_str()],
+					mermaid_quote(&Self::host_lines(host).join("<br/>"))
+				);
+			}
+			if domain.is_some() {
+				out.push_str("  end\n");
+			}
+		}
+		for host in &self.hosts {
+			for dep in &host.after {
+				let _ = writeln!(
+					out,
+					"  {} --> {}",
+					ids[dep.as_str()],
+					ids[host.name.as_str()]
+				);
+			}
+		}
+		for (i, (secret, owners)) in self.secrets.iter().enumerate() {
+			let _ = writeln!(out, "  s{i}[/{}/]", mermaid_quote(secret));
+			// Owners might not be hosts of the fleet, such secrets are reported by `fleet lint`
+			for owner in owners.iter().filter_map(|o| ids.get(o.as_str())) {
+				let _ = writeln!(out, "  s{i} -.-> {owner}");
+			}
+		}
+		out
+	}
+}
+
+fn dot_quote(s: &str) -> String {
+	let mut out = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"' | '\\' => {
+				out.push('\\');
+				out.push(c);
+			}
+			'\n' => out.push_str("\\n"),
+			_ => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+fn mermaid_quote(s: &str) -> String {
+	format!("\"{}\"", s.replace('"', "#quot;"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn host(name: &str, after: &[&str], wave: usize) -> PlannedHost {
+		PlannedHost {
+			name: name.to_owned(),
+			after: after.iter().map(|a| a.to_string()).collect(),
+			groups: vec![],
+			labels: BTreeMap::new(),
+			wave,
+		}
+	}
+
+	#[test]
+	fn render() {
+		let graph = DeployGraph {
+			hosts: vec![host("db1", &[], 1), host("web1", &["db1"], 2)],
+			domains: [("web1".to_owned(), "rack-a".to_owned())].into(),
+			secrets: [("ca".to_owned(), vec!["web1".to_owned()])].into(),
+		};
+		assert_eq!(
+			graph.render(GraphFormat::Dot),
+			"digraph fleet {
+	rankdir=LR;
+	\"db1\" [shape=box, label=\"db1\\nwave 1\"];
+	subgraph cluster_1 {
+		label=\"failure domain rack-a\";
+		\"web1\" [shape=box, label=\"web1\\nwave 2\"];
+	}
+	\"db1\" -> \"web1\";
+	\"secret:ca\" [shape=note, label=\"ca\"];
+	\"secret:ca\" -> \"web1\" [style=dashed];
+}
+"
+		);
+		assert_eq!(
+			graph.render(GraphFormat::Mermaid),
+			"flowchart LR
+  h0[\"db1<br/>wave 1\"]
+  subgraph d1 [\"failure domain rack-a\"]
+    h1[\"web1<br/>wave 2\"]
+  end
+  h0 --> h1
+  s0[/\"ca\"/]
+  s0 -.-> h1
+"
+		);
+	}
+}
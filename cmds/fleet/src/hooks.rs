@@ -0,0 +1,232 @@
+//! Deployment hooks, declared in `hosts.<name>.deploy.hooks`.
+//!
+//! Hooks are executed on the deployer machine, and receive deployment context both as
+//! separate `FLEET_*` environment variables, and as a single json object in `FLEET_HOOK_CONTEXT`.
+//!
+//! Variables from `hosts.<name>.deploy.hookEnv` are only passed to the hooks, which are listed in them.
+//! Secret values are decrypted once per phase, and only if some of the phase hooks need them.
+
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use fleet_base::{command, host::ConfigHost, secret_bytes::SecretBytes};
+use nix_eval::{nix_go, nix_go_json};
+use serde::{Deserialize, Serialize};
+use tracing::{info, info_span, Instrument};
+
+use crate::cmds::secrets::inputs::{self, SecretInput};
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum HookPhase {
+	PostBuild,
+	PostUpload,
+	PreActivate,
+	PostActivate,
+}
+impl HookPhase {
+	fn attr(&self) -> &'static str {
+		match self {
+			HookPhase::PostBuild => "postBuild",
+			HookPhase::PostUpload => "postUpload",
+			HookPhase::PreActivate => "preActivate",
+			HookPhase::PostActivate => "postActivate",
+		}
+	}
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookContext<'a> {
+	pub host: &'a str,
+	pub phase: HookPhase,
+	pub deployment_id: &'a str,
+	/// Deploy action name, i.e "switch"
+	pub action: &'a str,
+	pub toplevel: Option<&'a Path>,
+	/// Generation, which was current on the host before activation
+	pub previous_generation: Option<u32>,
+	/// Only set for post-activation hooks
+	pub outcome: Option<&'a str>,
+}
+
+/// Hook derivation might either be a plain executable (writeShellScript),
+/// or a package with single binary (writeShellApplication)
+fn hook_executable(built: &Path) -> Result<PathBuf> {
+	if built.is_file() {
+		return Ok(built.to_owned());
+	}
+	let bin = built.join("bin");
+	let mut entries = std::fs::read_dir(&bin)
+		.with_context(|| format!("hook {built:?} is neither executable nor has bin directory"))?;
+	let entry = entries
+		.next()
+		.ok_or_else(|| anyhow!("hook {built:?} has no executables"))??;
+	if entries.next().is_some() {
+		return Err(anyhow!("hook {built:?} has multiple executables in bin directory"));
+	}
+	Ok(entry.path())
+}
+
+/// Tied to deploy.nix
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HookEnv {
+	hooks: Vec<String>,
+	value: Option<String>,
+	secret: Option<String>,
+	shared: Option<String>,
+	part: String,
+}
+
+fn check_env_name(name: &str) -> Result<()> {
+	ensure!(
+		!name.is_empty()
+			&& !name.starts_with(|c: char| c.is_ascii_digit())
+			&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+		"hook environment variable name {name:?} is invalid"
+	);
+	ensure!(
+		!name.starts_with("FLEET_"),
+		"hook environment variable {name} uses reserved FLEET_ prefix"
+	);
+	Ok(())
+}
+
+enum EnvValue {
+	Plain(String),
+	/// Kept in locked memory until the hook is started, empty in dry-run mode
+	Secret(Arc<SecretBytes>),
+}
+
+struct ResolvedEnv {
+	hooks: Vec<String>,
+	value: EnvValue,
+}
+
+/// Values of variables, which are allowed for any of the hooks
+async fn resolve_env(
+	host: &ConfigHost,
+	env: BTreeMap<String, HookEnv>,
+	hooks: &[String],
+) -> Result<BTreeMap<String, ResolvedEnv>> {
+	let mut out = BTreeMap::new();
+	let mut secrets = BTreeMap::new();
+	for (name, var) in env {
+		if !var.hooks.iter().any(|h| hooks.contains(h)) {
+			continue;
+		}
+		check_env_name(&name)?;
+		let input = match (var.value, var.secret, var.shared) {
+			(Some(value), None, None) => {
+				out.insert(
+					name,
+					ResolvedEnv {
+						hooks: var.hooks,
+						value: EnvValue::Plain(value),
+					},
+				);
+				continue;
+			}
+			(None, Some(secret), None) => SecretInput::host(host.name.clone(), secret, var.part),
+			(None, None, Some(shared)) => SecretInput::shared(shared, var.part),
+			_ => bail!("hook environment variable {name} should have exactly one of value, secret or shared set"),
+		};
+		out.insert(
+			name.clone(),
+			ResolvedEnv {
+				hooks: var.hooks,
+				value: EnvValue::Secret(Arc::new(SecretBytes::new())),
+			},
+		);
+		secrets.insert(name, input);
+	}
+	// Hooks are not ran in dry-run mode, there is no need to decrypt anything
+	if secrets.is_empty() || command::is_dry_run() {
+		return Ok(out);
+	}
+	let dependent = format!("hook environment of {}", host.name);
+	let values = inputs::resolve(host.config(), &dependent, secrets, &[]).await?;
+	for (name, data) in values.into_values() {
+		data.expose_str()
+			.map_err(|_| anyhow!("value of hook environment variable {name} is not valid utf-8"))?;
+		out.get_mut(&name).expect("inserted above").value = EnvValue::Secret(Arc::new(data));
+	}
+	Ok(out)
+}
+
+fn opt_display(v: Option<impl ToString>) -> String {
+	v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+pub async fn run_hooks(host: &ConfigHost, context: &HookContext<'_>) -> Result<()> {
+	let deploy = host.deploy_options().await?;
+	let phase = context.phase.attr();
+	let hooks = nix_go!(deploy.hooks[{ phase }]);
+	let json = serde_json::to_string(context).expect("context is serializable");
+	let names = hooks.list_fields().await?;
+	if names.is_empty() {
+		return Ok(());
+	}
+	let env: BTreeMap<String, HookEnv> = nix_go_json!(deploy.hookEnv);
+	let env = resolve_env(host, env, &names).await?;
+	for name in names {
+		let span = info_span!("hook", phase, name);
+		async {
+			info!("running hook");
+			let hook = nix_go!(hooks[{ name }]);
+			let built = hook.build().await?;
+			let built = built
+				.get("out")
+				.ok_or_else(|| anyhow!("hook should produce \"out\" output"))?;
+			let executable = hook_executable(built)?;
+
+			let mut cmd = host.config().local_host().cmd(executable).await?;
+			cmd.env("FLEET_HOST", context.host)
+				.env("FLEET_PHASE", phase)
+				.env("FLEET_DEPLOYMENT_ID", context.deployment_id)
+				.env("FLEET_ACTION", context.action)
+				.env(
+					"FLEET_TOPLEVEL",
+					opt_display(context.toplevel.map(|p| p.display())),
+				)
+				.env(
+					"FLEET_PREVIOUS_GENERATION",
+					opt_display(context.previous_generation),
+				)
+				.env("FLEET_OUTCOME", opt_display(context.outcome))
+				.env("FLEET_HOOK_CONTEXT", &json);
+			for (var, resolved) in &env {
+				if !resolved.hooks.contains(&name) {
+					continue;
+				}
+				match &resolved.value {
+					EnvValue::Plain(value) => cmd.env(var, value),
+					EnvValue::Secret(value) => cmd.secret_env(var, value.clone()),
+				};
+			}
+			cmd.run().await.with_context(|| format!("hook {name} failed"))
+		}
+		.instrument(span)
+		.await?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn env_names() {
+		assert!(check_env_name("CLOUDFLARE_API_TOKEN").is_ok());
+		assert!(check_env_name("_x1").is_ok());
+		assert!(check_env_name("1X").is_err());
+		assert!(check_env_name("A=B").is_err());
+		assert!(check_env_name("FLEET_HOST").is_err());
+	}
+}
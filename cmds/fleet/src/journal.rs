@@ -0,0 +1,57 @@
+//! On-host journal of profile switches performed by fleet, stored in [`JOURNAL_PATH`].
+//!
+//! Unlike `nix-env --list-generations`, journal only contains generations deployed by fleet,
+//! together with the deployment outcome, so rollback targets can be chosen from known-good deployments.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use fleet_base::host::ConfigHost;
+use serde::{Deserialize, Serialize};
+
+pub const JOURNAL_PATH: &str = "/var/lib/fleet/journal.json";
+/// Journal is passed as a command argument on write, which is limited in size.
+const MAX_ENTRIES: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationRef {
+	pub id: u32,
+	pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalEntry {
+	pub deployment_id: String,
+	pub timestamp: DateTime<Utc>,
+	pub action: String,
+	pub before: GenerationRef,
+	pub after: GenerationRef,
+	/// Deployment outcome, as reported in telemetry, i.e "success"
+	pub outcome: String,
+	/// Specialisation of the `after` generation, which was activated
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub specialisation: Option<String>,
+}
+
+pub async fn read_journal(host: &ConfigHost) -> Result<Vec<JournalEntry>> {
+	let mut cmd = host.cmd("sh").await?;
+	cmd.arg("-c")
+		.arg(format!("cat {JOURNAL_PATH} 2>/dev/null || echo '[]'"));
+	let data = cmd.query().run_string().await?;
+	serde_json::from_str(&data).context("failed to parse fleet journal")
+}
+
+pub async fn append_journal(host: &ConfigHost, entry: JournalEntry) -> Result<()> {
+	let mut entries = read_journal(host).await?;
+	entries.push(entry);
+	let skip = entries.len().saturating_sub(MAX_ENTRIES);
+	let data = serde_json::to_string(&entries[skip..])?;
+
+	let mut cmd = host.cmd("sh").await?;
+	cmd.arg("-c")
+		.arg(format!("mkdir -p /var/lib/fleet && tmp=$(mktemp -p /var/lib/fleet journal.XXXXX) && printf '%s' \"$1\" > \"$tmp\" && chmod 644 \"$tmp\" && mv \"$tmp\" {JOURNAL_PATH}"))
+		.arg("sh")
+		.arg(data);
+	cmd.sudo().run().await.context("failed to write fleet journal")
+}
@@ -1,33 +1,100 @@
 #![recursion_limit = "512"]
 #![feature(try_blocks)]
 
+pub(crate) mod activation;
+pub(crate) mod audit;
+pub(crate) mod builders;
+pub(crate) mod cache_server;
 pub(crate) mod cmds;
+pub(crate) mod confirm;
 // pub(crate) mod command;
 pub(crate) mod extra_args;
+pub(crate) mod from_cache;
+pub(crate) mod generations;
+pub(crate) mod graph;
+pub(crate) mod hooks;
+pub(crate) mod journal;
+pub(crate) mod notify;
+pub(crate) mod policy;
+pub(crate) mod run_state;
+pub(crate) mod schedule;
+pub(crate) mod secure_boot;
+pub(crate) mod summary;
+pub(crate) mod telemetry;
+pub(crate) mod timeouts;
 
 use std::{ffi::OsString, process::ExitCode};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
+use builders::BuildersFrom;
 use clap::{CommandFactory, Parser};
 use cmds::{
 	build_systems::{BuildSystems, Deploy},
-	complete::Complete,
+	complete::{refresh_cache, Complete, Completions},
+	container::Container,
+	data::Data,
+	disko::Disko,
+	doctor::Doctor,
+	flash::Flash,
+	host::Host,
+	image::Image,
 	info::Info,
+	init_host::InitHost,
+	keys::Keys,
+	lint::Lint,
+	migrate::{Migrate, MigrateStorage},
+	power::Power,
+	probe::Probe,
+	push::Push,
+	rollback::Rollback,
 	secrets::Secret,
+	ssh::Ssh,
+	test_vm::TestVm,
 	tf::Tf,
+	verify::Verify,
+	watch::Watch,
 };
-use fleet_base::{host::Config, opts::FleetOpts};
+use fleet_base::{command, host::Config, opts::FleetOpts, sealed};
 use futures::{future::LocalBoxFuture, stream::FuturesUnordered, TryStreamExt};
 // use host::Config;
 #[cfg(feature = "indicatif")]
 use human_repr::HumanCount;
 #[cfg(feature = "indicatif")]
 use indicatif::{ProgressState, ProgressStyle};
-use tracing::{error, info, info_span, Instrument};
+use tracing::{error, info, info_span, warn, Instrument};
 #[cfg(feature = "indicatif")]
 use tracing_indicatif::IndicatifLayer;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+#[derive(Parser)]
+struct Seal {}
+impl Seal {
+	fn run(&self, config: &Config) -> Result<()> {
+		ensure!(!config.is_sealed(), "fleet data is already sealed");
+		config.set_sealed(true)?;
+		info!(
+			"fleet data is sealed to the recipients from {}, {} is removed",
+			config.fleet_file(sealed::RECIPIENTS_FILE),
+			config.fleet_file(sealed::PLAIN_FILE)
+		);
+		Ok(())
+	}
+}
+
+#[derive(Parser)]
+struct Unseal {}
+impl Unseal {
+	fn run(&self, config: &Config) -> Result<()> {
+		ensure!(config.is_sealed(), "fleet data is not sealed");
+		config.set_sealed(false)?;
+		info!(
+			"fleet data is stored in plain {}",
+			config.fleet_file(sealed::PLAIN_FILE)
+		);
+		Ok(())
+	}
+}
+
 #[derive(Parser)]
 struct Prefetch {}
 impl Prefetch {
@@ -56,7 +123,11 @@ impl Prefetch {
 				let mut status = config.local_host().cmd("nix").await?;
 				status.args(&config.nix_args);
 				status.arg("store").arg("prefetch-file").arg(path);
-				status.run_nix_string().instrument(span).await?;
+				status
+					.retry_transient()
+					.run_nix_string()
+					.instrument(span)
+					.await?;
 				Ok(())
 			}));
 		}
@@ -71,14 +142,65 @@ enum Opts {
 	BuildSystems(BuildSystems),
 
 	Deploy(Deploy),
+	/// Build systems and upload them to hosts and/or binary cache, without activation
+	Push(Push),
+	/// Switch host back to the previous generation and activate it
+	Rollback(Rollback),
 	/// Secret management
 	#[clap(subcommand)]
 	Secret(Secret),
+	/// Host adding, renaming, removal and ssh host key trust
+	#[clap(subcommand)]
+	Host(Host),
+	/// Build host image and write it to the block device
+	Flash(Flash),
+	/// Build images of multiple hosts to a single directory, with checksums and a release manifest
+	Image(Image),
+	/// Install host configuration on a fresh machine over ssh, using nixos-anywhere and disko
+	InitHost(InitHost),
+	/// Partition and format host disks, using disko
+	#[clap(subcommand)]
+	Disko(Disko),
 	/// Upload prefetch directory to the nix store
 	Prefetch(Prefetch),
+	/// Encrypt fleet data to the recipients listed in fleet.recipients
+	Seal(Seal),
+	/// Store fleet data unencrypted
+	Unseal(Unseal),
+	/// Fleet-wide keys management
+	#[clap(subcommand)]
+	Keys(Keys),
+	/// Upgrade fleet data to the format of this fleet version, done automatically on every run
+	Migrate(Migrate),
+	/// Convert fleet data between the single file, and the file per secret layouts
+	MigrateStorage(MigrateStorage),
+	/// Local history of fleet data changes
+	#[clap(subcommand)]
+	Data(Data),
+	/// Poll git branch, and deploy every new commit
+	Watch(Watch),
 	/// Config parsing
 	Info(Info),
-	/// Command completions
+	/// Check local environment and fleet config for common problems, and suggest fixes
+	Doctor(Doctor),
+	/// Check fleet config for suspicious patterns, with machine-readable output for CI
+	Lint(Lint),
+	/// Open shell or run command on the host, using connection parameters from the fleet config
+	Ssh(Ssh),
+	/// Power hosts on or off, using Wake-on-LAN, BMC or ssh
+	Power(Power),
+	/// Check that hosts run the configured system, with expected secrets and rollback units
+	Verify(Verify),
+	/// Build, push and roll out container images, declared in `containers`
+	#[clap(subcommand)]
+	Container(Container),
+	/// Boot host system in a local VM with placeholder secrets, and run its health checks
+	TestVm(TestVm),
+	/// Measure ssh latency and throughput to hosts, used to pick copy strategy on deploy
+	Probe(Probe),
+	/// Generate shell completions, which also complete host, tag and secret names
+	Completions(Completions),
+	/// Dynamic values for shell completions
 	#[clap(hide(true))]
 	Complete(Complete),
 	/// Compile and evaluate terranix configuration
@@ -90,6 +212,18 @@ enum Opts {
 struct RootOpts {
 	#[clap(flatten)]
 	fleet_opts: FleetOpts,
+	/// Print commands which would change local or remote state instead of running them,
+	/// only queries needed to plan the run (builds, state reads) are executed
+	#[clap(long)]
+	dry_run: bool,
+	/// How many times to run idempotent commands (builds, copies, queries), which have failed
+	/// with a transient error (busy nix database, substituter 5xx, dropped ssh connection)
+	#[clap(long, default_value = "3", value_parser = clap::value_parser!(u32).range(1..))]
+	transient_attempts: u32,
+	/// Use remote builders in addition to the local machine for `build-systems`, `deploy` and `push`,
+	/// `fleet` uses hosts with `fleet.builder.enable` set, generating nix `--builders` from their configs
+	#[clap(long, value_enum, env = "FLEET_BUILDERS_FROM")]
+	builders_from: Option<BuildersFrom>,
 	#[clap(subcommand)]
 	command: Opts,
 }
@@ -98,13 +232,36 @@ async fn run_command(config: &Config, opts: FleetOpts, command: Opts) -> Result<
 	match command {
 		Opts::BuildSystems(c) => c.run(config, &opts).await?,
 		Opts::Deploy(d) => d.run(config, &opts).await?,
+		Opts::Rollback(r) => r.run(config).await?,
+		Opts::Push(p) => p.run(config, &opts).await?,
 		Opts::Secret(s) => s.run(config, &opts).await?,
+		Opts::Host(h) => h.run(config).await?,
 		Opts::Info(i) => i.run(config).await?,
+		Opts::Lint(l) => l.run(config, &opts).await?,
+		Opts::Probe(p) => p.run(config, &opts).await?,
+		Opts::Verify(v) => v.run(config, &opts).await?,
+		Opts::Power(p) => p.run(config, &opts).await?,
+		Opts::Ssh(s) => s.run(config).await?,
 		Opts::Prefetch(p) => p.run(config).await?,
+		Opts::Seal(s) => s.run(config)?,
+		Opts::Unseal(u) => u.run(config)?,
+		Opts::Migrate(_)
+		| Opts::MigrateStorage(_)
+		| Opts::Data(_)
+		| Opts::Watch(_)
+		| Opts::Doctor(_) => {
+			unreachable!("handled before config is built")
+		}
+		Opts::Keys(k) => k.run_with_config(config).await?,
+		Opts::Flash(f) => f.run(config).await?,
+		Opts::Image(i) => i.run(config, &opts).await?,
+		Opts::InitHost(i) => i.run(config).await?,
+		Opts::Disko(d) => d.run(config).await?,
 		Opts::Tf(t) => t.run(config).await?,
-		// TODO: actually parse commands before starting the async runtime
-		Opts::Complete(c) => {
-			tokio::task::spawn_blocking(move || c.run(RootOpts::command())).await?
+		Opts::TestVm(t) => t.run(config).await?,
+		Opts::Container(c) => c.run(config).await?,
+		Opts::Completions(_) | Opts::Complete(_) => {
+			unreachable!("handled before async runtime is started")
 		}
 	};
 	Ok(())
@@ -174,8 +331,16 @@ fn setup_logging() {
 
 fn main() -> ExitCode {
 	let opts = RootOpts::parse();
-	if let Opts::Complete(c) = &opts.command {
-		c.run(RootOpts::command());
+	let completed = match &opts.command {
+		Opts::Completions(c) => Some(c.run(RootOpts::command())),
+		Opts::Complete(c) => Some(c.run(&opts.fleet_opts, RootOpts::command())),
+		_ => None,
+	};
+	if let Some(result) = completed {
+		if let Err(e) = result {
+			eprintln!("{e:#}");
+			return ExitCode::FAILURE;
+		}
 		return ExitCode::SUCCESS;
 	}
 
@@ -198,16 +363,69 @@ async fn async_main(opts: RootOpts) -> ExitCode {
 
 async fn main_real(opts: RootOpts) -> Result<()> {
 	nix_eval::init_tokio();
+	command::set_dry_run(opts.dry_run);
+	command::set_transient_attempts(opts.transient_attempts);
 
-	let nix_args = std::env::var_os("NIX_ARGS")
+	let mut nix_args = std::env::var_os("NIX_ARGS")
 		.map(|a| extra_args::parse_os(&a))
 		.transpose()?
 		.unwrap_or_default();
-	let config = opts.fleet_opts.build(nix_args).await?;
+	// Evaluation is started before the command is run, thus it should be configured here
+	let show_trace = match &opts.command {
+		Opts::BuildSystems(b) => b.build_log.show_trace,
+		Opts::Deploy(d) => d.build_log.show_trace,
+		_ => false,
+	};
+	if show_trace {
+		nix_args.push("--show-trace".into());
+	}
+	let build_host = match &opts.command {
+		Opts::BuildSystems(b) => b.rebuild.build_host.clone(),
+		Opts::Deploy(d) => d.rebuild.build_host.clone(),
+		_ => None,
+	};
+	ensure!(
+		build_host.is_none() || opts.builders_from.is_none(),
+		"--build-host can't be combined with --builders-from"
+	);
+	// Probing builders connects to every builder host, which is only worth it when systems are built
+	let builds_systems = matches!(
+		opts.command,
+		Opts::BuildSystems(_) | Opts::Deploy(_) | Opts::Push(_)
+	);
+	let builders_from = opts.builders_from.filter(|_| builds_systems);
+	if builders_from.is_some() || build_host.is_some() {
+		nix_args.extend(builders::nix_args(&std::env::current_dir()?));
+	}
+	let mut build_nix_args = vec![];
+	if build_host.is_some() {
+		// Systems are built on the build host, the same as nixos-rebuild does,
+		// evaluation and other builds (secret generators, hooks) stay local
+		build_nix_args.extend(["--max-jobs".into(), "0".into()]);
+	}
+	match &opts.command {
+		Opts::Migrate(m) => return m.run(&opts.fleet_opts),
+		Opts::MigrateStorage(m) => return m.run(&opts.fleet_opts),
+		Opts::Data(d) => return d.run(&opts.fleet_opts),
+		Opts::Keys(k) if !k.needs_config() => return k.run(),
+		Opts::Watch(w) => return w.run().await,
+		Opts::Doctor(d) => return d.run(&opts.fleet_opts, nix_args).await,
+		_ => {}
+	}
+	let config = opts.fleet_opts.build(nix_args, build_nix_args).await?;
+	if let Some(BuildersFrom::Fleet) = builders_from {
+		builders::write_machines(&config).await?;
+	}
+	if let Some(build_host) = &build_host {
+		builders::write_build_host(&config, build_host).await?;
+	}
 
 	match run_command(&config, opts.fleet_opts, opts.command).await {
 		Ok(()) => {
 			config.save()?;
+			if let Err(e) = refresh_cache(&config).await {
+				warn!("failed to refresh completion cache: {e:#}");
+			}
 			Ok(())
 		}
 		Err(e) => {
@@ -0,0 +1,171 @@
+//! Deployment notifications, sent to a webhook and/or as a desktop notification.
+//!
+//! Hosts which have failed or were rolled back are reported as soon as it happens, and the run
+//! summary is sent once the deployment is finished. Notification failures are never fatal.
+
+use std::io::Write as _;
+
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
+use fleet_base::host::Config;
+use serde_json::json;
+use tempfile::NamedTempFile;
+use tracing::{info, warn};
+
+use crate::{cmds::power::curl_config_escape, summary::RunSummary};
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum WebhookFormat {
+	/// `{"text": "..."}`, accepted by Slack incoming webhooks and Matrix hookshot
+	Text,
+	/// Structured event, for custom receivers
+	Json,
+}
+
+#[derive(Parser, Clone)]
+pub struct NotifyOpts {
+	/// Post deployment notifications to this webhook url
+	#[clap(long, env = "FLEET_NOTIFY_WEBHOOK")]
+	notify_webhook: Option<String>,
+	#[clap(long, value_enum, default_value = "text")]
+	notify_format: WebhookFormat,
+	/// Show deployment notifications on this machine, requires notify-send
+	#[clap(long)]
+	notify_desktop: bool,
+	/// Only notify about failed and rolled back hosts, and runs with such hosts
+	#[clap(long)]
+	notify_failures_only: bool,
+}
+
+struct Event<'a> {
+	kind: &'static str,
+	run_id: &'a str,
+	host: Option<&'a str>,
+	outcome: &'a str,
+	message: String,
+	summary: Option<String>,
+}
+
+impl NotifyOpts {
+	fn is_enabled(&self) -> bool {
+		self.notify_webhook.is_some() || self.notify_desktop
+	}
+
+	/// Outcome is one of "success", "failed", "rolled_back", "cancelled"
+	pub async fn host_outcome(&self, config: &Config, run_id: &str, host: &str, outcome: &str) {
+		if !matches!(outcome, "failed" | "rolled_back") {
+			return;
+		}
+		let message = match outcome {
+			"failed" => format!("fleet deployment {run_id}: {host} has failed"),
+			_ => format!("fleet deployment {run_id}: {host} was rolled back"),
+		};
+		self.send(
+			config,
+			Event {
+				kind: "host",
+				run_id,
+				host: Some(host),
+				outcome,
+				message,
+				summary: None,
+			},
+		)
+		.await
+	}
+
+	pub async fn finished(&self, config: &Config, run_id: &str, summary: &RunSummary) {
+		let failed = summary.failed();
+		if failed.is_empty() && self.notify_failures_only {
+			return;
+		}
+		let (outcome, message) = if failed.is_empty() {
+			(
+				"success",
+				format!("fleet deployment {run_id} has succeeded"),
+			)
+		} else {
+			(
+				"failed",
+				format!(
+					"fleet deployment {run_id} has finished, not deployed: {}",
+					failed.join(", ")
+				),
+			)
+		};
+		self.send(
+			config,
+			Event {
+				kind: "finished",
+				run_id,
+				host: None,
+				outcome,
+				message,
+				summary: summary.render(),
+			},
+		)
+		.await
+	}
+
+	async fn send(&self, config: &Config, event: Event<'_>) {
+		if !self.is_enabled() {
+			return;
+		}
+		if let Some(url) = &self.notify_webhook {
+			if let Err(e) = self.post(config, url, &event).await {
+				warn!("failed to send webhook notification: {e:#}");
+			}
+		}
+		if self.notify_desktop {
+			if let Err(e) = desktop(config, &event).await {
+				warn!("failed to show desktop notification: {e:#}");
+			}
+		}
+	}
+
+	async fn post(&self, config: &Config, url: &str, event: &Event<'_>) -> Result<()> {
+		let body = match self.notify_format {
+			WebhookFormat::Text => {
+				let mut text = event.message.clone();
+				if let Some(summary) = &event.summary {
+					text.push_str(&format!("\n```\n{summary}\n```"));
+				}
+				json!({ "text": text })
+			}
+			WebhookFormat::Json => json!({
+				"event": event.kind,
+				"deploymentId": event.run_id,
+				"host": event.host,
+				"outcome": event.outcome,
+				"message": event.message,
+				"summary": event.summary,
+			}),
+		};
+		let mut file = NamedTempFile::new()?;
+		serde_json::to_writer(&mut file, &body)?;
+		file.flush()?;
+
+		info!("sending notification");
+		// Webhook url is a credential, it is passed in the curl config on stdin,
+		// arguments are visible to other users
+		let mut cmd = config.local_host().cmd("curl").await?;
+		cmd.arg("--fail")
+			.arg("--silent")
+			.arg("--show-error")
+			.comparg("--config", "-")
+			.comparg("--header", "Content-Type: application/json")
+			.comparg("--data-binary", format!("@{}", file.path().display()))
+			.stdin(format!("url = \"{}\"\n", curl_config_escape(url)));
+		cmd.run().await.context("webhook request")
+	}
+}
+
+async fn desktop(config: &Config, event: &Event<'_>) -> Result<()> {
+	let mut cmd = config.local_host().cmd("notify-send").await?;
+	cmd.comparg("--app-name", "fleet");
+	if event.outcome != "success" {
+		cmd.comparg("--urgency", "critical");
+	}
+	cmd.arg("fleet").arg(&event.message);
+	cmd.run().await
+}
@@ -0,0 +1,110 @@
+//! Failure handling policies, declared per host in `hosts.<name>.deploy.policy`,
+//! and failure domain limits, declared in `failureDomains`.
+
+use std::{cell::RefCell, collections::BTreeMap};
+
+use anyhow::Result;
+use fleet_base::host::{Config, ConfigHost};
+use nix_eval::nix_go_json;
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HostPolicy {
+	pub activation_retries: u32,
+	/// Failure of this host halts the whole deployment
+	pub critical: bool,
+	pub failure_domain: Option<String>,
+}
+impl HostPolicy {
+	pub async fn for_host(host: &ConfigHost) -> Result<Self> {
+		let deploy = host.deploy_options().await?;
+		Ok(nix_go_json!(deploy.policy))
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FailureDomain {
+	max_failures: u32,
+}
+
+/// Counts failures of the run, and decides when the deployment should be halted
+#[derive(Default)]
+pub struct FailureTracker {
+	/// Domains without declared limit are not limited
+	limits: BTreeMap<String, u32>,
+	failures: RefCell<BTreeMap<String, u32>>,
+	/// Every failure halts the deployment, see `cli.deploy.failFast`
+	fail_fast: bool,
+}
+impl FailureTracker {
+	pub async fn new(config: &Config) -> Result<Self> {
+		let config_field = &config.config_field;
+		let domains: BTreeMap<String, FailureDomain> = nix_go_json!(config_field.failureDomains);
+		Ok(Self {
+			limits: domains
+				.into_iter()
+				.map(|(name, domain)| (name, domain.max_failures))
+				.collect(),
+			failures: RefCell::default(),
+			fail_fast: config.cli_defaults.deploy.fail_fast,
+		})
+	}
+
+	/// Returns the reason to halt the deployment, if this failure should halt it
+	pub fn record_failure(&self, host: &str, policy: &HostPolicy) -> Option<String> {
+		let domain_reason = policy.failure_domain.as_ref().and_then(|domain| {
+			let mut failures = self.failures.borrow_mut();
+			let count = failures.entry(domain.clone()).or_default();
+			*count += 1;
+			let limit = self.limits.get(domain)?;
+			(*count > *limit).then(|| {
+				format!("{count} hosts of failure domain {domain} have failed, at most {limit} failures are allowed")
+			})
+		});
+		if policy.critical {
+			return Some(format!("critical host {host} has failed"));
+		}
+		if self.fail_fast {
+			return Some(format!("host {host} has failed, and --fail-fast is set"));
+		}
+		domain_reason
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn domain_limit() {
+		let tracker = FailureTracker {
+			limits: [("rack-a".to_owned(), 1)].into_iter().collect(),
+			failures: RefCell::default(),
+			fail_fast: false,
+		};
+		let policy = HostPolicy {
+			failure_domain: Some("rack-a".to_owned()),
+			..Default::default()
+		};
+		assert_eq!(tracker.record_failure("a1", &policy), None);
+		assert!(tracker.record_failure("a2", &policy).is_some());
+
+		let unlimited = HostPolicy {
+			failure_domain: Some("rack-b".to_owned()),
+			..Default::default()
+		};
+		assert_eq!(tracker.record_failure("b1", &unlimited), None);
+		assert_eq!(tracker.record_failure("b2", &unlimited), None);
+
+		let critical = HostPolicy {
+			critical: true,
+			..Default::default()
+		};
+		assert_eq!(
+			tracker.record_failure("db1", &critical).as_deref(),
+			Some("critical host db1 has failed")
+		);
+	}
+}
@@ -0,0 +1,154 @@
+//! Per-run deployment state, used for resuming partially failed deployments.
+//!
+//! Stored in `.fleet/runs/<run-id>.json` of the fleet project.
+
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum RunPhase {
+	Built,
+	Uploaded,
+	Activated,
+}
+impl RunPhase {
+	pub fn name(&self) -> &'static str {
+		match self {
+			RunPhase::Built => "built",
+			RunPhase::Uploaded => "uploaded",
+			RunPhase::Activated => "activated",
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HostRunState {
+	/// Last successfully finished phase
+	pub phase: Option<RunPhase>,
+	pub built: Option<PathBuf>,
+	#[serde(default)]
+	pub failed: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct RunStateData {
+	hosts: BTreeMap<String, HostRunState>,
+}
+
+#[derive(Clone)]
+pub struct RunState {
+	path: PathBuf,
+	data: Arc<Mutex<RunStateData>>,
+}
+
+fn runs_dir(directory: &Path) -> PathBuf {
+	directory.join(".fleet/runs")
+}
+
+impl RunState {
+	pub fn new(directory: &Path, run_id: &str) -> Self {
+		Self {
+			path: runs_dir(directory).join(format!("{run_id}.json")),
+			data: Default::default(),
+		}
+	}
+	pub fn load(directory: &Path, run_id: &str) -> Result<Self> {
+		let path = runs_dir(directory).join(format!("{run_id}.json"));
+		let data = fs::read(&path).with_context(|| format!("run {run_id} not found"))?;
+		let data = serde_json::from_slice(&data).context("failed to parse run state")?;
+		Ok(Self {
+			path,
+			data: Arc::new(Mutex::new(data)),
+		})
+	}
+	/// Id of the last started run, run ids are sortable by time
+	pub fn latest_id(directory: &Path) -> Result<String> {
+		let mut ids = Vec::new();
+		for entry in fs::read_dir(runs_dir(directory)).context("no previous runs found")? {
+			let name = entry?.file_name();
+			let Some(id) = name.to_str().and_then(|n| n.strip_suffix(".json")) else {
+				continue;
+			};
+			ids.push(id.to_owned());
+		}
+		ids.sort();
+		ids.pop().ok_or_else(|| anyhow!("no previous runs found"))
+	}
+
+	pub fn host(&self, host: &str) -> Option<HostRunState> {
+		self.data.lock().unwrap().hosts.get(host).cloned()
+	}
+
+	pub fn record(&self, host: &str, phase: RunPhase, built: &Path) {
+		{
+			let mut data = self.data.lock().unwrap();
+			data.hosts.insert(
+				host.to_owned(),
+				HostRunState {
+					phase: Some(phase),
+					built: Some(built.to_owned()),
+					failed: false,
+				},
+			);
+		}
+		self.save_logged();
+	}
+	pub fn record_failure(&self, host: &str) {
+		{
+			let mut data = self.data.lock().unwrap();
+			// Host might fail before build, there is nothing to reuse, but we still want
+			// --only-failed to pick this host.
+			let state = data
+				.hosts
+				.entry(host.to_owned())
+				.or_insert_with(|| HostRunState {
+					phase: None,
+					built: None,
+					failed: false,
+				});
+			state.failed = true;
+		}
+		self.save_logged();
+	}
+
+	/// Host has succeeded on retry, `--only-failed` should no longer pick it
+	pub fn clear_failure(&self, host: &str) {
+		{
+			let mut data = self.data.lock().unwrap();
+			let Some(state) = data.hosts.get_mut(host) else {
+				return;
+			};
+			if !state.failed {
+				return;
+			}
+			state.failed = false;
+		}
+		self.save_logged();
+	}
+
+	fn save_logged(&self) {
+		if let Err(e) = self.save() {
+			warn!("failed to save run state: {e}");
+		}
+	}
+	fn save(&self) -> Result<()> {
+		let dir = self.path.parent().expect("run state is in runs dir");
+		fs::create_dir_all(dir)?;
+		let tmp = NamedTempFile::new_in(dir)?;
+		serde_json::to_writer_pretty(&tmp, &*self.data.lock().unwrap())?;
+		tmp.persist(&self.path)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,354 @@
+//! Ordering of per-host tasks, declared with `hosts.<name>.deploy.after`
+//! and `hosts.<name>.deploy.exclusiveGroups`, and limited per `hosts.<name>.deploy.labels` value
+//! with `--max-unavailable`.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt,
+	future::Future,
+	rc::Rc,
+	str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use fleet_base::host::ConfigHost;
+use futures::{future::Shared, FutureExt as _};
+use nix_eval::nix_go_json;
+use tokio::{
+	sync::{Mutex, Semaphore},
+	task::LocalSet,
+};
+use tracing::warn;
+
+struct Node {
+	host: ConfigHost,
+	after: Vec<String>,
+	groups: Vec<String>,
+	labels: BTreeMap<String, String>,
+}
+
+/// Host as it is scheduled, see [`Schedule::plan`]
+pub struct PlannedHost {
+	pub name: String,
+	/// Hosts, which should be deployed first
+	pub after: Vec<String>,
+	pub groups: Vec<String>,
+	pub labels: BTreeMap<String, String>,
+	/// Hosts of the same wave have no dependencies between them, wave 1 has no dependencies at all
+	pub wave: usize,
+}
+
+/// At most `count` hosts having the same value of `label` are running at once, `1 per zone`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaxUnavailable {
+	pub count: usize,
+	pub label: String,
+}
+impl FromStr for MaxUnavailable {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let Some((count, label)) = s.split_once(" per ") else {
+			return Err(format!("expected `<count> per <label>`, got {s:?}"));
+		};
+		let count: usize = count
+			.trim()
+			.parse()
+			.map_err(|_| format!("bad host count: {count:?}"))?;
+		if count == 0 {
+			return Err("host count should be positive".to_owned());
+		}
+		let label = label.trim();
+		if label.is_empty() || label.contains(char::is_whitespace) {
+			return Err(format!("bad label name: {label:?}"));
+		}
+		Ok(Self {
+			count,
+			label: label.to_owned(),
+		})
+	}
+}
+impl fmt::Display for MaxUnavailable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} per {}", self.count, self.label)
+	}
+}
+
+pub struct Schedule {
+	/// Topologically sorted
+	nodes: Vec<Node>,
+	/// Maximum number of concurrently running tasks
+	max_parallel: Option<usize>,
+	/// Maximum number of concurrently running tasks per label value, by label
+	max_unavailable: BTreeMap<String, usize>,
+}
+
+/// Returns host names in the order, where every host goes after its dependencies
+fn topo_sort(deps: &BTreeMap<String, Vec<String>>) -> Result<Vec<String>> {
+	#[derive(Clone, Copy, PartialEq)]
+	enum State {
+		Visiting,
+		Done,
+	}
+	fn visit<'a>(
+		name: &'a str,
+		deps: &'a BTreeMap<String, Vec<String>>,
+		state: &mut BTreeMap<&'a str, State>,
+		path: &mut Vec<&'a str>,
+		out: &mut Vec<String>,
+	) -> Result<()> {
+		match state.get(name) {
+			Some(State::Done) => return Ok(()),
+			Some(State::Visiting) => {
+				let start = path.iter().position(|p| *p == name).expect("in path");
+				let mut cycle = path[start..].to_vec();
+				cycle.push(name);
+				bail!("host dependency cycle: {}", cycle.join(" -> "));
+			}
+			None => {}
+		}
+		state.insert(name, State::Visiting);
+		path.push(name);
+		for dep in &deps[name] {
+			visit(dep, deps, state, path, out)?;
+		}
+		path.pop();
+		state.insert(name, State::Done);
+		out.push(name.to_owned());
+		Ok(())
+	}
+
+	let mut state = BTreeMap::new();
+	let mut out = Vec::new();
+	for name in deps.keys() {
+		visit(name, deps, &mut state, &mut Vec::new(), &mut out)?;
+	}
+	Ok(out)
+}
+
+impl Schedule {
+	/// Dependencies on hosts which are not in the `hosts` list (i.e skipped with --only)
+	/// are considered satisfied.
+	pub async fn new(hosts: Vec<ConfigHost>) -> Result<Self> {
+		let mut tags = BTreeMap::new();
+		for host in &hosts {
+			tags.insert(host.name.clone(), host.tags().await?);
+		}
+		let all_hosts = match hosts.first() {
+			Some(host) => host.config().list_hosts().await?,
+			None => vec![],
+		};
+		let all_names: BTreeSet<&str> = all_hosts.iter().map(|h| h.name.as_str()).collect();
+
+		let mut deps = BTreeMap::new();
+		let mut nodes = BTreeMap::new();
+		for host in hosts {
+			let deploy = host.deploy_options().await?;
+			let after_spec: Vec<String> = nix_go_json!(deploy.after);
+			let groups: Vec<String> = nix_go_json!(deploy.exclusiveGroups);
+			let labels: BTreeMap<String, String> = nix_go_json!(deploy.labels);
+			let mut after = BTreeSet::new();
+			for spec in after_spec {
+				if let Some(tag) = spec.strip_prefix('@') {
+					after.extend(
+						tags.iter()
+							.filter(|(name, tags)| **name != host.name && tags.iter().any(|t| t == tag))
+							.map(|(name, _)| name.clone()),
+					);
+				} else if !all_names.contains(spec.as_str()) {
+					bail!("host {} is ordered after unknown host {spec}", host.name);
+				} else if tags.contains_key(&spec) {
+					after.insert(spec);
+				}
+			}
+			let after: Vec<String> = after.into_iter().collect();
+			deps.insert(host.name.clone(), after.clone());
+			nodes.insert(
+				host.name.clone(),
+				Node {
+					host,
+					after,
+					groups,
+					labels,
+				},
+			);
+		}
+		let order = topo_sort(&deps)?;
+		Ok(Self {
+			nodes: order
+				.into_iter()
+				.map(|name| nodes.remove(&name).expect("sorted from nodes"))
+				.collect(),
+			max_parallel: None,
+			max_unavailable: BTreeMap::new(),
+		})
+	}
+
+	pub fn max_parallel(mut self, max_parallel: Option<usize>) -> Self {
+		self.max_parallel = max_parallel;
+		self
+	}
+
+	/// Limits are combined, the strictest limit for the same label wins. Hosts without
+	/// the label are not limited by it.
+	pub fn max_unavailable(mut self, limits: &[MaxUnavailable]) -> Self {
+		for limit in limits {
+			let count = self
+				.max_unavailable
+				.entry(limit.label.clone())
+				.or_insert(limit.count);
+			*count = (*count).min(limit.count);
+		}
+		for label in self.max_unavailable.keys() {
+			let unlabeled: Vec<&str> = self
+				.nodes
+				.iter()
+				.filter(|n| !n.labels.contains_key(label))
+				.map(|n| n.host.name.as_str())
+				.collect();
+			if !unlabeled.is_empty() {
+				warn!(
+					"hosts without label {label} are not limited by --max-unavailable: {}",
+					unlabeled.join(", ")
+				);
+			}
+		}
+		self
+	}
+
+	/// Hosts in the deployment order
+	pub fn plan(&self) -> Vec<PlannedHost> {
+		let mut waves: BTreeMap<&str, usize> = BTreeMap::new();
+		let mut out = Vec::new();
+		for node in &self.nodes {
+			// Nodes are sorted, dependencies are already processed
+			let deepest = node.after.iter().map(|d| waves[d.as_str()]).max();
+			let wave = deepest.unwrap_or(0) + 1;
+			waves.insert(&node.host.name, wave);
+			out.push(PlannedHost {
+				name: node.host.name.clone(),
+				after: node.after.clone(),
+				groups: node.groups.clone(),
+				labels: node.labels.clone(),
+				wave,
+			});
+		}
+		out
+	}
+
+	/// Spawns task for every host, task is started once all its dependencies have succeeded,
+	/// no other task from the same exclusive group is running, and the `--max-unavailable`
+	/// limits of its labels are not exhausted.
+	///
+	/// Task should return whether it has succeeded, dependents of failed task are not started.
+	pub fn spawn<F, Fut>(self, set: &LocalSet, task: F)
+	where
+		F: Fn(ConfigHost) -> Fut + 'static,
+		Fut: Future<Output = bool> + 'static,
+	{
+		let task = Rc::new(task);
+		let permits = self.max_parallel.map(|n| Rc::new(Semaphore::new(n)));
+		let mut group_locks: BTreeMap<String, Rc<Mutex<()>>> = BTreeMap::new();
+		let mut domain_permits: BTreeMap<(String, String), Rc<Semaphore>> = BTreeMap::new();
+		let mut spawned: BTreeMap<String, Shared<_>> = BTreeMap::new();
+		for node in self.nodes {
+			let deps = node
+				.after
+				.iter()
+				.map(|d| spawned.get(d).cloned().expect("dependencies are spawned first"))
+				.collect::<Vec<_>>();
+			// BTreeMap iteration is sorted, locks are always taken in the same order,
+			// thus tasks can't deadlock.
+			let locks = node
+				.groups
+				.iter()
+				.collect::<BTreeSet<_>>()
+				.into_iter()
+				.map(|g| group_locks.entry(g.clone()).or_default().clone())
+				.collect::<Vec<_>>();
+			// Same as with locks, permits are acquired in the sorted order, after the locks
+			let domains = self
+				.max_unavailable
+				.iter()
+				.filter_map(|(label, count)| {
+					let value = node.labels.get(label)?;
+					Some(
+						domain_permits
+							.entry((label.clone(), value.clone()))
+							.or_insert_with(|| Rc::new(Semaphore::new(*count)))
+							.clone(),
+					)
+				})
+				.collect::<Vec<_>>();
+			let name = node.host.name.clone();
+			let task = task.clone();
+			let permits = permits.clone();
+			let fut = async move {
+				for dep in deps {
+					if !dep.await {
+						warn!("not deploying {}: dependency has failed", node.host.name);
+						return false;
+					}
+				}
+				let mut guards = Vec::new();
+				for lock in &locks {
+					guards.push(lock.lock().await);
+				}
+				let mut domain_guards = Vec::new();
+				for domain in &domains {
+					domain_guards.push(domain.acquire().await.expect("semaphore is not closed"));
+				}
+				// Taken after group locks, so that waiting for the group doesn't occupy a slot
+				let _permit = match &permits {
+					Some(permits) => {
+						Some(permits.acquire().await.expect("semaphore is not closed"))
+					}
+					None => None,
+				};
+				task(node.host).await
+			}
+			.boxed_local()
+			.shared();
+			spawned.insert(name, fut.clone());
+			set.spawn_local(fut);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn deps(list: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+		list.iter()
+			.map(|(n, d)| (n.to_string(), d.iter().map(|d| d.to_string()).collect()))
+			.collect()
+	}
+
+	#[test]
+	fn sorted() {
+		let order = topo_sort(&deps(&[("web1", &["db1"]), ("db1", &[]), ("web2", &["db1", "web1"])]))
+			.unwrap();
+		assert_eq!(order, ["db1", "web1", "web2"]);
+	}
+
+	#[test]
+	fn max_unavailable() {
+		assert_eq!(
+			"1 per zone".parse::<MaxUnavailable>().unwrap(),
+			MaxUnavailable {
+				count: 1,
+				label: "zone".to_owned()
+			}
+		);
+		assert!("0 per zone".parse::<MaxUnavailable>().is_err());
+		assert!("1 zone".parse::<MaxUnavailable>().is_err());
+		assert!("x per zone".parse::<MaxUnavailable>().is_err());
+	}
+
+	#[test]
+	fn cycle() {
+		let err = topo_sort(&deps(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"])])).unwrap_err();
+		assert_eq!(err.to_string(), "host dependency cycle: a -> b -> c -> a");
+	}
+}
@@ -0,0 +1,226 @@
+//! Secure Boot signing of host bootables, configured with `fleet.secureBoot` in the nixos config.
+//!
+//! Bootables are signed after the build, on the deployer or on the signing host, and added to the
+//! store as a single directory in the ESP layout. It is uploaded along with the system and registered
+//! as a gc root, named after the system, from which the bootloader install hook picks signed files.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	path::{Component, Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use fleet_base::{
+	command,
+	host::{ConfigHost, Platform},
+};
+use nix_eval::nix_go_json;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::cmds::build_systems::is_valid_path;
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+enum SignTool {
+	Sbctl,
+	Sbsign,
+}
+
+/// Tied to secure-boot.nix
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SecureBootSettings {
+	enable: bool,
+	sign_on: Option<String>,
+	tool: SignTool,
+	key: Option<String>,
+	certificate: Option<String>,
+	#[serde(default)]
+	bootables: BTreeMap<String, PathBuf>,
+	roots_dir: String,
+}
+
+/// `/nix/store/<hash>-<name>` of a file inside of the store path
+fn store_path_of(path: &Path) -> Result<PathBuf> {
+	let out: PathBuf = path.components().take(4).collect();
+	ensure!(
+		out.starts_with("/nix/store") && out.components().count() == 4,
+		"{} is not in the nix store",
+		path.display()
+	);
+	Ok(out)
+}
+
+/// ESP-relative paths are joined to the signed directory, they shouldn't escape it
+fn check_install_path(path: &str) -> Result<()> {
+	ensure!(
+		Path::new(path)
+			.components()
+			.all(|c| matches!(c, Component::Normal(_))),
+		"bootable install path {path:?} should be relative, without .."
+	);
+	Ok(())
+}
+
+impl SecureBootSettings {
+	/// `None` if signing is not enabled for the host
+	pub async fn for_host(host: &ConfigHost) -> Result<Option<Self>> {
+		if host.platform().await? != Platform::Nixos {
+			return Ok(None);
+		}
+		let nixos = host.nixos_config().await?;
+		let settings: Self = nix_go_json!(nixos.fleet.secureBoot);
+		Ok(settings.enable.then_some(settings))
+	}
+
+	/// Signs bootables of the built system, returns store path of the signed directory,
+	/// which is available in the local store.
+	///
+	/// Bootables, which are not in the local store (i.e the system was built on the target),
+	/// are substituted on the signing host, they are never taken from the target itself.
+	pub async fn sign(&self, host: &ConfigHost) -> Result<Option<PathBuf>> {
+		if self.bootables.is_empty() {
+			return Ok(None);
+		}
+		for install_path in self.bootables.keys() {
+			check_install_path(install_path)?;
+		}
+		let config = host.config();
+		let signer = match &self.sign_on {
+			Some(name) => config.host(name).await?,
+			None => config.local_host(),
+		};
+		if command::is_dry_run() {
+			info!(
+				"dry-run, would sign {} on {} with {:?}",
+				self.bootables
+					.keys()
+					.cloned()
+					.collect::<Vec<_>>()
+					.join(", "),
+				signer.name,
+				self.tool
+			);
+			return Ok(None);
+		}
+		info!("signing bootables on {} with {:?}", signer.name, self.tool);
+
+		let sources = self
+			.bootables
+			.values()
+			.map(|p| store_path_of(p))
+			.collect::<Result<BTreeSet<_>>>()?;
+		let local_host = config.local_host();
+		for source in &sources {
+			if is_valid_path(&local_host, source).await {
+				signer.remote_derivation(source, false).await?;
+				continue;
+			}
+			let mut realise = signer.nix_cmd().await?;
+			realise.arg("build").arg("--no-link").arg(source);
+			realise.retry_transient().run_nix().await.with_context(|| {
+				format!(
+					"{} is not in the local store, and can't be substituted on {}",
+					source.display(),
+					signer.name
+				)
+			})?;
+		}
+
+		let dir = signer.mktemp_dir().await?;
+		let out = format!("{dir}/signed");
+		for (install_path, source) in &self.bootables {
+			let target = format!("{out}/{install_path}");
+			let parent = Path::new(&target)
+				.parent()
+				.expect("install path is not empty");
+			let mut mkdir = signer.cmd("mkdir").await?;
+			mkdir.arg("-p").arg(parent);
+			mkdir.run().await?;
+
+			let sign = match self.tool {
+				SignTool::Sbctl => {
+					let mut cmd = signer.cmd("sbctl").await?;
+					cmd.arg("sign").comparg("--output", &target).arg(source);
+					// Key database is only readable by root
+					cmd.sudo()
+				}
+				SignTool::Sbsign => {
+					let mut cmd = signer.cmd("sbsign").await?;
+					cmd.comparg("--key", self.key.as_ref().context("sbsign needs key")?)
+						.comparg(
+							"--cert",
+							self.certificate
+								.as_ref()
+								.context("sbsign needs certificate")?,
+						)
+						.comparg("--output", &target)
+						.arg(source);
+					cmd
+				}
+			};
+			sign.retry_transient()
+				.run()
+				.await
+				.with_context(|| format!("failed to sign {}", source.display()))?;
+		}
+		// sbctl leaves files owned by root
+		let mut chmod = signer.cmd("chmod").await?;
+		chmod.arg("-R").arg("a+rX").arg(&out);
+		chmod.sudo().run().await?;
+
+		let name = format!("{}-secure-boot", host.name);
+		let mut add = signer.nix_cmd().await?;
+		add.arg("store")
+			.arg("add-path")
+			.comparg("--name", &name)
+			.arg(&out);
+		let signed = PathBuf::from(add.retry_transient().run_nix_string().await?.trim());
+		ensure!(
+			signed.starts_with("/nix/store"),
+			"nix store add-path returned unexpected path: {}",
+			signed.display()
+		);
+		let mut cleanup = signer.cmd("rm").await?;
+		cleanup.arg("-rf").arg(&dir);
+		cleanup.sudo().run().await?;
+
+		signer.fetch_derivation(&signed).await?;
+		info!("bootables are signed: {}", signed.display());
+		Ok(Some(signed))
+	}
+
+	/// Registers uploaded signed bootables as the gc root, which is picked by the bootloader install hook
+	pub async fn register(&self, host: &ConfigHost, built: &Path, signed: &Path) -> Result<()> {
+		let system = built
+			.file_name()
+			.context("system path has no name")?
+			.to_string_lossy();
+		let mut mkdir = host.cmd("mkdir").await?;
+		mkdir.arg("-p").arg(&self.roots_dir);
+		mkdir.sudo().run().await?;
+		let mut root = host.cmd("nix-store").await?;
+		root.arg("--realise")
+			.comparg("--add-root", format!("{}/{system}", self.roots_dir))
+			.arg(signed);
+		root.sudo().run().await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn store_paths() {
+		assert_eq!(
+			store_path_of(Path::new("/nix/store/abc-linux-6.6/bzImage")).unwrap(),
+			Path::new("/nix/store/abc-linux-6.6")
+		);
+		assert!(store_path_of(Path::new("/boot/bzImage")).is_err());
+		assert!(check_install_path("EFI/nixos/x.efi").is_ok());
+		assert!(check_install_path("../x.efi").is_err());
+		assert!(check_install_path("/EFI/x.efi").is_err());
+	}
+}
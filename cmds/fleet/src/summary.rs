@@ -0,0 +1,138 @@
+//! Per-host summary, printed at the end of `fleet build-systems` and `fleet deploy`.
+
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use tabled::{Table, Tabled};
+use tracing::{info, warn};
+
+#[derive(Default)]
+struct HostSummary {
+	/// Last successfully finished phase
+	phase: Option<&'static str>,
+	duration: Option<Duration>,
+	/// System, which was active before the run
+	old: Option<PathBuf>,
+	new: Option<PathBuf>,
+	/// Same values as in [`crate::telemetry::Telemetry::record_outcome`]
+	outcome: Option<&'static str>,
+}
+
+#[derive(Tabled)]
+struct SummaryDisplay {
+	#[tabled(rename = "Host")]
+	host: String,
+	#[tabled(rename = "Outcome")]
+	outcome: String,
+	#[tabled(rename = "Phase")]
+	phase: String,
+	#[tabled(rename = "Duration")]
+	duration: String,
+	#[tabled(rename = "System")]
+	system: String,
+	#[tabled(rename = "Rolled back")]
+	rolled_back: bool,
+}
+
+fn display_path(path: Option<&Path>) -> String {
+	path.and_then(|p| p.file_name())
+		.map_or("?".to_owned(), |n| n.to_string_lossy().into_owned())
+}
+
+fn display_system(summary: &HostSummary) -> String {
+	match (&summary.old, &summary.new) {
+		(_, None) => String::new(),
+		(Some(old), Some(new)) if old == new => {
+			format!("{} (unchanged)", display_path(Some(new)))
+		}
+		(None, Some(new)) => display_path(Some(new)),
+		(old, Some(new)) => format!(
+			"{} -> {}",
+			display_path(old.as_deref()),
+			display_path(Some(new))
+		),
+	}
+}
+
+/// Shared summary collector, cheap to clone into per-host tasks.
+#[derive(Clone, Default)]
+pub struct RunSummary(Arc<Mutex<BTreeMap<String, HostSummary>>>);
+
+impl RunSummary {
+	fn with_host(&self, host: &str, f: impl FnOnce(&mut HostSummary)) {
+		let mut hosts = self.0.lock().unwrap();
+		f(hosts.entry(host.to_owned()).or_default())
+	}
+	pub fn record_old(&self, host: &str, old: PathBuf) {
+		self.with_host(host, |s| s.old = Some(old))
+	}
+	pub fn record_new(&self, host: &str, new: PathBuf) {
+		self.with_host(host, |s| s.new = Some(new))
+	}
+	pub fn record_phase(&self, host: &str, phase: &'static str) {
+		self.with_host(host, |s| s.phase = Some(phase))
+	}
+	pub fn record_outcome(&self, host: &str, outcome: &'static str, started: Instant) {
+		let elapsed = started.elapsed();
+		self.with_host(host, |s| {
+			s.outcome = Some(outcome);
+			s.duration = Some(elapsed);
+		})
+	}
+
+	/// Hosts, which were not processed successfully
+	pub fn failed(&self) -> Vec<String> {
+		self.0
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|(_, s)| s.outcome != Some("success"))
+			.map(|(host, _)| host.clone())
+			.collect()
+	}
+
+	/// Table of hosts, followed by outcome counts, `None` if no hosts were processed
+	pub fn render(&self) -> Option<String> {
+		let hosts = self.0.lock().unwrap();
+		if hosts.is_empty() {
+			return None;
+		}
+		let mut counts = BTreeMap::<&str, usize>::new();
+		let mut table = Vec::new();
+		for (host, summary) in hosts.iter() {
+			let outcome = summary.outcome.unwrap_or("unknown");
+			*counts.entry(outcome).or_default() += 1;
+			table.push(SummaryDisplay {
+				host: host.clone(),
+				outcome: outcome.to_owned(),
+				phase: summary.phase.unwrap_or("none").to_owned(),
+				duration: summary
+					.duration
+					.map_or(String::new(), |d| format!("{}s", d.as_secs())),
+				system: display_system(summary),
+				rolled_back: summary.outcome == Some("rolled_back"),
+			});
+		}
+		let counts = counts
+			.iter()
+			.map(|(outcome, count)| format!("{count} {outcome}"))
+			.collect::<Vec<_>>()
+			.join(", ");
+		Some(format!("{}\n{counts}", Table::new(table)))
+	}
+
+	pub fn print(&self) {
+		let Some(rendered) = self.render() else {
+			return;
+		};
+		if self.failed().is_empty() {
+			info!("summary\n{rendered}");
+		} else {
+			warn!("summary\n{rendered}");
+		}
+	}
+}
@@ -0,0 +1,166 @@
+//! Deployment metrics export.
+//!
+//! Metrics are collected in-process during the run, and pushed once at the end,
+//! so that a dashboard gets a single consistent sample per deployment.
+
+use std::{
+	collections::BTreeMap,
+	fmt::Write as _,
+	io::Write as _,
+	path::Path,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use fleet_base::host::Config;
+use tempfile::NamedTempFile;
+use tracing::info;
+
+#[derive(Parser, Clone)]
+pub struct TelemetryOpts {
+	/// Push deployment metrics to Prometheus Pushgateway at this url after run
+	#[clap(long, env = "FLEET_PUSHGATEWAY")]
+	pushgateway: Option<String>,
+	/// Job label to use for pushed metrics
+	#[clap(long, default_value = "fleet")]
+	pushgateway_job: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+	Build,
+	Copy,
+	Activate,
+	Reboot,
+}
+impl Phase {
+	fn name(&self) -> &'static str {
+		match self {
+			Phase::Build => "build",
+			Phase::Copy => "copy",
+			Phase::Activate => "activate",
+			Phase::Reboot => "reboot",
+		}
+	}
+}
+
+#[derive(Default)]
+struct HostMetrics {
+	phases: BTreeMap<Phase, Duration>,
+	closure_size: Option<u64>,
+	outcome: Option<&'static str>,
+}
+
+/// Shared metrics collector, cheap to clone into per-host tasks.
+#[derive(Clone, Default)]
+pub struct Telemetry(Arc<Mutex<BTreeMap<String, HostMetrics>>>);
+
+impl Telemetry {
+	fn with_host(&self, host: &str, f: impl FnOnce(&mut HostMetrics)) {
+		let mut hosts = self.0.lock().unwrap();
+		f(hosts.entry(host.to_owned()).or_default())
+	}
+	pub fn record_phase(&self, host: &str, phase: Phase, started: Instant) {
+		let elapsed = started.elapsed();
+		self.with_host(host, |m| {
+			m.phases.insert(phase, elapsed);
+		})
+	}
+	pub fn record_closure_size(&self, host: &str, size: u64) {
+		self.with_host(host, |m| m.closure_size = Some(size))
+	}
+	/// Outcome is one of "success", "failed", "rolled_back", "cancelled"
+	pub fn record_outcome(&self, host: &str, outcome: &'static str) {
+		self.with_host(host, |m| m.outcome = Some(outcome))
+	}
+
+	/// Render collected metrics in prometheus text exposition format
+	pub fn render(&self) -> String {
+		let hosts = self.0.lock().unwrap();
+		let mut out = String::new();
+
+		out.push_str("# TYPE fleet_deploy_phase_duration_seconds gauge\n");
+		for (host, m) in hosts.iter() {
+			for (phase, duration) in &m.phases {
+				let _ = writeln!(
+					out,
+					"fleet_deploy_phase_duration_seconds{{host=\"{host}\",phase=\"{}\"}} {}",
+					phase.name(),
+					duration.as_secs_f64(),
+				);
+			}
+		}
+
+		out.push_str("# TYPE fleet_deploy_closure_size_bytes gauge\n");
+		for (host, m) in hosts.iter() {
+			if let Some(size) = m.closure_size {
+				let _ = writeln!(
+					out,
+					"fleet_deploy_closure_size_bytes{{host=\"{host}\"}} {size}"
+				);
+			}
+		}
+
+		out.push_str("# TYPE fleet_deploy_hosts_total gauge\n");
+		let mut outcomes = <BTreeMap<&str, u64>>::new();
+		for m in hosts.values() {
+			if let Some(outcome) = m.outcome {
+				*outcomes.entry(outcome).or_default() += 1;
+			}
+		}
+		for outcome in ["success", "failed", "rolled_back"] {
+			let _ = writeln!(
+				out,
+				"fleet_deploy_hosts_total{{outcome=\"{outcome}\"}} {}",
+				outcomes.get(outcome).copied().unwrap_or(0)
+			);
+		}
+
+		out
+	}
+
+	pub async fn push(&self, config: &Config, opts: &TelemetryOpts) -> Result<()> {
+		let Some(gateway) = &opts.pushgateway else {
+			return Ok(());
+		};
+		info!("pushing deployment metrics");
+		let mut body = NamedTempFile::new()?;
+		body.write_all(self.render().as_bytes())?;
+		body.flush()?;
+
+		let mut cmd = config.local_host().cmd("curl").await?;
+		cmd.arg("--fail")
+			.arg("--silent")
+			.arg("--show-error")
+			.comparg("--data-binary", at_path(body.path()))
+			.arg(format!(
+				"{}/metrics/job/{}",
+				gateway.trim_end_matches('/'),
+				opts.pushgateway_job
+			));
+		cmd.run().await.context("pushgateway upload")
+	}
+}
+
+fn at_path(path: &Path) -> String {
+	format!("@{}", path.display())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn render_outcomes() {
+		let telemetry = Telemetry::default();
+		telemetry.record_outcome("a", "success");
+		telemetry.record_outcome("b", "rolled_back");
+		telemetry.record_closure_size("a", 1024);
+		let rendered = telemetry.render();
+		assert!(rendered.contains("fleet_deploy_hosts_total{outcome=\"success\"} 1\n"));
+		assert!(rendered.contains("fleet_deploy_hosts_total{outcome=\"failed\"} 0\n"));
+		assert!(rendered.contains("fleet_deploy_closure_size_bytes{host=\"a\"} 1024\n"));
+	}
+}
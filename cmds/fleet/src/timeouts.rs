@@ -0,0 +1,139 @@
+//! Per-phase deployment timeouts, declared in `hosts.<name>.deploy.timeouts`,
+//! and overridable for all hosts with `--build-timeout`/`--copy-timeout`/`--activation-timeout`/
+//! `--health-check-timeout`.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use fleet_base::host::ConfigHost;
+use nix_eval::nix_go_json;
+use serde::Deserialize;
+
+/// Parses duration in form of `90s`, `5m`, `1h30m`, number without unit is seconds
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+	let input = input.trim();
+	if let Ok(secs) = input.parse::<u64>() {
+		return Ok(Duration::from_secs(secs));
+	}
+	let mut total = 0u64;
+	let mut rest = input;
+	while !rest.is_empty() {
+		let digits = rest
+			.find(|c: char| !c.is_ascii_digit())
+			.ok_or_else(|| format!("missing unit in {input:?}"))?;
+		if digits == 0 {
+			return Err(format!("expected number in {input:?}"));
+		}
+		let value: u64 = rest[..digits]
+			.parse()
+			.map_err(|e| format!("bad number in {input:?}: {e}"))?;
+		rest = &rest[digits..];
+		let unit_len = rest
+			.find(|c: char| c.is_ascii_digit())
+			.unwrap_or(rest.len());
+		let multiplier = match &rest[..unit_len] {
+			"s" => 1,
+			"m" | "min" => 60,
+			"h" => 60 * 60,
+			"d" => 24 * 60 * 60,
+			unit => return Err(format!("unknown unit {unit:?} in {input:?}")),
+		};
+		rest = &rest[unit_len..];
+		total = value
+			.checked_mul(multiplier)
+			.and_then(|v| total.checked_add(v))
+			.ok_or_else(|| format!("duration {input:?} is too long"))?;
+	}
+	if total == 0 {
+		return Err("duration should not be zero".to_owned());
+	}
+	Ok(Duration::from_secs(total))
+}
+
+#[derive(Parser, Clone)]
+pub struct TimeoutOpts {
+	/// Maximum time to build host system, i.e 30m.
+	/// Overrides `hosts.<name>.deploy.timeouts.build`
+	#[clap(long, value_parser = parse_duration)]
+	build_timeout: Option<Duration>,
+	/// Maximum time to upload host system, overrides both the timeout estimated from
+	/// `fleet probe` results and `hosts.<name>.deploy.timeouts.copy`
+	#[clap(long, value_parser = parse_duration)]
+	copy_timeout: Option<Duration>,
+	/// Maximum time for host activation, after which the host is considered failed, and is rolled back.
+	/// Overrides `hosts.<name>.deploy.timeouts.activation`
+	#[clap(long, value_parser = parse_duration)]
+	activation_timeout: Option<Duration>,
+	/// Maximum time for the units started by activation to settle, after which the host
+	/// is considered failed, and is rolled back.
+	/// Overrides `hosts.<name>.deploy.timeouts.healthCheck`
+	#[clap(long, value_parser = parse_duration)]
+	health_check_timeout: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimeoutsConfig {
+	build: Option<String>,
+	copy: Option<String>,
+	activation: Option<String>,
+	health_check: Option<String>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct PhaseTimeouts {
+	pub build: Option<Duration>,
+	pub copy: Option<Duration>,
+	pub activation: Option<Duration>,
+	pub health_check: Option<Duration>,
+}
+
+fn parse_configured(host: &ConfigHost, phase: &str, value: Option<String>) -> Result<Option<Duration>> {
+	value
+		.map(|v| parse_duration(&v).map_err(anyhow::Error::msg))
+		.transpose()
+		.with_context(|| format!("invalid hosts.{}.deploy.timeouts.{phase}", host.name))
+}
+
+impl TimeoutOpts {
+	pub async fn for_host(&self, host: &ConfigHost) -> Result<PhaseTimeouts> {
+		let deploy = host.deploy_options().await?;
+		let config: TimeoutsConfig = nix_go_json!(deploy.timeouts);
+		Ok(PhaseTimeouts {
+			build: match self.build_timeout {
+				Some(t) => Some(t),
+				None => parse_configured(host, "build", config.build)?,
+			},
+			copy: match self.copy_timeout {
+				Some(t) => Some(t),
+				None => parse_configured(host, "copy", config.copy)?,
+			},
+			activation: match self.activation_timeout {
+				Some(t) => Some(t),
+				None => parse_configured(host, "activation", config.activation)?,
+			},
+			health_check: match self.health_check_timeout {
+				Some(t) => Some(t),
+				None => parse_configured(host, "healthCheck", config.health_check)?,
+			},
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn durations() {
+		assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+		assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+		assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+		assert_eq!(parse_duration("2min10s"), Ok(Duration::from_secs(130)));
+		assert!(parse_duration("5x").is_err());
+		assert!(parse_duration("m").is_err());
+		assert!(parse_duration("0s").is_err());
+		assert!(parse_duration("99999999999999999d").is_err());
+	}
+}
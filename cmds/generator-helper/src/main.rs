@@ -71,7 +71,32 @@ fn write_private(
 	Ok(())
 }
 
-type Identities = Vec<SshRecipient>;
+/// Host ssh key, or age recipient of a team member, allowed to read the secret
+#[derive(Clone)]
+enum AnyRecipient {
+	Ssh(SshRecipient),
+	Age(age::x25519::Recipient),
+}
+impl AnyRecipient {
+	fn parse(key: &str) -> Result<Self> {
+		if key.starts_with("age1") {
+			let recipient = age::x25519::Recipient::from_str(key)
+				.map_err(|e| anyhow!("parse recipient {key:?}: {e}"))?;
+			return Ok(Self::Age(recipient));
+		}
+		let recipient = SshRecipient::from_str(key)
+			.map_err(|e: ParseRecipientKeyError| anyhow!("parse recipient {key:?}: {e:?}"))?;
+		Ok(Self::Ssh(recipient))
+	}
+	fn boxed(&self) -> Box<dyn Recipient + Send> {
+		match self {
+			Self::Ssh(r) => Box::new(r.clone()),
+			Self::Age(r) => Box::new(r.clone()),
+		}
+	}
+}
+
+type Identities = Vec<AnyRecipient>;
 fn load_identities() -> Result<Identities> {
 	let list = env::var("GENERATOR_HELPER_IDENTITIES");
 	let list = match list {
@@ -83,21 +108,11 @@ fn load_identities() -> Result<Identities> {
 	};
 	let list = list.trim();
 	ensure!(!list.is_empty(), "no identities passed, can't encrypt data");
-	list.lines()
-		.map(age::ssh::Recipient::from_str)
-		.collect::<Result<Identities, ParseRecipientKeyError>>()
-		.map_err(|e| anyhow!("parse recipients: {e:?}"))
+	list.lines().map(AnyRecipient::parse).collect()
 }
 fn make_encryptor(r: &Identities) -> Result<Encryptor> {
-	Ok(Encryptor::with_recipients(
-		r.iter()
-			.map(|v| {
-				let coerced: Box<dyn Recipient + Send> = Box::new(v.clone());
-				coerced
-			})
-			.collect(),
-	)
-	.expect("list is not empty"))
+	Ok(Encryptor::with_recipients(r.iter().map(AnyRecipient::boxed).collect())
+		.expect("list is not empty"))
 }
 fn wrap_encoder<'t>(w: impl Write + 't, encoding: OutputEncoding) -> impl Write + 't {
 	fn coerce<'t>(w: impl Write + 't) -> Box<dyn Write + 't> {
@@ -0,0 +1,137 @@
+//! Environment files, rendered from installed secret parts, see nixos/env-files.nix.
+//!
+//! Values are read from the stable paths of already decrypted parts, so the files are rendered
+//! after secrets are installed, and units using them are restarted only if the content has changed.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs::{self, File},
+	io::Write,
+	os::unix::prelude::PermissionsExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use fleet_shared::secret_bytes::SecretBytes;
+use nix::unistd::{chown, Group, User};
+use serde::Deserialize;
+use tracing::{error, info_span};
+
+/// Tied to nixos/secrets.nix
+pub const ENV_FILES_PATH: &str = "/etc/fleet/env-files.json";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", untagged)]
+enum Variable {
+	Value { value: String },
+	Part { path: PathBuf },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EnvFile {
+	path: PathBuf,
+	owner: String,
+	group: String,
+	mode: String,
+	#[serde(default)]
+	restart_units: Vec<String>,
+	variables: BTreeMap<String, Variable>,
+}
+
+/// Double-quoted value in the systemd EnvironmentFile syntax, newlines are kept as is.
+/// Escaped characters are ascii, so escaping bytes keeps utf-8 valid.
+fn quote(value: &[u8], out: &mut SecretBytes) {
+	out.extend_from_slice(b"\"");
+	for c in value {
+		if matches!(c, b'"' | b'\\' | b'$' | b'`') {
+			out.extend_from_slice(b"\\");
+		}
+		out.extend_from_slice(&[*c]);
+	}
+	out.extend_from_slice(b"\"");
+}
+
+fn render(file: &EnvFile) -> Result<SecretBytes> {
+	let mut out = SecretBytes::new();
+	for (name, variable) in &file.variables {
+		let part;
+		let value = match variable {
+			Variable::Value { value } => value.as_bytes(),
+			Variable::Part { path } => {
+				part = File::open(path)
+					.and_then(SecretBytes::read_from)
+					.with_context(|| format!("failed to read {} for {name}", path.display()))?;
+				let value = part
+					.expose_str()
+					.map_err(|_| anyhow!("value of {name} is not utf-8"))?
+					.as_bytes();
+				// Most secrets are generated with trailing newline, which is never wanted in the variable
+				value.strip_suffix(b"\n").unwrap_or(value)
+			}
+		};
+		out.extend_from_slice(name.as_bytes());
+		out.extend_from_slice(b"=");
+		quote(value, &mut out);
+		out.extend_from_slice(b"\n");
+	}
+	Ok(out)
+}
+
+/// Returns whether the file was changed
+fn write(file: &EnvFile, content: &SecretBytes) -> Result<bool> {
+	let current = File::open(&file.path).and_then(SecretBytes::read_from);
+	if current.is_ok_and(|current| current.expose() == content.expose()) {
+		return Ok(false);
+	}
+	let dir = file.path.parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	// NamedTempFile is created with 0600 mode, content is not readable until the mode is applied
+	let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+	temp.write_all(content.expose())?;
+	temp.flush()?;
+	let mode = u32::from_str_radix(&file.mode, 8).context("failed to parse mode as octal")?;
+	fs::set_permissions(temp.path(), fs::Permissions::from_mode(mode))?;
+	let user = User::from_name(&file.owner)
+		.context("failed to get user")?
+		.ok_or_else(|| anyhow!("user not found"))?;
+	let group = Group::from_name(&file.group)
+		.context("failed to get group")?
+		.ok_or_else(|| anyhow!("group not found"))?;
+	chown(temp.path(), Some(user.uid), Some(group.gid)).context("failed to apply user/group")?;
+	temp.persist(&file.path).context("env file persist")?;
+	Ok(true)
+}
+
+/// Renders all env files, returning units to restart, and whether some files have failed
+pub fn install(spec: &Path) -> Result<(BTreeSet<String>, bool)> {
+	let data = fs::read(spec).context("failed to read env files specification")?;
+	let files: BTreeMap<String, EnvFile> =
+		serde_json::from_slice(&data).context("failed to parse env files specification")?;
+	let mut restart_units = BTreeSet::new();
+	let mut failed = false;
+	for (name, file) in files {
+		let _span = info_span!("env file", name = name).entered();
+		match render(&file).and_then(|content| write(&file, &content)) {
+			Ok(true) => restart_units.extend(file.restart_units),
+			Ok(false) => {}
+			Err(e) => {
+				error!("env file failed to render: {e:#}");
+				failed = true;
+			}
+		}
+	}
+	Ok((restart_units, failed))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn quoting() {
+		let mut out = SecretBytes::new();
+		quote(b"a\"b\\c$d`e\nf", &mut out);
+		assert_eq!(out.expose(), b"\"a\\\"b\\\\c\\$d\\`e\nf\"");
+	}
+}
@@ -1,14 +1,15 @@
 use std::{
-	collections::{BTreeMap, HashMap},
+	collections::{BTreeMap, BTreeSet, HashMap},
 	fs::{self, File},
 	io::{self, Cursor, Read, Write},
-	iter,
 	os::unix::prelude::PermissionsExt,
 	path::{Path, PathBuf},
+	process::{Command, Stdio},
 	str::{from_utf8, FromStr},
 };
 
 use age::{
+	secrecy::ExposeSecret as _,
 	ssh::{Identity as SshIdentity, Recipient as SshRecipient},
 	Decryptor, Encryptor, Identity, Recipient,
 };
@@ -17,14 +18,39 @@ use clap::Parser;
 use fleet_shared::SecretData;
 use nix::unistd::{chown, Group, User};
 use serde::Deserialize;
-use tracing::{error, info_span};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, info_span, warn};
 use tracing_subscriber::{filter::LevelFilter, EnvFilter};
 
+mod env_files;
+
 #[derive(Parser)]
 #[clap(author)]
 enum Opts {
 	/// Install secrets from json specification
-	Install { data: PathBuf },
+	Install {
+		data: PathBuf,
+		/// Reinstall all secrets, even if they are unchanged since the last installation
+		#[clap(long)]
+		force: bool,
+		/// Take encrypted secret data from the provisioned material, instead of specification
+		#[clap(long)]
+		material: Option<PathBuf>,
+		/// Render environment files from this specification after the secrets are installed
+		#[clap(long)]
+		env_files: Option<PathBuf>,
+	},
+	/// Store encrypted material of ephemeral secrets, pushed by `fleet secret reprovision`
+	///
+	/// Material is read from stdin, one `<secret>/<part>=<fleet encoded string>` per line
+	Provision {
+		#[clap(long, default_value = DEFAULT_MATERIAL_PATH)]
+		material: PathBuf,
+		/// Install secrets from this specification after storing the material, if it exists.
+		/// Otherwise secrets are installed on the next activation
+		#[clap(long)]
+		data: Option<PathBuf>,
+	},
 	/// Reencrypt secret using host key, outputting in fleet encoded string
 	Reencrypt {
 		#[clap(long)]
@@ -40,6 +66,12 @@ enum Opts {
 		#[clap(long)]
 		plaintext: bool,
 	},
+	/// Generate host encryption key sealed to the TPM, outputting its age recipient
+	EnrollTpm {
+		/// TPM PCRs the key is bound to, in systemd-cryptenroll format
+		#[clap(long, default_value = "7")]
+		pcrs: String,
+	},
 }
 
 #[derive(Deserialize)]
@@ -48,6 +80,12 @@ struct Part {
 	raw: SecretData,
 	path: PathBuf,
 	stable_path: PathBuf,
+	/// Additional location of the decrypted part
+	target: Option<PathBuf>,
+	// Overrides for secret-wide ownership
+	mode: Option<String>,
+	owner: Option<String>,
+	group: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -57,14 +95,111 @@ struct DataItem {
 	mode: String,
 	owner: String,
 	root_path: Option<PathBuf>,
+	#[serde(default)]
+	restart_units: Vec<String>,
+	#[serde(default)]
+	reload_units: Vec<String>,
 
 	#[serde(flatten)]
 	parts: BTreeMap<String, Part>,
 }
 
-type Data = HashMap<String, DataItem>;
+type Data = HashMap<String, serde_json::Value>;
+
+/// Tied to nixos/secrets.nix
+const DEFAULT_MATERIAL_PATH: &str = "/var/lib/fleet/secrets.json";
+/// Encrypted data of ephemeral secret parts, by secret and part name.
+type Material = BTreeMap<String, BTreeMap<String, SecretData>>;
 
-fn decrypt(input: &SecretData, identity: &dyn Identity) -> Result<Vec<u8>> {
+fn read_material(path: &Path) -> Result<Material> {
+	let data = fs::read(path).context("failed to read provisioned material")?;
+	serde_json::from_slice(&data).context("failed to parse provisioned material")
+}
+fn write_material(path: &Path, material: &Material) -> Result<()> {
+	let dir = path.parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+	// NamedTempFile is created with 0600 mode
+	let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+	serde_json::to_writer(&mut temp, material)?;
+	temp.persist(path).context("material persist")?;
+	Ok(())
+}
+fn parse_material(parts: &str) -> Result<Material> {
+	let mut material = Material::new();
+	for part in parts.lines().filter(|l| !l.is_empty()) {
+		let (id, data) = part
+			.split_once('=')
+			.ok_or_else(|| anyhow!("expected <secret>/<part>=<data>, got {part:?}"))?;
+		let (secret, part) = id
+			.split_once('/')
+			.ok_or_else(|| anyhow!("expected <secret>/<part>, got {id:?}"))?;
+		let data: SecretData = data
+			.parse()
+			.map_err(|e| anyhow!("failed to parse {id}: {e}"))?;
+		material
+			.entry(secret.to_owned())
+			.or_default()
+			.insert(part.to_owned(), data);
+	}
+	Ok(material)
+}
+/// Ephemeral secret specification has no encrypted data, it is kept separately
+fn fill_material(name: &str, value: &mut serde_json::Value, material: &Material) -> Result<()> {
+	let parts = material.get(name).ok_or_else(|| {
+		anyhow!("no material is provisioned for this secret, run `fleet secret reprovision`")
+	})?;
+	for (part, raw) in parts {
+		if let Some(part) = value.get_mut(part).and_then(|p| p.as_object_mut()) {
+			part.insert("raw".to_owned(), serde_json::to_value(raw)?);
+		}
+	}
+	Ok(())
+}
+
+/// Hashes of the secrets installed during the previous run, stored on tmpfs together
+/// with the secrets themselves, so that it is reset on reboot.
+const STATE_PATH: &str = "/run/secrets/.fleet-state.json";
+type State = BTreeMap<String, String>;
+
+fn read_state() -> State {
+	let Ok(data) = fs::read(STATE_PATH) else {
+		return State::new();
+	};
+	match serde_json::from_slice(&data) {
+		Ok(v) => v,
+		Err(e) => {
+			error!("failed to parse installed secrets state, reinstalling everything: {e}");
+			State::new()
+		}
+	}
+}
+fn write_state(state: &State) -> Result<()> {
+	let mut temp = tempfile::NamedTempFile::new_in("/run/secrets")?;
+	serde_json::to_writer(&mut temp, state)?;
+	temp.persist(STATE_PATH).context("state persist")?;
+	Ok(())
+}
+
+/// Hash of everything affecting secret files: ciphertext, recipients (embedded into ciphertext),
+/// paths and ownership.
+fn secret_hash(value: &serde_json::Value) -> String {
+	let mut hasher = Sha256::new();
+	hasher.update(value.to_string().as_bytes());
+	hex::encode(hasher.finalize())
+}
+
+fn is_installed(item: &DataItem) -> bool {
+	item.parts
+		.values()
+		.all(|p| {
+			p.path.exists()
+				&& p.stable_path.exists()
+				&& p.target.as_ref().map_or(true, |t| t.exists())
+		})
+}
+
+fn decrypt(input: &SecretData, identities: &[Box<dyn Identity>]) -> Result<Vec<u8>> {
 	ensure!(input.encrypted, "passed data is not encrypted!");
 	let mut input = Cursor::new(&input.data);
 	let decryptor = Decryptor::new(&mut input).context("failed to init decryptor")?;
@@ -73,7 +208,7 @@ fn decrypt(input: &SecretData, identity: &dyn Identity) -> Result<Vec<u8>> {
 		Decryptor::Passphrase(_) => bail!("should be recipients"),
 	};
 	let mut decryptor = decryptor
-		.decrypt(iter::once(identity as &dyn age::Identity))
+		.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
 		.context("failed to decrypt, wrong key?")?;
 
 	let mut decrypted = Vec::new();
@@ -82,17 +217,22 @@ fn decrypt(input: &SecretData, identity: &dyn Identity) -> Result<Vec<u8>> {
 		.context("failed to decrypt")?;
 	Ok(decrypted)
 }
+/// Targets are host ssh keys, and keys of team members, which might also be age recipients
 fn encrypt(input: &[u8], targets: Vec<String>) -> Result<SecretData> {
 	let recipients = targets
 		.into_iter()
-		.map(|t| {
-			SshRecipient::from_str(&t).map_err(|e| anyhow!("failed to parse recipient: {e:?}"))
+		.map(|t| -> Result<Box<dyn Recipient + Send>> {
+			if t.starts_with("age1") {
+				let recipient = age::x25519::Recipient::from_str(&t)
+					.map_err(|e| anyhow!("failed to parse recipient: {e}"))?;
+				Ok(Box::new(recipient))
+			} else {
+				let recipient = SshRecipient::from_str(&t)
+					.map_err(|e| anyhow!("failed to parse recipient: {e:?}"))?;
+				Ok(Box::new(recipient))
+			}
 		})
-		.collect::<Result<Vec<SshRecipient>>>()?;
-	let recipients = recipients
-		.into_iter()
-		.map(|v| Box::new(v) as Box<dyn Recipient + Send>)
-		.collect::<Vec<_>>();
+		.collect::<Result<Vec<_>>>()?;
 	let mut encrypted = vec![];
 	let mut encryptor = Encryptor::with_recipients(recipients)
 		.expect("recipients provided")
@@ -106,7 +246,7 @@ fn encrypt(input: &[u8], targets: Vec<String>) -> Result<SecretData> {
 	})
 }
 
-fn init_part(identity: &dyn Identity, item: &DataItem, value: &Part) -> Result<()> {
+fn init_part(identities: &[Box<dyn Identity>], item: &DataItem, value: &Part) -> Result<()> {
 	let stable_dir = value.stable_path.parent().expect("not root");
 
 	// Right now stable & non-stable data are both located in this dir.
@@ -118,7 +258,7 @@ fn init_part(identity: &dyn Identity, item: &DataItem, value: &Part) -> Result<(
 
 	let private = value.raw.encrypted;
 	let data = if private {
-		decrypt(&value.raw, identity)?
+		decrypt(&value.raw, identities)?
 	} else {
 		value.raw.data.to_owned()
 	};
@@ -129,22 +269,38 @@ fn init_part(identity: &dyn Identity, item: &DataItem, value: &Part) -> Result<(
 	stable_temp.flush()?;
 
 	let mode = if private {
+		let mode = value.mode.as_ref().unwrap_or(&item.mode);
 		fs::Permissions::from_mode(
-			u32::from_str_radix(&item.mode, 8).context("failed to parse mode as octal")?,
+			u32::from_str_radix(mode, 8).context("failed to parse mode as octal")?,
 		)
 	} else {
 		fs::Permissions::from_mode(0o444)
 	};
 	fs::set_permissions(stable_temp.path(), mode.clone()).context("stable temp mode")?;
-	fs::set_permissions(&value.path, mode).context("hashed mode")?;
+	fs::set_permissions(&value.path, mode.clone()).context("hashed mode")?;
+
+	let mut target_temp = if let Some(target) = &value.target {
+		let target_dir = target.parent().expect("not root");
+		std::fs::create_dir_all(target_dir)?;
+		let mut target_temp = tempfile::NamedTempFile::new_in(target_dir)
+			.context("failed to create target tempfile")?;
+		target_temp.write_all(&data)?;
+		target_temp.flush()?;
+		fs::set_permissions(target_temp.path(), mode.clone()).context("target temp mode")?;
+		Some(target_temp)
+	} else {
+		None
+	};
 
 	// Files are initially owned by root, thus making set mode first inaccessible to user, and then
 	// altering user/group.
 	if private {
-		let user = User::from_name(&item.owner)
+		let owner = value.owner.as_ref().unwrap_or(&item.owner);
+		let group = value.group.as_ref().unwrap_or(&item.group);
+		let user = User::from_name(owner)
 			.context("failed to get user")?
 			.ok_or_else(|| anyhow!("user not found"))?;
-		let group = Group::from_name(&item.group)
+		let group = Group::from_name(group)
 			.context("failed to get group")?
 			.ok_or_else(|| anyhow!("group not found"))?;
 
@@ -152,15 +308,41 @@ fn init_part(identity: &dyn Identity, item: &DataItem, value: &Part) -> Result<(
 			.context("failed to apply user/group")?;
 		chown(&value.path, Some(user.uid), Some(group.gid))
 			.context("failed to apply user/group")?;
+		if let Some(target_temp) = &target_temp {
+			chown(target_temp.path(), Some(user.uid), Some(group.gid))
+				.context("failed to apply user/group")?;
+		}
 	}
 
 	stable_temp
 		.persist(&value.stable_path)
 		.context("stable persist")?;
+	if let (Some(target_temp), Some(target)) = (target_temp.take(), &value.target) {
+		target_temp.persist(target).context("target persist")?;
+	}
 	Ok(())
 }
 
-fn init_secret(identity: &age::ssh::Identity, value: &DataItem) -> Result<()> {
+/// Units are restarted without blocking, as secrets might be installed before systemd
+/// is ready to process jobs, i.e during activation or in sysinit.target.
+fn notify_units(verb: &str, units: &[String]) {
+	if units.is_empty() {
+		return;
+	}
+	info!("{verb} {}", units.join(" "));
+	let status = Command::new("systemctl")
+		.arg(verb)
+		.arg("--no-block")
+		.args(units)
+		.status();
+	match status {
+		Ok(s) if s.success() => {}
+		Ok(s) => error!("systemctl {verb} failed: {s}"),
+		Err(e) => error!("failed to run systemctl: {e}"),
+	}
+}
+
+fn init_secret(identities: &[Box<dyn Identity>], value: &DataItem) -> Result<()> {
 	if let Some(root_path) = &value.root_path {
 		if !fs::metadata(root_path).map(|m| m.is_dir()).unwrap_or(false) {
 			fs::create_dir(root_path).context("failed to create secret directory")?;
@@ -169,7 +351,7 @@ fn init_secret(identity: &age::ssh::Identity, value: &DataItem) -> Result<()> {
 	let mut errored = false;
 	for (part_id, part) in value.parts.iter() {
 		let _span = info_span!("part", part_id = part_id);
-		if let Err(e) = init_part(identity, value, part) {
+		if let Err(e) = init_part(identities, value, part) {
 			error!("failed to init part {part_id}: {e}");
 			errored = true;
 		}
@@ -179,7 +361,7 @@ fn init_secret(identity: &age::ssh::Identity, value: &DataItem) -> Result<()> {
 	Ok(())
 }
 
-fn host_identity() -> anyhow::Result<SshIdentity> {
+fn ssh_host_identity() -> anyhow::Result<SshIdentity> {
 	let identity = SshIdentity::from_buffer(
 		&mut Cursor::new(
 			fs::read("/etc/ssh/ssh_host_ed25519_key").context("failed to read host private key")?,
@@ -190,7 +372,85 @@ fn host_identity() -> anyhow::Result<SshIdentity> {
 	Ok(identity)
 }
 
-fn install(data: &Path) -> anyhow::Result<()> {
+/// Tied to `fleet keys enroll-tpm`
+const TPM_KEY_PATH: &str = "/var/lib/fleet/host-key.cred";
+/// Credential name is authenticated by systemd-creds, so the sealed key can't be swapped
+const TPM_KEY_NAME: &str = "fleet-host-key";
+
+fn unseal_tpm_key() -> Result<age::x25519::Identity> {
+	let output = Command::new("systemd-creds")
+		.arg("decrypt")
+		.arg(format!("--name={TPM_KEY_NAME}"))
+		.arg(TPM_KEY_PATH)
+		.arg("-")
+		.output()
+		.context("failed to run systemd-creds")?;
+	ensure!(
+		output.status.success(),
+		"systemd-creds decrypt failed: {}",
+		String::from_utf8_lossy(&output.stderr).trim()
+	);
+	let key = String::from_utf8(output.stdout).context("sealed key is not utf-8")?;
+	age::x25519::Identity::from_str(key.trim())
+		.map_err(|e| anyhow!("failed to parse sealed key: {e}"))
+}
+
+/// TPM-sealed key goes first, ssh host key is still needed for secrets
+/// which were encrypted before the enrollment
+fn host_identities() -> Result<Vec<Box<dyn Identity>>> {
+	let mut out: Vec<Box<dyn Identity>> = Vec::new();
+	if Path::new(TPM_KEY_PATH).exists() {
+		out.push(Box::new(
+			unseal_tpm_key().context("failed to unseal TPM host key")?,
+		));
+	}
+	match ssh_host_identity() {
+		Ok(identity) => out.push(Box::new(identity)),
+		Err(e) if !out.is_empty() => warn!("ssh host key is not available: {e:#}"),
+		Err(e) => return Err(e),
+	}
+	Ok(out)
+}
+
+fn enroll_tpm(pcrs: &str) -> Result<String> {
+	let identity = age::x25519::Identity::generate();
+	let dir = Path::new(TPM_KEY_PATH).parent().expect("not root");
+	fs::create_dir_all(dir)?;
+	let temp = tempfile::NamedTempFile::new_in(dir)?;
+	let mut child = Command::new("systemd-creds")
+		.arg("encrypt")
+		.arg("--with-key=tpm2")
+		.arg(format!("--tpm2-pcrs={pcrs}"))
+		.arg(format!("--name={TPM_KEY_NAME}"))
+		.arg("-")
+		.arg(temp.path())
+		.stdin(Stdio::piped())
+		.spawn()
+		.context("failed to run systemd-creds")?;
+	child
+		.stdin
+		.take()
+		.expect("piped")
+		.write_all(identity.to_string().expose_secret().as_bytes())?;
+	let status = child.wait()?;
+	ensure!(status.success(), "systemd-creds encrypt failed: {status}");
+	temp.persist(TPM_KEY_PATH).context("sealed key persist")?;
+
+	// Key is only registered in fleet data, if it can be unsealed
+	let public = identity.to_public().to_string();
+	ensure!(
+		unseal_tpm_key()?.to_public().to_string() == public,
+		"unsealed key doesn't match the generated one"
+	);
+	Ok(public)
+}
+
+fn install(
+	data: &Path,
+	force: bool,
+	material: Option<&Path>,
+	env_files: Option<&Path>,
+) -> anyhow::Result<()> {
 	let data = fs::read(data).context("failed to read secrets data")?;
 	let data_str = from_utf8(&data).context("failed to read data to string")?;
 	let data: Data = serde_json::from_str(data_str).context("failed to parse data")?;
@@ -202,15 +462,72 @@ fn install(data: &Path) -> anyhow::Result<()> {
 		fs::create_dir("/run/secrets").context("failed to create secrets directory")?;
 	}
 
-	let identity = host_identity()?;
+	let identities = host_identities()?;
+	let material = material.map(read_material).transpose()?;
+
+	let previous = if force { State::new() } else { read_state() };
+	let mut state = State::new();
 
+	let total = data.len();
+	let mut updated = 0;
+	let mut restart_units = BTreeSet::new();
+	let mut reload_units = BTreeSet::new();
 	let mut failed = false;
-	for (name, value) in data {
+	for (name, mut value) in data {
 		let _span = info_span!("init", name = name);
-		if let Err(e) = init_secret(&identity, &value) {
+		if let Some(material) = &material {
+			if let Err(e) = fill_material(&name, &mut value, material) {
+				error!("{e}");
+				failed = true;
+				continue;
+			}
+		}
+		let hash = secret_hash(&value);
+		let item: DataItem = match serde_json::from_value(value) {
+			Ok(v) => v,
+			Err(e) => {
+				error!("failed to parse secret: {e}");
+				failed = true;
+				continue;
+			}
+		};
+		if previous.get(&name) == Some(&hash) && is_installed(&item) {
+			state.insert(name, hash);
+			continue;
+		}
+		updated += 1;
+		if let Err(e) = init_secret(&identities, &item) {
 			error!("secret failed to initialize: {e}");
 			failed = true;
+			continue;
 		}
+		restart_units.extend(item.restart_units);
+		reload_units.extend(item.reload_units);
+		state.insert(name, hash);
+	}
+	info!("{updated} of {total} secrets updated");
+	if let Some(env_files) = env_files {
+		match env_files::install(env_files) {
+			Ok((units, env_failed)) => {
+				restart_units.extend(units);
+				failed |= env_failed;
+			}
+			Err(e) => {
+				error!("{e:#}");
+				failed = true;
+			}
+		}
+	}
+	notify_units(
+		"try-restart",
+		&restart_units.into_iter().collect::<Vec<_>>(),
+	);
+	notify_units(
+		"try-reload-or-restart",
+		&reload_units.into_iter().collect::<Vec<_>>(),
+	);
+	if let Err(e) = write_state(&state) {
+		error!("failed to save installed secrets state: {e}");
 	}
 	if failed {
 		bail!("one or more secrets failed");
@@ -233,18 +550,41 @@ fn main() -> anyhow::Result<()> {
 	let opts = Opts::parse();
 
 	match opts {
-		Opts::Install { data } => install(&data),
+		Opts::Install {
+			data,
+			force,
+			material,
+			env_files,
+		} => install(&data, force, material.as_deref(), env_files.as_deref()),
+		Opts::Provision { material, data } => {
+			let mut parts = String::new();
+			io::stdin()
+				.read_to_string(&mut parts)
+				.context("failed to read material")?;
+			write_material(&material, &parse_material(&parts)?)?;
+			info!("material provisioned");
+			if let Some(data) = data.filter(|d| d.exists()) {
+				let env_files = Path::new(env_files::ENV_FILES_PATH);
+				install(
+					&data,
+					false,
+					Some(&material),
+					env_files.exists().then_some(env_files),
+				)?;
+			}
+			Ok(())
+		}
 		Opts::Reencrypt { secret, targets } => {
-			let identity = host_identity()?;
-			let decrypted = decrypt(&secret, &identity).context("during decryption")?;
+			let identities = host_identities()?;
+			let decrypted = decrypt(&secret, &identities).context("during decryption")?;
 			let encrypted = encrypt(&decrypted, targets).context("during re-encryption")?;
 
 			println!("{encrypted}");
 			Ok(())
 		}
 		Opts::Decrypt { secret, plaintext } => {
-			let identity = host_identity()?;
-			let decrypted = decrypt(&secret, &identity).context("during decryption")?;
+			let identities = host_identities()?;
+			let decrypted = decrypt(&secret, &identities).context("during decryption")?;
 
 			if plaintext {
 				let s = String::from_utf8(decrypted).context("output is not utf8")?;
@@ -260,5 +600,9 @@ fn main() -> anyhow::Result<()> {
 			}
 			Ok(())
 		}
+		Opts::EnrollTpm { pcrs } => {
+			println!("{}", enroll_tpm(&pcrs)?);
+			Ok(())
+		}
 	}
 }
@@ -1,8 +1,9 @@
 //! Collection of handlers, which transform program-specific stdout format to tracing
 
 use std::{
-	collections::HashMap,
+	collections::{BTreeMap, HashMap},
 	sync::{Arc, LazyLock, Mutex},
+	time::{Duration, Instant},
 };
 
 use regex::Regex;
@@ -47,10 +48,137 @@ impl Handler for NoopHandler {
 	fn handle_line(&mut self, _e: &str) {}
 }
 
+/// How often the build progress is printed as the regular log
+const REPORT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Progress of the `Builds` or `CopyPaths` nix activity
+#[derive(Default, Clone, Copy)]
+struct Counter {
+	done: u64,
+	expected: u64,
+	running: u64,
+	failed: u64,
+}
+impl Counter {
+	fn render(&self, verb: &str) -> Option<String> {
+		if self.expected == 0 {
+			return None;
+		}
+		let mut out = format!("{verb} {}/{}", self.done, self.expected);
+		if self.running != 0 {
+			out.push_str(&format!(", {} running", self.running));
+		}
+		// Waiting for dependencies or for a build slot
+		let postponed = self
+			.expected
+			.saturating_sub(self.done + self.running + self.failed);
+		if postponed != 0 {
+			out.push_str(&format!(", {postponed} postponed"));
+		}
+		if self.failed != 0 {
+			out.push_str(&format!(", {} failed", self.failed));
+		}
+		Some(out)
+	}
+}
+
+struct RunningBuild {
+	drv: String,
+	phase: Option<String>,
+	started: Instant,
+}
+
+/// Overall progress of the nix invocation, similar to what nix-output-monitor shows
+#[derive(Default)]
+struct BuildProgress {
+	/// Activity types by id, progress results only refer to the activity id
+	activities: HashMap<u64, u32>,
+	builds: Counter,
+	downloads: Counter,
+	running: BTreeMap<u64, RunningBuild>,
+	#[cfg(feature = "indicatif")]
+	span: Option<Span>,
+	last_report: Option<Instant>,
+}
+impl BuildProgress {
+	fn summary(&self) -> Option<String> {
+		let parts: Vec<String> = [
+			self.builds.render("built"),
+			self.downloads.render("downloaded"),
+		]
+		.into_iter()
+		.flatten()
+		.collect();
+		if parts.is_empty() {
+			return None;
+		}
+		Some(parts.join("; "))
+	}
+	/// Currently running builds, with their phases and durations
+	fn tree(&self) -> String {
+		let mut out = String::new();
+		let last = self.running.len().saturating_sub(1);
+		for (i, build) in self.running.values().enumerate() {
+			out.push_str(if i == last { "└─ " } else { "├─ " });
+			out.push_str(&build.drv);
+			if let Some(phase) = &build.phase {
+				out.push_str(&format!(" [{phase}]"));
+			}
+			out.push_str(&format!(" {}s\n", build.started.elapsed().as_secs()));
+		}
+		out
+	}
+	fn update(&mut self) {
+		#[cfg(feature = "indicatif")]
+		if let Some(summary) = self.summary() {
+			let span = self.span.get_or_insert_with(|| {
+				let span = info_span!("progress");
+				span.pb_start();
+				span
+			});
+			span.pb_set_message(&summary);
+		}
+	}
+	/// Without indicatif, there is no other way to tell if nix is stuck or just busy.
+	/// With build logs streamed, running builds are also listed.
+	fn report(&mut self, tree: bool) {
+		if cfg!(feature = "indicatif") && !tree {
+			return;
+		}
+		if self
+			.last_report
+			.is_some_and(|last| last.elapsed() < REPORT_INTERVAL)
+		{
+			return;
+		}
+		let Some(summary) = self.summary() else {
+			return;
+		};
+		self.last_report = Some(Instant::now());
+		if tree && !self.running.is_empty() {
+			info!(target: "nix", "{summary}\n{}", self.tree().trim_end());
+		} else {
+			info!(target: "nix", "{summary}");
+		}
+	}
+}
+
 /// Transform nix internal-json logs to tracing spans.
 #[derive(Default)]
 pub struct NixHandler {
 	spans: HashMap<u64, Span>,
+	/// With indicatif, build log lines are only shown as the span message,
+	/// when enabled, they are also printed as the regular log, along with the tree of running builds.
+	stream_build_logs: bool,
+	progress: BuildProgress,
+}
+impl NixHandler {
+	pub fn streaming() -> Self {
+		Self {
+			stream_build_logs: true,
+			..Default::default()
+		}
+	}
 }
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
@@ -109,6 +237,9 @@ impl Handler for NixHandler {
 					return;
 				}
 			};
+			if let NixLog::Start { id, typ, .. } = &log {
+				self.progress.activities.insert(*id, *typ);
+			}
 			match log {
 				NixLog::Msg { msg, raw_msg, .. } => {
 					#[allow(clippy::nonminimal_bool)]
@@ -146,6 +277,14 @@ impl Handler for NixHandler {
 						#[cfg(feature = "indicatif")]
 						span.pb_start();
 						self.spans.insert(id, span);
+						self.progress.running.insert(
+							id,
+							RunningBuild {
+								drv: drv.to_owned(),
+								phase: None,
+								started: Instant::now(),
+							},
+						);
 					} else {
 						warn!("bad build log: {:?}", log)
 					}
@@ -268,14 +407,15 @@ impl Handler for NixHandler {
 				}
 				NixLog::Stop { id, .. } => {
 					self.spans.remove(&id);
+					self.progress.activities.remove(&id);
+					self.progress.running.remove(&id);
 				}
 				NixLog::Result { fields, id, typ } if typ == 101 && !fields.is_empty() => {
 					if let Some(span) = self.spans.get(&id) {
 						if let LogField::String(s) = &fields[0] {
 							#[cfg(feature = "indicatif")]
 							span.pb_set_message(&process_message(s.trim()));
-							#[cfg(not(feature = "indicatif"))]
-							{
+							if self.stream_build_logs || cfg!(not(feature = "indicatif")) {
 								let _span = span.enter();
 								info!("{}", process_message(s));
 							}
@@ -288,6 +428,24 @@ impl Handler for NixHandler {
 					// dbg!(fields, id, typ);
 				}
 				NixLog::Result { fields, id, typ } if typ == 105 && fields.len() >= 4 => {
+					if let [LogField::Num(done), LogField::Num(expected), LogField::Num(running), LogField::Num(failed)] =
+						&fields[..4]
+					{
+						let counter = Counter {
+							done: *done,
+							expected: *expected,
+							running: *running,
+							failed: *failed,
+						};
+						// Builds and CopyPaths activities, overall progress
+						match self.progress.activities.get(&id) {
+							Some(104) => self.progress.builds = counter,
+							Some(103) => self.progress.downloads = counter,
+							_ => {}
+						}
+						self.progress.update();
+						self.progress.report(self.stream_build_logs);
+					}
 					if let Some(span) = self.spans.get(&id) {
 						if let [LogField::Num(done), LogField::Num(expected), LogField::Num(_running), LogField::Num(_failed)] =
 							&fields[..4]
@@ -307,8 +465,19 @@ impl Handler for NixHandler {
 					}
 					// dbg!(fields, id, typ);
 				}
-				NixLog::Result { typ, .. } if typ == 104 || typ == 106 => {
-					// Set phase, expected
+				NixLog::Result { fields, id, typ } if typ == 104 => {
+					if let (Some(build), [LogField::String(phase), ..]) =
+						(self.progress.running.get_mut(&id), &fields[..])
+					{
+						#[cfg(feature = "indicatif")]
+						if let Some(span) = self.spans.get(&id) {
+							span.pb_set_message(phase);
+						}
+						build.phase = Some(phase.clone());
+					}
+				}
+				NixLog::Result { typ, .. } if typ == 106 => {
+					// Set expected, progress results carry the same information
 				}
 				_ => warn!("unknown log: {:?}", log),
 			};
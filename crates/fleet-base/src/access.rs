@@ -0,0 +1,176 @@
+//! Delegated access to shared secrets: `sharedSecrets.<name>.admins`/`readers` list public keys
+//! (age or ssh) of team members, secret is encrypted to them in addition to owner hosts,
+//! so that they can decrypt it locally with their admin identity.
+//!
+//! Only admins may modify secrets with non-empty admin list. This check is performed by fleet
+//! itself, confidentiality is guaranteed by encryption only: non-readers can't decrypt the secret.
+//!
+//! Deployer tokens (`deployers`) are restricted identities for CI, secrets with `ci = true` are
+//! encrypted to them as well. Operations, which deployers are not allowed to perform, are refused
+//! with [`Config::ensure_not_deployer`].
+
+use std::collections::BTreeMap;
+
+use age::Recipient;
+use anyhow::{ensure, Context, Result};
+use fleet_shared::SecretData;
+use nix_eval::{nix_go, nix_go_json};
+
+use crate::{
+	fleetdata::decrypt_secret_data_async,
+	host::Config,
+	sealed::{self, parse_recipient},
+	secret_bytes::SecretBytes,
+};
+
+#[derive(Default, Debug, Clone)]
+pub struct SecretAccess {
+	/// May modify the secret, and are also readers
+	pub admins: Vec<String>,
+	pub readers: Vec<String>,
+	/// Deployer keys, if the secret has `ci = true`
+	pub deployers: Vec<String>,
+}
+impl SecretAccess {
+	pub fn is_restricted(&self) -> bool {
+		!self.admins.is_empty() || !self.readers.is_empty()
+	}
+	/// Keys secret should be encrypted to, in addition to owner hosts
+	pub fn recipients(&self) -> Vec<String> {
+		let mut out = Vec::new();
+		for key in self
+			.admins
+			.iter()
+			.chain(&self.readers)
+			.chain(&self.deployers)
+		{
+			let key = key.trim().to_owned();
+			if !out.contains(&key) {
+				out.push(key);
+			}
+		}
+		out
+	}
+}
+
+impl Config {
+	/// Access lists of the shared secret, empty for secrets which are not declared in the configuration
+	pub async fn shared_secret_access(&self, secret: &str) -> Result<SecretAccess> {
+		let config_field = &self.config_field;
+		if !nix_go!(config_field.sharedSecrets)
+			.has_field(secret)
+			.await?
+		{
+			return Ok(SecretAccess::default());
+		}
+		let ci: bool = nix_go_json!(config_field.sharedSecrets[{ secret }].ci);
+		Ok(SecretAccess {
+			admins: nix_go_json!(config_field.sharedSecrets[{ secret }].admins),
+			readers: nix_go_json!(config_field.sharedSecrets[{ secret }].readers),
+			deployers: if ci {
+				self.deployer_keys().await?
+			} else {
+				Vec::new()
+			},
+		})
+	}
+
+	/// Public keys of deployer tokens, see `fleet keys issue-deployer`
+	pub async fn deployer_keys(&self) -> Result<Vec<String>> {
+		let config_field = &self.config_field;
+		let deployers: BTreeMap<String, String> = nix_go_json!(config_field.deployers);
+		Ok(deployers.into_values().collect())
+	}
+
+	/// Whether the operator identity is a deployer token, checked the same way as in
+	/// [`Self::ensure_secret_admin`]
+	pub async fn is_deployer(&self) -> Result<bool> {
+		let keys = self.deployer_keys().await?;
+		if keys.is_empty() {
+			return Ok(false);
+		}
+		let Ok(identities) = self.identities.identities() else {
+			return Ok(false);
+		};
+		let recipients = keys
+			.iter()
+			.map(|k| parse_recipient(k))
+			.collect::<Result<Vec<_>>>()
+			.context("bad deployers")?;
+		let challenge = b"fleet deployer check";
+		let encrypted = sealed::seal(challenge, recipients)?;
+		Ok(sealed::unseal(&encrypted, identities).is_ok_and(|d| d == challenge))
+	}
+
+	/// Fails if the operator identity is a deployer token
+	pub async fn ensure_not_deployer(&self, action: &str) -> Result<()> {
+		ensure!(
+			!self.is_deployer().await?,
+			"deployer token can't {action}, admin identity is required"
+		);
+		Ok(())
+	}
+
+	/// Owner host keys and team member keys, shared secret should be encrypted to
+	pub async fn shared_secret_recipients(
+		&self,
+		owners: &[String],
+		access: &SecretAccess,
+	) -> Result<Vec<Box<dyn Recipient + Send>>> {
+		let mut out = Vec::new();
+		for owner in owners {
+			out.push(parse_recipient(self.key(owner).await?.trim())?);
+		}
+		for key in access.recipients() {
+			out.push(parse_recipient(&key)?);
+		}
+		Ok(out)
+	}
+
+	/// Fails unless the operator identity is one of the secret admins, or secret has no admins.
+	///
+	/// Identity is checked by decrypting a message encrypted to the admin keys,
+	/// which works for every supported key kind.
+	pub fn ensure_secret_admin(&self, secret: &str, access: &SecretAccess) -> Result<()> {
+		if access.admins.is_empty() {
+			return Ok(());
+		}
+		let recipients = access
+			.admins
+			.iter()
+			.map(|k| parse_recipient(k))
+			.collect::<Result<Vec<_>>>()
+			.with_context(|| format!("bad sharedSecrets.{secret}.admins"))?;
+		// Every encryption uses a fresh file key, so the message itself doesn't need to be random
+		let challenge = b"fleet secret admin check";
+		let encrypted = sealed::seal(challenge, recipients)?;
+		let identities = self
+			.identities
+			.identities()
+			.context("secret has admins list, admin identity is required to modify it")?;
+		ensure!(
+			sealed::unseal(&encrypted, identities).is_ok_and(|d| d == challenge),
+			"only admins of {secret} may modify it, your identity is not in sharedSecrets.{secret}.admins"
+		);
+		Ok(())
+	}
+
+	/// Decrypts secret with the operator identity, which should be one of the secret readers
+	pub async fn decrypt_as_reader(&self, secret: &str, data: &SecretData) -> Result<SecretBytes> {
+		ensure!(data.encrypted, "secret is not encrypted");
+		if self.is_deployer().await? {
+			ensure!(
+				!self.shared_secret_access(secret).await?.deployers.is_empty(),
+				"{secret} can't be read with the deployer token, it has no sharedSecrets.{secret}.ci set"
+			);
+		}
+		let identities = self.identities.shared_identities()?;
+		decrypt_secret_data_async(identities, data.data.clone())
+			.await
+			.with_context(|| {
+				format!(
+					"failed to decrypt {secret}, is your identity in its admins or readers list?"
+				)
+			})
+	}
+}
@@ -0,0 +1,513 @@
+//! Attribution of fleet data entries: hosts, host secrets and shared secrets record the admin who
+//! has created and last modified them, and are signed with the admin ssh key (`ssh-keygen -Y sign`),
+//! so that changes made bypassing fleet are detected by `fleet secret info` and `fleet doctor`.
+//!
+//! Key is taken from `FLEET_AUDIT_KEY`, defaulting to `~/.ssh/id_ed25519`, same as for the audit log.
+//! Signing keys are validated against the `adminKeys` fleet option, see [`AllowedSigners`],
+//! and entries are verified when fleet data is loaded, see [`verify_data`]. Entries, which were
+//! verified by the previous runs, and weren't changed since then, are not verified again.
+
+use std::{
+	collections::BTreeMap,
+	env, fmt, fs,
+	io::Write as _,
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+	sync::OnceLock,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tracing::warn;
+
+use crate::fleetdata::{FleetData, FleetSecret, FleetSharedSecret, HostData};
+
+const SIGNATURE_NAMESPACE: &str = "fleet-data";
+
+/// Admin, who has made the change
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Author {
+	/// `user@machine` of the admin
+	pub name: String,
+	/// Fingerprint of the admin ssh key, `None` if the change is not signed
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub key: Option<String>,
+	pub at: DateTime<Utc>,
+}
+impl fmt::Display for Author {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} at {}", self.name, self.at)?;
+		if let Some(key) = &self.key {
+			write!(f, " ({key})")?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Attribution {
+	pub created_by: Author,
+	pub modified_by: Author,
+	/// Armored ssh signature of the entry, serialized without this field
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub signature: Option<String>,
+}
+
+/// Fleet data entry, which records its authors
+pub trait Attributed: Serialize + Clone {
+	fn attribution(&self) -> Option<&Attribution>;
+	fn attribution_mut(&mut self) -> &mut Option<Attribution>;
+}
+impl Attributed for HostData {
+	fn attribution(&self) -> Option<&Attribution> {
+		self.attribution.as_ref()
+	}
+	fn attribution_mut(&mut self) -> &mut Option<Attribution> {
+		&mut self.attribution
+	}
+}
+impl Attributed for FleetSecret {
+	fn attribution(&self) -> Option<&Attribution> {
+		self.attribution.as_ref()
+	}
+	fn attribution_mut(&mut self) -> &mut Option<Attribution> {
+		&mut self.attribution
+	}
+}
+/// Owners and readers are signed together with the secret
+impl Attributed for FleetSharedSecret {
+	fn attribution(&self) -> Option<&Attribution> {
+		self.secret.attribution.as_ref()
+	}
+	fn attribution_mut(&mut self) -> &mut Option<Attribution> {
+		&mut self.secret.attribution
+	}
+}
+
+/// Location of the entry in fleet data, it is signed too, so entries can't be swapped
+#[derive(Clone, Copy)]
+pub enum Entry<'e> {
+	Host(&'e str),
+	HostSecret { host: &'e str, secret: &'e str },
+	SharedSecret(&'e str),
+}
+impl fmt::Display for Entry<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Entry::Host(host) => write!(f, "host {host}"),
+			Entry::HostSecret { host, secret } => write!(f, "secret {secret} of host {host}"),
+			Entry::SharedSecret(secret) => write!(f, "shared secret {secret}"),
+		}
+	}
+}
+
+fn payload(entry: Entry<'_>, value: &impl Attributed) -> Result<Vec<u8>> {
+	let mut value = value.clone();
+	if let Some(attribution) = value.attribution_mut() {
+		attribution.signature = None;
+	}
+	Ok(format!("{entry}\n{}", serde_json::to_string(&value)?).into_bytes())
+}
+
+pub enum Verification {
+	/// Entry was written by an older fleet version, or signing has failed
+	Unsigned,
+	Valid,
+	Invalid(String),
+}
+impl fmt::Display for Verification {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Verification::Unsigned => write!(f, "not signed"),
+			Verification::Valid => write!(f, "valid"),
+			Verification::Invalid(e) => write!(f, "INVALID, {e}"),
+		}
+	}
+}
+
+/// `user@machine` of the operator
+pub fn actor() -> String {
+	let user = env::var("USER").unwrap_or_else(|_| "unknown".to_owned());
+	let machine = hostname::get()
+		.ok()
+		.and_then(|h| h.into_string().ok())
+		.unwrap_or_else(|| "unknown".to_owned());
+	format!("{user}@{machine}")
+}
+
+fn signing_key() -> Option<PathBuf> {
+	if let Some(key) = env::var_os("FLEET_AUDIT_KEY") {
+		return Some(key.into());
+	}
+	let key = PathBuf::from(env::var_os("HOME")?).join(".ssh/id_ed25519");
+	key.exists().then_some(key)
+}
+
+/// ssh-keygen reads signed data from stdin
+fn ssh_keygen(args: &[&str], data: &[u8]) -> Result<String> {
+	let mut child = Command::new("ssh-keygen")
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.context("failed to run ssh-keygen")?;
+	child.stdin.take().expect("piped").write_all(data)?;
+	let output = child.wait_with_output()?;
+	ensure!(
+		output.status.success(),
+		"ssh-keygen failed: {}",
+		String::from_utf8_lossy(&output.stderr).trim()
+	);
+	Ok(String::from_utf8(output.stdout)?)
+}
+
+/// `SHA256:...` fingerprint, as printed by ssh-keygen
+fn find_fingerprint(output: &str) -> Option<String> {
+	output
+		.split_whitespace()
+		.find(|w| w.starts_with("SHA256:"))
+		.map(ToOwned::to_owned)
+}
+
+fn key_fingerprint(key: &Path) -> Result<String> {
+	let key = key.to_str().context("non-utf8 key path")?;
+	let output = ssh_keygen(&["-l", "-f", key], &[])?;
+	find_fingerprint(&output).context("ssh-keygen printed no fingerprint")
+}
+
+/// Fingerprint of the operator signing key, computed once per run
+pub fn signing_fingerprint() -> Option<String> {
+	static FINGERPRINT: OnceLock<Option<String>> = OnceLock::new();
+	FINGERPRINT
+		.get_or_init(|| {
+			let key = signing_key()?;
+			key_fingerprint(&key)
+				.inspect_err(|e| warn!("failed to read signing key fingerprint: {e:#}"))
+				.ok()
+		})
+		.clone()
+}
+
+/// Signs data with the operator ssh key
+pub fn sign(namespace: &str, data: &[u8]) -> Result<String> {
+	let Some(key) = signing_key() else {
+		bail!("no signing key found, set FLEET_AUDIT_KEY");
+	};
+	let key = key.to_str().context("non-utf8 key path")?;
+	ssh_keygen(&["-Y", "sign", "-f", key, "-n", namespace], data)
+}
+
+/// Admin keys, allowed to sign fleet data entries, see `adminKeys` option
+pub struct AllowedSigners {
+	file: Option<NamedTempFile>,
+	/// Contents of the allowed signers file, entries are verified again when it changes
+	contents: String,
+}
+impl AllowedSigners {
+	/// Without admin keys, signatures are only checked to match the data, trusting the key is up to the reader
+	pub fn any() -> Self {
+		Self {
+			file: None,
+			contents: String::new(),
+		}
+	}
+	pub fn new(keys: &BTreeMap<String, String>) -> Result<Self> {
+		if keys.is_empty() {
+			return Ok(Self::any());
+		}
+		let mut contents = String::new();
+		for (admin, key) in keys {
+			ensure!(
+				!admin.is_empty() && !admin.contains(|c: char| c.is_whitespace() || c == ','),
+				"admin name {admin:?} can't be used as ssh principal"
+			);
+			contents.push_str(&format!(
+				"{admin} namespaces=\"{SIGNATURE_NAMESPACE}\" {}\n",
+				key.trim()
+			));
+		}
+		let mut file = NamedTempFile::new()?;
+		file.write_all(contents.as_bytes())?;
+		file.flush()?;
+		Ok(Self {
+			file: Some(file),
+			contents,
+		})
+	}
+	/// Whether admin keys are configured, and every entry should be signed by one of them
+	pub fn is_enforced(&self) -> bool {
+		self.file.is_some()
+	}
+	fn path(&self) -> Result<Option<&str>> {
+		self.file
+			.as_ref()
+			.map(|f| f.path().to_str().context("non-utf8 temp path"))
+			.transpose()
+	}
+}
+
+/// Digests of the entries, which were verified by the previous runs, by entry name.
+///
+/// Stored in `.fleet/verified/<fleet>.json`, digest covers the entry, its signature and admin keys,
+/// so the entry is verified again if any of them has changed.
+#[derive(Serialize, Deserialize, Default)]
+pub struct VerifiedEntries(BTreeMap<String, String>);
+impl VerifiedEntries {
+	fn path(directory: &Path, fleet: &str) -> PathBuf {
+		directory
+			.join(".fleet/verified")
+			.join(format!("{fleet}.json"))
+	}
+	/// Missing or corrupted file only causes every entry to be verified again
+	pub fn load(directory: &Path, fleet: &str) -> Self {
+		let Ok(data) = fs::read(Self::path(directory, fleet)) else {
+			return Self::default();
+		};
+		serde_json::from_slice(&data).unwrap_or_default()
+	}
+	pub fn save(&self, directory: &Path, fleet: &str) -> Result<()> {
+		let path = Self::path(directory, fleet);
+		let dir = path.parent().expect("not root");
+		fs::create_dir_all(dir)?;
+		let tmp = NamedTempFile::new_in(dir)?;
+		serde_json::to_writer(&tmp, self)?;
+		tmp.persist(path)?;
+		Ok(())
+	}
+}
+
+fn entry_digest(
+	entry: Entry<'_>,
+	value: &impl Attributed,
+	allowed_signers: &AllowedSigners,
+) -> Result<String> {
+	let mut hasher = Sha256::new();
+	hasher.update(allowed_signers.contents.as_bytes());
+	hasher.update(payload(entry, value)?);
+	if let Some(signature) = value.attribution().and_then(|a| a.signature.as_ref()) {
+		hasher.update(signature.as_bytes());
+	}
+	Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Checks signature made by any key, returns fingerprint of the key, if ssh-keygen reports it
+pub fn check_signature(namespace: &str, data: &[u8], signature: &str) -> Result<Option<String>> {
+	let mut file = NamedTempFile::new()?;
+	file.write_all(signature.as_bytes())?;
+	let path = file.path().to_str().context("non-utf8 temp path")?;
+	let output = ssh_keygen(
+		&["-Y", "check-novalidate", "-n", namespace, "-s", path],
+		data,
+	)?;
+	Ok(find_fingerprint(&output))
+}
+
+/// Checks that the signature was made by one of the allowed signers, returns fingerprint of its key
+fn check_allowed_signature(
+	allowed_signers: &str,
+	data: &[u8],
+	signature: &str,
+) -> Result<Option<String>> {
+	let mut file = NamedTempFile::new()?;
+	file.write_all(signature.as_bytes())?;
+	let path = file.path().to_str().context("non-utf8 temp path")?;
+	let principals = ssh_keygen(
+		&["-Y", "find-principals", "-f", allowed_signers, "-s", path],
+		&[],
+	)
+	.context("signing key is not in adminKeys")?;
+	let principal = principals
+		.lines()
+		.next()
+		.context("signing key is not in adminKeys")?;
+	let output = ssh_keygen(
+		&[
+			"-Y",
+			"verify",
+			"-f",
+			allowed_signers,
+			"-I",
+			principal,
+			"-n",
+			SIGNATURE_NAMESPACE,
+			"-s",
+			path,
+		],
+		data,
+	)?;
+	Ok(find_fingerprint(&output))
+}
+
+/// Records the operator as the last author of the entry, and signs it.
+/// Signing failure is not fatal, yet reported, the entry is left unsigned.
+pub fn stamp(entry: Entry<'_>, value: &mut impl Attributed) {
+	let author = Author {
+		name: actor(),
+		key: signing_fingerprint(),
+		at: Utc::now(),
+	};
+	let has_key = author.key.is_some();
+	let attribution = value.attribution_mut();
+	*attribution = Some(Attribution {
+		created_by: attribution
+			.take()
+			.map_or_else(|| author.clone(), |a| a.created_by),
+		modified_by: author,
+		signature: None,
+	});
+	let result: Result<String> = try {
+		if !has_key {
+			Err(anyhow!("no signing key found, set FLEET_AUDIT_KEY"))?;
+		}
+		sign(SIGNATURE_NAMESPACE, &payload(entry, value)?)?
+	};
+	let attribution = value
+		.attribution_mut()
+		.as_mut()
+		.expect("attribution is set");
+	match result {
+		Ok(signature) => attribution.signature = Some(signature),
+		Err(e) => {
+			warn!("{entry} is not signed: {e:#}");
+			attribution.modified_by.key = None;
+		}
+	}
+}
+
+/// Checks that the entry wasn't modified since it was signed by its last author,
+/// and that the author is one of the allowed signers
+pub fn verify(
+	entry: Entry<'_>,
+	value: &impl Attributed,
+	allowed_signers: &AllowedSigners,
+) -> Verification {
+	let Some(attribution) = value.attribution() else {
+		return Verification::Unsigned;
+	};
+	let Some(signature) = &attribution.signature else {
+		return Verification::Unsigned;
+	};
+	let result: Result<()> = try {
+		let payload = payload(entry, value)?;
+		let signer = match allowed_signers.path()? {
+			Some(allowed) => check_allowed_signature(allowed, &payload, signature)?,
+			None => check_signature(SIGNATURE_NAMESPACE, &payload, signature)?,
+		};
+		if let (Some(signer), Some(key)) = (signer, &attribution.modified_by.key) {
+			if signer != *key {
+				Err(anyhow!(
+					"signed with {signer}, while the last author key is {key}"
+				))?;
+			}
+		}
+	};
+	match result {
+		Ok(()) => Verification::Valid,
+		Err(e) => Verification::Invalid(format!("{e:#}")),
+	}
+}
+
+/// Verifies every entry of fleet data. Entries found in `cache` are skipped,
+/// and valid entries are recorded to it.
+pub fn verify_data(
+	data: &FleetData,
+	allowed_signers: &AllowedSigners,
+	mut cache: Option<&mut VerifiedEntries>,
+) -> Vec<(String, Verification)> {
+	let mut verified = Vec::new();
+	let mut check = |entry: Entry<'_>, value: &dyn VerifyEntry| {
+		let name = entry.to_string();
+		let digest = value.digest(entry, allowed_signers).ok();
+		if let (Some(cache), Some(digest)) = (cache.as_deref(), &digest) {
+			if cache.0.get(&name) == Some(digest) {
+				verified.push((name, Verification::Valid));
+				return;
+			}
+		}
+		let verification = value.verify(entry, allowed_signers);
+		if let (Some(cache), Some(digest)) = (cache.as_deref_mut(), digest) {
+			if matches!(verification, Verification::Valid) {
+				cache.0.insert(name.clone(), digest);
+			} else {
+				cache.0.remove(&name);
+			}
+		}
+		verified.push((name, verification));
+	};
+	for (name, host) in &data.hosts {
+		check(Entry::Host(name), host);
+	}
+	for (name, secret) in &data.shared_secrets {
+		check(Entry::SharedSecret(name), secret);
+	}
+	for (host, secrets) in &data.host_secrets {
+		for (name, secret) in secrets {
+			check(Entry::HostSecret { host, secret: name }, secret);
+		}
+	}
+	verified
+}
+
+/// Object-safe part of [`Attributed`], used to verify entries of different types together
+trait VerifyEntry {
+	fn digest(&self, entry: Entry<'_>, allowed_signers: &AllowedSigners) -> Result<String>;
+	fn verify(&self, entry: Entry<'_>, allowed_signers: &AllowedSigners) -> Verification;
+}
+impl<T: Attributed> VerifyEntry for T {
+	fn digest(&self, entry: Entry<'_>, allowed_signers: &AllowedSigners) -> Result<String> {
+		entry_digest(entry, self, allowed_signers)
+	}
+	fn verify(&self, entry: Entry<'_>, allowed_signers: &AllowedSigners) -> Verification {
+		verify(entry, self, allowed_signers)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn payload_covers_entry() {
+		let mut host = HostData {
+			encryption_key: "age1key".to_owned(),
+			..HostData::default()
+		};
+		let unsigned = payload(Entry::Host("a"), &host).unwrap();
+		host.attribution = Some(Attribution {
+			created_by: Author {
+				name: "admin@laptop".to_owned(),
+				key: None,
+				at: Utc::now(),
+			},
+			modified_by: Author {
+				name: "admin@laptop".to_owned(),
+				key: None,
+				at: Utc::now(),
+			},
+			signature: None,
+		});
+		let attributed = payload(Entry::Host("a"), &host).unwrap();
+		assert_ne!(unsigned, attributed);
+
+		host.attribution.as_mut().unwrap().signature = Some("sig".to_owned());
+		assert_eq!(payload(Entry::Host("a"), &host).unwrap(), attributed);
+		assert_ne!(payload(Entry::Host("b"), &host).unwrap(), attributed);
+	}
+
+	#[test]
+	fn fingerprints() {
+		assert_eq!(
+			find_fingerprint("256 SHA256:abc admin@laptop (ED25519)").as_deref(),
+			Some("SHA256:abc")
+		);
+		assert_eq!(find_fingerprint("no key here"), None);
+	}
+}
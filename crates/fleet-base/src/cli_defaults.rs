@@ -0,0 +1,22 @@
+//! Project-wide defaults of CLI options, declared in `cli` fleet option.
+//!
+//! Options passed on the command line always take precedence, global options are merged
+//! with their defaults in [`crate::opts::FleetOpts::build`].
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CliDefaults {
+	pub deploy: DeployDefaults,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeployDefaults {
+	/// Name of the deploy action, i.e "switch"
+	pub action: Option<String>,
+	pub rollback_timeout: Option<String>,
+	pub max_parallel: Option<usize>,
+	pub fail_fast: bool,
+}
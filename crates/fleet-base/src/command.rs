@@ -1,15 +1,125 @@
-use std::{ffi::OsStr, pin, process::Stdio, sync::Arc, task::Poll};
+use std::{
+	collections::{hash_map::RandomState, VecDeque},
+	ffi::OsStr,
+	hash::{BuildHasher as _, Hasher as _},
+	mem, pin,
+	process::Stdio,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, Ordering},
+		Arc,
+	},
+	task::Poll,
+	time::Duration,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context as _, Result};
 use better_command::{Handler, NixHandler, PlainHandler};
 use futures::StreamExt;
 use itertools::Either;
 use openssh::{OverSsh, OwningCommand, Session};
-use tokio::{io::AsyncRead, process::Command, select};
+use tokio::{
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt as _},
+	process::Command,
+	select,
+	time::sleep,
+};
 use tokio_util::codec::{BytesCodec, FramedRead, LinesCodec};
-use tracing::debug;
+use tracing::{debug, info, warn};
 
-use crate::host::EscalationStrategy;
+use crate::{host::EscalationStrategy, secret_bytes::SecretBytes};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Enables dry-run mode for the rest of the process.
+///
+/// In this mode only queries are executed, that is commands marked with [`MyCommand::query`],
+/// as their output is needed to plan the rest of the run (i.e generation listing, store path
+/// validity). Other commands are printed, and reported as succeeded with empty output.
+pub fn set_dry_run(dry_run: bool) {
+	DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+pub fn is_dry_run() -> bool {
+	DRY_RUN.load(Ordering::Relaxed)
+}
+
+static TRANSIENT_ATTEMPTS: AtomicU32 = AtomicU32::new(3);
+
+/// How many times commands, which have failed with transient errors, are attempted,
+/// see [`MyCommand::retry_transient`]
+pub fn set_transient_attempts(attempts: u32) {
+	TRANSIENT_ATTEMPTS.store(attempts.max(1), Ordering::Relaxed);
+}
+
+/// Stderr fragments of failures, which are likely to go away on retry, with their descriptions.
+/// Nix messages are matched in the internal-json lines as-is, none of fragments need escaping.
+const TRANSIENT_ERRORS: &[(&str, &str)] = &[
+	("' is busy", "nix database is busy"),
+	("HTTP error 5", "substituter server error"),
+	("Connection reset by peer", "connection reset"),
+	("Connection timed out", "connection timed out"),
+	("Connection closed by", "connection closed"),
+	("kex_exchange_identification", "ssh handshake failed"),
+	("Broken pipe", "connection lost"),
+	("unexpected end-of-file", "connection lost"),
+];
+/// Stderr lines kept for failure classification
+const TAIL_LINES: usize = 20;
+
+/// Passes lines to the inner handler, remembering the last ones
+struct TailHandler<'h> {
+	inner: &'h mut dyn Handler,
+	tail: VecDeque<String>,
+}
+impl<'h> TailHandler<'h> {
+	fn new(inner: &'h mut dyn Handler) -> Self {
+		Self {
+			inner,
+			tail: VecDeque::new(),
+		}
+	}
+	fn transient_reason(&self) -> Option<&'static str> {
+		TRANSIENT_ERRORS
+			.iter()
+			.find(|(fragment, _)| self.tail.iter().any(|l| l.contains(fragment)))
+			.map(|(_, reason)| *reason)
+	}
+}
+impl Handler for TailHandler<'_> {
+	fn handle_line(&mut self, e: &str) {
+		if self.tail.len() == TAIL_LINES {
+			self.tail.pop_front();
+		}
+		self.tail.push_back(e.to_owned());
+		self.inner.handle_line(e);
+	}
+}
+
+/// Exponential, starting at 1s and capped at 30s, with up to 50% jitter,
+/// so that concurrently failed commands don't retry at the same time
+fn backoff(attempt: u32) -> Duration {
+	let base = Duration::from_secs(1 << attempt.min(5)).min(Duration::from_secs(30));
+	let jitter = RandomState::new().build_hasher().finish() % 500;
+	base + base * jitter as u32 / 1000
+}
+
+#[derive(Clone, Copy)]
+enum RunMode {
+	Plain { stdout: bool },
+	Nix { stdout: bool },
+}
+
+/// Environment variables and `name=value` arguments, which values are hidden in the dry-run output
+const SENSITIVE_NAMES: &[&str] = &["SECRET", "TOKEN", "PASS", "IDENTIT", "KEY"];
+fn is_sensitive(name: &str) -> bool {
+	let name = name.to_ascii_uppercase();
+	SENSITIVE_NAMES.iter().any(|s| name.contains(s))
+}
+fn redact_arg(arg: &str) -> String {
+	match arg.split_once('=') {
+		Some((name, _)) if is_sensitive(name) => format!("{name}=<redacted>"),
+		_ => arg.to_owned(),
+	}
+}
 
 fn escape_bash(input: &str, out: &mut String) {
 	const TO_ESCAPE: &str = "$ !\"#&'()*,;<>?[\\]^`{|}";
@@ -35,24 +145,45 @@ pub struct MyCommand {
 	command: String,
 	args: Vec<String>,
 	env: Vec<(String, String)>,
+	/// Variables, which values are always redacted, and only exposed when the command is spawned,
+	/// see [`MyCommand::secret_env`]
+	secret_env: Vec<(String, Arc<SecretBytes>)>,
 	ssh_session: Option<Arc<Session>>,
+	/// Name of the host `ssh_session` is connected to, only used for display
+	ssh_host: Option<String>,
 	escalation: EscalationStrategy,
 	escalate: bool,
+	stream_build_logs: bool,
+	retry_transient: bool,
+	/// See [`MyCommand::stream_output`]
+	stream_output: bool,
+	/// See [`MyCommand::query`]
+	query: bool,
+	/// Data written to the command stdin, see [`MyCommand::stdin`]
+	stdin: Option<Arc<SecretBytes>>,
 }
 impl MyCommand {
 	pub fn new_on(
 		escalation: EscalationStrategy,
 		cmd: impl AsRef<OsStr>,
 		session: Arc<Session>,
+		host: impl Into<String>,
 	) -> Self {
 		assert!(!cmd.as_ref().is_empty());
 		Self {
 			command: ostoutf8(cmd),
 			args: vec![],
 			env: vec![],
+			secret_env: vec![],
 			ssh_session: Some(session),
+			ssh_host: Some(host.into()),
 			escalation,
 			escalate: false,
+			stream_build_logs: false,
+			retry_transient: false,
+			stream_output: false,
+			query: false,
+			stdin: None,
 		}
 	}
 	pub fn new(escalation: EscalationStrategy, cmd: impl AsRef<OsStr>) -> Self {
@@ -61,24 +192,48 @@ impl MyCommand {
 			command: ostoutf8(cmd),
 			args: vec![],
 			env: vec![],
+			secret_env: vec![],
 			ssh_session: None,
+			ssh_host: None,
 			escalation,
 			escalate: false,
+			stream_build_logs: false,
+			retry_transient: false,
+			stream_output: false,
+			query: false,
+			stdin: None,
 		}
 	}
 	fn new_here(&self, cmd: impl AsRef<OsStr>) -> Self {
 		if let Some(ssh_session) = self.ssh_session.clone() {
-			Self::new_on(self.escalation, cmd, ssh_session)
+			let host = self.ssh_host.clone().expect("set together with session");
+			Self::new_on(self.escalation, cmd, ssh_session, host)
 		} else {
 			Self::new(self.escalation, cmd)
 		}
 	}
 
+	/// Plain and secret environment variables, secrets are exposed here
+	fn exposed_env(&self) -> impl Iterator<Item = (&str, &str)> {
+		self.env
+			.iter()
+			.map(|(k, v)| (k.as_str(), v.as_str()))
+			.chain(self.secret_env.iter().map(|(k, v)| {
+				(
+					k.as_str(),
+					v.expose_str().expect("checked in MyCommand::secret_env"),
+				)
+			}))
+	}
+	fn has_env(&self) -> bool {
+		!self.env.is_empty() || !self.secret_env.is_empty()
+	}
+
 	fn into_args(self) -> Vec<String> {
 		let mut out = Vec::new();
-		if !self.env.is_empty() {
+		if self.has_env() {
 			out.push("env".to_owned());
-			for (k, v) in self.env {
+			for (k, v) in self.exposed_env() {
 				assert!(!k.contains('='));
 				out.push(format!("{k}={v}"));
 			}
@@ -94,29 +249,29 @@ impl MyCommand {
 	/// FIXME: Insecure, as arguments might be seen by other users on the same machine.
 	/// Figure out some way to transfer environment using stdio?
 	fn translate_env_into_env(self) -> Self {
-		if self.env.is_empty() {
+		if !self.has_env() {
 			return self;
 		}
 		let mut out = self.new_here("env");
-		for (k, v) in self.env {
+		for (k, v) in self.exposed_env() {
 			assert!(!k.contains('='));
 			out.arg(format!("{k}={v}"));
 		}
-		out.arg(self.command);
-		out.args(self.args);
+		out.arg(&self.command);
+		out.args(&self.args);
 
 		out
 	}
 	fn into_string(self) -> String {
 		let mut out = String::new();
-		if !self.env.is_empty() {
+		if self.has_env() {
 			out.push_str("env");
-			for (k, v) in self.env {
+			for (k, v) in self.exposed_env() {
 				out.push(' ');
 				assert!(!k.contains('='));
-				escape_bash(&k, &mut out);
+				escape_bash(k, &mut out);
 				out.push('=');
-				escape_bash(&v, &mut out);
+				escape_bash(v, &mut out);
 			}
 		}
 		if !out.is_empty() {
@@ -130,11 +285,9 @@ impl MyCommand {
 		out
 	}
 	fn into_command(self) -> Command {
-		let mut out = Command::new(self.command);
-		out.args(self.args);
-		for (k, v) in self.env {
-			out.env(k, v);
-		}
+		let mut out = Command::new(&self.command);
+		out.args(&self.args);
+		out.envs(self.exposed_env());
 		out
 	}
 	fn into_command_new(self) -> Result<Either<Command, openssh::OwningCommand<Arc<Session>>>> {
@@ -172,6 +325,16 @@ impl MyCommand {
 			.push((name.as_ref().to_owned(), value.as_ref().to_owned()));
 		self
 	}
+	/// Same as [`MyCommand::env`], but the value is never displayed, regardless of the variable name,
+	/// and is kept in locked memory until the command is spawned. Value should be valid utf-8.
+	pub fn secret_env(&mut self, name: impl AsRef<str>, value: Arc<SecretBytes>) -> &mut Self {
+		assert!(
+			value.expose_str().is_ok(),
+			"secret environment value is not utf-8"
+		);
+		self.secret_env.push((name.as_ref().to_owned(), value));
+		self
+	}
 	pub fn args<V: AsRef<OsStr>>(&mut self, args: impl IntoIterator<Item = V>) -> &mut Self {
 		for arg in args.into_iter() {
 			let arg = arg.as_ref();
@@ -179,10 +342,47 @@ impl MyCommand {
 		}
 		self
 	}
+	/// Passes data to the command stdin instead of the arguments, which are visible to other users
+	/// of the machine, i.e for secrets and credentials. Data is kept locked in memory.
+	pub fn stdin(&mut self, data: impl Into<SecretBytes>) -> &mut Self {
+		self.stdin = Some(Arc::new(data.into()));
+		self
+	}
 	pub fn sudo(mut self) -> Self {
 		self.escalate = true;
 		self
 	}
+	/// Print every nix build log line, not only the last one in the progress bar
+	pub fn stream_build_logs(mut self) -> Self {
+		self.stream_build_logs = true;
+		self
+	}
+	/// Log stdout lines as they arrive, in addition to capturing them with [`Self::run_string`],
+	/// for long-running commands, which output is also parsed
+	pub fn stream_output(mut self) -> Self {
+		self.stream_output = true;
+		self
+	}
+	/// Command only reads the state, and is executed even in dry-run mode, as its output is needed
+	/// to plan the run. Every other command is only printed in dry-run mode.
+	pub fn query(mut self) -> Self {
+		self.query = true;
+		self
+	}
+	/// Retry the command, if it fails with a known transient error (busy nix database,
+	/// substituter 5xx, dropped connection). Only idempotent commands should opt in,
+	/// i.e builds and copies, but not profile switches.
+	pub fn retry_transient(mut self) -> Self {
+		self.retry_transient = true;
+		self
+	}
+	fn nix_handler(&self) -> NixHandler {
+		if self.stream_build_logs {
+			NixHandler::streaming()
+		} else {
+			NixHandler::default()
+		}
+	}
 	fn wrap_sudo_if_needed(self) -> Self {
 		if !self.escalate {
 			return self;
@@ -217,13 +417,140 @@ impl MyCommand {
 		}
 	}
 
-	pub async fn run(self) -> Result<()> {
-		let str = self.clone().into_string();
-		let cmd = self.wrap_sudo_if_needed().into_command_new()?;
-		match cmd {
-			Either::Left(cmd) => run_nix_inner(str, cmd, &mut PlainHandler).await?,
-			Either::Right(cmd) => run_nix_inner_ssh(str, cmd, &mut PlainHandler).await?,
+	fn redacted(&self) -> Self {
+		let mut redacted = self.clone();
+		for (name, value) in &mut redacted.env {
+			if is_sensitive(name) {
+				*value = "<redacted>".to_owned();
+			}
+		}
+		for (name, _) in mem::take(&mut redacted.secret_env) {
+			redacted.env.push((name, "<redacted>".to_owned()));
+		}
+		for arg in &mut redacted.args {
+			*arg = redact_arg(arg);
+		}
+		redacted
+	}
+	/// Command as it would be executed, with escalation and ssh wrappers,
+	/// and with sensitive values redacted
+	fn display(&self) -> String {
+		let redacted = self.redacted();
+		let host = redacted.ssh_host.clone();
+		let command = redacted.wrap_sudo_if_needed().into_string();
+		match host {
+			Some(host) => format!("ssh {host} -- {command}"),
+			None => command,
+		}
+	}
+	/// In dry-run mode prints the command, and returns whether it should be skipped
+	fn dry_run_skip(&self) -> bool {
+		if !is_dry_run() {
+			return false;
+		}
+		if self.query {
+			info!("dry-run, querying: {}", self.display());
+			return false;
+		}
+		info!("dry-run, would run: {}", self.display());
+		true
+	}
+
+	async fn attempt(
+		&self,
+		mode: RunMode,
+		handler: &mut dyn Handler,
+	) -> Result<Option<SecretBytes>> {
+		let str = self.redacted().into_string();
+		let stdin = self.stdin.as_deref().map(SecretBytes::expose);
+		Ok(match mode {
+			RunMode::Plain { stdout } => {
+				let cmd = self.clone().wrap_sudo_if_needed().into_command_new()?;
+				match cmd {
+					Either::Left(cmd) => {
+						run_nix_inner_raw(
+							str,
+							cmd,
+							stdout,
+							handler,
+							None,
+							self.stream_output,
+							stdin,
+						)
+						.await?
+					}
+					Either::Right(cmd) => {
+						run_nix_inner_raw_ssh(
+							str,
+							cmd,
+							stdout,
+							handler,
+							None,
+							self.stream_output,
+							stdin,
+						)
+						.await?
+					}
+				}
+			}
+			RunMode::Nix { stdout } => {
+				let mut nix = self.clone();
+				nix.arg("--log-format").arg("internal-json");
+				let mut cmd = nix.wrap_sudo_if_needed().into_command();
+				if !stdout {
+					cmd.stdout(Stdio::inherit());
+				}
+				run_nix_inner_raw(str, cmd, stdout, handler, None, self.stream_output, stdin)
+					.await?
+			}
+		})
+	}
+	async fn run_with_retries(self, mode: RunMode) -> Result<Option<SecretBytes>> {
+		let attempts = if self.retry_transient {
+			TRANSIENT_ATTEMPTS.load(Ordering::Relaxed)
+		} else {
+			1
 		};
+		let mut attempt = 1;
+		loop {
+			let mut plain = PlainHandler;
+			let mut nix = self.nix_handler();
+			let inner: &mut dyn Handler = match mode {
+				RunMode::Plain { .. } => &mut plain,
+				RunMode::Nix { .. } => &mut nix,
+			};
+			let mut handler = TailHandler::new(inner);
+			let e = match self.attempt(mode, &mut handler).await {
+				Ok(out) => return Ok(out),
+				Err(e) => e,
+			};
+			let Some(reason) = handler.transient_reason() else {
+				return Err(e);
+			};
+			if attempt >= attempts {
+				if attempts == 1 {
+					return Err(e);
+				}
+				return Err(e.context(format!(
+					"gave up after {attempts} attempts, last failure: {reason}"
+				)));
+			}
+			let delay = backoff(attempt);
+			warn!(
+				"{reason}, retrying in {:.1}s ({attempt}/{attempts})",
+				delay.as_secs_f32()
+			);
+			sleep(delay).await;
+			attempt += 1;
+		}
+	}
+
+	pub async fn run(self) -> Result<()> {
+		if self.dry_run_skip() {
+			return Ok(());
+		}
+		self.run_with_retries(RunMode::Plain { stdout: false })
+			.await?;
 		Ok(())
 	}
 	pub async fn run_string(self) -> Result<String> {
@@ -231,28 +558,34 @@ impl MyCommand {
 		Ok(String::from_utf8(bytes)?)
 	}
 	pub async fn run_bytes(self) -> Result<Vec<u8>> {
-		let str = self.clone().into_string();
-		let cmd = self.wrap_sudo_if_needed().into_command_new()?;
-		let v = match cmd {
-			Either::Left(cmd) => run_nix_inner_stdout(str, cmd, &mut PlainHandler).await?,
-			Either::Right(cmd) => run_nix_inner_stdout_ssh(str, cmd, &mut PlainHandler).await?,
-		};
-		Ok(v)
+		Ok(self.run_secret().await?.into_unprotected())
+	}
+	/// Same as [`Self::run_bytes`], for commands printing secrets, output buffer is locked
+	/// in memory and wiped on drop
+	pub async fn run_secret(self) -> Result<SecretBytes> {
+		if self.dry_run_skip() {
+			return Ok(SecretBytes::new());
+		}
+		let out = self
+			.run_with_retries(RunMode::Plain { stdout: true })
+			.await?;
+		Ok(out.expect("has out"))
 	}
 
-	pub async fn run_nix_string(mut self) -> Result<String> {
-		let str = self.clone().into_string();
-		self.arg("--log-format").arg("internal-json");
-		let cmd = self.wrap_sudo_if_needed().into_command();
-		let bytes = run_nix_inner_stdout(str, cmd, &mut NixHandler::default()).await?;
-		Ok(String::from_utf8(bytes)?)
+	pub async fn run_nix_string(self) -> Result<String> {
+		if self.dry_run_skip() {
+			return Ok(String::new());
+		}
+		let out = self.run_with_retries(RunMode::Nix { stdout: true }).await?;
+		Ok(String::from_utf8(out.expect("has out").into_unprotected())?)
 	}
-	pub async fn run_nix(mut self) -> Result<()> {
-		let str = self.clone().into_string();
-		self.arg("--log-format").arg("internal-json");
-		let mut cmd = self.wrap_sudo_if_needed().into_command();
-		cmd.stdout(Stdio::inherit());
-		run_nix_inner(str, cmd, &mut NixHandler::default()).await
+	pub async fn run_nix(self) -> Result<()> {
+		if self.dry_run_skip() {
+			return Ok(());
+		}
+		self.run_with_retries(RunMode::Nix { stdout: false })
+			.await?;
+		Ok(())
 	}
 }
 
@@ -267,37 +600,19 @@ impl AsyncRead for EmptyAsyncRead {
 	}
 }
 
-async fn run_nix_inner_stdout(
-	str: String,
-	cmd: Command,
-	handler: &mut dyn Handler,
-) -> Result<Vec<u8>> {
-	Ok(run_nix_inner_raw(str, cmd, true, handler, None)
-		.await?
-		.expect("has out"))
+/// Writes stdin data, and closes the pipe, so that the command sees EOF
+async fn feed_stdin(mut pipe: impl AsyncWrite + Unpin, data: &[u8]) -> std::io::Result<()> {
+	pipe.write_all(data).await?;
+	pipe.shutdown().await
 }
-async fn run_nix_inner(str: String, cmd: Command, handler: &mut dyn Handler) -> Result<()> {
-	let v = run_nix_inner_raw(str, cmd, false, handler, None).await?;
-	assert!(v.is_none());
-	Ok(())
-}
-async fn run_nix_inner_stdout_ssh(
-	str: String,
-	cmd: OwningCommand<Arc<Session>>,
-	handler: &mut dyn Handler,
-) -> Result<Vec<u8>> {
-	Ok(run_nix_inner_raw_ssh(str, cmd, true, handler, None)
-		.await?
-		.expect("has out"))
-}
-async fn run_nix_inner_ssh(
-	str: String,
-	cmd: OwningCommand<Arc<Session>>,
-	handler: &mut dyn Handler,
-) -> Result<()> {
-	let v = run_nix_inner_raw_ssh(str, cmd, false, handler, None).await?;
-	assert!(v.is_none());
-	Ok(())
+
+/// Logs complete lines of the streamed output, keeping the incomplete one in `pending`
+fn stream_lines(pending: &mut Vec<u8>, chunk: &[u8]) {
+	pending.extend_from_slice(chunk);
+	while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+		let line = pending.drain(..=end).collect::<Vec<_>>();
+		info!("{}", String::from_utf8_lossy(&line).trim_end());
+	}
 }
 
 async fn run_nix_inner_raw(
@@ -306,11 +621,28 @@ async fn run_nix_inner_raw(
 	want_stdout: bool,
 	err_handler: &mut dyn Handler,
 	mut out_handler: Option<&mut dyn Handler>,
-) -> Result<Option<Vec<u8>>> {
+	stream: bool,
+	stdin: Option<&[u8]>,
+) -> Result<Option<SecretBytes>> {
 	cmd.stderr(Stdio::piped());
 	cmd.stdout(Stdio::piped());
+	if stdin.is_some() {
+		cmd.stdin(Stdio::piped());
+	}
+	// Command future might be dropped, i.e on deployment cancellation, process shouldn't outlive it
+	cmd.kill_on_drop(true);
 	debug!("running command {str:?} on local");
 	let mut child = cmd.spawn()?;
+	// Written concurrently with reading the output, as the command might not consume all of
+	// the input before its output pipes are drained
+	let pipe = child.stdin.take();
+	let mut feed = pin::pin!(async move {
+		match (pipe, stdin) {
+			(Some(pipe), Some(data)) => feed_stdin(pipe, data).await,
+			_ => Ok(()),
+		}
+	});
+	let mut fed = false;
 	let mut stderr = child.stderr.take().unwrap();
 	let stdout = child.stdout.take().unwrap();
 	let mut err = FramedRead::new(&mut stderr, LinesCodec::new());
@@ -326,9 +658,16 @@ async fn run_nix_inner_raw(
 
 	// while let Some(line) = read.next().await? {}
 
-	let mut out_buf = if want_stdout { Some(vec![]) } else { None };
+	// Output might be a decrypted secret, see [`MyCommand::run_secret`]
+	let mut out_buf = want_stdout.then(SecretBytes::new);
+	// Incomplete line of the streamed output, see [`MyCommand::stream_output`]
+	let mut pending = Vec::new();
 	loop {
 		select! {
+			r = &mut feed, if !fed => {
+				fed = true;
+				r.context("failed to write command stdin")?;
+			},
 			e = err.next() => {
 				if let Some(e) = e {
 					let e = e?;
@@ -337,7 +676,11 @@ async fn run_nix_inner_raw(
 			},
 			o = ob.next() => {
 				if let Some(o) = o {
-					out_buf.as_mut().expect("stdout == wants_stdout").extend_from_slice(&o?);
+					let o = o?;
+					if stream {
+						stream_lines(&mut pending, &o);
+					}
+					out_buf.as_mut().expect("stdout == wants_stdout").extend_from_slice(&o);
 				}
 			},
 			o = ol.next() => {
@@ -360,6 +703,9 @@ async fn run_nix_inner_raw(
 			}
 		}
 	}
+	if !pending.is_empty() {
+		stream_lines(&mut pending, b"\n");
+	}
 
 	Ok(out_buf)
 }
@@ -369,11 +715,25 @@ async fn run_nix_inner_raw_ssh(
 	want_stdout: bool,
 	err_handler: &mut dyn Handler,
 	mut out_handler: Option<&mut dyn Handler>,
-) -> Result<Option<Vec<u8>>> {
+	stream: bool,
+	stdin: Option<&[u8]>,
+) -> Result<Option<SecretBytes>> {
 	debug!("running command {str:?} over ssh");
 	cmd.stderr(openssh::Stdio::piped());
 	cmd.stdout(openssh::Stdio::piped());
+	if stdin.is_some() {
+		cmd.stdin(openssh::Stdio::piped());
+	}
 	let mut child = cmd.spawn().await?;
+	// See [`run_nix_inner_raw`]
+	let pipe = child.stdin().take();
+	let mut feed = pin::pin!(async move {
+		match (pipe, stdin) {
+			(Some(pipe), Some(data)) => feed_stdin(pipe, data).await,
+			_ => Ok(()),
+		}
+	});
+	let mut fed = false;
 	let mut stderr = child.stderr().take().unwrap();
 	let stdout = child.stdout().take().unwrap();
 	let mut err = FramedRead::new(&mut stderr, LinesCodec::new());
@@ -389,11 +749,18 @@ async fn run_nix_inner_raw_ssh(
 
 	// while let Some(line) = read.next().await? {}
 
-	let mut out_buf = if want_stdout { Some(vec![]) } else { None };
+	// Output might be a decrypted secret, see [`MyCommand::run_secret`]
+	let mut out_buf = want_stdout.then(SecretBytes::new);
+	// Incomplete line of the streamed output, see [`MyCommand::stream_output`]
+	let mut pending = Vec::new();
 
 	let mut wait_future = pin::pin!(child.wait());
 	loop {
 		select! {
+			r = &mut feed, if !fed => {
+				fed = true;
+				r.context("failed to write command stdin")?;
+			},
 			e = err.next() => {
 				if let Some(e) = e {
 					let e = e?;
@@ -402,7 +769,11 @@ async fn run_nix_inner_raw_ssh(
 			},
 			o = ob.next() => {
 				if let Some(o) = o {
-					out_buf.as_mut().expect("stdout == wants_stdout").extend_from_slice(&o?);
+					let o = o?;
+					if stream {
+						stream_lines(&mut pending, &o);
+					}
+					out_buf.as_mut().expect("stdout == wants_stdout").extend_from_slice(&o);
 				}
 			},
 			o = ol.next() => {
@@ -425,6 +796,9 @@ async fn run_nix_inner_raw_ssh(
 			}
 		}
 	}
+	if !pending.is_empty() {
+		stream_lines(&mut pending, b"\n");
+	}
 
 	Ok(out_buf)
 }
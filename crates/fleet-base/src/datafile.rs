@@ -0,0 +1,503 @@
+//! Persistence of fleet data file (`fleet.nix`/`fleet.nix.age`, `fleet.<fleet>.nix` for non-default fleets).
+//!
+//! Multiple fleet invocations might work on the same project concurrently, on save, file is locked,
+//! and changes made by other invocations since the load are merged with ours. Secrets are merged
+//! as a whole, conflict is only reported when the same entry was changed differently.
+//!
+//! Data might also be stored in the split layout (`fleet.d/`, `fleet.<fleet>.d/`), where every
+//! secret is stored in its own file, to keep diffs small:
+//! - `fleet.nix` - everything except secrets
+//! - `shared/<secret>.nix` - shared secrets
+//! - `hosts/<host>/<secret>.nix` - host secrets
+//!
+//! In this layout, raw data is a bundle of all the files, so that the rest of fleet doesn't
+//! need to know which layout is used.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs::{self, File},
+	io::Write as _,
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::ValueEnum;
+use nix::fcntl::{Flock, FlockArg};
+use serde_json::{Map, Value};
+use tempfile::NamedTempFile;
+
+use crate::{fleetdata::FleetData, keys::IdentityStore, migrate, sealed};
+
+const LOCK_FILE: &str = ".fleet.lock";
+pub const SPLIT_DIR: &str = "fleet.d";
+const SHARED_DIR: &str = "shared";
+const HOSTS_DIR: &str = "hosts";
+/// Prefix of the raw data in the split layout, followed by json map of relative path to contents
+const SPLIT_MAGIC: &[u8] = b"# fleet split data\n";
+const HEADER: &str = "# This file contains fleet state and shouldn't be edited by hand\n\n";
+const FOOTER: &str = "\n\n# vim: ts=2 et nowrap\n";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Layout {
+	/// Single `fleet.nix` file
+	Single,
+	/// Directory with a file per secret
+	Split,
+}
+
+fn split_dir(directory: &Path, fleet: &str) -> PathBuf {
+	directory.join(sealed::fleet_file(fleet, SPLIT_DIR))
+}
+
+/// Split layout is used if its directory exists
+pub fn layout(directory: &Path, fleet: &str) -> Layout {
+	if split_dir(directory, fleet).is_dir() {
+		Layout::Split
+	} else {
+		Layout::Single
+	}
+}
+
+/// Layout of the raw data, see [`read_raw`]
+pub fn raw_layout(raw: &[u8]) -> Layout {
+	if raw.starts_with(SPLIT_MAGIC) {
+		Layout::Split
+	} else {
+		Layout::Single
+	}
+}
+
+/// Data file, as it was on disk when it was last loaded or saved
+pub struct DataBase {
+	/// Raw file contents, to quickly check if file was modified
+	pub raw: Vec<u8>,
+	pub value: Value,
+}
+
+/// Exclusive advisory lock of the fleet data, released on drop
+pub fn lock(directory: &Path) -> Result<Flock<File>> {
+	let file = File::create(directory.join(LOCK_FILE)).context("failed to create lock file")?;
+	Flock::lock(file, FlockArg::LockExclusive)
+		.map_err(|(_, e)| e)
+		.context("failed to lock fleet data")
+}
+
+fn single_path(directory: &Path, fleet: &str, is_sealed: bool) -> PathBuf {
+	directory.join(sealed::fleet_file(
+		fleet,
+		if is_sealed {
+			sealed::SEALED_FILE
+		} else {
+			sealed::PLAIN_FILE
+		},
+	))
+}
+
+/// Data file, or top-level file of the split layout, which is touched on every change
+pub fn data_path(directory: &Path, fleet: &str, is_sealed: bool) -> PathBuf {
+	match layout(directory, fleet) {
+		Layout::Single => single_path(directory, fleet, is_sealed),
+		Layout::Split => split_dir(directory, fleet).join(sealed::PLAIN_FILE),
+	}
+}
+
+/// Relative paths of data files in the split layout
+fn split_files(dir: &Path) -> Result<Vec<String>> {
+	fn walk(dir: &Path, prefix: &str, out: &mut Vec<String>) -> Result<()> {
+		for entry in fs::read_dir(dir).with_context(|| format!("failed to read {dir:?}"))? {
+			let entry = entry?;
+			let Some(name) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+				continue;
+			};
+			let rel = format!("{prefix}{name}");
+			if entry.file_type()?.is_dir() {
+				walk(&entry.path(), &format!("{rel}/"), out)?;
+			} else if name.ends_with(".nix") {
+				out.push(rel);
+			}
+		}
+		Ok(())
+	}
+	let mut out = Vec::new();
+	walk(dir, "", &mut out)?;
+	out.sort();
+	Ok(out)
+}
+
+pub fn read_raw(directory: &Path, fleet: &str, is_sealed: bool) -> Result<Vec<u8>> {
+	if layout(directory, fleet) == Layout::Split {
+		ensure!(
+			!is_sealed,
+			"fleet data is both sealed and split, remove one of the layouts"
+		);
+		let dir = split_dir(directory, fleet);
+		let mut files = BTreeMap::new();
+		for rel in split_files(&dir)? {
+			let path = dir.join(&rel);
+			let text = fs::read_to_string(&path)
+				.with_context(|| format!("failed to read fleet data from {path:?}"))?;
+			files.insert(rel, text);
+		}
+		let mut raw = SPLIT_MAGIC.to_vec();
+		serde_json::to_writer(&mut raw, &files)?;
+		return Ok(raw);
+	}
+	let path = data_path(directory, fleet, is_sealed);
+	fs::read(&path).with_context(|| format!("failed to read fleet data from {path:?}"))
+}
+
+fn parse_split(raw: &[u8]) -> Result<Value> {
+	let files: BTreeMap<String, String> =
+		serde_json::from_slice(raw).context("split fleet data bundle is invalid")?;
+	let root = files
+		.get(sealed::PLAIN_FILE)
+		.context("split fleet data has no fleet.nix")?;
+	let mut value: Value = nixlike::parse_str(root)?;
+	let out = value
+		.as_object_mut()
+		.context("fleet data is not an attribute set")?;
+	for (rel, text) in &files {
+		if rel == sealed::PLAIN_FILE {
+			continue;
+		}
+		let secret: Value =
+			nixlike::parse_str(text).with_context(|| format!("failed to parse {rel}"))?;
+		let parts = rel
+			.strip_suffix(".nix")
+			.expect("only nix files are read")
+			.split('/')
+			.collect::<Vec<_>>();
+		let (field, host, name) = match parts.as_slice() {
+			[SHARED_DIR, name] => ("sharedSecrets", None, *name),
+			[HOSTS_DIR, host, name] => ("hostSecrets", Some(*host), *name),
+			_ => bail!("unexpected file in split fleet data: {rel}"),
+		};
+		let mut map = out
+			.entry(field)
+			.or_insert_with(|| Value::Object(Map::new()))
+			.as_object_mut()
+			.with_context(|| format!("{field} is not an attribute set"))?;
+		if let Some(host) = host {
+			map = map
+				.entry(host)
+				.or_insert_with(|| Value::Object(Map::new()))
+				.as_object_mut()
+				.expect("inserted as object");
+		}
+		map.insert(name.to_owned(), secret);
+	}
+	Ok(value)
+}
+
+/// Decrypts and parses data file without interpreting it, data might be of an older version
+pub fn parse_value(raw: &[u8], is_sealed: bool, identities: &IdentityStore) -> Result<Value> {
+	if let Some(raw) = raw.strip_prefix(SPLIT_MAGIC) {
+		return parse_split(raw);
+	}
+	let text = if is_sealed {
+		let identities = identities
+			.identities()
+			.context("fleet data is sealed, admin identity is required")?;
+		String::from_utf8(sealed::unseal(raw, identities)?).context("sealed fleet data is not utf-8")?
+	} else {
+		String::from_utf8(raw.to_vec()).context("fleet data is not utf-8")?
+	};
+	Ok(nixlike::parse_str(&text)?)
+}
+
+/// Parses data file, upgrading it to the current version in memory, see [`migrate`]
+pub fn parse(raw: &[u8], is_sealed: bool, identities: &IdentityStore) -> Result<FleetData> {
+	let mut value = parse_value(raw, is_sealed, identities)?;
+	migrate::upgrade(&mut value)?;
+	serde_json::from_value(value).context("fleet data is invalid")
+}
+
+/// Secret and host names are used as file names in the split layout
+fn check_file_name(name: &str) -> Result<()> {
+	ensure!(
+		!name.is_empty() && !name.starts_with('.') && !name.contains('/'),
+		"{name:?} can't be stored in the split fleet data layout"
+	);
+	Ok(())
+}
+
+fn encode_split(data: &FleetData) -> Result<Vec<u8>> {
+	let mut value = serde_json::to_value(data)?;
+	let root = value.as_object_mut().expect("fleet data is a struct");
+	let shared = root.remove("sharedSecrets");
+	let host_secrets = root.remove("hostSecrets");
+
+	let mut files = BTreeMap::new();
+	files.insert(
+		sealed::PLAIN_FILE.to_owned(),
+		format!("{HEADER}{}{FOOTER}", nixlike::serialize(&value)?),
+	);
+	let as_object = |v: Option<Value>| match v {
+		Some(Value::Object(o)) => o,
+		_ => Map::new(),
+	};
+	for (name, secret) in as_object(shared) {
+		check_file_name(&name)?;
+		files.insert(
+			format!("{SHARED_DIR}/{name}.nix"),
+			format!("{HEADER}{}{FOOTER}", nixlike::serialize(secret)?),
+		);
+	}
+	for (host, secrets) in as_object(host_secrets) {
+		check_file_name(&host)?;
+		for (name, secret) in as_object(Some(secrets)) {
+			check_file_name(&name)?;
+			files.insert(
+				format!("{HOSTS_DIR}/{host}/{name}.nix"),
+				format!("{HEADER}{}{FOOTER}", nixlike::serialize(secret)?),
+			);
+		}
+	}
+	let mut raw = SPLIT_MAGIC.to_vec();
+	serde_json::to_writer(&mut raw, &files)?;
+	Ok(raw)
+}
+
+/// Serializes data in the on-disk format of the current layout, sealed data is encrypted to the recipients
+pub fn encode(data: &FleetData, directory: &Path, fleet: &str, is_sealed: bool) -> Result<Vec<u8>> {
+	encode_as(data, directory, fleet, is_sealed, layout(directory, fleet))
+}
+
+pub fn encode_as(
+	data: &FleetData,
+	directory: &Path,
+	fleet: &str,
+	is_sealed: bool,
+	layout: Layout,
+) -> Result<Vec<u8>> {
+	if layout == Layout::Split {
+		ensure!(
+			!is_sealed,
+			"split fleet data can't be sealed, run `fleet migrate-storage single` first"
+		);
+		return encode_split(data);
+	}
+	let data = nixlike::serialize(data)?;
+	let data = format!("{HEADER}{data}{FOOTER}");
+	if is_sealed {
+		let recipients = sealed::read_recipients(directory, fleet)?;
+		sealed::seal(data.as_bytes(), recipients)
+	} else {
+		Ok(data.into_bytes())
+	}
+}
+
+/// Only changed files are rewritten, files of removed secrets are removed
+fn write_split(dir: &Path, raw: &[u8]) -> Result<()> {
+	let files: BTreeMap<String, String> =
+		serde_json::from_slice(raw).context("split fleet data bundle is invalid")?;
+	fs::create_dir_all(dir).with_context(|| format!("failed to create {dir:?}"))?;
+	let mut changed = false;
+	for (rel, text) in &files {
+		let path = dir.join(rel);
+		if fs::read(&path).is_ok_and(|old| old == text.as_bytes()) {
+			continue;
+		}
+		let parent = path.parent().expect("file is in the data directory");
+		fs::create_dir_all(parent)?;
+		let mut tempfile = NamedTempFile::new_in(parent)
+			.with_context(|| format!("failed to create updated version of {path:?}"))?;
+		tempfile.write_all(text.as_bytes())?;
+		tempfile.persist(&path)?;
+		changed = true;
+	}
+	let expected = files.keys().collect::<BTreeSet<_>>();
+	for rel in split_files(dir)? {
+		if expected.contains(&rel) {
+			continue;
+		}
+		let path = dir.join(&rel);
+		fs::remove_file(&path).with_context(|| format!("failed to remove {path:?}"))?;
+		// Fails if there are other files in the host directory
+		let _ = fs::remove_dir(path.parent().expect("file is in the data directory"));
+		changed = true;
+	}
+	// Top-level file mtime is used to detect changes, see [`data_path`]
+	if changed {
+		File::options()
+			.append(true)
+			.open(dir.join(sealed::PLAIN_FILE))?
+			.set_modified(SystemTime::now())?;
+	}
+	Ok(())
+}
+
+/// Atomically replaces data file, caller should hold the [`lock`]
+///
+/// Layout is determined by the raw data, see [`encode_as`].
+pub fn write(directory: &Path, fleet: &str, is_sealed: bool, raw: &[u8]) -> Result<()> {
+	if let Some(raw) = raw.strip_prefix(SPLIT_MAGIC) {
+		return write_split(&split_dir(directory, fleet), raw);
+	}
+	let mut tempfile = NamedTempFile::new_in(directory).context("failed to create updated version of fleet.nix in the same directory as original.\nDo you have write access to it? Access only to the fleet.nix won't be enough, the directory is used for atomic overwrite operation.\nIt is not recommended to use fleet by root anyway, move fleet project to your home directory.")?;
+	tempfile.write_all(raw)?;
+	tempfile.persist(single_path(directory, fleet, is_sealed))?;
+	Ok(())
+}
+
+/// Converts fleet data to the specified layout, returns false if it is already in this layout.
+///
+/// Data should be of the current version, see [`migrate::upgrade_file`].
+pub fn convert(
+	directory: &Path,
+	fleet: &str,
+	identities: &IdentityStore,
+	target: Layout,
+) -> Result<bool> {
+	let _lock = lock(directory)?;
+	if layout(directory, fleet) == target {
+		return Ok(false);
+	}
+	let is_sealed = sealed::is_sealed(directory, fleet);
+	let raw = read_raw(directory, fleet, is_sealed)?;
+	let data = parse(&raw, is_sealed, identities)?;
+	let raw = encode_as(&data, directory, fleet, is_sealed, target)?;
+	write(directory, fleet, is_sealed, &raw)?;
+	match target {
+		Layout::Split => fs::remove_file(single_path(directory, fleet, is_sealed))?,
+		Layout::Single => fs::remove_dir_all(split_dir(directory, fleet))?,
+	}
+	Ok(true)
+}
+
+/// Only top-level maps, and per-host secret maps are merged by key,
+/// everything else (i.e secrets themselves) is replaced atomically
+fn mergeable(path: &[String]) -> bool {
+	match path {
+		[] | [_] => true,
+		[first, _] => first == "hostSecrets",
+		_ => false,
+	}
+}
+
+fn merge_value(
+	path: &mut Vec<String>,
+	base: Option<&Value>,
+	ours: Option<&Value>,
+	theirs: Option<&Value>,
+	conflicts: &mut Vec<String>,
+) -> Option<Value> {
+	if ours == theirs || theirs == base {
+		return ours.cloned();
+	}
+	if ours == base {
+		return theirs.cloned();
+	}
+	let empty = Map::new();
+	let as_object = |v: Option<&Value>| match v {
+		None => Some(&empty),
+		Some(Value::Object(o)) => Some(o),
+		Some(_) => None,
+	};
+	if mergeable(path) {
+		if let (Some(base), Some(ours), Some(theirs)) =
+			(as_object(base), as_object(ours), as_object(theirs))
+		{
+			let mut keys = ours.keys().chain(theirs.keys()).collect::<Vec<_>>();
+			keys.sort();
+			keys.dedup();
+			let mut out = Map::new();
+			for key in keys {
+				path.push(key.clone());
+				let merged = merge_value(path, base.get(key), ours.get(key), theirs.get(key), conflicts);
+				path.pop();
+				if let Some(merged) = merged {
+					out.insert(key.clone(), merged);
+				}
+			}
+			return Some(Value::Object(out));
+		}
+	}
+	conflicts.push(if path.is_empty() {
+		"<root>".to_owned()
+	} else {
+		path.join(".")
+	});
+	ours.cloned()
+}
+
+/// Three-way merge of fleet data, fails if the same entry was changed both by us and by them
+pub fn merge(base: &Value, ours: &Value, theirs: &Value) -> Result<Value> {
+	let mut conflicts = Vec::new();
+	let merged = merge_value(
+		&mut Vec::new(),
+		Some(base),
+		Some(ours),
+		Some(theirs),
+		&mut conflicts,
+	);
+	if !conflicts.is_empty() {
+		bail!(
+			"fleet data was concurrently modified by another fleet invocation, conflicting entries:\n{}",
+			conflicts.join("\n")
+		);
+	}
+	Ok(merged.expect("root is always present"))
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[test]
+	fn split_roundtrip() {
+		let value = json!({
+			"version": migrate::CURRENT_VERSION,
+			"hosts": {"a": {"encryptionKey": "age1"}},
+			"sharedSecrets": {"x": {"owners": ["a"], "createdAt": "2024-01-01T00:00:00Z"}},
+			"hostSecrets": {"a": {"y": {"createdAt": "2024-01-01T00:00:00Z"}}},
+		});
+		let data: FleetData = serde_json::from_value(value).unwrap();
+		let raw = encode_split(&data).unwrap();
+		let files: BTreeMap<String, String> =
+			serde_json::from_slice(raw.strip_prefix(SPLIT_MAGIC).unwrap()).unwrap();
+		assert_eq!(
+			files.keys().collect::<Vec<_>>(),
+			["fleet.nix", "hosts/a/y.nix", "shared/x.nix"]
+		);
+		let parsed = parse(&raw, false, &IdentityStore::new(None, None)).unwrap();
+		assert_eq!(
+			serde_json::to_value(&parsed).unwrap(),
+			serde_json::to_value(&data).unwrap()
+		);
+	}
+
+	#[test]
+	fn independent_additions() {
+		let base = json!({"version": "0.1.0", "hostSecrets": {"a": {"x": {"v": 1}}}});
+		let ours = json!({"version": "0.1.0", "hostSecrets": {"a": {"x": {"v": 1}, "y": {"v": 2}}}});
+		let theirs = json!({"version": "0.1.0", "hostSecrets": {"a": {"x": {"v": 1}}, "b": {"z": {"v": 3}}}});
+		assert_eq!(
+			merge(&base, &ours, &theirs).unwrap(),
+			json!({"version": "0.1.0", "hostSecrets": {"a": {"x": {"v": 1}, "y": {"v": 2}}, "b": {"z": {"v": 3}}}})
+		);
+	}
+
+	#[test]
+	fn removal() {
+		let base = json!({"sharedSecrets": {"x": {"v": 1}, "y": {"v": 2}}});
+		let ours = json!({"sharedSecrets": {"y": {"v": 2}}});
+		let theirs = json!({"sharedSecrets": {"x": {"v": 1}, "y": {"v": 2}, "z": {"v": 3}}});
+		assert_eq!(
+			merge(&base, &ours, &theirs).unwrap(),
+			json!({"sharedSecrets": {"y": {"v": 2}, "z": {"v": 3}}})
+		);
+	}
+
+	#[test]
+	fn conflict() {
+		let base = json!({"sharedSecrets": {"x": {"v": 1, "owners": ["a"]}}});
+		let ours = json!({"sharedSecrets": {"x": {"v": 2, "owners": ["a"]}}});
+		let theirs = json!({"sharedSecrets": {"x": {"v": 1, "owners": ["b"]}}});
+		let err = merge(&base, &ours, &theirs).unwrap_err();
+		assert!(err.to_string().ends_with("sharedSecrets.x"));
+	}
+}
@@ -0,0 +1,42 @@
+//! Evaluation results, memoized for the whole run.
+//!
+//! Commands list hosts, filter them by tags, and enumerate their secrets multiple times,
+//! every such query is a nix evaluation roundtrip. Only results, which don't depend on
+//! fleet data are memoized, as data might be updated during the run.
+//!
+//! Nix values are bound to the session they were evaluated in, and thus are only memoized
+//! for the main session, see [`crate::host::Config::host`]. Nix arguments are fixed for the
+//! whole run, so they are not a part of the key.
+
+use std::{
+	collections::BTreeMap,
+	sync::{Mutex, MutexGuard},
+};
+
+use nix_eval::Value;
+
+use crate::host::Platform;
+
+#[derive(Default)]
+pub(crate) struct Memoized {
+	pub host_names: Option<Vec<String>>,
+	pub configured_shared: Option<Vec<String>>,
+	/// `hosts.<name>`
+	pub host_fields: BTreeMap<String, Value>,
+	/// `hosts.<name>.nixos.config`, with warnings checked
+	pub nixos_configs: BTreeMap<String, Value>,
+	pub tags: BTreeMap<String, Vec<String>>,
+	pub platforms: BTreeMap<String, Platform>,
+	pub configured_secrets: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Default)]
+pub struct EvalCache {
+	memoized: Mutex<Memoized>,
+}
+
+impl EvalCache {
+	pub(crate) fn get(&self) -> MutexGuard<'_, Memoized> {
+		self.memoized.lock().unwrap()
+	}
+}
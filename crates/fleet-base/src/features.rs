@@ -0,0 +1,27 @@
+//! Project-wide feature flags, declared in `features` fleet option.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use tracing::warn;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Features {
+	pub require_signatures: bool,
+	pub detect_local_host_by_key: bool,
+	pub strict_host_keys: bool,
+	/// Flags introduced by newer fleet versions
+	#[serde(flatten)]
+	pub unknown: BTreeMap<String, bool>,
+}
+
+impl Features {
+	pub fn warn_unknown(&self) {
+		for (name, enabled) in &self.unknown {
+			if *enabled {
+				warn!("fleet feature {name} is enabled, but not supported by this fleet version, consider upgrading");
+			}
+		}
+	}
+}
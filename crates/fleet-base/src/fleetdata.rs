@@ -1,24 +1,40 @@
 use std::{
 	collections::BTreeMap,
 	io::{self, Cursor},
+	sync::{Arc, LazyLock},
+	thread::available_parallelism,
 };
 
-use age::Recipient;
+use age::{Decryptor, Recipient};
+use anyhow::{bail, Result};
 use chrono::{DateTime, Utc};
 use fleet_shared::SecretData;
 use itertools::Itertools;
 use serde::{de::Error, Deserialize, Serialize};
 use serde_json::Value;
+use tokio::{sync::Semaphore, task::spawn_blocking};
 
-#[derive(Serialize, Deserialize, Default)]
+use crate::{attribution::Attribution, sealed::BoxedIdentity, secret_bytes::SecretBytes};
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct HostData {
 	#[serde(default)]
 	#[serde(skip_serializing_if = "String::is_empty")]
 	pub encryption_key: String,
+	/// Trusted ssh host keys in openssh format, connections to the host are only accepted
+	/// if it presents one of them, see `fleet host trust`
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub host_keys: Vec<String>,
+	/// Admins, who have added and last modified the host entry, see [`crate::attribution`]
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub attribution: Option<Attribution>,
 }
 
-const VERSION: &str = "0.1.0";
+const VERSION: &str = crate::migrate::CURRENT_VERSION;
+/// Data is upgraded to the current version before deserialization, see [`crate::migrate`]
 pub struct FleetDataVersion;
 impl Serialize for FleetDataVersion {
 	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -36,7 +52,7 @@ impl<'de> Deserialize<'de> for FleetDataVersion {
 		let version = String::deserialize(deserializer)?;
 		if version != VERSION {
 			return Err(D::Error::custom(format!(
-				"fleet.nix data version mismatch, expected {VERSION}, got {version}.\nFollow the docs for migration instruction"
+				"fleet.nix data version mismatch, expected {VERSION}, got {version}.\nRun `fleet migrate` to upgrade it"
 			)));
 		}
 		Ok(Self)
@@ -68,6 +84,11 @@ pub struct FleetData {
 #[must_use]
 pub struct FleetSharedSecret {
 	pub owners: Vec<String>,
+	/// Keys of team members, secret is encrypted to in addition to owners,
+	/// see [`crate::access::SecretAccess`]
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub readers: Vec<String>,
 	#[serde(flatten)]
 	pub secret: FleetSecret,
 }
@@ -75,17 +96,26 @@ pub struct FleetSharedSecret {
 /// Returns None if recipients.is_empty()
 pub fn encrypt_secret_data(
 	recipients: impl IntoIterator<Item = impl Recipient + Send + 'static>,
-	data: Vec<u8>,
+	data: impl Into<SecretBytes>,
 ) -> Option<SecretData> {
-	let mut encrypted = vec![];
 	let recipients = recipients
 		.into_iter()
 		.map(|v| Box::new(v) as Box<dyn Recipient + Send>)
 		.collect_vec();
+	encrypt_secret_data_to(recipients, data)
+}
+
+/// Same as [`encrypt_secret_data`], for recipients of different kinds
+pub fn encrypt_secret_data_to(
+	recipients: Vec<Box<dyn Recipient + Send>>,
+	data: impl Into<SecretBytes>,
+) -> Option<SecretData> {
+	let data = data.into();
+	let mut encrypted = vec![];
 	let mut encryptor = age::Encryptor::with_recipients(recipients)?
 		.wrap_output(&mut encrypted)
 		.expect("in memory write");
-	io::copy(&mut Cursor::new(data), &mut encryptor).expect("in memory copy");
+	io::copy(&mut data.expose(), &mut encryptor).expect("in memory copy");
 	encryptor.finish().expect("in memory flush");
 	Some(SecretData {
 		data: encrypted,
@@ -93,6 +123,47 @@ pub fn encrypt_secret_data(
 	})
 }
 
+/// Age operations are CPU-bound, so they are moved off the async runtime to the blocking pool,
+/// at most one operation per CPU is performed at once.
+static CRYPTO_PERMITS: LazyLock<Semaphore> =
+	LazyLock::new(|| Semaphore::new(available_parallelism().map_or(4, |n| n.get())));
+
+/// Runs age operation on the crypto worker pool
+pub async fn spawn_crypto<T: Send + 'static>(op: impl FnOnce() -> T + Send + 'static) -> T {
+	let _permit = CRYPTO_PERMITS
+		.acquire()
+		.await
+		.expect("semaphore is never closed");
+	spawn_blocking(op).await.expect("crypto operation panicked")
+}
+
+/// Same as [`encrypt_secret_data_to`], performed on the crypto worker pool
+pub async fn encrypt_secret_data_async(
+	recipients: Vec<Box<dyn Recipient + Send>>,
+	data: impl Into<SecretBytes>,
+) -> Option<SecretData> {
+	let data = data.into();
+	spawn_crypto(move || encrypt_secret_data_to(recipients, data)).await
+}
+
+/// Decrypts binary age data, i.e secret part encrypted with [`encrypt_secret_data`]
+pub fn decrypt_secret_data(identities: &[BoxedIdentity], data: &[u8]) -> Result<SecretBytes> {
+	let Decryptor::Recipients(decryptor) = Decryptor::new(Cursor::new(data))? else {
+		bail!("secret should be encrypted to recipients");
+	};
+	let mut reader =
+		decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))?;
+	Ok(SecretBytes::read_from(reader)?)
+}
+
+/// Same as [`decrypt_secret_data`], performed on the crypto worker pool
+pub async fn decrypt_secret_data_async(
+	identities: Arc<[BoxedIdentity]>,
+	data: Vec<u8>,
+) -> Result<SecretBytes> {
+	spawn_crypto(move || decrypt_secret_data(&identities, &data)).await
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FleetSecretPart {
 	pub raw: SecretData,
@@ -107,6 +178,14 @@ pub struct FleetSecret {
 	#[serde(default)]
 	#[serde(skip_serializing_if = "Option::is_none", alias = "expire_at")]
 	pub expires_at: Option<DateTime<Utc>>,
+	/// When the secret was delivered to the installer by `fleet init-host`
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub bootstrapped_at: Option<DateTime<Utc>>,
+	/// Admins, who have created and last modified the secret, see [`crate::attribution`]
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub attribution: Option<Attribution>,
 
 	#[serde(flatten)]
 	pub parts: BTreeMap<String, FleetSecretPart>,
@@ -0,0 +1,332 @@
+//! Local history of fleet data, see `fleet data log` and `fleet data undo`.
+//!
+//! Every saved version of the data file is kept as a zstd-compressed snapshot in
+//! `.fleet/history/<fleet>/`, in the same raw form as it is stored on disk, so sealed data
+//! stays encrypted. Only the last [`HISTORY_LIMIT`] revisions are kept.
+
+use std::{
+	fs,
+	io::Write as _,
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+	thread,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use tempfile::NamedTempFile;
+
+use crate::datafile::{self, Layout};
+
+const HISTORY_LIMIT: usize = 100;
+const EXTENSION: &str = "zst";
+
+/// Storage mode of the snapshot, data can only be restored in the same mode
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+	Plain,
+	Sealed,
+	Split,
+}
+impl Kind {
+	pub fn of(raw: &[u8], is_sealed: bool) -> Self {
+		match (datafile::raw_layout(raw), is_sealed) {
+			(Layout::Split, _) => Self::Split,
+			(Layout::Single, true) => Self::Sealed,
+			(Layout::Single, false) => Self::Plain,
+		}
+	}
+	fn name(self) -> &'static str {
+		match self {
+			Self::Plain => "plain",
+			Self::Sealed => "sealed",
+			Self::Split => "split",
+		}
+	}
+	fn parse(name: &str) -> Option<Self> {
+		Some(match name {
+			"plain" => Self::Plain,
+			"sealed" => Self::Sealed,
+			"split" => Self::Split,
+			_ => return None,
+		})
+	}
+	pub fn is_sealed(self) -> bool {
+		self == Self::Sealed
+	}
+}
+
+pub struct Revision {
+	pub id: u64,
+	pub time: DateTime<Utc>,
+	pub kind: Kind,
+	path: PathBuf,
+}
+impl Revision {
+	/// File name is `<id>-<unix timestamp>-<kind>.zst`
+	fn parse(path: PathBuf) -> Option<Self> {
+		let name = path.file_name()?.to_str()?;
+		let name = name.strip_suffix(EXTENSION)?.strip_suffix('.')?;
+		let mut parts = name.splitn(3, '-');
+		let id = parts.next()?.parse().ok()?;
+		let time = DateTime::from_timestamp(parts.next()?.parse().ok()?, 0)?;
+		let kind = Kind::parse(parts.next()?)?;
+		Some(Self {
+			id,
+			time,
+			kind,
+			path,
+		})
+	}
+	/// Raw data file contents
+	pub fn read(&self) -> Result<Vec<u8>> {
+		let compressed =
+			fs::read(&self.path).with_context(|| format!("failed to read revision {}", self.id))?;
+		zstd(&["-d"], &compressed)
+			.with_context(|| format!("failed to decompress revision {}", self.id))
+	}
+}
+
+fn history_dir(directory: &Path, fleet: &str) -> PathBuf {
+	directory.join(".fleet/history").join(fleet)
+}
+
+/// Runs zstd binary, passing the input through stdin
+fn zstd(args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+	let mut child = Command::new("zstd")
+		.arg("-q")
+		.arg("-c")
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.context("failed to run zstd, is it installed?")?;
+	let mut stdin = child.stdin.take().expect("piped");
+	// Output is read concurrently, otherwise both processes might block on full pipes
+	let output = thread::scope(|s| {
+		let writer = s.spawn(move || stdin.write_all(input));
+		let output = child.wait_with_output();
+		let written = writer.join().expect("writer doesn't panic");
+		output.map(|output| (output, written))
+	})?;
+	let (output, written) = output;
+	if !output.status.success() {
+		bail!(
+			"zstd has failed: {}",
+			String::from_utf8_lossy(&output.stderr).trim()
+		);
+	}
+	written.context("failed to pass data to zstd")?;
+	Ok(output.stdout)
+}
+
+/// Revisions, oldest first
+pub fn list(directory: &Path, fleet: &str) -> Result<Vec<Revision>> {
+	let dir = history_dir(directory, fleet);
+	if !dir.exists() {
+		return Ok(vec![]);
+	}
+	let mut out = Vec::new();
+	for entry in fs::read_dir(&dir).with_context(|| format!("failed to read {dir:?}"))? {
+		if let Some(revision) = Revision::parse(entry?.path()) {
+			out.push(revision);
+		}
+	}
+	out.sort_by_key(|r| r.id);
+	Ok(out)
+}
+
+fn write_revision(dir: &Path, id: u64, kind: Kind, raw: &[u8]) -> Result<()> {
+	let compressed = zstd(&["-19"], raw)?;
+	let name = format!(
+		"{id:06}-{}-{}.{EXTENSION}",
+		Utc::now().timestamp(),
+		kind.name()
+	);
+	let mut file = NamedTempFile::new_in(dir)?;
+	file.write_all(&compressed)?;
+	file.persist(dir.join(name))?;
+	Ok(())
+}
+
+/// Records the new version of the data file, caller should hold the [`datafile::lock`].
+///
+/// Previous version is recorded too, if it is not the latest revision, i.e on the first save,
+/// or if the file was edited by hand.
+pub fn record(
+	directory: &Path,
+	fleet: &str,
+	is_sealed: bool,
+	previous: &[u8],
+	raw: &[u8],
+) -> Result<()> {
+	let dir = history_dir(directory, fleet);
+	fs::create_dir_all(&dir).with_context(|| format!("failed to create {dir:?}"))?;
+	let revisions = list(directory, fleet)?;
+	let latest = revisions.last().map(Revision::read).transpose()?;
+	let mut next = revisions.last().map_or(1, |r| r.id + 1);
+	let mut latest = latest.as_deref();
+	if !previous.is_empty() && latest != Some(previous) {
+		write_revision(&dir, next, Kind::of(previous, is_sealed), previous)?;
+		next += 1;
+		latest = Some(previous);
+	}
+	if latest != Some(raw) {
+		write_revision(&dir, next, Kind::of(raw, is_sealed), raw)?;
+	}
+
+	let revisions = list(directory, fleet)?;
+	let outdated = revisions.len().saturating_sub(HISTORY_LIMIT);
+	for revision in &revisions[..outdated] {
+		fs::remove_file(&revision.path)
+			.with_context(|| format!("failed to remove revision {}", revision.id))?;
+	}
+	Ok(())
+}
+
+/// Revision, which should be restored by undo: the specified one, or the one before the latest
+pub fn undo_target(revisions: &[Revision], id: Option<u64>) -> Result<&Revision> {
+	if let Some(id) = id {
+		return revisions
+			.iter()
+			.find(|r| r.id == id)
+			.with_context(|| format!("revision {id} is not in the history"));
+	}
+	ensure!(
+		revisions.len() >= 2,
+		"fleet data history has no previous revisions"
+	);
+	Ok(&revisions[revisions.len() - 2])
+}
+
+fn entries(value: &Value, field: &str) -> Map<String, Value> {
+	value
+		.get(field)
+		.and_then(Value::as_object)
+		.cloned()
+		.unwrap_or_default()
+}
+
+fn diff_secrets(
+	old: &Map<String, Value>,
+	new: &Map<String, Value>,
+	what: &str,
+	out: &mut Vec<String>,
+) {
+	for (name, secret) in new {
+		match old.get(name) {
+			None => out.push(format!("{what} {name} added")),
+			Some(old) if old == secret => {}
+			Some(old) if old.get("createdAt") != secret.get("createdAt") => {
+				out.push(format!("{what} {name} rotated"))
+			}
+			Some(_) => out.push(format!("{what} {name} updated")),
+		}
+	}
+	for name in old.keys() {
+		if !new.contains_key(name) {
+			out.push(format!("{what} {name} removed"));
+		}
+	}
+}
+
+/// Human-readable changes between two versions of fleet data, see [`datafile::parse_value`]
+pub fn diff(old: &Value, new: &Value) -> Vec<String> {
+	let mut out = Vec::new();
+	if old.get("version") != new.get("version") {
+		out.push(format!(
+			"version {} -> {}",
+			old.get("version").unwrap_or(&Value::Null),
+			new.get("version").unwrap_or(&Value::Null)
+		));
+	}
+
+	let (old_hosts, new_hosts) = (entries(old, "hosts"), entries(new, "hosts"));
+	for (name, host) in &new_hosts {
+		match old_hosts.get(name) {
+			None => out.push(format!("host {name} added")),
+			Some(old) if old != host => out.push(format!("host {name} updated")),
+			Some(_) => {}
+		}
+	}
+	for name in old_hosts.keys() {
+		if !new_hosts.contains_key(name) {
+			out.push(format!("host {name} removed"));
+		}
+	}
+
+	diff_secrets(
+		&entries(old, "sharedSecrets"),
+		&entries(new, "sharedSecrets"),
+		"shared secret",
+		&mut out,
+	);
+	let (old_host_secrets, new_host_secrets) =
+		(entries(old, "hostSecrets"), entries(new, "hostSecrets"));
+	let mut hosts = old_host_secrets
+		.keys()
+		.chain(new_host_secrets.keys())
+		.collect::<Vec<_>>();
+	hosts.sort();
+	hosts.dedup();
+	for host in hosts {
+		let secrets = |v: &Map<String, Value>| {
+			v.get(host)
+				.and_then(Value::as_object)
+				.cloned()
+				.unwrap_or_default()
+		};
+		diff_secrets(
+			&secrets(&old_host_secrets),
+			&secrets(&new_host_secrets),
+			&format!("{host} secret"),
+			&mut out,
+		);
+	}
+
+	if entries(old, "extra") != entries(new, "extra") {
+		out.push("extra data updated".to_owned());
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[test]
+	fn revision_names() {
+		let revision = Revision::parse(PathBuf::from("/h/000012-1700000000-sealed.zst")).unwrap();
+		assert_eq!(revision.id, 12);
+		assert_eq!(revision.kind, Kind::Sealed);
+		assert_eq!(revision.time.timestamp(), 1700000000);
+		assert!(Revision::parse(PathBuf::from("/h/.tmpXXXX")).is_none());
+	}
+
+	#[test]
+	fn secret_changes() {
+		let old = json!({
+			"hosts": {"a": {}},
+			"sharedSecrets": {"x": {"createdAt": "1"}, "y": {"createdAt": "1"}},
+			"hostSecrets": {"a": {"z": {"createdAt": "1"}}},
+		});
+		let new = json!({
+			"hosts": {"a": {}, "b": {}},
+			"sharedSecrets": {"x": {"createdAt": "2"}},
+			"hostSecrets": {"a": {"z": {"createdAt": "1", "owners": ["a"]}}},
+		});
+		assert_eq!(
+			diff(&old, &new),
+			[
+				"host b added",
+				"shared secret x rotated",
+				"shared secret y removed",
+				"a secret z updated",
+			]
+		);
+	}
+}
@@ -1,35 +1,78 @@
 use std::{
 	cell::OnceCell,
+	collections::BTreeMap,
 	ffi::{OsStr, OsString},
-	fmt::Display,
-	io::Write,
+	fmt::{Display, Write as _},
 	ops::Deref,
-	path::PathBuf,
+	path::{Path, PathBuf},
 	str::FromStr,
-	sync::{Arc, Mutex, MutexGuard, OnceLock},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex, MutexGuard, OnceLock,
+	},
 };
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use fleet_shared::SecretData;
-use nix_eval::{nix_go, nix_go_json, util::assert_warn, Value};
-use openssh::SessionBuilder;
-use serde::de::DeserializeOwned;
-use tempfile::NamedTempFile;
+use nix_eval::{nix_go, nix_go_json, util::assert_warn, EvalScheduler, Value};
+use openssh::{KnownHosts, SessionBuilder};
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::{info, warn};
 
 use crate::{
+	attribution::{self, AllowedSigners, Entry},
+	cli_defaults::CliDefaults,
 	command::MyCommand,
+	datafile::{self, DataBase},
+	eval_cache::{EvalCache, Memoized},
+	features::Features,
 	fleetdata::{FleetData, FleetSecret, FleetSharedSecret},
+	history,
+	keys::IdentityStore,
+	remote_nix::RemoteNix,
+	sealed,
+	secret_bytes::SecretBytes,
+	transport::{copy_nix, Transport},
 };
 
 pub struct FleetConfigInternals {
 	pub local_system: String,
 	pub directory: PathBuf,
-	pub data: Mutex<FleetData>,
+	/// Selected attribute of `fleetConfigurations`, see [`sealed::fleet_file`]
+	pub fleet: String,
+	pub data: Arc<Mutex<FleetData>>,
+	/// Workers for concurrent per-host evaluation, each holding its own `config_field`
+	pub eval: EvalScheduler<Value>,
+	/// Feature flags of this fleet project
+	pub features: Features,
+	/// Defaults of CLI options, declared in the fleet config, and overridden by global options
+	pub cli_defaults: CliDefaults,
+	/// Keys of the admins, who may sign fleet data, see [`attribution`]
+	pub allowed_signers: AllowedSigners,
+	/// Admin identities
+	pub identities: IdentityStore,
+	/// Fleet data, as it is stored on disk, used to merge concurrent modifications on save
+	pub data_base: Mutex<DataBase>,
+	/// Whether fleet data is stored encrypted, see [`sealed`]
+	pub sealed: AtomicBool,
 	pub nix_args: Vec<OsString>,
+	/// Arguments of nix invocations, which build host systems, passed after `nix_args`
+	pub build_nix_args: Vec<OsString>,
+	/// Per-host nix arguments passed with `--nix-arg host=...`
+	pub host_nix_args: BTreeMap<String, Vec<OsString>>,
+	/// Fleet nix signing key, see `nixSigning` option
+	pub signing_key: Option<PathBuf>,
 	/// fleet_config.config
 	pub config_field: Value,
+	/// Memoized evaluation results of `config_field`
+	pub eval_cache: EvalCache,
+	/// Probed nix installations of the hosts, see [`ConfigHost::remote_nix`]
+	pub remote_nix: Mutex<BTreeMap<String, RemoteNix>>,
 	// TODO: Remove with connectivity refactor
 	pub localhost: String,
+	/// Ssh host key of the machine fleet is running on, used to detect the local host
+	/// even if its name in fleet differs from the machine hostname.
+	pub local_host_key: Option<String>,
 
 	/// import nixpkgs {system = local};
 	pub default_pkgs: Value,
@@ -54,6 +97,70 @@ pub enum EscalationStrategy {
 	Su,
 }
 
+/// How the host system is built and activated
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Platform {
+	Nixos,
+	Darwin,
+	HomeManager,
+}
+impl Platform {
+	/// Whether host has system-wide profile at /nix/var/nix/profiles/system
+	pub fn has_system_profile(&self) -> bool {
+		matches!(self, Self::Nixos | Self::Darwin)
+	}
+}
+
+/// Connection parameters of the host, declared in `hosts.<name>.ssh`
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SshTarget {
+	pub address: String,
+	pub user: Option<String>,
+	pub port: Option<u16>,
+	pub jump_hosts: Vec<String>,
+	#[serde(default)]
+	pub transport: Transport,
+	/// zstd level for the `nar-stream` transport
+	#[serde(default = "default_compression_level")]
+	pub compression_level: u8,
+	/// Known hosts file with trusted host keys, see [`Config::known_hosts_file`]
+	#[serde(skip)]
+	pub known_hosts: Option<PathBuf>,
+}
+fn default_compression_level() -> u8 {
+	3
+}
+impl SshTarget {
+	/// `[user@]address`
+	pub fn destination(&self) -> String {
+		match &self.user {
+			Some(user) => format!("{user}@{}", self.address),
+			None => self.address.clone(),
+		}
+	}
+	/// Options which should be passed to the ssh binary, in addition to destination
+	pub fn ssh_args(&self) -> Vec<String> {
+		let mut out = vec![];
+		if let Some(port) = self.port {
+			out.push("-p".to_owned());
+			out.push(port.to_string());
+		}
+		if !self.jump_hosts.is_empty() {
+			out.push("-J".to_owned());
+			out.push(self.jump_hosts.join(","));
+		}
+		if let Some(known_hosts) = &self.known_hosts {
+			out.push("-o".to_owned());
+			out.push("StrictHostKeyChecking=yes".to_owned());
+			out.push("-o".to_owned());
+			out.push(format!("UserKnownHostsFile={}", known_hosts.display()));
+		}
+		out
+	}
+}
+
 pub struct ConfigHost {
 	config: Config,
 	pub name: String,
@@ -61,6 +168,8 @@ pub struct ConfigHost {
 
 	pub host_config: Option<Value>,
 	pub nixos_config: OnceCell<Value>,
+	/// Whether host values are evaluated in the main session, and thus can be memoized
+	main_session: bool,
 
 	// TODO: Move command helpers away with connectivity refactor
 	pub local: bool,
@@ -68,6 +177,9 @@ pub struct ConfigHost {
 }
 // TODO: Move command helpers away with connectivity refactor
 impl ConfigHost {
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
 	pub async fn escalation_strategy(&self) -> Result<EscalationStrategy> {
 		// Prefer sudo, as run0 has some gotchas with polkit
 		// and too many repeating prompts.
@@ -85,15 +197,53 @@ impl ConfigHost {
 		if let Some(session) = &self.session.get() {
 			return Ok((*session).clone());
 		};
-		let session = SessionBuilder::default();
-		let session = session
-			.connect(&self.name)
-			.await
-			.map_err(|e| anyhow!("ssh error while connecting to {}: {e}", self.name))?;
+		let target = self.ssh_target().await?;
+		let mut session = SessionBuilder::default();
+		if let Some(user) = &target.user {
+			session.user(user.clone());
+		}
+		if let Some(port) = target.port {
+			session.port(port);
+		}
+		if !target.jump_hosts.is_empty() {
+			session.jump_hosts(&target.jump_hosts);
+		}
+		if let Some(known_hosts) = &target.known_hosts {
+			session
+				.user_known_hosts_file(known_hosts)
+				.known_hosts_check(KnownHosts::Strict);
+		}
+		let session = session.connect(&target.address).await.map_err(|e| {
+			let hint = if target.known_hosts.is_some() {
+				"\nif host key has legitimately changed, run `fleet host trust` again"
+			} else {
+				""
+			};
+			anyhow!("ssh error while connecting to {}: {e}{hint}", self.name)
+		})?;
 		let session = Arc::new(session);
 		self.session.set(session.clone()).expect("TOCTOU happened");
 		Ok(session)
 	}
+	/// Connection parameters without host key verification, for hosts which are being provisioned,
+	/// and thus have no stable host key yet.
+	pub async fn ssh_target_unverified(&self) -> Result<SshTarget> {
+		let Some(host_config) = &self.host_config else {
+			bail!("local host has no ssh target");
+		};
+		Ok(nix_go_json!(host_config.ssh))
+	}
+	pub async fn ssh_target(&self) -> Result<SshTarget> {
+		let mut target = self.ssh_target_unverified().await?;
+		target.known_hosts = self.config.known_hosts_file(&self.name)?;
+		if target.known_hosts.is_none() && self.config.features.strict_host_keys {
+			bail!(
+				"host {0} has no trusted ssh host keys, run `fleet host trust {0}`",
+				self.name
+			);
+		}
+		Ok(target)
+	}
 	pub async fn mktemp_dir(&self) -> Result<String> {
 		let mut cmd = self.cmd("mktemp").await?;
 		cmd.arg("-d");
@@ -103,17 +253,17 @@ impl ConfigHost {
 	pub async fn read_file_bin(&self, path: impl AsRef<OsStr>) -> Result<Vec<u8>> {
 		let mut cmd = self.cmd("cat").await?;
 		cmd.arg(path);
-		cmd.run_bytes().await
+		cmd.query().retry_transient().run_bytes().await
 	}
 	pub async fn read_file_text(&self, path: impl AsRef<OsStr>) -> Result<String> {
 		let mut cmd = self.cmd("cat").await?;
 		cmd.arg(path);
-		cmd.run_string().await
+		cmd.query().retry_transient().run_string().await
 	}
 	pub async fn read_dir(&self, path: impl AsRef<OsStr>) -> Result<Vec<String>> {
 		let mut cmd = self.cmd("ls").await?;
 		cmd.arg(path);
-		let out = cmd.run_string().await?;
+		let out = cmd.query().retry_transient().run_string().await?;
 		let mut lines = out.split('\n');
 		if let Some(last) = lines.next_back() {
 			ensure!(last.is_empty(), "output of ls should end with newline");
@@ -128,7 +278,7 @@ impl ConfigHost {
 	pub async fn read_env(&self, env: &str) -> Result<String> {
 		let mut cmd = self.cmd("printenv").await?;
 		cmd.arg(env);
-		cmd.run_string().await
+		cmd.query().run_string().await
 	}
 	pub async fn find_in_path(&self, command: &str) -> Result<String> {
 		// // `which` is not a part of coreutils, and it might not exist on machine.
@@ -151,7 +301,7 @@ impl ConfigHost {
 			)
 			.await?;
 		cmd.arg(command);
-		cmd.run_string().await
+		cmd.query().run_string().await
 	}
 	pub async fn read_file_value<D: FromStr>(&self, path: impl AsRef<OsStr>) -> Result<D>
 	where
@@ -173,24 +323,31 @@ impl ConfigHost {
 			Ok(MyCommand::new(escalation, cmd))
 		} else {
 			let session = self.open_session().await?;
-			Ok(MyCommand::new_on(escalation, cmd, session))
+			Ok(MyCommand::new_on(escalation, cmd, session, &self.name))
 		}
 	}
 
-	pub async fn decrypt(&self, data: SecretData) -> Result<Vec<u8>> {
+	pub async fn decrypt(&self, data: SecretData) -> Result<SecretBytes> {
 		ensure!(data.encrypted, "secret is not encrypted");
 		let mut cmd = self.cmd("fleet-install-secrets").await?;
 		cmd.arg("decrypt").eqarg("--secret", data.to_string());
 		let encoded = cmd
 			.sudo()
-			.run_string()
+			.query()
+			.run_secret()
 			.await
 			.context("failed to call remote host for decrypt")?;
-		let data: SecretData = encoded.parse().map_err(|e| anyhow!("{e}"))?;
+		let data: SecretData = encoded.expose_str()?.parse().map_err(|e| anyhow!("{e}"))?;
 		ensure!(!data.encrypted, "secret came out encrypted");
-		Ok(data.data)
+		Ok(data.data.into())
 	}
-	pub async fn reencrypt(&self, data: SecretData, targets: Vec<String>) -> Result<SecretData> {
+	/// `readers` are public keys of team members, see [`crate::access::SecretAccess`]
+	pub async fn reencrypt(
+		&self,
+		data: SecretData,
+		targets: Vec<String>,
+		readers: &[String],
+	) -> Result<SecretData> {
 		ensure!(data.encrypted, "secret is not encrypted");
 		let mut cmd = self.cmd("fleet-install-secrets").await?;
 		cmd.arg("reencrypt").eqarg("--secret", data.to_string());
@@ -198,6 +355,9 @@ impl ConfigHost {
 			let key = self.config.key(&target).await?;
 			cmd.eqarg("--targets", key);
 		}
+		for reader in readers {
+			cmd.eqarg("--targets", reader);
+		}
 		let encoded = cmd
 			.sudo()
 			.run_string()
@@ -207,23 +367,118 @@ impl ConfigHost {
 		ensure!(data.encrypted, "secret came out not encrypted");
 		Ok(data)
 	}
+	/// Whether decrypted secrets are only kept on tmpfs, see `fleet.secrets.ephemeral`
+	pub async fn has_ephemeral_secrets(&self) -> Result<bool> {
+		let nixos = self.nixos_config().await?;
+		Ok(nix_go_json!(nixos.fleet.secrets.ephemeral))
+	}
+	/// Pushes encrypted material of ephemeral secrets, and reinstalls already deployed secrets using it.
+	///
+	/// With `system`, companion of the system being deployed is used, as the current system might not have it yet.
+	pub async fn provision_secrets(&self, system: Option<&Path>) -> Result<()> {
+		let nixos = self.nixos_config().await?;
+		let material: BTreeMap<String, BTreeMap<String, SecretData>> =
+			nix_go_json!(nixos.fleet.secrets.material);
+		let material_path: String = nix_go_json!(nixos.fleet.secrets.materialPath);
+		let (mut cmd, data) = match system {
+			Some(system) => (
+				self.cmd(system.join("sw/bin/fleet-install-secrets"))
+					.await?,
+				system.join("etc/fleet/secrets.json"),
+			),
+			None => (
+				self.cmd("fleet-install-secrets").await?,
+				PathBuf::from("/etc/fleet/secrets.json"),
+			),
+		};
+		// Encrypted, yet it is not for other users of the host to see
+		let mut parts = String::new();
+		for (secret, secret_parts) in material {
+			for (part, data) in secret_parts {
+				writeln!(parts, "{secret}/{part}={data}").expect("fmt");
+			}
+		}
+		// Tied to nixos/secrets.nix
+		cmd.arg("provision")
+			.eqarg("--material", material_path)
+			.eqarg("--data", data)
+			.stdin(parts);
+		cmd.sudo()
+			.run()
+			.await
+			.context("failed to provision secrets")
+	}
 	/// Returns path for futureproofing, as path might change i.e on conversion to CA
-	pub async fn remote_derivation(&self, path: &PathBuf) -> Result<PathBuf> {
+	///
+	/// `compress` enables ssh compression, which is beneficial on slow links.
+	pub async fn remote_derivation(&self, path: &PathBuf, compress: bool) -> Result<PathBuf> {
 		if self.local {
 			// Path is located locally, thus already trusted.
 			return Ok(path.to_owned());
 		}
+		let target = self.ssh_target().await?;
+		let transport = self.resolve_transport(target.transport).await?;
+		match transport {
+			Transport::Auto => unreachable!("resolved"),
+			Transport::SshNg | Transport::Ssh => {
+				let substitute = self
+					.remote_nix()
+					.await?
+					.supports_substitute_on_destination();
+				copy_nix(&target, transport, path, compress, substitute).await?
+			}
+			Transport::NarStream => self.copy_nar_stream(&target, path).await?,
+		}
+		Ok(path.to_owned())
+	}
+	/// Copies store path from this host to the local store, reverse of [`Self::remote_derivation`].
+	///
+	/// Signatures are not checked, path should be produced by the host itself.
+	pub async fn fetch_derivation(&self, path: &Path) -> Result<()> {
+		if self.local {
+			return Ok(());
+		}
+		let target = self.ssh_target().await?;
+		let scheme = if self.remote_nix().await?.supports_ssh_ng() {
+			"ssh-ng"
+		} else {
+			"ssh"
+		};
 		let mut nix = MyCommand::new(
 			// Not used
 			EscalationStrategy::Su,
 			"nix",
 		);
+		let ssh_args = target.ssh_args();
+		if !ssh_args.is_empty() {
+			nix.env("NIX_SSHOPTS", ssh_args.join(" "));
+		}
 		nix.arg("copy")
-			.arg("--substitute-on-destination")
-			.comparg("--to", format!("ssh-ng://{}", self.name))
+			.arg("--no-check-sigs")
+			.comparg("--from", format!("{scheme}://{}", target.destination()))
 			.arg(path);
-		nix.run_nix().await.context("nix copy")?;
-		Ok(path.to_owned())
+		nix.retry_transient().run_nix().await.context("nix copy")
+	}
+	/// Total size of the store path closure, in bytes
+	pub async fn closure_size(&self, path: &PathBuf) -> Result<u64> {
+		let mut cmd = self.cmd("nix").await?;
+		cmd.arg("path-info")
+			.arg("--closure-size")
+			.arg("--json")
+			.arg(path);
+		let out = cmd.query().run_string().await?;
+		let info: serde_json::Value =
+			serde_json::from_str(&out).context("failed to parse path-info output")?;
+		// Older nix versions return list of objects, newer return object keyed by path.
+		let entry = match &info {
+			serde_json::Value::Array(items) => items.first(),
+			serde_json::Value::Object(items) => items.values().next(),
+			_ => None,
+		};
+		entry
+			.and_then(|e| e.get("closureSize"))
+			.and_then(|s| s.as_u64())
+			.ok_or_else(|| anyhow!("nix path-info returned no closure size for {path:?}"))
 	}
 	pub async fn systemctl_stop(&self, name: &str) -> Result<()> {
 		let mut cmd = self.cmd("systemctl").await?;
@@ -255,12 +510,23 @@ impl ConfigHost {
 		let Some(host_config) = &self.host_config else {
 			return Ok(vec![]);
 		};
-		let tags: Vec<String> = nix_go_json!(host_config.tags);
+		let cached = self.memoized().tags.get(&self.name).cloned();
+		let tags = match cached {
+			Some(tags) => tags,
+			None => {
+				let tags: Vec<String> = nix_go_json!(host_config.tags);
+				self.memoized().tags.insert(self.name.clone(), tags.clone());
+				tags
+			}
+		};
 
 		let _ = self.groups.set(tags.clone());
 
 		Ok(tags)
 	}
+	fn memoized(&self) -> MutexGuard<'_, Memoized> {
+		self.config.eval_cache.get()
+	}
 	pub async fn nixos_config(&self) -> Result<Value> {
 		if let Some(v) = self.nixos_config.get() {
 			return Ok(v.clone());
@@ -268,8 +534,23 @@ impl ConfigHost {
 		let Some(host_config) = &self.host_config else {
 			bail!("local host has no nixos_config");
 		};
-		let nixos_config = nix_go!(host_config.nixos.config);
-		assert_warn("nixos config evaluation", &nixos_config).await?;
+		let cached = self
+			.main_session
+			.then(|| self.memoized().nixos_configs.get(&self.name).cloned())
+			.flatten();
+		let nixos_config = match cached {
+			Some(v) => v,
+			None => {
+				let nixos_config = nix_go!(host_config.nixos.config);
+				assert_warn("nixos config evaluation", &nixos_config).await?;
+				if self.main_session {
+					self.memoized()
+						.nixos_configs
+						.insert(self.name.clone(), nixos_config.clone());
+				}
+				nixos_config
+			}
+		};
 
 		let _ = self.nixos_config.set(nixos_config.clone());
 
@@ -277,6 +558,9 @@ impl ConfigHost {
 	}
 
 	pub async fn list_configured_secrets(&self) -> Result<Vec<String>> {
+		if let Some(v) = self.memoized().configured_secrets.get(&self.name) {
+			return Ok(v.clone());
+		}
 		let nixos = self.nixos_config().await?;
 		let secrets = nix_go!(nixos.secrets);
 		let mut out = Vec::new();
@@ -288,6 +572,9 @@ impl ConfigHost {
 			}
 			out.push(name);
 		}
+		self.memoized()
+			.configured_secrets
+			.insert(self.name.clone(), out.clone());
 		Ok(out)
 	}
 	pub async fn secret_field(&self, name: &str) -> Result<Value> {
@@ -295,6 +582,62 @@ impl ConfigHost {
 		Ok(nix_go!(nixos.secrets[{ name }]))
 	}
 
+	pub async fn platform(&self) -> Result<Platform> {
+		let Some(host_config) = &self.host_config else {
+			return Ok(Platform::Nixos);
+		};
+		if let Some(platform) = self.memoized().platforms.get(&self.name) {
+			return Ok(*platform);
+		}
+		let platform: Platform = nix_go_json!(host_config.platform);
+		self.memoized()
+			.platforms
+			.insert(self.name.clone(), platform);
+		Ok(platform)
+	}
+	/// Buildable system attribute, i.e "toplevel" or "sdImage"
+	pub async fn system_attr(&self, attr: &str) -> Result<Value> {
+		let Some(host_config) = &self.host_config else {
+			bail!("local host has no system");
+		};
+		Ok(match self.platform().await? {
+			Platform::Nixos => {
+				let nixos = self.nixos_config().await?;
+				nix_go!(nixos.system.build[{ attr }])
+			}
+			Platform::Darwin => {
+				nix_go!(host_config.darwinConfiguration.config.system.build[{ attr }])
+			}
+			Platform::HomeManager => {
+				ensure!(
+					attr == "toplevel",
+					"home-manager hosts only support building toplevel"
+				);
+				nix_go!(host_config.homeConfiguration.activationPackage)
+			}
+		})
+	}
+
+	/// Fleet deployment options for this host (`hosts.<name>.deploy`)
+	pub async fn deploy_options(&self) -> Result<Value> {
+		let Some(host_config) = &self.host_config else {
+			bail!("local host has no deploy options");
+		};
+		Ok(nix_go!(host_config.deploy))
+	}
+
+	/// Nix arguments, which should be used in addition to global `nix_args` when building this host:
+	/// `hosts.<name>.deploy.nixArgs`, followed by the ones passed with `--nix-arg`
+	pub async fn extra_nix_args(&self) -> Result<Vec<OsString>> {
+		let deploy = self.deploy_options().await?;
+		let from_config: Vec<String> = nix_go_json!(deploy.nixArgs);
+		let mut out: Vec<OsString> = from_config.into_iter().map(OsString::from).collect();
+		if let Some(cli) = self.config.host_nix_args.get(&self.name) {
+			out.extend(cli.iter().cloned());
+		}
+		Ok(out)
+	}
+
 	/// Packages for this host, resolved with nixpkgs overlays
 	pub async fn pkgs(&self) -> Result<Value> {
 		let Some(host_config) = &self.host_config else {
@@ -314,6 +657,7 @@ impl Config {
 			session: OnceLock::new(),
 			host_config: None,
 			nixos_config: OnceCell::new(),
+			main_session: false,
 			groups: {
 				let cell = OnceCell::new();
 				let _ = cell.set(vec![]);
@@ -322,25 +666,74 @@ impl Config {
 		}
 	}
 
+	fn memoized(&self) -> MutexGuard<'_, Memoized> {
+		self.eval_cache.get()
+	}
+	/// Host, which attributes are evaluated in the main session, and memoized for the whole run
 	pub async fn host(&self, name: &str) -> Result<ConfigHost> {
-		let config = &self.config_field;
-		let host_config = nix_go!(config.hosts[{ name }]);
-
+		let cached = self.memoized().host_fields.get(name).cloned();
+		let host_config = match cached {
+			Some(v) => v,
+			None => {
+				let config = &self.config_field;
+				let host_config = nix_go!(config.hosts[{ name }]);
+				self.memoized()
+					.host_fields
+					.insert(name.to_owned(), host_config.clone());
+				host_config
+			}
+		};
 		Ok(ConfigHost {
+			main_session: true,
+			..self.host_with(name, host_config)
+		})
+	}
+	/// Host, which attributes are evaluated in the session of the passed config field,
+	/// i.e of the eval worker.
+	pub async fn host_on(&self, config_field: &Value, name: &str) -> Result<ConfigHost> {
+		let config = config_field;
+		let host_config = nix_go!(config.hosts[{ name }]);
+		Ok(self.host_with(name, host_config))
+	}
+	fn host_with(&self, name: &str, host_config: Value) -> ConfigHost {
+		ConfigHost {
 			config: self.clone(),
 			name: name.to_owned(),
 			host_config: Some(host_config),
 			nixos_config: OnceCell::new(),
+			main_session: false,
 			groups: OnceCell::new(),
 			
 			// TODO: Remove with connectivit refactor
-			local: self.localhost == name,
+			local: self.is_local(name),
 			session: OnceLock::new(),
-		})
+		}
+	}
+	/// Commands for the local host are executed directly, without ssh.
+	pub fn is_local(&self, name: &str) -> bool {
+		if self.localhost == name {
+			return true;
+		}
+		if !self.features.detect_local_host_by_key {
+			return false;
+		}
+		let Some(local_key) = &self.local_host_key else {
+			return false;
+		};
+		self.cached_key(name)
+			.is_some_and(|key| key.trim() == local_key.trim())
 	}
 	pub async fn list_hosts(&self) -> Result<Vec<ConfigHost>> {
-		let config = &self.config_field;
-		let names = nix_go!(config.hosts).list_fields().await?;
+		let cached = self.memoized().host_names.clone();
+		let names = match cached {
+			Some(names) => names,
+			None => {
+				let config = &self.config_field;
+				let names = nix_go!(config.hosts).list_fields().await?;
+				self.memoized().host_names = Some(names.clone());
+				names
+			}
+		};
 		let mut out = vec![];
 		for name in names {
 			out.push(self.host(&name).await?);
@@ -355,8 +748,13 @@ impl Config {
 
 	/// Shared secrets configured in fleet.nix or in flake
 	pub async fn list_configured_shared(&self) -> Result<Vec<String>> {
+		if let Some(v) = &self.memoized().configured_shared {
+			return Ok(v.clone());
+		}
 		let config_field = &self.config_field;
-		Ok(nix_go!(config_field.sharedSecrets).list_fields().await?)
+		let shared = nix_go!(config_field.sharedSecrets).list_fields().await?;
+		self.memoized().configured_shared = Some(shared.clone());
+		Ok(shared)
 	}
 	/// Shared secrets configured in fleet.nix
 	pub fn list_shared(&self) -> Vec<String> {
@@ -367,7 +765,9 @@ impl Config {
 		let data = self.data();
 		data.shared_secrets.contains_key(name)
 	}
-	pub fn replace_shared(&self, name: String, shared: FleetSharedSecret) {
+	/// Secret is attributed to the operator, see [`attribution::stamp`]
+	pub fn replace_shared(&self, name: String, mut shared: FleetSharedSecret) {
+		attribution::stamp(Entry::SharedSecret(&name), &mut shared);
 		let mut data = self.data_mut();
 		data.shared_secrets.insert(name.to_owned(), shared);
 	}
@@ -391,7 +791,15 @@ impl Config {
 		};
 		host_secrets.contains_key(secret)
 	}
-	pub fn insert_secret(&self, host: &str, secret: String, value: FleetSecret) {
+	/// Secret is attributed to the operator, see [`attribution::stamp`]
+	pub fn insert_secret(&self, host: &str, secret: String, mut value: FleetSecret) {
+		attribution::stamp(
+			Entry::HostSecret {
+				host,
+				secret: &secret,
+			},
+			&mut value,
+		);
 		let mut data = self.data_mut();
 		let host_secrets = data.host_secrets.entry(host.to_owned()).or_default();
 		host_secrets.insert(secret, value);
@@ -435,18 +843,58 @@ impl Config {
 		self.data.lock().unwrap()
 	}
 	pub fn save(&self) -> Result<()> {
-		let mut tempfile = NamedTempFile::new_in(self.directory.clone()).context("failed to create updated version of fleet.nix in the same directory as original.\nDo you have write access to it? Access only to the fleet.nix won't be enough, the directory is used for atomic overwrite operation.\nIt is not recommended to use fleet by root anyway, move fleet project to your home directory.")?;
-		let data = nixlike::serialize(&self.data() as &FleetData)?;
-		tempfile.write_all(
-			format!(
-				"# This file contains fleet state and shouldn't be edited by hand\n\n{}\n\n# vim: ts=2 et nowrap\n",
-				data
-			)
-			.as_bytes(),
-		)?;
-		let mut fleet_data_path = self.directory.clone();
-		fleet_data_path.push("fleet.nix");
-		tempfile.persist(fleet_data_path)?;
+		let _lock = datafile::lock(&self.directory)?;
+		let mut base = self.data_base.lock().unwrap();
+		let is_sealed = self.is_sealed();
+		// File might be missing, when the storage mode was just changed
+		let on_disk = datafile::read_raw(&self.directory, &self.fleet, is_sealed).ok();
+		if on_disk.as_ref().is_some_and(|raw| *raw != base.raw) {
+			let theirs = datafile::parse(on_disk.as_ref().expect("checked"), is_sealed, &self.identities)?;
+			let theirs = serde_json::to_value(&theirs)?;
+			let mut data = self.data_mut();
+			let ours = serde_json::to_value(&*data)?;
+			let merged = datafile::merge(&base.value, &ours, &theirs)?;
+			*data = serde_json::from_value(merged).context("merged fleet data is invalid")?;
+			info!("merged fleet data changes made by another fleet invocation");
+		}
+
+		let value = serde_json::to_value(&*self.data())?;
+		let raw = datafile::encode(&self.data(), &self.directory, &self.fleet, is_sealed)?;
+		datafile::write(&self.directory, &self.fleet, is_sealed, &raw)?;
+		let previous = on_disk.unwrap_or_default();
+		if raw != previous {
+			if let Err(e) =
+				history::record(&self.directory, &self.fleet, is_sealed, &previous, &raw)
+			{
+				warn!("failed to record fleet data history: {e:#}");
+			}
+		}
+		*base = DataBase { raw, value };
 		Ok(())
 	}
+	pub fn is_sealed(&self) -> bool {
+		self.sealed.load(Ordering::Relaxed)
+	}
+	/// Name of the file, specific to the selected fleet
+	pub fn fleet_file(&self, file: &str) -> String {
+		sealed::fleet_file(&self.fleet, file)
+	}
+	/// Switches storage mode of fleet data, old file is removed after new one is saved.
+	/// Does nothing if the mode is already selected.
+	pub fn set_sealed(&self, enable: bool) -> Result<()> {
+		if enable == self.is_sealed() {
+			return Ok(());
+		}
+		self.sealed.store(enable, Ordering::Relaxed);
+		self.save()?;
+		let old = if enable {
+			sealed::PLAIN_FILE
+		} else {
+			sealed::SEALED_FILE
+		};
+		match std::fs::remove_file(self.directory.join(self.fleet_file(old))) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(()),
+		}
+	}
 }
@@ -0,0 +1,100 @@
+//! Dynamic host inventory, i.e instances of an autoscaling group.
+//!
+//! Host source is a shell command, which prints json list of hosts:
+//! `[{"name": "web-1", "template": "web", "address": "10.0.0.5", "tags": ["eu"]}]`.
+//! Hosts are passed to the evaluation as `data.dynamicHosts`, and are configured by the
+//! referenced `hostTemplates` entry, see `modules/host-templates.nix`.
+
+use std::{collections::BTreeMap, process::Command};
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DynamicHost {
+	pub template: String,
+	#[serde(default)]
+	pub address: Option<String>,
+	#[serde(default)]
+	pub tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct InventoryEntry {
+	name: String,
+	#[serde(flatten)]
+	host: DynamicHost,
+}
+
+fn parse(output: &[u8]) -> Result<BTreeMap<String, DynamicHost>> {
+	let entries: Vec<InventoryEntry> =
+		serde_json::from_slice(output).context("host source output is not a valid host list")?;
+	let mut hosts = BTreeMap::new();
+	for entry in entries {
+		ensure!(
+			!entry.name.is_empty(),
+			"host source returned host without name"
+		);
+		let name = entry.name;
+		ensure!(
+			hosts.insert(name.clone(), entry.host).is_none(),
+			"host source returned host {name} twice"
+		);
+	}
+	Ok(hosts)
+}
+
+/// Runs host source command with `sh -c`
+pub fn load(command: &str) -> Result<BTreeMap<String, DynamicHost>> {
+	let output = Command::new("sh")
+		.arg("-c")
+		.arg(command)
+		.output()
+		.with_context(|| format!("failed to run host source {command:?}"))?;
+	ensure!(
+		output.status.success(),
+		"host source {command:?} failed with {}: {}",
+		output.status,
+		String::from_utf8_lossy(&output.stderr).trim()
+	);
+	parse(&output.stdout)
+}
+
+/// Fleet data, as it is passed to `fleetConfigurations`, with dynamic hosts added
+pub fn eval_data(mut data: Value, hosts: &BTreeMap<String, DynamicHost>) -> Value {
+	if !hosts.is_empty() {
+		data.as_object_mut()
+			.expect("fleet data is a struct")
+			.insert(
+				"dynamicHosts".to_owned(),
+				serde_json::to_value(hosts).expect("dynamic hosts are serializable"),
+			);
+	}
+	data
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_inventory() {
+		let hosts = parse(
+			br#"[{"name": "web-1", "template": "web", "address": "10.0.0.5"}, {"name": "web-2", "template": "web", "tags": ["eu"]}]"#,
+		)
+		.unwrap();
+		assert_eq!(
+			hosts["web-1"],
+			DynamicHost {
+				template: "web".to_owned(),
+				address: Some("10.0.0.5".to_owned()),
+				tags: vec![],
+			}
+		);
+		assert_eq!(hosts["web-2"].tags, ["eu"]);
+		assert!(
+			parse(br#"[{"name": "a", "template": "t"}, {"name": "a", "template": "t"}]"#).is_err()
+		);
+	}
+}
@@ -1,12 +1,154 @@
-use std::str::FromStr as _;
+use std::{
+	fs::{self, OpenOptions},
+	io::{BufRead as _, BufReader, Write as _},
+	path::{Path, PathBuf},
+	sync::{Arc, OnceLock},
+};
 
-use age::Recipient;
-use anyhow::{anyhow, Result};
+use age::{secrecy::SecretString, Decryptor, Recipient};
+use anyhow::{bail, Context, Result};
 use futures::{StreamExt as _, TryStreamExt as _};
 use itertools::Itertools as _;
-use tracing::warn;
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+use tempfile::NamedTempFile;
+use tracing::{info, warn};
 
-use crate::host::Config;
+use crate::{
+	attribution::{self, Entry},
+	fleetdata::HostData,
+	host::Config,
+	sealed::{self, parse_identities, BoxedIdentity},
+	secret_bytes::SecretBytes,
+};
+
+/// Admin identities, used i.e to decrypt sealed fleet data.
+///
+/// Identities are loaded on first use and kept in memory for the rest of the run, so the keyring
+/// passphrase is asked at most once. Sources are tried in order:
+/// - `--identity`/`FLEET_IDENTITY`, path to the identity file
+/// - `--keyring`/`FLEET_KEYRING`, passphrase-encrypted (`age -p`) identity file,
+///   defaults to `~/.config/fleet/keyring.age`
+/// - unencrypted `~/.ssh/id_ed25519`
+pub struct IdentityStore {
+	identity: Option<PathBuf>,
+	keyring: Option<PathBuf>,
+	loaded: OnceLock<Arc<[BoxedIdentity]>>,
+}
+
+fn home_path(path: &str) -> Option<PathBuf> {
+	let home = std::env::var_os("HOME")?;
+	Some(Path::new(&home).join(path))
+}
+
+/// File in the fleet user config directory, `~/.config/fleet`
+fn config_path(file: &str) -> Option<PathBuf> {
+	Some(match std::env::var_os("XDG_CONFIG_HOME") {
+		Some(config) => Path::new(&config).join("fleet").join(file),
+		None => home_path(".config/fleet")?.join(file),
+	})
+}
+
+fn default_keyring() -> Option<PathBuf> {
+	let path = config_path("keyring.age")?;
+	path.exists().then_some(path)
+}
+
+/// Nix signing key, used when `nixSigning.publicKey` is configured
+pub fn default_signing_key() -> Option<PathBuf> {
+	config_path("signing-key")
+}
+
+/// Reads passphrase from the controlling terminal, with echo disabled
+fn prompt_passphrase(prompt: &str) -> Result<SecretString> {
+	let tty = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.open("/dev/tty")
+		.context("no terminal to ask keyring passphrase")?;
+	let original = tcgetattr(&tty)?;
+	let mut silent = original.clone();
+	silent.local_flags.remove(LocalFlags::ECHO);
+	tcsetattr(&tty, SetArg::TCSANOW, &silent)?;
+	let result: Result<String> = try {
+		(&tty).write_all(prompt.as_bytes())?;
+		(&tty).flush()?;
+		let mut line = String::new();
+		BufReader::new(&tty).read_line(&mut line)?;
+		(&tty).write_all(b"\n")?;
+		line
+	};
+	tcsetattr(&tty, SetArg::TCSANOW, &original)?;
+	let mut line = result?;
+	// Truncated in place, copying would leave the passphrase in the freed buffer
+	line.truncate(line.trim_end_matches(['\r', '\n']).len());
+	Ok(SecretString::new(line))
+}
+
+/// Private key files are read without intermediate copies, see [`SecretBytes::read_from`]
+fn read_key(path: &Path) -> Result<SecretBytes> {
+	Ok(SecretBytes::read_from(fs::File::open(path)?)?)
+}
+
+fn unlock_keyring(path: &Path) -> Result<Vec<BoxedIdentity>> {
+	let data = fs::read(path).with_context(|| format!("failed to read keyring {path:?}"))?;
+	let decryptor = Decryptor::new(age::armor::ArmoredReader::new(data.as_slice()))
+		.context("failed to init keyring decryptor")?;
+	let Decryptor::Passphrase(decryptor) = decryptor else {
+		bail!("keyring {path:?} should be encrypted with passphrase");
+	};
+	let passphrase = prompt_passphrase(&format!("Passphrase for {}: ", path.display()))?;
+	let reader = decryptor
+		.decrypt(&passphrase, None)
+		.context("failed to unlock keyring, wrong passphrase?")?;
+	let decrypted = SecretBytes::read_from(reader)?;
+	info!("keyring unlocked");
+	parse_identities(decrypted.expose(), &path.display().to_string())
+}
+
+impl IdentityStore {
+	pub fn new(identity: Option<PathBuf>, keyring: Option<PathBuf>) -> Self {
+		Self {
+			identity,
+			keyring,
+			loaded: OnceLock::new(),
+		}
+	}
+
+	fn load(&self) -> Result<Vec<BoxedIdentity>> {
+		if let Some(identity) = &self.identity {
+			// Keys passed by value would leak through the process list and shell history
+			if identity.to_string_lossy().starts_with("AGE-SECRET-KEY-") {
+				bail!("--identity/FLEET_IDENTITY should be a path to the identity file, not the key itself");
+			}
+			let data = read_key(identity)
+				.with_context(|| format!("failed to read identity {identity:?}"))?;
+			return parse_identities(data.expose(), &identity.display().to_string());
+		}
+		if let Some(keyring) = self.keyring.clone().or_else(default_keyring) {
+			return unlock_keyring(&keyring);
+		}
+		if let Some(ssh) = home_path(".ssh/id_ed25519").filter(|p| p.exists()) {
+			let data = read_key(&ssh)?;
+			return parse_identities(data.expose(), &ssh.display().to_string());
+		}
+		bail!("no admin identity found, use --identity or --keyring")
+	}
+
+	pub fn identities(&self) -> Result<&[BoxedIdentity]> {
+		Ok(self.loaded()?)
+	}
+	/// Same as [`Self::identities`], for use on the crypto worker pool
+	pub fn shared_identities(&self) -> Result<Arc<[BoxedIdentity]>> {
+		Ok(self.loaded()?.clone())
+	}
+	fn loaded(&self) -> Result<&Arc<[BoxedIdentity]>> {
+		if let Some(loaded) = self.loaded.get() {
+			return Ok(loaded);
+		}
+		let identities = self.load()?;
+		Ok(self.loaded.get_or_init(|| identities.into()))
+	}
+}
 
 impl Config {
 	pub fn cached_key(&self, host: &str) -> Option<String> {
@@ -19,10 +161,52 @@ impl Config {
 		}
 		key.cloned()
 	}
+	pub fn trusted_host_keys(&self, host: &str) -> Vec<String> {
+		let data = self.data();
+		data.hosts
+			.get(host)
+			.map(|h| h.host_keys.clone())
+			.unwrap_or_default()
+	}
+	/// Replaces trusted ssh host keys of the host
+	pub fn trust_host_keys(&self, host: &str, keys: Vec<String>) {
+		let keys = keys.into_iter().map(|k| k.trim().to_owned()).collect();
+		self.update_host(host, |data| data.host_keys = keys);
+	}
+	/// Known hosts file, containing only trusted keys of the host, `None` if the host has no trusted keys.
+	///
+	/// File is only used for connections to this host, thus keys are written with `*` host pattern,
+	/// which avoids guessing the name ssh would look up after applying user ssh config.
+	pub fn known_hosts_file(&self, host: &str) -> Result<Option<PathBuf>> {
+		let keys = self.trusted_host_keys(host);
+		if keys.is_empty() {
+			return Ok(None);
+		}
+		let content: String = keys.iter().map(|k| format!("* {k}\n")).collect();
+		let dir = self.directory.join(".fleet/known_hosts");
+		let path = dir.join(host);
+		if fs::read_to_string(&path).ok().as_deref() != Some(content.as_str()) {
+			fs::create_dir_all(&dir)?;
+			let mut tmp = NamedTempFile::new_in(&dir)?;
+			tmp.write_all(content.as_bytes())?;
+			tmp.persist(&path)?;
+		}
+		Ok(Some(path))
+	}
 	pub fn update_key(&self, host: &str, key: String) {
+		let key = key.trim().to_string();
+		self.update_host(host, |data| data.encryption_key = key);
+	}
+	/// Host entry is attributed to the operator, if it was changed, see [`attribution::stamp`]
+	fn update_host(&self, host: &str, update: impl FnOnce(&mut HostData)) {
 		let mut data = self.data_mut();
-		let host = data.hosts.entry(host.to_string()).or_default();
-		host.encryption_key = key.trim().to_string();
+		let entry = data.hosts.entry(host.to_string()).or_default();
+		let mut updated = entry.clone();
+		update(&mut updated);
+		if updated != *entry {
+			attribution::stamp(Entry::Host(host), &mut updated);
+			*entry = updated;
+		}
 	}
 
 	pub async fn key(&self, host: &str) -> anyhow::Result<String> {
@@ -33,18 +217,18 @@ impl Config {
 			let host = self.host(host).await?;
 			let mut cmd = host.cmd("cat").await?;
 			cmd.arg("/etc/ssh/ssh_host_ed25519_key.pub");
-			let key = cmd.run_string().await?;
+			let key = cmd.query().run_string().await?;
 			self.update_key(&host.name, key.clone());
 			Ok(key)
 		}
 	}
-	/// Insecure, requires root
-	pub async fn recipient(&self, host: &str) -> anyhow::Result<impl Recipient> {
+	/// Host key is either ssh host key, or TPM-sealed age key, see `fleet keys enroll-tpm`
+	pub async fn recipient(&self, host: &str) -> anyhow::Result<Box<dyn Recipient + Send>> {
 		let key = self.key(host).await?;
-		age::ssh::Recipient::from_str(&key).map_err(|e| anyhow!("parse recipient error: {:?}", e))
+		sealed::parse_recipient(key.trim())
 	}
 
-	pub async fn recipients(&self, hosts: Vec<String>) -> Result<Vec<impl Recipient>> {
+	pub async fn recipients(&self, hosts: Vec<String>) -> Result<Vec<Box<dyn Recipient + Send>>> {
 		futures::stream::iter(hosts.iter())
 			.then(|m| self.recipient(m.as_ref()))
 			.try_collect::<Vec<_>>()
@@ -1,5 +1,21 @@
+#![feature(try_blocks)]
+
+pub use fleet_shared::secret_bytes;
+
+pub mod access;
+pub mod attribution;
+pub mod cli_defaults;
+pub mod datafile;
+pub mod eval_cache;
+pub mod features;
 pub mod fleetdata;
+pub mod history;
 pub mod host;
+pub mod host_source;
 pub mod command;
 pub mod opts;
-mod keys;
+pub mod remote_nix;
+pub mod sealed;
+pub mod keys;
+pub mod migrate;
+pub mod transport;
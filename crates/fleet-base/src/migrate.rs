@@ -0,0 +1,179 @@
+//! Fleet data migrations.
+//!
+//! Data file is upgraded to [`CURRENT_VERSION`] on load, by applying every migration starting
+//! from the file version. Migrations work on the untyped data, as older schemas are not
+//! representable by [`FleetData`]. Original file is kept as a backup next to the data file.
+
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use serde_json::{Map, Value};
+use tracing::info;
+
+use crate::{datafile, fleetdata::FleetData, keys::IdentityStore, sealed};
+
+pub const CURRENT_VERSION: &str = "0.1.0";
+
+struct Migration {
+	/// Version data is migrated from, `None` for data without version field
+	from: Option<&'static str>,
+	to: &'static str,
+	/// Returns human readable descriptions of the changes made
+	apply: fn(&mut Map<String, Value>) -> Result<Vec<String>>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+	from: None,
+	to: "0.1.0",
+	apply: unversioned_to_0_1_0,
+}];
+
+/// Secret parts were plain `public`/`secret` strings, now every part is stored under the `raw`
+/// attribute, with encoding prefix. See MIGRATION.adoc
+fn unversioned_to_0_1_0(data: &mut Map<String, Value>) -> Result<Vec<String>> {
+	let mut changes = Vec::new();
+	let mut migrate_secret = |name: String, secret: &mut Value| {
+		let Some(secret) = secret.as_object_mut() else {
+			return;
+		};
+		for (part, prefix) in [
+			("public", "<PLAINTEXT>"),
+			("secret", "<ENCRYPTED><Z85-ENCODED>\n"),
+		] {
+			if let Some(Value::String(value)) = secret.get(part) {
+				let raw = format!("{prefix}{value}");
+				secret.insert(part.to_owned(), serde_json::json!({ "raw": raw }));
+				changes.push(format!("moved {name}.{part} to {name}.{part}.raw"));
+			}
+		}
+	};
+	if let Some(Value::Object(shared)) = data.get_mut("sharedSecrets") {
+		for (name, secret) in shared {
+			migrate_secret(format!("sharedSecrets.{name}"), secret);
+		}
+	}
+	if let Some(Value::Object(hosts)) = data.get_mut("hostSecrets") {
+		for (host, secrets) in hosts {
+			let Some(secrets) = secrets.as_object_mut() else {
+				continue;
+			};
+			for (name, secret) in secrets {
+				migrate_secret(format!("hostSecrets.{host}.{name}"), secret);
+			}
+		}
+	}
+	Ok(changes)
+}
+
+fn data_version(data: &Map<String, Value>) -> Result<Option<String>> {
+	match data.get("version") {
+		None => Ok(None),
+		Some(Value::String(v)) => Ok(Some(v.clone())),
+		Some(v) => bail!("fleet data version should be a string, got {v}"),
+	}
+}
+
+/// Version of the data file, `None` for data without version field
+pub fn version(data: &Value) -> Result<Option<String>> {
+	let data = data.as_object().context("fleet data should be an attribute set")?;
+	data_version(data)
+}
+
+/// Upgrades data to the [`CURRENT_VERSION`], returns the list of changes, empty if data is up to date.
+pub fn upgrade(data: &mut Value) -> Result<Vec<String>> {
+	let data = data
+		.as_object_mut()
+		.context("fleet data should be an attribute set")?;
+	let mut changes = Vec::new();
+	loop {
+		let version = data_version(data)?;
+		if version.as_deref() == Some(CURRENT_VERSION) {
+			return Ok(changes);
+		}
+		let Some(migration) = MIGRATIONS.iter().find(|m| m.from == version.as_deref()) else {
+			let version = version.as_deref().unwrap_or("<unset>");
+			bail!(
+				"fleet data version {version} is not supported by this fleet binary (expected {CURRENT_VERSION}), it was probably written by a newer fleet version"
+			);
+		};
+		let from = migration.from.unwrap_or("<unset>");
+		for change in (migration.apply)(data)? {
+			changes.push(format!("{from} => {}: {change}", migration.to));
+		}
+		data.insert("version".to_owned(), Value::String(migration.to.to_owned()));
+		changes.push(format!("{from} => {}: set version", migration.to));
+	}
+}
+
+/// Upgrades the data file in place, original file is backed up as `<data file>.<old version>-<timestamp>.bak`.
+///
+/// With `dry_run`, only returns the list of changes.
+pub fn upgrade_file(
+	directory: &Path,
+	fleet: &str,
+	identities: &IdentityStore,
+	dry_run: bool,
+) -> Result<Vec<String>> {
+	let _lock = datafile::lock(directory)?;
+	let is_sealed = sealed::is_sealed(directory, fleet);
+	let raw = datafile::read_raw(directory, fleet, is_sealed)?;
+	let mut value = datafile::parse_value(&raw, is_sealed, identities)?;
+	let old_version = version(&value)?;
+	let changes = upgrade(&mut value)?;
+	if changes.is_empty() || dry_run {
+		return Ok(changes);
+	}
+	let data: FleetData =
+		serde_json::from_value(value).context("migrated fleet data is invalid")?;
+
+	let path = datafile::data_path(directory, fleet, is_sealed);
+	let mut backup = path.clone().into_os_string();
+	backup.push(format!(
+		".{}-{}.bak",
+		old_version.as_deref().unwrap_or("unversioned"),
+		Utc::now().format("%Y%m%d%H%M%S")
+	));
+	fs::write(&backup, &raw).with_context(|| format!("failed to write backup to {backup:?}"))?;
+	info!("fleet data backup is written to {backup:?}");
+
+	let raw = datafile::encode(&data, directory, fleet, is_sealed)?;
+	datafile::write(directory, fleet, is_sealed, &raw)?;
+	Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[test]
+	fn unversioned() {
+		let mut data = json!({
+			"hosts": {"a": {"encryptionKey": "ssh-ed25519 AAAA"}},
+			"hostSecrets": {"a": {"x": {"createdAt": "2024-03-01T15:54:32Z", "public": "example", "secret": "vp%d6"}}},
+		});
+		let changes = upgrade(&mut data).unwrap();
+		assert_eq!(changes.len(), 3);
+		assert_eq!(
+			data,
+			json!({
+				"version": "0.1.0",
+				"hosts": {"a": {"encryptionKey": "ssh-ed25519 AAAA"}},
+				"hostSecrets": {"a": {"x": {
+					"createdAt": "2024-03-01T15:54:32Z",
+					"public": {"raw": "<PLAINTEXT>example"},
+					"secret": {"raw": "<ENCRYPTED><Z85-ENCODED>\nvp%d6"},
+				}}},
+			})
+		);
+		assert!(upgrade(&mut data).unwrap().is_empty());
+	}
+
+	#[test]
+	fn newer() {
+		let mut data = json!({"version": "9.0.0"});
+		assert!(upgrade(&mut data).is_err());
+	}
+}
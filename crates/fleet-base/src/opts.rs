@@ -2,13 +2,14 @@ use std::{
 	collections::BTreeMap,
 	env::current_dir,
 	ffi::OsString,
+	path::PathBuf,
 	str::FromStr,
-	sync::{Arc, Mutex},
+	sync::{atomic::AtomicBool, Arc, Mutex},
 };
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use clap::Parser;
-use nix_eval::{nix_go, nix_go_json, util::assert_warn, NixSessionPool, Value};
+use nix_eval::{nix_go, nix_go_json, util::assert_warn, EvalScheduler, NixSessionPool, Value};
 use nom::{
 	bytes::complete::take_while1,
 	character::complete::char,
@@ -16,34 +17,120 @@ use nom::{
 	multi::separated_list1,
 	sequence::{preceded, separated_pair},
 };
+use regex::Regex;
+use tracing::{info, warn};
 
 use crate::{
+	attribution::{self, AllowedSigners, Verification, VerifiedEntries},
+	cli_defaults::CliDefaults,
+	datafile::{self, DataBase},
+	eval_cache::EvalCache,
+	features::Features,
 	fleetdata::FleetData,
 	host::{Config, ConfigHost, FleetConfigInternals},
+	host_source,
+	keys::{self, IdentityStore},
+	migrate, sealed,
 };
 
+/// Host selector, used by `--only` and `--skip`
+#[derive(Clone, Debug)]
+pub enum HostPattern {
+	/// Exact host name
+	Name(String),
+	/// `@tag` or `@tag:tag`, hosts having the tag
+	Tag(String),
+	/// Host name glob, `*` matches any sequence of characters, `[...]` matches a character class
+	Glob(Regex),
+	/// `/regex/`, hosts with names containing a match
+	Regex(Regex),
+}
+impl HostPattern {
+	fn parse(input: &str) -> Result<Self, String> {
+		if let Some(tag) = input.strip_prefix('@') {
+			let tag = tag.strip_prefix("tag:").unwrap_or(tag);
+			if tag.is_empty() {
+				return Err("empty tag name".to_owned());
+			}
+			return Ok(Self::Tag(tag.to_owned()));
+		}
+		if let Some(regex) = input.strip_prefix('/') {
+			let regex = regex
+				.strip_suffix('/')
+				.ok_or_else(|| format!("unterminated regex: {input:?}"))?;
+			let regex = Regex::new(regex).map_err(|e| format!("invalid regex: {e}"))?;
+			return Ok(Self::Regex(regex));
+		}
+		if input.contains(['*', '[']) {
+			let mut regex = "^".to_owned();
+			let mut in_class = false;
+			for c in input.chars() {
+				match c {
+					'*' if !in_class => regex.push_str(".*"),
+					'[' if !in_class => {
+						in_class = true;
+						regex.push('[');
+					}
+					']' if in_class => {
+						in_class = false;
+						regex.push(']');
+					}
+					c => regex.push_str(&regex::escape(&c.to_string())),
+				}
+			}
+			regex.push('$');
+			let regex = Regex::new(&regex).map_err(|e| format!("invalid glob {input:?}: {e}"))?;
+			return Ok(Self::Glob(regex));
+		}
+		if input.is_empty() {
+			return Err("empty host name".to_owned());
+		}
+		Ok(Self::Name(input.to_owned()))
+	}
+	fn matches_name(&self, name: &str) -> bool {
+		match self {
+			HostPattern::Name(n) => n == name,
+			HostPattern::Tag(_) => false,
+			HostPattern::Glob(r) | HostPattern::Regex(r) => r.is_match(name),
+		}
+	}
+	pub async fn matches(&self, host: &ConfigHost) -> Result<bool> {
+		Ok(match self {
+			HostPattern::Tag(tag) => host.tags().await?.contains(tag),
+			_ => self.matches_name(&host.name),
+		})
+	}
+}
+impl FromStr for HostPattern {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::parse(s)
+	}
+}
+
 #[derive(Clone)]
-pub enum HostItem {
-	Host {
-		name: String,
-		attrs: BTreeMap<String, String>,
-	},
-	Tag {
-		name: String,
-		attrs: BTreeMap<String, String>,
-	},
+pub struct HostItem {
+	pub pattern: HostPattern,
+	pub attrs: BTreeMap<String, String>,
 }
 fn host_item_parser(input: &str) -> Result<HostItem, String> {
 	fn err_to_string(err: nom::Err<nom::error::Error<&str>>) -> String {
 		err.to_string()
 	}
 
-	let (input, is_tag) = map(opt(char('@')), |c| c.is_some())(input).map_err(err_to_string)?;
-	let (input, name) = map(
-		take_while1(|v| v != ',' && v != '?' && v != '@'),
-		str::to_owned,
-	)(input)
-	.map_err(err_to_string)?;
+	// Regex might contain `?`, thus it is delimited by slashes instead.
+	let pattern_end = if input.starts_with('/') {
+		input[1..]
+			.match_indices('/')
+			.map(|(i, _)| i + 2)
+			.find(|&i| input[i..].is_empty() || input[i..].starts_with('?'))
+			.ok_or_else(|| format!("unterminated regex: {input:?}"))?
+	} else {
+		input.find('?').unwrap_or(input.len())
+	};
+	let (pattern, input) = input.split_at(pattern_end);
+	let pattern = HostPattern::parse(pattern)?;
 
 	let kw_item = separated_pair(
 		map(take_while1(|v| v != '&' && v != '='), str::to_owned),
@@ -60,23 +147,32 @@ fn host_item_parser(input: &str) -> Result<HostItem, String> {
 	if !input.is_empty() {
 		return Err(format!("unexpected trailing input: {input:?}"));
 	}
-	Ok(if is_tag {
-		HostItem::Tag { name, attrs }
-	} else {
-		HostItem::Host { name, attrs }
-	})
+	Ok(HostItem { pattern, attrs })
+}
+
+fn host_nix_arg_parser(input: &str) -> Result<(String, Vec<OsString>), String> {
+	let (host, args) = input
+		.split_once('=')
+		.ok_or_else(|| "expected host=args".to_owned())?;
+	let args = shlex::split(args).ok_or_else(|| format!("invalid arguments: {args:?}"))?;
+	Ok((host.to_owned(), args.into_iter().map(OsString::from).collect()))
 }
 
 // TODO: Rename to HostSelector
 #[derive(Parser, Clone)]
 pub struct FleetOpts {
-	/// All hosts except those would be skipped
+	/// All hosts except those would be skipped.
+	///
+	/// Accepts host name, `@tag`, glob (`web-*`, `db[12]`) or `/regex/`,
+	/// optionally followed by action attributes: `web-*?specialisation=foo`
 	#[clap(long, number_of_values = 1, value_parser = host_item_parser)]
 	pub only: Vec<HostItem>,
 
-	/// Hosts to skip
+	/// Hosts to skip, takes precedence over --only.
+	///
+	/// Accepts host name, `@tag`, glob or `/regex/`
 	#[clap(long, number_of_values = 1)]
-	pub skip: Vec<String>,
+	pub skip: Vec<HostPattern>,
 
 	/// Host, which should be threaten as current machine
 	// TODO: Replace with connectivity refactor
@@ -88,37 +184,65 @@ pub struct FleetOpts {
 	// TODO: Remove, as it is not used anymore.
 	#[clap(long, default_value = "detect")]
 	pub local_system: String,
+
+	/// Admin identity, i.e used to decrypt sealed fleet data (fleet.nix.age),
+	/// path to age identity file or unencrypted ssh private key
+	#[clap(long, env = "FLEET_IDENTITY")]
+	pub identity: Option<PathBuf>,
+	/// Passphrase-encrypted file with admin identities, defaults to ~/.config/fleet/keyring.age
+	#[clap(long, env = "FLEET_KEYRING")]
+	pub keyring: Option<PathBuf>,
+	/// Nix signing key, used to sign deployed closures when `nixSigning.publicKey` is configured,
+	/// defaults to ~/.config/fleet/signing-key
+	#[clap(long, env = "FLEET_SIGNING_KEY")]
+	pub signing_key: Option<PathBuf>,
+
+	/// Fleet to operate on, attribute of `fleetConfigurations` flake output.
+	/// Every fleet has its own data file: `fleet.nix` for the default one, `fleet.<name>.nix` for others
+	#[clap(long, env = "FLEET_NAME", default_value = sealed::DEFAULT_FLEET)]
+	pub fleet: String,
+
+	/// Number of concurrent per-host evaluations and builds,
+	/// every job is a separate nix process with its own copy of fleet configuration
+	#[clap(long, env = "FLEET_EVAL_JOBS", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+	pub eval_jobs: u32,
+
+	/// Extra nix arguments for building specific host, in form of `host=--option sandbox false`,
+	/// take precedence over `hosts.<name>.deploy.nixArgs`
+	#[clap(long = "nix-arg", number_of_values = 1, value_parser = host_nix_arg_parser)]
+	pub host_nix_args: Vec<(String, Vec<OsString>)>,
+
+	/// Shell command, which prints json list of dynamic hosts, i.e `./inventory.sh`,
+	/// every host is configured by one of `hostTemplates`
+	#[clap(long, env = "FLEET_HOST_SOURCE")]
+	pub host_source: Option<String>,
+
+	/// Maximum number of hosts deployed at once, defaults to `cli.deploy.maxParallel`,
+	/// unlimited if not set
+	#[clap(long)]
+	pub max_parallel: Option<usize>,
+	/// Halt the deployment on the first failed host, defaults to `cli.deploy.failFast`
+	#[clap(long)]
+	pub fail_fast: bool,
+	/// Accept fleet data entries not signed by any of `adminKeys`, i.e data created before
+	/// the keys were configured. Entries are signed once they are modified.
+	#[clap(long, env = "FLEET_ALLOW_UNSIGNED")]
+	pub allow_unsigned: bool,
 }
 
 impl FleetOpts {
 	pub async fn should_skip(&self, host: &ConfigHost) -> Result<bool> {
-		if self.skip.iter().any(|h| h as &str == host.name) {
-			return Ok(true);
+		for pattern in &self.skip {
+			if pattern.matches(host).await? {
+				return Ok(true);
+			}
 		}
 		if self.only.is_empty() {
 			return Ok(false);
 		}
-		let mut have_group_matches = false;
-		for item in self.only.iter() {
-			match item {
-				HostItem::Host { name, .. } if *name == host.name => {
-					return Ok(false);
-				}
-				HostItem::Tag { .. } => {
-					have_group_matches = true;
-				}
-				_ => {}
-			}
-		}
-		if have_group_matches {
-			let host_tags = host.tags().await?;
-			for item in self.only.iter() {
-				match item {
-					HostItem::Tag { name, .. } if host_tags.contains(name) => {
-						return Ok(false);
-					}
-					_ => {}
-				}
+		for item in &self.only {
+			if item.pattern.matches(host).await? {
+				return Ok(false);
 			}
 		}
 		Ok(true)
@@ -131,35 +255,21 @@ impl FleetOpts {
 		let str = self.action_attr_str(host, attr).await?;
 		Ok(str.map(|v| T::from_str(&v)).transpose()?)
 	}
+	/// Attributes of exact host name selectors take precedence over patterns and tags
 	pub async fn action_attr_str(&self, host: &ConfigHost, attr: &str) -> Result<Option<String>> {
-		if self.only.is_empty() {
-			return Ok(None);
-		}
-		let mut have_group_matches = false;
-		for item in self.only.iter() {
-			match item {
-				HostItem::Host { name, attrs }
-					if *name == host.name && attrs.contains_key(attr) =>
-				{
-					return Ok(attrs.get(attr).cloned());
-				}
-				HostItem::Tag { attrs, .. } if attrs.contains_key(attr) => {
-					have_group_matches = true;
+		for item in &self.only {
+			if matches!(&item.pattern, HostPattern::Name(name) if *name == host.name) {
+				if let Some(value) = item.attrs.get(attr) {
+					return Ok(Some(value.clone()));
 				}
-				_ => {}
 			}
 		}
-		if have_group_matches {
-			let host_tags = host.tags().await?;
-			for item in self.only.iter() {
-				match item {
-					HostItem::Tag { name, attrs }
-						if host_tags.contains(name) && attrs.contains_key(attr) =>
-					{
-						return Ok(attrs.get(attr).cloned());
-					}
-					_ => {}
-				}
+		for item in &self.only {
+			if matches!(item.pattern, HostPattern::Name(_)) || !item.attrs.contains_key(attr) {
+				continue;
+			}
+			if item.pattern.matches(host).await? {
+				return Ok(item.attrs.get(attr).cloned());
 			}
 		}
 		Ok(None)
@@ -169,10 +279,21 @@ impl FleetOpts {
 	}
 
 	// TODO: Config should be detached from opts.
-	pub async fn build(&self, nix_args: Vec<OsString>) -> Result<Config> {
+	/// `build_nix_args` are only passed to nix invocations, which build host systems
+	pub async fn build(
+		&self,
+		nix_args: Vec<OsString>,
+		build_nix_args: Vec<OsString>,
+	) -> Result<Config> {
 		let directory = current_dir()?;
 
-		let pool = NixSessionPool::new(directory.as_os_str().to_owned(), nix_args.clone()).await?;
+		// Main session is not managed by eval scheduler
+		let pool = NixSessionPool::new(
+			directory.as_os_str().to_owned(),
+			nix_args.clone(),
+			self.eval_jobs + 1,
+		)
+		.await?;
 		let root_field = pool.get().await?;
 
 		let builtins_field = Value::binding(root_field.clone(), "builtins").await?;
@@ -182,18 +303,76 @@ impl FleetOpts {
 			self.local_system.clone()
 		};
 
-		let mut fleet_data_path = directory.clone();
-		fleet_data_path.push("fleet.nix");
-		let bytes = std::fs::read_to_string(fleet_data_path)?;
-		let data: Mutex<FleetData> = nixlike::parse_str(&bytes)?;
+		let identities = IdentityStore::new(self.identity.clone(), self.keyring.clone());
+		for change in migrate::upgrade_file(&directory, &self.fleet, &identities, false)? {
+			info!("migrated fleet data: {change}");
+		}
+		let is_sealed = sealed::is_sealed(&directory, &self.fleet);
+		let raw = datafile::read_raw(&directory, &self.fleet, is_sealed)?;
+		let loaded = datafile::parse(&raw, is_sealed, &identities)?;
+		let data_base = DataBase {
+			raw,
+			value: serde_json::to_value(&loaded)?,
+		};
+		let dynamic_hosts = match &self.host_source {
+			Some(command) => host_source::load(command)?,
+			None => BTreeMap::new(),
+		};
+		let eval_data = host_source::eval_data(data_base.value.clone(), &dynamic_hosts);
+		let data: Arc<Mutex<FleetData>> = Arc::new(Mutex::new(loaded));
 
 		let fleet_root = Value::binding(root_field, "fleetConfigurations").await?;
-		let fleet_field = nix_go!(fleet_root.default({ data }));
+		let fleet_name = &self.fleet;
+		let fleet_field = nix_go!(fleet_root[{ fleet_name }]({ eval_data }));
 
 		let config_field = nix_go!(fleet_field.config);
 
 		assert_warn("fleet config evaluation", &config_field).await?;
 
+		let features: Features = nix_go_json!(config_field.features);
+		features.warn_unknown();
+		let mut cli_defaults: CliDefaults = nix_go_json!(config_field.cli);
+		if self.max_parallel.is_some() {
+			cli_defaults.deploy.max_parallel = self.max_parallel;
+		}
+		cli_defaults.deploy.fail_fast |= self.fail_fast;
+
+		let admin_keys: BTreeMap<String, String> = nix_go_json!(config_field.adminKeys);
+		let allowed_signers = AllowedSigners::new(&admin_keys)?;
+		let mut verified_entries = VerifiedEntries::load(&directory, &self.fleet);
+		let mut invalid = Vec::new();
+		let mut unsigned = Vec::new();
+		for (entry, verification) in attribution::verify_data(
+			&data.lock().unwrap(),
+			&allowed_signers,
+			Some(&mut verified_entries),
+		) {
+			match verification {
+				Verification::Valid => {}
+				Verification::Unsigned => unsigned.push(entry),
+				Verification::Invalid(e) => invalid.push(format!("{entry}: {e}")),
+			}
+		}
+		ensure!(
+			invalid.is_empty(),
+			"fleet data was modified bypassing fleet, review the change in `fleet data log`, restore it with `fleet data undo`:\n{}",
+			invalid.join("\n")
+		);
+		if allowed_signers.is_enforced() && !unsigned.is_empty() {
+			ensure!(
+				self.allow_unsigned,
+				"fleet data entries are not signed by any of adminKeys, review them, and pass `--allow-unsigned` to accept them until they are modified:\n{}",
+				unsigned.join("\n")
+			);
+			warn!(
+				"accepting unsigned fleet data entries: {}",
+				unsigned.join(", ")
+			);
+		}
+		if let Err(e) = verified_entries.save(&directory, &self.fleet) {
+			warn!("failed to save verified fleet data entries: {e}");
+		}
+
 		let import = nix_go!(builtins_field.import);
 		let overlays = nix_go!(config_field.nixpkgs.overlays);
 		let nixpkgs = nix_go!(fleet_field.nixpkgs.buildUsing | import);
@@ -203,14 +382,95 @@ impl FleetOpts {
 			system: { self.local_system.clone() },
 		}));
 
+		let worker_data = data.clone();
+		let worker_fleet = self.fleet.clone();
+		let eval = EvalScheduler::new(
+			pool,
+			self.eval_jobs as usize,
+			config_field.clone(),
+			move |session| {
+				// Fleet data might be updated since the start, i.e by secret generation.
+				let data = serde_json::to_value(&*worker_data.lock().unwrap())
+					.expect("fleet data is serializable");
+				let data = host_source::eval_data(data, &dynamic_hosts);
+				let fleet_name = worker_fleet.clone();
+				Box::pin(async move {
+					let fleet_root = Value::binding(session, "fleetConfigurations").await?;
+					Ok(nix_go!(fleet_root[{ fleet_name }]({ data }).config))
+				})
+			},
+		);
+
+		let mut host_nix_args = BTreeMap::<String, Vec<OsString>>::new();
+		for (host, args) in &self.host_nix_args {
+			host_nix_args
+				.entry(host.clone())
+				.or_default()
+				.extend(args.iter().cloned());
+		}
+
 		Ok(Config(Arc::new(FleetConfigInternals {
 			directory,
+			fleet: self.fleet.clone(),
+			eval,
 			data,
+			features,
+			cli_defaults,
+			allowed_signers,
+			identities,
+			data_base: Mutex::new(data_base),
+			sealed: AtomicBool::new(is_sealed),
 			local_system,
 			nix_args,
+			build_nix_args,
+			host_nix_args,
 			config_field,
+			eval_cache: EvalCache::default(),
+			remote_nix: Default::default(),
 			default_pkgs,
+			signing_key: self
+				.signing_key
+				.clone()
+				.or_else(|| keys::default_signing_key().filter(|p| p.exists())),
 			localhost: self.localhost.to_owned(),
+			local_host_key: std::fs::read_to_string("/etc/ssh/ssh_host_ed25519_key.pub").ok(),
 		})))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(input: &str) -> HostItem {
+		host_item_parser(input).unwrap()
+	}
+
+	#[test]
+	fn patterns() {
+		assert!(matches!(parse("web1").pattern, HostPattern::Name(n) if n == "web1"));
+		assert!(matches!(parse("@prod").pattern, HostPattern::Tag(t) if t == "prod"));
+		assert!(matches!(parse("@tag:prod").pattern, HostPattern::Tag(t) if t == "prod"));
+
+		let glob = parse("web-*").pattern;
+		assert!(glob.matches_name("web-1"));
+		assert!(!glob.matches_name("db-web-1"));
+		let class = parse("db[12]").pattern;
+		assert!(class.matches_name("db2"));
+		assert!(!class.matches_name("db3"));
+
+		let regex = parse(r"/(gpu|storage)-\d+/").pattern;
+		assert!(regex.matches_name("gpu-12"));
+		assert!(!regex.matches_name("gpu-x"));
+	}
+
+	#[test]
+	fn attrs() {
+		let item = parse("/web-\\d?/?specialisation=foo");
+		assert!(item.pattern.matches_name("web-"));
+		assert_eq!(item.attrs.get("specialisation").map(String::as_str), Some("foo"));
+		let item = parse("web-*?a=1&b=2");
+		assert!(matches!(item.pattern, HostPattern::Glob(_)));
+		assert_eq!(item.attrs.len(), 2);
+	}
+}
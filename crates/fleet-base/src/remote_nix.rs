@@ -0,0 +1,191 @@
+//! Nix installation of the host, probed once per run.
+//!
+//! Fleets might mix hosts running different nix versions, so commands are adapted to the nix of
+//! the host, instead of failing with nix errors in the middle of the deployment.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, ensure, Context, Result};
+use tracing::{info, warn};
+
+use crate::{command::MyCommand, host::ConfigHost, transport::parse_nix_version};
+
+/// First nix version with the new cli (`nix store`, `nix profile`), gated by `nix-command`
+/// experimental feature, and with `nix-daemon --stdio`, used by `ssh-ng://` stores
+pub const NIX_COMMAND_MIN_VERSION: (u32, u32) = (2, 4);
+/// `nix-store --serve` of nix 1.x can't substitute paths on request
+const SUBSTITUTE_MIN_VERSION: (u32, u32) = (2, 0);
+
+const PROBE_SEPARATOR: &str = "---fleet-probe---";
+/// Prints `nix --version`, `nix-env --version` and nix config, separated by [`PROBE_SEPARATOR`].
+/// Missing commands leave their sections empty, so only a failure to reach the host fails the probe.
+/// `nix show-config` is deprecated in favor of `nix config show` since nix 2.20.
+const PROBE_SCRIPT: &str = r#"
+nix --version 2>/dev/null; echo "$1"
+nix-env --version 2>/dev/null; echo "$1"
+nix config show 2>/dev/null || nix show-config 2>/dev/null
+true
+"#;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RemoteNix {
+	/// `None` if nix is not installed, or its version can't be parsed
+	pub version: Option<(u32, u32)>,
+	/// Enabled experimental features
+	pub features: BTreeSet<String>,
+	/// Whether legacy `nix-env` is installed, minimal installations might only have the new cli
+	pub has_nix_env: bool,
+}
+
+impl RemoteNix {
+	/// Parses `nix --version` and `nix config show` outputs, config is not available if
+	/// `nix-command` is disabled
+	pub fn parse(version: &str, config: Option<&str>) -> Self {
+		let features = config
+			.into_iter()
+			.flat_map(str::lines)
+			.filter_map(|l| {
+				let (key, value) = l.split_once('=')?;
+				(key.trim() == "experimental-features").then_some(value)
+			})
+			.flat_map(str::split_whitespace)
+			.map(str::to_owned)
+			.collect();
+		Self {
+			version: parse_nix_version(version),
+			features,
+			has_nix_env: false,
+		}
+	}
+
+	/// Parses output of [`PROBE_SCRIPT`]
+	fn parse_probe(output: &str) -> Self {
+		let mut sections = output.split(PROBE_SEPARATOR).map(str::trim);
+		let nix = sections.next().unwrap_or_default();
+		let nix_env = sections.next().unwrap_or_default();
+		let config = sections.next().filter(|c| !c.is_empty());
+		Self {
+			has_nix_env: !nix_env.is_empty(),
+			// Nix 1.x has no nix command
+			..Self::parse(if nix.is_empty() { nix_env } else { nix }, config)
+		}
+	}
+
+	fn at_least(&self, version: (u32, u32)) -> bool {
+		self.version.is_some_and(|v| v >= version)
+	}
+	pub fn supports_ssh_ng(&self) -> bool {
+		self.at_least(NIX_COMMAND_MIN_VERSION)
+	}
+	/// Whether `nix copy --substitute-on-destination` can be used
+	pub fn supports_substitute_on_destination(&self) -> bool {
+		self.at_least(SUBSTITUTE_MIN_VERSION)
+	}
+	pub fn has_flakes(&self) -> bool {
+		self.features.contains("flakes")
+	}
+}
+
+impl ConfigHost {
+	/// Probed nix installation of the host, memoized for the whole run.
+	/// Failed probes are not memoized, so that a host, which was unreachable, is probed again on use.
+	pub async fn remote_nix(&self) -> Result<RemoteNix> {
+		if let Some(cached) = self.config().remote_nix.lock().unwrap().get(&self.name) {
+			return Ok(cached.clone());
+		}
+		let mut cmd = self.cmd("sh").await?;
+		cmd.arg("-c")
+			.arg(PROBE_SCRIPT)
+			.arg("sh")
+			.arg(PROBE_SEPARATOR);
+		let output = cmd
+			.query()
+			.run_string()
+			.await
+			.with_context(|| format!("failed to probe nix of {}", self.name))?;
+		let remote = RemoteNix::parse_probe(&output);
+		match remote.version {
+			None => warn!("failed to detect nix version, assuming legacy nix"),
+			Some(version) if version < NIX_COMMAND_MIN_VERSION => warn!(
+				"nix {}.{} is older than {}.{}, falling back to the legacy ssh store, nix store commands are unavailable",
+				version.0, version.1, NIX_COMMAND_MIN_VERSION.0, NIX_COMMAND_MIN_VERSION.1,
+			),
+			Some(version) => info!(
+				"detected nix {}.{}, experimental features: {}",
+				version.0,
+				version.1,
+				remote.features.iter().cloned().collect::<Vec<_>>().join(" "),
+			),
+		}
+		self.config()
+			.remote_nix
+			.lock()
+			.unwrap()
+			.insert(self.name.clone(), remote.clone());
+		Ok(remote)
+	}
+
+	/// `nix` command of the host, with `nix-command` feature enabled if it is not already
+	pub async fn nix_cmd(&self) -> Result<MyCommand> {
+		let remote = self.remote_nix().await?;
+		let Some(version) = remote.version else {
+			bail!(
+				"nix version of {} is unknown, nix command is unavailable",
+				self.name
+			);
+		};
+		ensure!(
+			version >= NIX_COMMAND_MIN_VERSION,
+			"nix {}.{} on {} is too old, nix {}.{} or newer is required",
+			version.0,
+			version.1,
+			self.name,
+			NIX_COMMAND_MIN_VERSION.0,
+			NIX_COMMAND_MIN_VERSION.1,
+		);
+		let mut cmd = self.cmd("nix").await?;
+		if !remote.features.contains("nix-command") {
+			cmd.comparg("--extra-experimental-features", "nix-command");
+		}
+		Ok(cmd)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse() {
+		let modern = RemoteNix::parse(
+			"nix (Nix) 2.18.1\n",
+			Some("allowed-users = *\nexperimental-features = flakes nix-command\nkeep-going = false\n"),
+		);
+		assert_eq!(modern.version, Some((2, 18)));
+		assert!(modern.has_flakes());
+		assert!(modern.supports_ssh_ng());
+
+		let legacy = RemoteNix::parse("nix-env (Nix) 2.3.16", None);
+		assert!(!legacy.has_flakes());
+		assert!(!legacy.supports_ssh_ng());
+		assert!(legacy.supports_substitute_on_destination());
+
+		let probed = RemoteNix::parse_probe(&format!(
+			"nix (Nix) 2.24.9\n{PROBE_SEPARATOR}\n{PROBE_SEPARATOR}\nexperimental-features = nix-command\n"
+		));
+		assert_eq!(probed.version, Some((2, 24)));
+		assert!(probed.features.contains("nix-command"));
+		assert!(!probed.has_nix_env);
+
+		let probed = RemoteNix::parse_probe(&format!(
+			"\n{PROBE_SEPARATOR}\nnix-env (Nix) 1.11.16\n{PROBE_SEPARATOR}\n"
+		));
+		assert_eq!(probed.version, Some((1, 11)));
+		assert!(probed.has_nix_env);
+		assert!(probed.features.is_empty());
+
+		let ancient = RemoteNix::parse("nix-env (Nix) 1.11.16", None);
+		assert!(!ancient.supports_substitute_on_destination());
+		assert!(!RemoteNix::parse("", None).supports_substitute_on_destination());
+	}
+}
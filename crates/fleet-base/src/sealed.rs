@@ -0,0 +1,160 @@
+//! Sealed mode of fleet data: `fleet.nix` is stored encrypted to the admin identities
+//! as `fleet.nix.age`, recipients are listed in `fleet.recipients`.
+//!
+//! Even without secret values, fleet data discloses host list, secret names and owners,
+//! which might be undesirable for public repositories.
+
+use std::{
+	fs,
+	io::{BufReader, Cursor, Read, Write},
+	path::Path,
+	str::FromStr,
+};
+
+use age::{
+	armor::{ArmoredReader, ArmoredWriter, Format},
+	Decryptor, Encryptor, Identity, Recipient,
+};
+use anyhow::{anyhow, bail, Context, Result};
+
+pub const PLAIN_FILE: &str = "fleet.nix";
+pub const SEALED_FILE: &str = "fleet.nix.age";
+pub const RECIPIENTS_FILE: &str = "fleet.recipients";
+/// Fleet used when `--fleet` is not specified, its files have no fleet name infix
+pub const DEFAULT_FLEET: &str = "default";
+
+/// Name of the per-fleet file, i.e `fleet.nix` for the default fleet,
+/// and `fleet.staging.nix` for the `staging` fleet.
+pub fn fleet_file(fleet: &str, file: &str) -> String {
+	if fleet == DEFAULT_FLEET {
+		return file.to_owned();
+	}
+	let rest = file.strip_prefix("fleet.").expect("fleet file name");
+	format!("fleet.{fleet}.{rest}")
+}
+
+pub fn is_sealed(directory: &Path, fleet: &str) -> bool {
+	directory.join(fleet_file(fleet, SEALED_FILE)).exists()
+}
+
+/// Recipients file has the same format as `age -R`: one age or ssh public key per line,
+/// empty lines and lines starting with `#` are ignored.
+pub fn read_recipients(directory: &Path, fleet: &str) -> Result<Vec<Box<dyn Recipient + Send>>> {
+	let path = directory.join(fleet_file(fleet, RECIPIENTS_FILE));
+	let data = fs::read_to_string(&path)
+		.with_context(|| format!("failed to read sealing recipients from {path:?}"))?;
+	let mut out: Vec<Box<dyn Recipient + Send>> = Vec::new();
+	for line in data.lines().map(str::trim) {
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		out.push(parse_recipient(line)?);
+	}
+	if out.is_empty() {
+		bail!("no recipients found in {path:?}");
+	}
+	Ok(out)
+}
+
+/// Either age x25519 recipient (`age1...`), or ssh public key
+pub fn parse_recipient(key: &str) -> Result<Box<dyn Recipient + Send>> {
+	if key.starts_with("age1") {
+		let recipient = age::x25519::Recipient::from_str(key)
+			.map_err(|e| anyhow!("bad age recipient {key:?}: {e}"))?;
+		Ok(Box::new(recipient))
+	} else {
+		let recipient = age::ssh::Recipient::from_str(key)
+			.map_err(|e| anyhow!("bad ssh recipient {key:?}: {e:?}"))?;
+		Ok(Box::new(recipient))
+	}
+}
+
+pub type BoxedIdentity = Box<dyn Identity + Send + Sync>;
+
+/// Identity might be either age identity file, or an unencrypted ssh private key.
+///
+/// `name` is only used for error reporting.
+pub fn parse_identities(data: &[u8], name: &str) -> Result<Vec<BoxedIdentity>> {
+	let text = String::from_utf8_lossy(data);
+	if text.contains("AGE-SECRET-KEY-") {
+		let mut out: Vec<BoxedIdentity> = Vec::new();
+		for line in text.lines().map(str::trim) {
+			if !line.starts_with("AGE-SECRET-KEY-") {
+				continue;
+			}
+			let identity = age::x25519::Identity::from_str(line)
+				.map_err(|e| anyhow!("bad age identity in {name}: {e}"))?;
+			out.push(Box::new(identity));
+		}
+		return Ok(out);
+	}
+	let identity = age::ssh::Identity::from_buffer(
+		BufReader::new(Cursor::new(data)),
+		Some(name.to_owned()),
+	)
+	.with_context(|| format!("failed to parse ssh identity {name}"))?;
+	match identity {
+		age::ssh::Identity::Unencrypted(_) => Ok(vec![Box::new(identity)]),
+		age::ssh::Identity::Encrypted(_) => {
+			bail!("passphrase protected ssh keys are not supported, use age identity or keyring instead")
+		}
+		age::ssh::Identity::Unsupported(k) => bail!("unsupported ssh key: {k:?}"),
+	}
+}
+
+pub fn seal(data: &[u8], recipients: Vec<Box<dyn Recipient + Send>>) -> Result<Vec<u8>> {
+	let mut out = vec![];
+	let armored = ArmoredWriter::wrap_output(&mut out, Format::AsciiArmor)?;
+	let mut encryptor = Encryptor::with_recipients(recipients)
+		.ok_or_else(|| anyhow!("no recipients"))?
+		.wrap_output(armored)?;
+	encryptor.write_all(data)?;
+	encryptor.finish()?.finish()?;
+	Ok(out)
+}
+
+pub fn unseal(data: &[u8], identities: &[BoxedIdentity]) -> Result<Vec<u8>> {
+	let decryptor = Decryptor::new(ArmoredReader::new(data)).context("failed to init decryptor")?;
+	let Decryptor::Recipients(decryptor) = decryptor else {
+		bail!("sealed fleet data should be encrypted to recipients, not passphrase");
+	};
+	let mut reader = decryptor
+		.decrypt(identities.iter().map(|i| i.as_ref() as &dyn Identity))
+		.context("failed to decrypt sealed fleet data, is your identity in the recipients list?")?;
+	let mut out = vec![];
+	reader.read_to_end(&mut out)?;
+	Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fleet_files() {
+		assert_eq!(fleet_file(DEFAULT_FLEET, PLAIN_FILE), "fleet.nix");
+		assert_eq!(fleet_file("staging", PLAIN_FILE), "fleet.staging.nix");
+		assert_eq!(fleet_file("staging", SEALED_FILE), "fleet.staging.nix.age");
+		assert_eq!(fleet_file("staging", RECIPIENTS_FILE), "fleet.staging.recipients");
+	}
+
+	#[test]
+	fn roundtrip() {
+		let identity = age::x25519::Identity::generate();
+		let recipients: Vec<Box<dyn Recipient + Send>> = vec![Box::new(identity.to_public())];
+		let sealed = seal(b"{ version = \"0.1.0\"; }", recipients).unwrap();
+		assert!(sealed.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+		let identities: Vec<BoxedIdentity> = vec![Box::new(identity)];
+		let unsealed = unseal(&sealed, &identities).unwrap();
+		assert_eq!(unsealed, b"{ version = \"0.1.0\"; }");
+	}
+
+	#[test]
+	fn wrong_identity() {
+		let identity = age::x25519::Identity::generate();
+		let recipients: Vec<Box<dyn Recipient + Send>> = vec![Box::new(identity.to_public())];
+		let sealed = seal(b"data", recipients).unwrap();
+		let other: Vec<BoxedIdentity> = vec![Box::new(age::x25519::Identity::generate())];
+		assert!(unseal(&sealed, &other).is_err());
+	}
+}
@@ -0,0 +1,202 @@
+//! Closure transfer to remote hosts, selected with `hosts.<name>.ssh.transport`.
+
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+	command::MyCommand,
+	host::{ConfigHost, EscalationStrategy, SshTarget},
+};
+
+/// Paths per remote `nix-store --check-validity` call
+const VALIDITY_CHECK_CHUNK: usize = 1000;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+	/// Pick the best supported transport, based on remote nix version
+	#[default]
+	Auto,
+	/// `nix copy` over nix-daemon protocol
+	SshNg,
+	/// `nix copy` over legacy `nix-store --serve` protocol
+	Ssh,
+	/// `nix-store --export` stream, compressed with zstd, piped over plain ssh
+	NarStream,
+}
+
+/// Parses `major.minor` from `nix --version` output, i.e `nix (Nix) 2.18.1`
+pub fn parse_nix_version(output: &str) -> Option<(u32, u32)> {
+	let version = output.split_whitespace().last()?;
+	let mut parts = version.split(['.', 'p', 'r', '-']);
+	let major = parts.next()?.parse().ok()?;
+	let minor = parts.next()?.parse().ok()?;
+	Some((major, minor))
+}
+
+impl ConfigHost {
+	/// Replaces [`Transport::Auto`] with the detected transport, and checks that
+	/// explicitly configured transport is supported by the host.
+	pub async fn resolve_transport(&self, configured: Transport) -> Result<Transport> {
+		match configured {
+			Transport::Auto => {}
+			Transport::NarStream => {
+				if self.find_in_path("zstd").await.is_err() {
+					warn!("zstd is not available on the host, falling back to ssh transport");
+					return Ok(Transport::Ssh);
+				}
+				return Ok(Transport::NarStream);
+			}
+			Transport::SshNg => {
+				if !self.remote_nix().await?.supports_ssh_ng() {
+					warn!("host nix doesn't support ssh-ng, falling back to ssh transport");
+					return Ok(Transport::Ssh);
+				}
+				return Ok(Transport::SshNg);
+			}
+			transport => return Ok(transport),
+		}
+		// Very old hosts have no nix command, but nix-store --serve works there.
+		let transport = if self.remote_nix().await?.supports_ssh_ng() {
+			Transport::SshNg
+		} else {
+			Transport::Ssh
+		};
+		info!("detected transport: {transport:?}");
+		Ok(transport)
+	}
+
+	/// Sends closure paths, which are not yet valid on the host, as a single
+	/// zstd-compressed `nix-store --export` stream.
+	pub(crate) async fn copy_nar_stream(&self, target: &SshTarget, path: &Path) -> Result<()> {
+		let mut requisites = MyCommand::new(
+			// Not used
+			EscalationStrategy::Su,
+			"nix-store",
+		);
+		requisites.arg("--query").arg("--requisites").arg(path);
+		// Requisites are topologically sorted, dependencies go first, as --import requires.
+		let requisites = requisites
+			.query()
+			.run_string()
+			.await
+			.context("failed to query closure")?;
+		let requisites: Vec<&str> = requisites.lines().collect();
+
+		// Closures of desktop systems have tens of thousands paths, which wouldn't fit into
+		// a single remote command line.
+		let mut invalid = BTreeSet::new();
+		for chunk in requisites.chunks(VALIDITY_CHECK_CHUNK) {
+			let mut check = self.cmd("nix-store").await?;
+			check
+				.arg("--check-validity")
+				.arg("--print-invalid")
+				.args(chunk);
+			let out = check
+				.query()
+				.run_string()
+				.await
+				.context("failed to check remote store")?;
+			invalid.extend(out.lines().map(str::to_owned));
+		}
+		// Keep requisites order
+		let missing: Vec<&str> = requisites
+			.into_iter()
+			.filter(|p| invalid.contains(*p))
+			.collect();
+		if missing.is_empty() {
+			info!("closure is already present on the host");
+			return Ok(());
+		}
+		info!("streaming {} missing paths", missing.len());
+
+		let escalate = match self.escalation_strategy().await? {
+			EscalationStrategy::Sudo => "sudo ",
+			EscalationStrategy::Run0 => "run0 ",
+			// Expecting to be connected as root
+			EscalationStrategy::Su => "",
+		};
+		// The stream is piped into plain ssh, connected with the same parameters as fleet.
+		// Remote login shell might not support pipefail, but truncated stream fails the import anyway.
+		let mut ssh = target.ssh_args();
+		ssh.push(target.destination());
+		let ssh = shlex::try_join(ssh.iter().map(String::as_str))
+			.context("ssh arguments can't be quoted")?;
+		let script = format!(
+			"mapfile -t paths && nix-store --export \"${{paths[@]}}\" | zstd -c -T0 -{} | ssh {ssh} 'zstd -dc | {escalate}nix-store --import > /dev/null'",
+			target.compression_level,
+		);
+		// Paths are passed on stdin, and exported in chunks, as the whole closure might not fit
+		// into the local command line either. Chunks go in order, so dependencies are always
+		// imported first.
+		for chunk in missing.chunks(VALIDITY_CHECK_CHUNK) {
+			let mut cmd = MyCommand::new(
+				// Not used
+				EscalationStrategy::Su,
+				"bash",
+			);
+			cmd.arg("-o")
+				.arg("pipefail")
+				.arg("-c")
+				.arg(&script)
+				.stdin(chunk.iter().map(|p| format!("{p}\n")).collect::<String>());
+			cmd.run().await.context("nar stream")?;
+		}
+		Ok(())
+	}
+}
+
+/// `nix copy` using either `ssh-ng://` or `ssh://` store
+pub(crate) async fn copy_nix(
+	target: &SshTarget,
+	transport: Transport,
+	path: &Path,
+	compress: bool,
+	substitute: bool,
+) -> Result<()> {
+	let scheme = match transport {
+		Transport::SshNg => "ssh-ng",
+		Transport::Ssh => "ssh",
+		_ => unreachable!("not a nix store transport"),
+	};
+	let mut nix = MyCommand::new(
+		// Not used
+		EscalationStrategy::Su,
+		"nix",
+	);
+	let ssh_args = target.ssh_args();
+	if !ssh_args.is_empty() {
+		nix.env("NIX_SSHOPTS", ssh_args.join(" "));
+	}
+	nix.arg("copy");
+	if substitute {
+		nix.arg("--substitute-on-destination");
+	}
+	nix.comparg(
+		"--to",
+		format!(
+			"{scheme}://{}{}",
+			target.destination(),
+			if compress { "?compress=true" } else { "" }
+		),
+	)
+	.arg(path);
+	nix.retry_transient().run_nix().await.context("nix copy")?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn nix_version() {
+		assert_eq!(parse_nix_version("nix (Nix) 2.18.1\n"), Some((2, 18)));
+		assert_eq!(parse_nix_version("nix-env (Nix) 2.3.16"), Some((2, 3)));
+		assert_eq!(parse_nix_version("nix (Nix) 2.25.0pre20241101_dirty"), Some((2, 25)));
+		assert_eq!(parse_nix_version("garbage"), None);
+	}
+}
@@ -7,12 +7,22 @@ use base64::engine::{general_purpose::STANDARD_NO_PAD, Engine};
 use serde::{de::Error, Deserialize, Deserializer, Serialize};
 use unicode_categories::UnicodeCategories;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(PartialEq, Clone)]
 pub struct SecretData {
 	pub data: Vec<u8>,
 	pub encrypted: bool,
 }
 
+/// Data is not printed, as it might be decrypted
+impl fmt::Debug for SecretData {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("SecretData")
+			.field("data", &format_args!("<{} bytes>", self.data.len()))
+			.field("encrypted", &self.encrypted)
+			.finish()
+	}
+}
+
 const BASE64_ENCODED_PREFIX: &str = "<BASE64-ENCODED>\n";
 const Z85_ENCODED_PREFIX: &str = "<Z85-ENCODED>\n";
 // Multiline text in Nix can only end with \n, which is not cool for actual single-line strings.
@@ -63,7 +73,7 @@ impl FromStr for SecretData {
 		} else {
 			let secret_prefix = format!("{SECRET_PREFIX}{Z85_ENCODED_PREFIX}");
 			return Err(format!(
-				"unknown secret encoding. If you're migrating from old version of fleet, prefix public secret fields with {PLAINTEXT_PREFIX:?}, and encrypted data with {secret_prefix:?}: {string}"
+				"unknown secret encoding. If you're migrating from old version of fleet, prefix public secret fields with {PLAINTEXT_PREFIX:?}, and encrypted data with {secret_prefix:?}"
 			));
 		};
 		Ok(Self { data, encrypted })
@@ -1,2 +1,3 @@
 mod encoding;
+pub mod secret_bytes;
 pub use encoding::SecretData;
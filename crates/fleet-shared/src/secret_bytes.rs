@@ -0,0 +1,191 @@
+//! Buffer for decrypted secrets and private keys.
+//!
+//! Memory is locked with mlock where possible, so it is never swapped out, and is wiped on drop,
+//! including the old allocation when the buffer grows. Debug output never contains the data.
+
+use std::{
+	fmt,
+	io::{self, Read},
+	mem,
+	ptr::NonNull,
+	sync::atomic::{compiler_fence, Ordering},
+};
+
+use anyhow::{anyhow, Result};
+use nix::sys::mman::{mlock, munlock};
+
+pub struct SecretBytes {
+	data: Vec<u8>,
+	locked: bool,
+}
+
+/// Overwrites the whole allocation, not only the initialized part
+fn wipe(data: &mut Vec<u8>) {
+	let ptr = data.as_mut_ptr();
+	for i in 0..data.capacity() {
+		// SAFETY: the whole capacity is allocated, and u8 has no invalid values
+		unsafe { ptr.add(i).write_volatile(0) };
+	}
+	compiler_fence(Ordering::SeqCst);
+}
+
+fn wipe_slice(data: &mut [u8]) {
+	for byte in data.iter_mut() {
+		// SAFETY: reference is valid for writes
+		unsafe { (byte as *mut u8).write_volatile(0) };
+	}
+	compiler_fence(Ordering::SeqCst);
+}
+
+/// Locking is best-effort, it fails when RLIMIT_MEMLOCK is exhausted
+fn lock(data: &mut Vec<u8>) -> bool {
+	if data.capacity() == 0 {
+		return false;
+	}
+	let ptr = NonNull::new(data.as_mut_ptr()).expect("allocated vec is not null");
+	// SAFETY: range is the allocation of the vec
+	unsafe { mlock(ptr.cast(), data.capacity()) }.is_ok()
+}
+
+/// Locks are per page, so this might unlock a page still used by another buffer,
+/// which is acceptable, as that buffer is still wiped on drop
+fn unlock(data: &mut Vec<u8>) {
+	let ptr = NonNull::new(data.as_mut_ptr()).expect("allocated vec is not null");
+	// SAFETY: range is the allocation of the vec
+	let _ = unsafe { munlock(ptr.cast(), data.capacity()) };
+}
+
+impl SecretBytes {
+	pub fn new() -> Self {
+		Self {
+			data: Vec::new(),
+			locked: false,
+		}
+	}
+	pub fn with_capacity(capacity: usize) -> Self {
+		Vec::with_capacity(capacity).into()
+	}
+	/// Reads the reader to the end, without leaving copies of the data in intermediate buffers
+	pub fn read_from(mut reader: impl Read) -> io::Result<Self> {
+		let mut out = Self::new();
+		let mut chunk = [0u8; 4096];
+		let result = loop {
+			match reader.read(&mut chunk) {
+				Ok(0) => break Ok(()),
+				Ok(n) => out.extend_from_slice(&chunk[..n]),
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+				Err(e) => break Err(e),
+			}
+		};
+		wipe_slice(&mut chunk);
+		result.map(|()| out)
+	}
+
+	pub fn expose(&self) -> &[u8] {
+		&self.data
+	}
+	/// Error never contains the data
+	pub fn expose_str(&self) -> Result<&str> {
+		std::str::from_utf8(&self.data).map_err(|_| anyhow!("secret is not valid utf-8"))
+	}
+	pub fn len(&self) -> usize {
+		self.data.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.data.is_empty()
+	}
+
+	pub fn extend_from_slice(&mut self, data: &[u8]) {
+		let needed = self.data.len() + data.len();
+		if needed > self.data.capacity() {
+			// Grown manually, as Vec would leave the old allocation as is
+			let mut grown = Self::with_capacity(needed.max(self.data.capacity() * 2).max(64));
+			grown.data.extend_from_slice(&self.data);
+			mem::swap(self, &mut grown);
+		}
+		self.data.extend_from_slice(data);
+	}
+
+	/// Buffer for APIs, which only accept plain vecs, it is no longer locked nor wiped on drop
+	pub fn into_unprotected(mut self) -> Vec<u8> {
+		if mem::take(&mut self.locked) {
+			unlock(&mut self.data);
+		}
+		mem::take(&mut self.data)
+	}
+}
+
+impl Default for SecretBytes {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Vec allocation is reused as is
+impl From<Vec<u8>> for SecretBytes {
+	fn from(mut data: Vec<u8>) -> Self {
+		let locked = lock(&mut data);
+		Self { data, locked }
+	}
+}
+impl From<String> for SecretBytes {
+	fn from(data: String) -> Self {
+		data.into_bytes().into()
+	}
+}
+
+impl Clone for SecretBytes {
+	fn clone(&self) -> Self {
+		let mut out = Self::with_capacity(self.len());
+		out.extend_from_slice(&self.data);
+		out
+	}
+}
+
+impl fmt::Debug for SecretBytes {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "SecretBytes(<redacted, {} bytes>)", self.len())
+	}
+}
+
+impl io::Write for SecretBytes {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.extend_from_slice(buf);
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Drop for SecretBytes {
+	fn drop(&mut self) {
+		wipe(&mut self.data);
+		if self.locked {
+			unlock(&mut self.data);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn buffer() {
+		let mut secret = SecretBytes::from(b"hunter2".to_vec());
+		assert_eq!(format!("{secret:?}"), "SecretBytes(<redacted, 7 bytes>)");
+		let long = vec![b'x'; 1000];
+		secret.extend_from_slice(&long);
+		assert_eq!(secret.len(), 1007);
+		assert!(secret.expose().starts_with(b"hunter2x"));
+		assert_eq!(secret.clone().expose(), secret.expose());
+
+		let read = SecretBytes::read_from(&[0xff, 0xfe][..]).unwrap();
+		assert_eq!(read.expose(), [0xff, 0xfe]);
+		assert_eq!(
+			read.expose_str().unwrap_err().to_string(),
+			"secret is not valid utf-8"
+		);
+	}
+}
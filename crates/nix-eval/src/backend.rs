@@ -0,0 +1,77 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use better_command::NixHandler;
+use serde::de::DeserializeOwned;
+
+#[cfg(feature = "nix-c-api")]
+use crate::capi::CapiSession;
+use crate::{session::NixSessionInner, Result};
+
+/// Evaluation session, either `nix repl` subprocess, or in-process evaluator.
+pub enum NixBackend {
+	Repl(NixSessionInner),
+	#[cfg(feature = "nix-c-api")]
+	Capi(CapiSession),
+}
+
+impl NixBackend {
+	pub(crate) async fn execute_assign(&mut self, expr: impl AsRef<str>) -> Result<u32> {
+		match self {
+			Self::Repl(s) => s.execute_assign(expr).await,
+			#[cfg(feature = "nix-c-api")]
+			Self::Capi(s) => s.execute_assign(expr).await,
+		}
+	}
+	pub(crate) async fn execute_expression_to_json<V: DeserializeOwned>(
+		&mut self,
+		expr: impl AsRef<str>,
+	) -> Result<V> {
+		match self {
+			Self::Repl(s) => s.execute_expression_to_json(expr.as_ref()).await,
+			#[cfg(feature = "nix-c-api")]
+			Self::Capi(s) => s.execute_expression_to_json(expr).await,
+		}
+	}
+	pub(crate) async fn execute_expression_number(&mut self, expr: &str) -> Result<u64> {
+		match self {
+			Self::Repl(s) => s.execute_expression_number(expr).await,
+			#[cfg(feature = "nix-c-api")]
+			Self::Capi(s) => s.execute_expression_to_json(expr).await,
+		}
+	}
+	/// Builds session field, outer error is the evaluation error, inner is the build failure.
+	pub(crate) async fn build(
+		&mut self,
+		id: u32,
+	) -> Result<Result<HashMap<String, PathBuf>, String>> {
+		let s = match self {
+			Self::Repl(s) => s,
+			#[cfg(feature = "nix-c-api")]
+			Self::Capi(s) => return s.build(id).await,
+		};
+		let out = s
+			.execute_expression_raw(format!(":b sess_field_{id}"), &mut NixHandler::default())
+			.await?;
+		if out.is_empty() {
+			return Ok(Err("build produced no output".to_owned()));
+		}
+		let Some(out) = out.strip_prefix("This derivation produced the following outputs:\n")
+		else {
+			return Ok(Err(format!("failed to parse output: {out}")));
+		};
+		Ok(Ok(out
+			.split('\n')
+			.filter(|v| !v.is_empty())
+			.map(|v| v.split_once(" -> ").expect("unexpected build output"))
+			.map(|(a, b)| (a.trim_start().to_owned(), PathBuf::from(b)))
+			.collect()))
+	}
+	/// Field id may be reused, value will be freed on reassignment
+	pub(crate) fn free(&mut self, id: u32) {
+		match self {
+			Self::Repl(s) => s.free_list.push(id),
+			#[cfg(feature = "nix-c-api")]
+			Self::Capi(s) => s.free_list.push(id),
+		}
+	}
+}
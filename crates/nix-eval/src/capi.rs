@@ -0,0 +1,619 @@
+//! In-process evaluation backend, using Nix C API (`nix-expr-c`, `nix-store-c`).
+//!
+//! Every session owns its own `EvalState`, which lives on a dedicated thread registered in bdw-gc,
+//! as neither the state nor the gc may be touched by tokio workers.
+//!
+//! Session fields (`sess_field_<id>`) can't be defined in the evaluator scope as in the repl,
+//! instead every expression is wrapped into a function, receiving flake outputs and used fields.
+//! Expressions are applied lazily, so errors are reported at the same place as with the repl backend.
+
+use std::{
+	collections::{BTreeSet, HashMap},
+	ffi::{c_char, c_uint, c_void, CStr, CString, OsStr, OsString},
+	path::PathBuf,
+	ptr, slice,
+	sync::{mpsc, LazyLock, OnceLock},
+	thread,
+};
+
+use itertools::Itertools as _;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+use crate::{Error, Result};
+
+#[allow(non_camel_case_types)]
+mod sys {
+	use std::ffi::{c_char, c_int, c_uint, c_void};
+
+	#[repr(C)]
+	pub struct nix_c_context {
+		_private: [u8; 0],
+	}
+	#[repr(C)]
+	pub struct Store {
+		_private: [u8; 0],
+	}
+	#[repr(C)]
+	pub struct StorePath {
+		_private: [u8; 0],
+	}
+	#[repr(C)]
+	pub struct EvalState {
+		_private: [u8; 0],
+	}
+	#[repr(C)]
+	pub struct nix_value {
+		_private: [u8; 0],
+	}
+	#[repr(C)]
+	pub struct BindingsBuilder {
+		_private: [u8; 0],
+	}
+	#[repr(C)]
+	pub struct GC_stack_base {
+		pub mem_base: *mut c_void,
+	}
+
+	pub type nix_err = c_int;
+	pub const NIX_OK: nix_err = 0;
+
+	pub type nix_get_string_callback =
+		unsafe extern "C" fn(start: *const c_char, n: c_uint, user_data: *mut c_void);
+	pub type nix_realise_callback =
+		unsafe extern "C" fn(user_data: *mut c_void, outname: *const c_char, out: *const c_char);
+
+	// Linked by build.rs
+	extern "C" {
+		pub fn nix_c_context_create() -> *mut nix_c_context;
+		pub fn nix_c_context_free(context: *mut nix_c_context);
+		pub fn nix_err_code(context: *const nix_c_context) -> nix_err;
+		pub fn nix_err_msg(
+			context: *mut nix_c_context,
+			read_context: *const nix_c_context,
+			n: *mut c_uint,
+		) -> *const c_char;
+		pub fn nix_libutil_init(context: *mut nix_c_context) -> nix_err;
+		pub fn nix_libstore_init(context: *mut nix_c_context) -> nix_err;
+		pub fn nix_libexpr_init(context: *mut nix_c_context) -> nix_err;
+		pub fn nix_setting_set(
+			context: *mut nix_c_context,
+			key: *const c_char,
+			value: *const c_char,
+		) -> nix_err;
+
+		pub fn nix_store_open(
+			context: *mut nix_c_context,
+			uri: *const c_char,
+			params: *mut *mut *const c_char,
+		) -> *mut Store;
+		pub fn nix_store_free(store: *mut Store);
+		pub fn nix_store_parse_path(
+			context: *mut nix_c_context,
+			store: *mut Store,
+			path: *const c_char,
+		) -> *mut StorePath;
+		pub fn nix_store_path_free(path: *mut StorePath);
+		pub fn nix_store_realise(
+			context: *mut nix_c_context,
+			store: *mut Store,
+			path: *mut StorePath,
+			user_data: *mut c_void,
+			callback: Option<nix_realise_callback>,
+		) -> nix_err;
+
+		pub fn nix_state_create(
+			context: *mut nix_c_context,
+			lookup_path: *mut *const c_char,
+			store: *mut Store,
+		) -> *mut EvalState;
+		pub fn nix_state_free(state: *mut EvalState);
+		pub fn nix_expr_eval_from_string(
+			context: *mut nix_c_context,
+			state: *mut EvalState,
+			expr: *const c_char,
+			path: *const c_char,
+			value: *mut nix_value,
+		) -> nix_err;
+		pub fn nix_value_call(
+			context: *mut nix_c_context,
+			state: *mut EvalState,
+			f: *mut nix_value,
+			arg: *mut nix_value,
+			value: *mut nix_value,
+		) -> nix_err;
+		pub fn nix_value_force(
+			context: *mut nix_c_context,
+			state: *mut EvalState,
+			value: *mut nix_value,
+		) -> nix_err;
+		pub fn nix_init_apply(
+			context: *mut nix_c_context,
+			value: *mut nix_value,
+			f: *mut nix_value,
+			arg: *mut nix_value,
+		) -> nix_err;
+		pub fn nix_alloc_value(
+			context: *mut nix_c_context,
+			state: *mut EvalState,
+		) -> *mut nix_value;
+		pub fn nix_gc_decref(context: *mut nix_c_context, object: *const c_void) -> nix_err;
+		pub fn nix_get_string(
+			context: *mut nix_c_context,
+			value: *const nix_value,
+			callback: Option<nix_get_string_callback>,
+			user_data: *mut c_void,
+		) -> nix_err;
+		pub fn nix_make_bindings_builder(
+			context: *mut nix_c_context,
+			state: *mut EvalState,
+			capacity: usize,
+		) -> *mut BindingsBuilder;
+		pub fn nix_bindings_builder_insert(
+			context: *mut nix_c_context,
+			builder: *mut BindingsBuilder,
+			name: *const c_char,
+			value: *mut nix_value,
+		) -> nix_err;
+		pub fn nix_bindings_builder_free(builder: *mut BindingsBuilder);
+		pub fn nix_make_attrs(
+			context: *mut nix_c_context,
+			value: *mut nix_value,
+			builder: *mut BindingsBuilder,
+		) -> nix_err;
+
+		pub fn GC_get_stack_base(base: *mut GC_stack_base) -> c_int;
+		pub fn GC_register_my_thread(base: *const GC_stack_base) -> c_int;
+		pub fn GC_unregister_my_thread() -> c_int;
+		pub fn GC_allow_register_threads();
+	}
+}
+
+/// Nix arguments, passed to the repl, converted to settings.
+///
+/// Returns `None` if there are arguments, which can only be handled by the nix cli (i.e `--override-input`),
+/// in which case the repl backend should be used.
+pub(crate) fn settings_from_args(nix_args: &[OsString]) -> Option<Vec<(String, String)>> {
+	let mut settings = Vec::new();
+	let mut args = nix_args.iter().map(|a| a.to_str());
+	while let Some(arg) = args.next() {
+		match arg? {
+			"--show-trace" => settings.push(("show-trace".to_owned(), "true".to_owned())),
+			"--option" => {
+				let key = args.next()??;
+				let value = args.next()??;
+				settings.push((key.to_owned(), value.to_owned()));
+			}
+			_ => return None,
+		}
+	}
+	Some(settings)
+}
+
+fn nix_string(value: &str) -> Result<CString> {
+	CString::new(value).map_err(|_| Error::NixError("string contains nul byte".to_owned()))
+}
+
+unsafe extern "C" fn collect_string(start: *const c_char, n: c_uint, user_data: *mut c_void) {
+	let out = unsafe { &mut *user_data.cast::<String>() };
+	let data = unsafe { slice::from_raw_parts(start.cast::<u8>(), n as usize) };
+	out.push_str(&String::from_utf8_lossy(data));
+}
+
+unsafe extern "C" fn collect_output(
+	user_data: *mut c_void,
+	outname: *const c_char,
+	out: *const c_char,
+) {
+	let outputs = unsafe { &mut *user_data.cast::<HashMap<String, PathBuf>>() };
+	let (outname, out) = unsafe { (CStr::from_ptr(outname), CStr::from_ptr(out)) };
+	outputs.insert(
+		outname.to_string_lossy().into_owned(),
+		PathBuf::from(out.to_string_lossy().into_owned()),
+	);
+}
+
+static SESS_FIELD: LazyLock<Regex> =
+	LazyLock::new(|| Regex::new(r"\bsess_field_(\d+)\b").expect("valid regex"));
+
+/// Owned by the evaluator thread
+struct Evaluator {
+	context: *mut sys::nix_c_context,
+	store: *mut sys::Store,
+	state: *mut sys::EvalState,
+	directory: CString,
+	/// Flake outputs, top-level bindings of the session
+	root: *mut sys::nix_value,
+	fields: HashMap<u32, *mut sys::nix_value>,
+}
+
+impl Evaluator {
+	fn new(flake: &OsStr, settings: &[(String, String)]) -> Result<Self> {
+		static INIT: OnceLock<Result<(), String>> = OnceLock::new();
+		let directory = nix_string(
+			flake
+				.to_str()
+				.ok_or(Error::SessionInit("non-utf8 flake path"))?,
+		)?;
+		let context = unsafe { sys::nix_c_context_create() };
+		if context.is_null() {
+			return Err(Error::SessionInit("failed to allocate nix context"));
+		}
+		let mut evaluator = Self {
+			context,
+			store: ptr::null_mut(),
+			state: ptr::null_mut(),
+			directory,
+			root: ptr::null_mut(),
+			fields: HashMap::new(),
+		};
+		INIT.get_or_init(|| {
+			(|| {
+				evaluator.check(unsafe { sys::nix_libutil_init(context) })?;
+				evaluator.check(unsafe { sys::nix_libstore_init(context) })?;
+				evaluator.check(unsafe { sys::nix_libexpr_init(context) })?;
+				// libexpr_init has initialized gc on this thread
+				unsafe { sys::GC_allow_register_threads() };
+				Ok(())
+			})()
+			.map_err(|e: Error| e.to_string())
+		})
+		.clone()
+		.map_err(Error::NixError)?;
+		let mut settings = settings.to_vec();
+		settings.push((
+			"extra-experimental-features".to_owned(),
+			"flakes".to_owned(),
+		));
+		for (key, value) in settings {
+			let (ckey, cvalue) = (nix_string(&key)?, nix_string(&value)?);
+			if let Err(e) = evaluator
+				.check(unsafe { sys::nix_setting_set(context, ckey.as_ptr(), cvalue.as_ptr()) })
+			{
+				warn!("failed to apply nix setting {key}: {e}");
+			}
+		}
+
+		evaluator.store = unsafe { sys::nix_store_open(context, ptr::null(), ptr::null_mut()) };
+		evaluator.check_last()?;
+		evaluator.state =
+			unsafe { sys::nix_state_create(context, ptr::null_mut(), evaluator.store) };
+		evaluator.check_last()?;
+
+		// Same bindings, as in `nix repl <flake>`
+		let root = evaluator.alloc()?;
+		let expr = nix_string(&format!(
+			"builtins.getFlake {}",
+			nixlike::escape_string(&evaluator.directory.to_string_lossy())
+		))?;
+		evaluator.root = root;
+		evaluator.check(unsafe {
+			sys::nix_expr_eval_from_string(
+				context,
+				evaluator.state,
+				expr.as_ptr(),
+				evaluator.directory.as_ptr(),
+				root,
+			)
+		})?;
+		Ok(evaluator)
+	}
+
+	fn last_error(&self) -> String {
+		let mut n = 0;
+		let msg = unsafe { sys::nix_err_msg(ptr::null_mut(), self.context, &mut n) };
+		if msg.is_null() {
+			return "unknown nix error".to_owned();
+		}
+		let msg = unsafe { slice::from_raw_parts(msg.cast::<u8>(), n as usize) };
+		String::from_utf8_lossy(msg).into_owned()
+	}
+	fn check(&self, err: sys::nix_err) -> Result<()> {
+		if err == sys::NIX_OK {
+			return Ok(());
+		}
+		Err(Error::NixError(self.last_error()))
+	}
+	/// For functions, reporting errors only through the context
+	fn check_last(&self) -> Result<()> {
+		self.check(unsafe { sys::nix_err_code(self.context) })
+	}
+
+	fn alloc(&self) -> Result<*mut sys::nix_value> {
+		let value = unsafe { sys::nix_alloc_value(self.context, self.state) };
+		if value.is_null() {
+			return Err(Error::NixError(self.last_error()));
+		}
+		Ok(value)
+	}
+	fn decref(&self, value: *mut sys::nix_value) {
+		unsafe { sys::nix_gc_decref(self.context, value.cast_const().cast()) };
+	}
+
+	/// Creates a thunk of `expr`, with session fields and flake outputs in scope
+	fn thunk(&self, expr: &str) -> Result<*mut sys::nix_value> {
+		debug!("{expr}");
+		let used: BTreeSet<u32> = SESS_FIELD
+			.captures_iter(expr)
+			.map(|c| c[1].parse().expect("matched by regex"))
+			.collect();
+		// Lambda arguments take precedence over `with`, flake outputs never shadow fields
+		let wrapped = nix_string(&format!(
+			"__fleet_root: {{ {} }}: with __fleet_root; ({expr})",
+			used.iter().map(|id| format!("sess_field_{id}")).join(", ")
+		))?;
+
+		let mut temporary = vec![];
+		let result = (|| {
+			let function = self.alloc()?;
+			temporary.push(function);
+			self.check(unsafe {
+				sys::nix_expr_eval_from_string(
+					self.context,
+					self.state,
+					wrapped.as_ptr(),
+					self.directory.as_ptr(),
+					function,
+				)
+			})?;
+			let scoped = self.alloc()?;
+			temporary.push(scoped);
+			self.check(unsafe {
+				sys::nix_value_call(self.context, self.state, function, self.root, scoped)
+			})?;
+
+			let fields = self.alloc()?;
+			temporary.push(fields);
+			let builder =
+				unsafe { sys::nix_make_bindings_builder(self.context, self.state, used.len()) };
+			self.check_last()?;
+			let built = (|| {
+				for id in &used {
+					let Some(&field) = self.fields.get(id) else {
+						return Err(Error::NixError(format!(
+							"undefined variable 'sess_field_{id}'"
+						)));
+					};
+					let name = nix_string(&format!("sess_field_{id}"))?;
+					self.check(unsafe {
+						sys::nix_bindings_builder_insert(
+							self.context,
+							builder,
+							name.as_ptr(),
+							field,
+						)
+					})?;
+				}
+				self.check(unsafe { sys::nix_make_attrs(self.context, fields, builder) })
+			})();
+			unsafe { sys::nix_bindings_builder_free(builder) };
+			built?;
+
+			let value = self.alloc()?;
+			if let Err(e) =
+				self.check(unsafe { sys::nix_init_apply(self.context, value, scoped, fields) })
+			{
+				self.decref(value);
+				return Err(e);
+			}
+			Ok(value)
+		})();
+		for value in temporary {
+			self.decref(value);
+		}
+		result
+	}
+
+	fn assign(&mut self, id: u32, expr: &str) -> Result<()> {
+		let value = self.thunk(expr)?;
+		if let Some(old) = self.fields.insert(id, value) {
+			self.decref(old);
+		}
+		Ok(())
+	}
+	fn string(&self, expr: &str) -> Result<String> {
+		let value = self.thunk(expr)?;
+		let result = (|| {
+			self.check(unsafe { sys::nix_value_force(self.context, self.state, value) })?;
+			let mut out = String::new();
+			self.check(unsafe {
+				sys::nix_get_string(
+					self.context,
+					value,
+					Some(collect_string),
+					ptr::from_mut(&mut out).cast(),
+				)
+			})?;
+			Ok(out)
+		})();
+		self.decref(value);
+		result
+	}
+	fn build(&self, id: u32) -> Result<Result<HashMap<String, PathBuf>, String>> {
+		let drv_path = self.string(&format!("sess_field_{id}.drvPath"))?;
+		let drv_path = nix_string(&drv_path)?;
+		let path =
+			unsafe { sys::nix_store_parse_path(self.context, self.store, drv_path.as_ptr()) };
+		self.check_last()?;
+		let mut outputs = HashMap::new();
+		let result = self.check(unsafe {
+			sys::nix_store_realise(
+				self.context,
+				self.store,
+				path,
+				ptr::from_mut(&mut outputs).cast(),
+				Some(collect_output),
+			)
+		});
+		unsafe { sys::nix_store_path_free(path) };
+		Ok(match result {
+			Ok(()) if outputs.is_empty() => Err("build produced no output".to_owned()),
+			Ok(()) => Ok(outputs),
+			Err(e) => Err(e.to_string()),
+		})
+	}
+}
+
+impl Drop for Evaluator {
+	fn drop(&mut self) {
+		for (_, value) in self.fields.drain() {
+			unsafe { sys::nix_gc_decref(self.context, value.cast_const().cast()) };
+		}
+		unsafe {
+			if !self.root.is_null() {
+				sys::nix_gc_decref(self.context, self.root.cast_const().cast());
+			}
+			if !self.state.is_null() {
+				sys::nix_state_free(self.state);
+			}
+			if !self.store.is_null() {
+				sys::nix_store_free(self.store);
+			}
+			sys::nix_c_context_free(self.context);
+		}
+	}
+}
+
+enum Request {
+	Assign {
+		id: u32,
+		expr: String,
+		reply: oneshot::Sender<Result<()>>,
+	},
+	String {
+		expr: String,
+		reply: oneshot::Sender<Result<String>>,
+	},
+	Build {
+		id: u32,
+		reply: oneshot::Sender<Result<Result<HashMap<String, PathBuf>, String>>>,
+	},
+}
+
+fn evaluator_thread(
+	flake: OsString,
+	settings: Vec<(String, String)>,
+	init: mpsc::Sender<Result<()>>,
+	requests: mpsc::Receiver<Request>,
+) {
+	let mut base = sys::GC_stack_base {
+		mem_base: ptr::null_mut(),
+	};
+	// Fails with GC_DUPLICATE for the thread, which has initialized gc, it is fine
+	unsafe {
+		sys::GC_get_stack_base(&mut base);
+		sys::GC_register_my_thread(&base);
+	}
+	match Evaluator::new(&flake, &settings) {
+		Ok(mut evaluator) => {
+			let _ = init.send(Ok(()));
+			for request in requests {
+				match request {
+					Request::Assign { id, expr, reply } => {
+						let _ = reply.send(evaluator.assign(id, &expr));
+					}
+					Request::String { expr, reply } => {
+						let _ = reply.send(evaluator.string(&expr));
+					}
+					Request::Build { id, reply } => {
+						let _ = reply.send(evaluator.build(id));
+					}
+				}
+			}
+		}
+		Err(e) => {
+			let _ = init.send(Err(e));
+		}
+	}
+	unsafe { sys::GC_unregister_my_thread() };
+}
+
+pub struct CapiSession {
+	requests: mpsc::Sender<Request>,
+	next_id: u32,
+	pub(crate) free_list: Vec<u32>,
+}
+
+impl CapiSession {
+	pub(crate) fn new(flake: &OsStr, settings: Vec<(String, String)>) -> Result<Self> {
+		let (init_tx, init_rx) = mpsc::channel();
+		let (requests, requests_rx) = mpsc::channel();
+		let flake = flake.to_owned();
+		thread::Builder::new()
+			.name("nix-eval".to_owned())
+			.spawn(move || evaluator_thread(flake, settings, init_tx, requests_rx))?;
+		init_rx.recv().map_err(|_| Error::Terminated)??;
+		Ok(Self {
+			requests,
+			next_id: 0,
+			free_list: vec![],
+		})
+	}
+
+	async fn request<T>(&self, request: impl FnOnce(oneshot::Sender<T>) -> Request) -> Result<T> {
+		let (reply, response) = oneshot::channel();
+		self.requests
+			.send(request(reply))
+			.map_err(|_| Error::Terminated)?;
+		response.await.map_err(|_| Error::Terminated)
+	}
+
+	pub(crate) async fn execute_assign(&mut self, expr: impl AsRef<str>) -> Result<u32> {
+		let id = self.allocate_id();
+		let expr = expr.as_ref().to_owned();
+		self.request(|reply| Request::Assign { id, expr, reply })
+			.await??;
+		Ok(id)
+	}
+	pub(crate) async fn execute_expression_to_json<V: DeserializeOwned>(
+		&mut self,
+		expr: impl AsRef<str>,
+	) -> Result<V> {
+		let expr = format!("builtins.toJSON ({})", expr.as_ref());
+		let json = self
+			.request(|reply| Request::String { expr, reply })
+			.await??;
+		Ok(serde_json::from_str(&json)?)
+	}
+	pub(crate) async fn build(
+		&mut self,
+		id: u32,
+	) -> Result<Result<HashMap<String, PathBuf>, String>> {
+		self.request(|reply| Request::Build { id, reply }).await?
+	}
+
+	/// Id should be immediately used
+	fn allocate_id(&mut self) -> u32 {
+		if let Some(free) = self.free_list.pop() {
+			free
+		} else {
+			let v = self.next_id;
+			self.next_id += 1;
+			v
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn args_to_settings() {
+		let args = |a: &[&str]| a.iter().map(OsString::from).collect::<Vec<_>>();
+		assert_eq!(
+			settings_from_args(&args(&["--show-trace", "--option", "cores", "4"])),
+			Some(vec![
+				("show-trace".to_owned(), "true".to_owned()),
+				("cores".to_owned(), "4".to_owned()),
+			])
+		);
+		assert_eq!(
+			settings_from_args(&args(&["--override-input", "nixpkgs", "/nixpkgs"])),
+			None
+		);
+		assert_eq!(settings_from_args(&args(&["--option", "cores"])), None);
+	}
+}
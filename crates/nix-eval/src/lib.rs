@@ -2,30 +2,31 @@
 //! or with tvix (once it is able to build NixOS).
 //!
 //! Current api is awful, little effort was put into this implementation.
+//!
+//! With `nix-c-api` feature, sessions are evaluated in-process using Nix C API,
+//! `nix repl` subprocess is still used as a fallback, when the evaluator can't be initialized,
+//! or nix arguments can't be applied to it.
 
 use std::sync::Arc;
 
 pub use pool::NixSessionPool;
+pub use scheduler::EvalScheduler;
 use pool::NixSessionPoolInner;
 use r2d2::PooledConnection;
 pub use session::{Error, Result};
 pub use value::{Index, Value};
 
+mod backend;
+#[cfg(feature = "nix-c-api")]
+mod capi;
 mod pool;
+mod scheduler;
 mod session;
 mod value;
 // Contains macros helpers
 #[doc(hidden)]
 pub mod macros;
 pub mod util;
-// #[allow(non_upper_case_globals, non_camel_case_types, non_snake_case)]
-// mod nix_raw {
-// 	include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
-// }
-
-// fn init() {
-// 	nix_raw::libutil_init();
-// }
 
 #[derive(Clone)]
 pub struct NixSession(pub(crate) Arc<tokio::sync::Mutex<PooledConnection<NixSessionPoolInner>>>);
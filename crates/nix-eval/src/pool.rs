@@ -1,18 +1,39 @@
-use std::ffi::OsString;
-use std::sync::{Arc, OnceLock};
+#[cfg(feature = "nix-c-api")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+	ffi::OsString,
+	sync::{Arc, OnceLock},
+};
 
 use r2d2::Pool;
+#[cfg(feature = "nix-c-api")]
+use tracing::{debug, warn};
 
-use crate::session::NixSessionInner;
-use crate::{Error, NixSession, Result};
+#[cfg(feature = "nix-c-api")]
+use crate::capi::{self, CapiSession};
+use crate::{backend::NixBackend, session::NixSessionInner, Error, NixSession, Result};
 
 pub struct NixSessionPool(Pool<NixSessionPoolInner>);
 impl NixSessionPool {
-	pub async fn new(flake: OsString, nix_args: Vec<OsString>) -> Result<Self> {
+	pub async fn new(flake: OsString, nix_args: Vec<OsString>, max_size: u32) -> Result<Self> {
+		#[cfg(feature = "nix-c-api")]
+		let capi_settings = capi::settings_from_args(&nix_args);
+		#[cfg(feature = "nix-c-api")]
+		if capi_settings.is_none() {
+			debug!("nix arguments are not supported by in-process evaluator, using nix repl");
+		}
 		let inner = tokio::task::block_in_place(|| {
 			r2d2::Builder::<NixSessionPoolInner>::new()
 				.min_idle(Some(0))
-				.build(NixSessionPoolInner { flake, nix_args })
+				.max_size(max_size)
+				.build(NixSessionPoolInner {
+					flake,
+					nix_args,
+					#[cfg(feature = "nix-c-api")]
+					capi_failed: AtomicBool::new(false),
+					#[cfg(feature = "nix-c-api")]
+					capi_settings,
+				})
 		})?;
 		Ok(Self(inner))
 	}
@@ -25,20 +46,42 @@ impl NixSessionPool {
 pub(crate) struct NixSessionPoolInner {
 	flake: OsString,
 	nix_args: Vec<OsString>,
+	/// In-process evaluator has failed to start once, the rest of sessions are using nix repl
+	#[cfg(feature = "nix-c-api")]
+	capi_failed: AtomicBool,
+	/// Settings for in-process evaluator, `None` if `nix_args` can only be handled by nix repl
+	#[cfg(feature = "nix-c-api")]
+	capi_settings: Option<Vec<(String, String)>>,
 }
 
 impl r2d2::ManageConnection for NixSessionPoolInner {
-	type Connection = NixSessionInner;
+	type Connection = NixBackend;
 	type Error = Error;
 	fn connect(&self) -> std::result::Result<Self::Connection, Self::Error> {
+		#[cfg(feature = "nix-c-api")]
+		if let Some(settings) = self
+			.capi_settings
+			.as_ref()
+			.filter(|_| !self.capi_failed.load(Ordering::Relaxed))
+		{
+			match CapiSession::new(&self.flake, settings.clone()) {
+				Ok(session) => return Ok(NixBackend::Capi(session)),
+				Err(e) => {
+					warn!("failed to start in-process evaluator, falling back to nix repl: {e}");
+					self.capi_failed.store(true, Ordering::Relaxed);
+				}
+			}
+		}
 		let _v = TOKIO_RUNTIME
 			.get()
 			.expect("missed tokio runtime init!")
 			.enter();
-		Ok(futures::executor::block_on(NixSessionInner::new(
-			self.flake.as_os_str(),
-			self.nix_args.iter().map(OsString::as_os_str),
-		))?)
+		Ok(NixBackend::Repl(futures::executor::block_on(
+			NixSessionInner::new(
+				self.flake.as_os_str(),
+				self.nix_args.iter().map(OsString::as_os_str),
+			),
+		)?))
 	}
 
 	fn is_valid(&self, conn: &mut Self::Connection) -> std::result::Result<(), Self::Error> {
@@ -0,0 +1,66 @@
+//! Distributes evaluations between multiple nix repl sessions.
+//!
+//! Single repl session is single-threaded, so evaluation of per-host attributes
+//! in one session is serialized. Scheduler spawns up to `jobs` sessions (workers), each
+//! initialized with the shared value (i.e fleet configuration) once, and then reused for
+//! the following evaluations.
+
+use std::{future::Future, sync::Mutex};
+
+use futures::future::LocalBoxFuture;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::{Error, NixSession, NixSessionPool, Result};
+
+type InitFn<T> = dyn Fn(NixSession) -> LocalBoxFuture<'static, Result<T>> + Send + Sync;
+
+pub struct EvalScheduler<T> {
+	pool: NixSessionPool,
+	init: Box<InitFn<T>>,
+	idle: Mutex<Vec<T>>,
+	jobs: Semaphore,
+}
+
+impl<T> EvalScheduler<T> {
+	/// `initial` is the already initialized worker, which is used first,
+	/// new workers are only spawned when there are concurrent evaluations.
+	pub fn new(
+		pool: NixSessionPool,
+		jobs: usize,
+		initial: T,
+		init: impl Fn(NixSession) -> LocalBoxFuture<'static, Result<T>> + Send + Sync + 'static,
+	) -> Self {
+		assert!(jobs > 0, "at least one eval job is required");
+		Self {
+			pool,
+			init: Box::new(init),
+			idle: Mutex::new(vec![initial]),
+			jobs: Semaphore::new(jobs),
+		}
+	}
+
+	/// Runs evaluation on the idle worker, or on the new one, if there are none, and the limit
+	/// of concurrent evaluations is not reached.
+	pub async fn run<F, Fut, R, E>(&self, f: F) -> Result<R, E>
+	where
+		T: Clone,
+		F: FnOnce(T) -> Fut,
+		Fut: Future<Output = Result<R, E>>,
+		E: From<Error>,
+	{
+		let _permit = self.jobs.acquire().await.expect("semaphore is not closed");
+		let worker = self.idle.lock().unwrap().pop();
+		let worker = match worker {
+			Some(worker) => worker,
+			None => {
+				debug!("spawning new eval worker");
+				let session = self.pool.get().await?;
+				(self.init)(session).await?
+			}
+		};
+		let result = f(worker.clone()).await;
+		self.idle.lock().unwrap().push(worker);
+		result
+	}
+}
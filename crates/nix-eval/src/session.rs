@@ -20,6 +20,8 @@ pub enum Error {
 	SessionInit(&'static str),
 	#[error("unexpected end of output, nix crashed?")]
 	MissingDelimiter,
+	#[error("evaluator thread has terminated")]
+	Terminated,
 
 	#[error("expression did'nt produce any output")]
 	ExpectedOutput,
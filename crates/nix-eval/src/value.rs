@@ -1,6 +1,5 @@
 use std::{collections::HashMap, fmt, path::PathBuf, sync::Arc};
 
-use better_command::NixHandler;
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{macros::NixExprBuilder, nix_go, Error, NixSession, Result};
@@ -232,35 +231,17 @@ impl Value {
 	}
 	pub async fn build(&self) -> Result<HashMap<String, PathBuf>> {
 		let id = self.0.value.expect("can't use build on not-value");
-		let query = format!(":b sess_field_{id}");
-		let vid = self
-			.0
+		self.0
 			.session
 			.0
 			.lock()
 			.await
-			.execute_expression_raw(&query, &mut NixHandler::default())
-			.await?;
-		if vid.is_empty() {
-			return Err(Error::BuildFailed {
+			.build(id)
+			.await?
+			.map_err(|error| Error::BuildFailed {
 				attribute: self.attribute(),
-				error: "build produced no output".to_owned(),
-			});
-		}
-		let Some(vid) = vid.strip_prefix("This derivation produced the following outputs:\n")
-		else {
-			return Err(Error::BuildFailed {
-				attribute: self.attribute(),
-				error: format!("failed to parse output: {vid}"),
-			});
-		};
-		let outputs = vid
-			.split('\n')
-			.filter(|v| !v.is_empty())
-			.map(|v| v.split_once(" -> ").expect("unexpected build output"))
-			.map(|(a, b)| (a.trim_start().to_owned(), PathBuf::from(b)))
-			.collect();
-		Ok(outputs)
+				error,
+			})
 	}
 
 	fn attribute(&self) -> String {
@@ -283,7 +264,7 @@ impl Drop for ValueInner {
 	fn drop(&mut self) {
 		if let Some(id) = self.value {
 			if let Ok(mut lock) = self.session.0.try_lock() {
-				lock.free_list.push(id)
+				lock.free(id)
 			}
 			// Leaked
 		}
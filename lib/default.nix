@@ -4,7 +4,8 @@
   inherit (lib.options) mkOption mergeOneOption;
   inherit (lib.modules) mkOverride;
   inherit (lib.types) listOf submodule attrsOf mkOptionType;
-  inherit (lib.strings) optionalString hasPrefix removePrefix;
+  inherit (lib.strings) optionalString hasPrefix removePrefix escapeShellArg concatMapStringsSep replaceStrings;
+  inherit (lib.lists) head;
 in rec {
   types = {
     overlay = mkOptionType {
@@ -117,12 +118,57 @@ in rec {
         encoding = "base64";
       };
 
+    # TLS certificate issued with ACME DNS-01 challenge by lego, on the machine running fleet, so the
+    # host doesn't need to be reachable by the CA. credentialsFile is an env file with the lego DNS provider
+    # credentials (i.e CLOUDFLARE_DNS_API_TOKEN=...), relative to the fleet project.
+    # Renewed by `fleet secret check`, as the certificate expiration is recorded.
+    mkAcmeCertificate = {
+      domains,
+      email,
+      dnsProvider,
+      credentialsFile,
+      server ? "https://acme-v02.api.letsencrypt.org/directory",
+      keyType ? "ec256",
+    }: {
+      lego,
+      openssl,
+      coreutils,
+      mkSecretGenerator,
+      ...
+    }:
+      mkSecretGenerator {
+        script = ''
+          mkdir $out
+
+          if test -z "''${FLEET_PROJECT:-}"; then
+            echo "acme generator should be run on the machine with fleet project"
+            exit 1
+          fi
+          set -a
+          . "$FLEET_PROJECT/${credentialsFile}"
+          set +a
+
+          ${lego}/bin/lego --accept-tos --path ./lego \
+            --server ${escapeShellArg server} --email ${escapeShellArg email} \
+            --key-type ${escapeShellArg keyType} --dns ${escapeShellArg dnsProvider} \
+            ${concatMapStringsSep " " (d: "--domains ${escapeShellArg d}") domains} \
+            run
+
+          cert=./lego/certificates/${escapeShellArg (replaceStrings ["*"] ["_"] (head domains))}
+          cat $cert.crt | gh public -o $out/cert
+          cat $cert.key | gh private -o $out/key
+
+          not_after=$(${openssl}/bin/openssl x509 -in $cert.crt -noout -enddate | cut -d= -f2)
+          ${coreutils}/bin/date -u -d "$not_after" +"%Y-%m-%dT%H:%M:%SZ" | tr -d '\n' > $out/expires_at
+        '';
+      };
+
     # Wireguard
     # mkWireguard = {}: mkX25519 {encoding = "base64";};
     # mkWireguardPsk = {}: mkBase64Bytes {count = 32;};
   };
 
-  inherit (secrets) mkPassword mkEd25519 mkX25519 mkRsa mkBytes mkHexBytes mkBase64Bytes;
+  inherit (secrets) mkPassword mkEd25519 mkX25519 mkRsa mkBytes mkHexBytes mkBase64Bytes mkAcmeCertificate;
 
   strings = let
     plaintextPrefix = "<PLAINTEXT>";
@@ -0,0 +1,22 @@
+# Tied to build_systems.rs
+{lib, ...}: let
+  inherit (lib.options) mkOption literalExpression;
+  inherit (lib.types) attrsOf package;
+in {
+  options.buildSystems = mkOption {
+    description = ''
+      Additional per-host build outputs (`buildSystems.<attr>.<host>`), such as VM or cloud images,
+      built with `fleet build-systems package --attr <attr>`.
+      Results are linked to the working directory as `<attr>-<host>`.
+    '';
+    type = attrsOf (attrsOf package);
+    default = {};
+    example = literalExpression ''
+      {
+        vmImage = lib.mapAttrs (_: host: host.nixos.config.system.build.vm) config.hosts;
+        proxmoxImage.web1 = config.hosts.web1.nixos.config.system.build.VMA;
+      }
+    '';
+  };
+  _file = ./build-systems.nix;
+}
@@ -0,0 +1,68 @@
+# Tied to fleet-base/src/cli_defaults.rs
+{
+  config,
+  fleetLib,
+  lib,
+  ...
+}: let
+  inherit (lib.options) mkOption;
+  inherit (lib.modules) mkIf mkDefault;
+  inherit (lib.types) submodule nullOr enum str ints bool;
+  inherit (fleetLib.options) mkHostsOption;
+
+  cfg = config.cli;
+  _file = ./cli.nix;
+in {
+  options = {
+    cli = mkOption {
+      description = ''
+        Project-wide defaults of fleet command line options, so that team conventions don't
+        require wrapping fleet in scripts. Options passed on the command line take precedence.
+      '';
+      default = {};
+      type = submodule {
+        options = {
+          sshUser = mkOption {
+            description = "Default of `hosts.<name>.ssh.user`.";
+            type = nullOr str;
+            default = null;
+            example = "deploy";
+          };
+          deploy = mkOption {
+            description = "Defaults of `fleet deploy` options.";
+            default = {};
+            type = submodule {
+              options = {
+                action = mkOption {
+                  description = "Action to execute, when it is not specified on the command line.";
+                  type = nullOr (enum ["upload" "test" "boot" "switch"]);
+                  default = null;
+                };
+                rollbackTimeout = mkOption {
+                  description = "Default of `--rollback-timeout`, in systemd time span format.";
+                  type = nullOr str;
+                  default = null;
+                  example = "5min";
+                };
+                maxParallel = mkOption {
+                  description = "Default of `--max-parallel`, maximum number of hosts deployed at once.";
+                  type = nullOr ints.positive;
+                  default = null;
+                };
+                failFast = mkOption {
+                  description = "Default of `--fail-fast`, halt the deployment on the first failed host.";
+                  type = bool;
+                  default = false;
+                };
+              };
+            };
+          };
+        };
+      };
+    };
+    hosts = mkHostsOption {
+      inherit _file;
+      config.ssh.user = mkIf (cfg.sshUser != null) (mkDefault cfg.sshUser);
+    };
+  };
+}
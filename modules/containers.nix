@@ -0,0 +1,82 @@
+# Tied to container.rs
+{
+  lib,
+  config,
+  ...
+}: let
+  inherit (lib.options) mkOption literalExpression;
+  inherit (lib.types) attrsOf submodule nullOr enum str lines;
+in {
+  options.containers = mkOption {
+    description = ''
+      Container targets, deployed with `fleet container deploy`.
+      Image of the container `<name>` is `buildSystems.ociImage.<name>`, built with dockerTools
+      (`buildImage`/`buildLayeredImage` archive, or `streamLayeredImage` script).
+    '';
+    default = {};
+    type = attrsOf (submodule ({name, ...}: {
+      options = {
+        repository = mkOption {
+          description = "Registry repository image is pushed to.";
+          type = str;
+          example = "registry.example.com/team/app";
+        };
+        tag = mkOption {
+          description = "Image tag, defaults to the hash of the image store path, so every image version gets its own tag.";
+          type = nullOr str;
+          default = null;
+        };
+        pushTool = mkOption {
+          description = ''
+            Tool used to push the image, registry credentials are taken from its configuration
+            (`skopeo login`/`crane auth login`).
+          '';
+          type = enum ["skopeo" "crane"];
+          default = "skopeo";
+        };
+        rollout = mkOption {
+          description = "How the pushed image is rolled out, image is only pushed if not set.";
+          default = null;
+          type = nullOr (submodule ({config, ...}: {
+            options = {
+              host = mkOption {
+                description = "Fleet host running the container, rollout command is executed there over ssh.";
+                type = str;
+              };
+              runtime = mkOption {
+                description = "Container runtime of the host.";
+                type = enum ["docker" "podman"];
+                default = "docker";
+              };
+              command = mkOption {
+                description = ''
+                  Rollout command, executed as root, pushed image reference is passed in `IMAGE` environment variable.
+                  By default, image is pulled and `oci-containers` unit of the container is restarted.
+                '';
+                type = lines;
+                default = ''
+                  ${config.runtime} pull "$IMAGE"
+                  systemctl restart ${config.runtime}-${name}.service
+                '';
+                defaultText = literalExpression ''"<runtime> pull \"$IMAGE\"; systemctl restart <runtime>-<name>.service"'';
+              };
+            };
+          }));
+        };
+      };
+    }));
+    example = literalExpression ''
+      {
+        app = {
+          repository = "registry.example.com/app";
+          rollout.host = "docker1";
+        };
+      }
+    '';
+  };
+  config.assertions = lib.mapAttrsToList (name: _: {
+    assertion = config.buildSystems ? ociImage && config.buildSystems.ociImage ? ${name};
+    message = "container ${name} has no image, buildSystems.ociImage.${name} should be set";
+  }) config.containers;
+  _file = ./containers.nix;
+}
@@ -0,0 +1,220 @@
+# Tied to build_systems.rs
+{fleetLib, lib, ...}: let
+  inherit (lib.options) mkOption;
+  inherit (lib.types) submodule nullOr ints enum attrsOf package listOf str bool;
+  inherit (lib.attrsets) genAttrs;
+  inherit (fleetLib.options) mkHostsOption;
+
+  _file = ./deploy.nix;
+in {
+  options = {
+    failureDomains = mkOption {
+      description = ''
+        Limits of failures per failure domain (rack, availability zone), hosts declare their domain
+        in `hosts.<name>.deploy.policy.failureDomain`. Domains without declared limit are not limited.
+      '';
+      default = {};
+      type = attrsOf (submodule {
+        options.maxFailures = mkOption {
+          description = ''
+            Deployment is halted once more hosts of the domain have failed,
+            hosts which haven't started activation yet are not deployed.
+          '';
+          type = ints.unsigned;
+          example = 1;
+        };
+      });
+    };
+    hosts = mkHostsOption {
+      inherit _file;
+      options = {
+        deploy = mkOption {
+          description = "Options controlling how fleet deploys this host.";
+          default = {};
+          type = submodule {
+            options = {
+              maxClosureSize = mkOption {
+                description = ''
+                  Maximum size of the built system closure in bytes.
+                  Useful for hosts with small storage, i.e SD cards, where it is better to fail before uploading
+                  closure which won't fit.
+                '';
+                type = nullOr ints.unsigned;
+                default = null;
+                example = 4 * 1024 * 1024 * 1024;
+              };
+              closureSizeExceeded = mkOption {
+                description = "What to do when the built closure exceeds maxClosureSize.";
+                type = enum ["fail" "warn"];
+                default = "fail";
+              };
+              nixArgs = mkOption {
+                description = ''
+                  Extra arguments for nix, used when building this host, appended to the global NIX_ARGS.
+                  `--nix-arg host=...` passed to fleet is appended after these.
+                '';
+                type = listOf str;
+                default = [];
+                example = ["--option" "sandbox" "false"];
+              };
+              timeouts = mkOption {
+                description = ''
+                  Maximum duration of deployment phases, i.e "30m" or "1h30m", no timeout if null.
+                  Host is considered failed on timeout, timed out activation is rolled back.
+                  Overridden by --build-timeout, --copy-timeout, --activation-timeout and --health-check-timeout.
+                '';
+                default = {};
+                type = submodule {
+                  options = genAttrs ["build" "copy" "activation" "healthCheck"] (phase:
+                    mkOption {
+                      description = "Timeout of ${phase} phase.";
+                      type = nullOr str;
+                      default = null;
+                      example = "10m";
+                    });
+                };
+              };
+              after = mkOption {
+                description = ''
+                  Hosts which should be successfully deployed before this host, host names or tags prefixed with `@`.
+                  Dependencies on hosts which are not being deployed in the current run are considered satisfied.
+                '';
+                type = listOf str;
+                default = [];
+                example = ["db1" "@routers"];
+              };
+              policy = mkOption {
+                description = "How failures of this host are handled.";
+                default = {};
+                type = submodule {
+                  options = {
+                    activationRetries = mkOption {
+                      description = "How many times failed (or rolled back) activation is retried.";
+                      type = ints.unsigned;
+                      default = 0;
+                    };
+                    critical = mkOption {
+                      description = ''
+                        Failure of this host halts the whole deployment,
+                        hosts which haven't started activation yet are not deployed.
+                      '';
+                      type = bool;
+                      default = false;
+                    };
+                    failureDomain = mkOption {
+                      description = "Failure domain of the host, failures are limited by `failureDomains.<name>.maxFailures`.";
+                      type = nullOr str;
+                      default = null;
+                      example = "rack-a";
+                    };
+                  };
+                };
+              };
+              exclusiveGroups = mkOption {
+                description = ''
+                  Hosts sharing any of the groups are never deployed concurrently,
+                  i.e to never take both HA routers down together.
+                '';
+                type = listOf str;
+                default = [];
+                example = ["routers"];
+              };
+              seedHost = mkOption {
+                description = ''
+                  Fleet host in the same site, through which the closure is uploaded when the link to this host
+                  is slow (see `fleet probe`): closure is uploaded to the seed host once, and this host copies it from there.
+                  Root of this host should be able to connect to the seed host over ssh.
+                '';
+                type = nullOr str;
+                default = null;
+                example = "site-a-gateway";
+              };
+              profileTool = mkOption {
+                description = ''
+                  Tool managing the system profile generations, used to switch and roll back the profile.
+                  `auto` picks `nix-profile` only if the host has no nix-env.
+                '';
+                type = enum ["auto" "nix-env" "nix-profile"];
+                default = "auto";
+              };
+              labels = mkOption {
+                description = ''
+                  Failure domain labels of the host, limited with `fleet deploy --max-unavailable "1 per zone"`.
+                '';
+                type = attrsOf str;
+                default = {};
+                example = {
+                  zone = "eu-1";
+                  rack = "a";
+                };
+              };
+              hooks = mkOption {
+                description = ''
+                  Executables to run on the deployer machine around deployment phases, executed in attribute name order.
+
+                  Deployment context is passed as FLEET_HOST, FLEET_PHASE, FLEET_DEPLOYMENT_ID, FLEET_ACTION, FLEET_TOPLEVEL,
+                  FLEET_PREVIOUS_GENERATION and FLEET_OUTCOME environment variables, and as a single json object in
+                  FLEET_HOOK_CONTEXT. Additional variables and secrets are passed to hooks with `hookEnv`.
+
+                  Failure of postBuild, postUpload or preActivate hook aborts the host deployment.
+                '';
+                default = {};
+                type = submodule {
+                  options = genAttrs ["postBuild" "postUpload" "preActivate" "postActivate"] (phase:
+                    mkOption {
+                      description = "Hooks to run at ${phase} phase.";
+                      type = attrsOf package;
+                      default = {};
+                    });
+                };
+              };
+              hookEnv = mkOption {
+                description = ''
+                  Additional environment variables for hooks, only passed to hooks listed in their `hooks` attribute.
+
+                  Value is either set directly, i.e from the host config, or taken from the part of a secret of this host,
+                  or of a shared secret, which is decrypted on the deployer the same way `fleet secret read` does.
+                '';
+                default = {};
+                example = {
+                  CLOUDFLARE_API_TOKEN = {
+                    shared = "cloudflare-token";
+                    hooks = ["dns-update"];
+                  };
+                };
+                type = attrsOf (submodule {
+                  options = {
+                    hooks = mkOption {
+                      description = "Names of hooks, in any phase, which receive the variable.";
+                      type = listOf str;
+                    };
+                    value = mkOption {
+                      description = "Plain value of the variable.";
+                      type = nullOr str;
+                      default = null;
+                    };
+                    secret = mkOption {
+                      description = "Secret of this host, to take the value from.";
+                      type = nullOr str;
+                      default = null;
+                    };
+                    shared = mkOption {
+                      description = "Shared secret, to take the value from.";
+                      type = nullOr str;
+                      default = null;
+                    };
+                    part = mkOption {
+                      description = "Part of the secret, to take the value from.";
+                      type = str;
+                      default = "secret";
+                    };
+                  };
+                });
+              };
+            };
+          };
+        };
+      };
+    };
+  };
+}
@@ -0,0 +1,44 @@
+# Tied to fleet-base/src/features.rs
+{lib, ...}: let
+  inherit (lib.options) mkOption;
+  inherit (lib.types) submodule bool attrsOf;
+in {
+  options.features = mkOption {
+    description = ''
+      Project-wide toggles for fleet behavior, allowing behavioral migrations to be rolled out
+      via configuration instead of synchronized CLI upgrades across the team.
+
+      Flags unknown to the used fleet version are reported, and otherwise ignored.
+    '';
+    default = {};
+    type = submodule {
+      freeformType = attrsOf bool;
+      options = {
+        requireSignatures = mkOption {
+          description = ''
+            Fail the upload if deployer was unable to sign store paths,
+            instead of relying on the target host to trust unsigned paths.
+          '';
+          type = bool;
+          default = false;
+        };
+        detectLocalHostByKey = mkOption {
+          description = ''
+            Treat the host as the deployer machine if its cached ssh host key matches the key of the machine
+            fleet is running on, and deploy it without ssh.
+          '';
+          type = bool;
+          default = true;
+        };
+        strictHostKeys = mkOption {
+          description = ''
+            Refuse to connect to hosts without trusted ssh host keys (`fleet host trust`),
+            instead of falling back to the user ssh known hosts.
+          '';
+          type = bool;
+          default = false;
+        };
+      };
+    };
+  };
+}
@@ -0,0 +1,59 @@
+# Tied to fleet-base/src/host_source.rs
+{
+  lib,
+  fleetLib,
+  config,
+  ...
+}: let
+  inherit (fleetLib.options) mkDataOption;
+  inherit (lib.options) mkOption;
+  inherit (lib.modules) mkIf;
+  inherit (lib.attrsets) mapAttrs;
+  inherit (lib.types) attrsOf deferredModule submodule str nullOr listOf;
+in {
+  options = {
+    hostTemplates = mkOption {
+      type = attrsOf deferredModule;
+      default = {};
+      description = ''
+        Host configurations, shared by dynamic hosts provided by `--host-source`,
+        i.e instances of an autoscaling group.
+      '';
+    };
+    data = mkDataOption {
+      options.dynamicHosts = mkOption {
+        type = attrsOf (submodule {
+          options = {
+            template = mkOption {
+              type = str;
+              description = "Attribute of `hostTemplates`, used as host configuration.";
+            };
+            address = mkOption {
+              type = nullOr str;
+              default = null;
+              description = "Ssh address of the host.";
+            };
+            tags = mkOption {
+              type = listOf str;
+              default = [];
+              description = "Tags, added to the tags of the template.";
+            };
+          };
+        });
+        default = {};
+        internal = true;
+        description = "Hosts provided by `--host-source`, not persisted in fleet data.";
+      };
+    };
+  };
+  config.hosts =
+    mapAttrs (name: host: {
+      imports = [
+        (config.hostTemplates.${host.template}
+          or (throw "dynamic host ${name} uses unknown template ${host.template}"))
+      ];
+      ssh.address = mkIf (host.address != null) host.address;
+      tags = host.tags;
+    })
+    config.data.dynamicHosts;
+}
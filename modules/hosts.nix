@@ -6,7 +6,7 @@
   inherit (fleetLib.modules) mkFleetGeneratorDefault;
   inherit (fleetLib.types) mkHostsType mkDataType;
   inherit (lib.options) mkOption;
-  inherit (lib.types) str listOf attrsOf submodule;
+  inherit (lib.types) str listOf attrsOf submodule enum nullOr unspecified port ints;
 in {
   options = {
     data = mkOption {
@@ -20,7 +20,13 @@ in {
             type = attrsOf (submodule {
               options.encryptionKey = mkOption {
                 type = str;
-                description = "Rage SSH encryption key for secrets.";
+                description = "Rage encryption key for secrets, either SSH host key, or TPM-sealed age key.";
+              };
+              options.attribution = mkOption {
+                type = nullOr unspecified;
+                description = "Admins, who have added and last modified this host, with their signature. Managed by fleet.";
+                default = null;
+                internal = true;
               };
             });
           };
@@ -39,6 +45,34 @@ in {
             type = str;
             example = "x86_64-linux";
           };
+          platform = mkOption {
+            description = ''
+              Platform of the host, which defines how the system is built and activated.
+              For darwin and home-manager platforms, darwinConfiguration/homeConfiguration should be set.
+            '';
+            type = enum ["nixos" "darwin" "home-manager"];
+            default = "nixos";
+          };
+          darwinConfiguration = mkOption {
+            description = "Result of nix-darwin `darwinSystem` call, used for hosts with darwin platform.";
+            type = nullOr unspecified;
+            default = null;
+          };
+          homeConfiguration = mkOption {
+            description = "Result of home-manager `homeManagerConfiguration` call, used for hosts with home-manager platform.";
+            type = nullOr unspecified;
+            default = null;
+          };
+          nixosConfiguration = mkOption {
+            description = ''
+              NixOS configuration evaluated outside of fleet, i.e `inputs.self.nixosConfigurations.<name>`.
+              When set, the host system is this configuration extended with fleet modules and `nixos` options,
+              instead of a new system evaluated with `nixpkgs.buildUsing`, so that existing configurations
+              can be migrated to fleet one host at a time.
+            '';
+            type = nullOr unspecified;
+            default = null;
+          };
           tags = mkOption {
             description = "Host tag. In CLI, you can refer to all hosts having this tag using @tag syntax.";
             type = listOf str;
@@ -60,6 +94,56 @@ in {
             };
             description = "Network definition of host";
           };
+          ssh = mkOption {
+            type = submodule {
+              options = {
+                address = mkOption {
+                  description = "Address fleet connects to, defaults to the host name, which is then resolved by ssh config.";
+                  type = str;
+                  default = config._module.args.name;
+                  defaultText = "<name>";
+                };
+                user = mkOption {
+                  description = "User to connect as.";
+                  type = nullOr str;
+                  default = null;
+                };
+                port = mkOption {
+                  description = "Ssh port.";
+                  type = nullOr port;
+                  default = null;
+                };
+                jumpHosts = mkOption {
+                  description = "Hosts to connect through, in `[user@]host[:port]` format.";
+                  type = listOf str;
+                  default = [];
+                };
+                transport = mkOption {
+                  description = ''
+                    How system closures are copied to the host.
+
+                    - `ssh-ng`: `nix copy` over `nix-daemon --stdio`, requires nix >= 2.4 on the host.
+                    - `ssh`: `nix copy` over legacy `nix-store --serve` protocol, works with ancient nix versions.
+                    - `nar-stream`: zstd-compressed `nix-store --export` stream piped over plain ssh,
+                      only missing paths are sent, useful for constrained links.
+                    - `auto`: `ssh-ng` if the host nix supports it, `ssh` otherwise.
+                  '';
+                  type = enum ["auto" "ssh-ng" "ssh" "nar-stream"];
+                  default = "auto";
+                };
+                compressionLevel = mkOption {
+                  description = ''
+                    zstd compression level for the `nar-stream` transport,
+                    higher levels are slower, but might be worth it on very slow links.
+                  '';
+                  type = ints.between 1 19;
+                  default = 3;
+                };
+              };
+            };
+            default = {};
+            description = "How fleet connects to the host, used for deployments and `fleet ssh`.";
+          };
         };
         config = {
           nixos.networking.hostName = mkFleetGeneratorDefault config._module.args.name;
@@ -1,10 +1,18 @@
 [
   ./assertions.nix
+  ./build-systems.nix
+  ./cli.nix
+  ./containers.nix
+  ./deploy.nix
+  ./features.nix
   ./fleetLib.nix
+  ./host-templates.nix
   ./hosts.nix
   ./meta.nix
+  ./nix-signing.nix
   ./nixos.nix
   ./nixpkgs.nix
+  ./power.nix
   ./secrets.nix
   ./secrets-data.nix
 ]
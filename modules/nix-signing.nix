@@ -0,0 +1,31 @@
+# Tied to build_systems.rs
+{
+  lib,
+  config,
+  ...
+}: let
+  inherit (lib.options) mkOption;
+  inherit (lib.modules) mkIf;
+  inherit (lib.types) nullOr str;
+  cfg = config.nixSigning;
+in {
+  options.nixSigning = {
+    publicKey = mkOption {
+      description = ''
+        Public part of the fleet nix signing key, generated by `fleet keys generate-signing-key`.
+
+        When set, deployed closures are signed with the fleet key instead of the deployer machine key,
+        hosts only accept paths signed by trusted keys, and signatures are verified on hosts after upload.
+      '';
+      type = nullOr str;
+      default = null;
+      example = "fleet-1:9cr2Ky2ljbSnJ6t4WmLjkKB5nWDKtRwxRXhYkNMRqSM=";
+    };
+  };
+  config.nixos = mkIf (cfg.publicKey != null) {
+    nix.settings = {
+      trusted-public-keys = [cfg.publicKey];
+      require-sigs = true;
+    };
+  };
+}
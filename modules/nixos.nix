@@ -7,7 +7,7 @@
   inherit (lib.attrsets) mapAttrs;
   inherit (lib.options) mkOption;
   inherit (lib.types) deferredModule;
-  inherit (lib.modules) mkRemovedOptionModule;
+  inherit (lib.modules) mkRemovedOptionModule mkIf mkDefault;
   inherit (fleetLib.options) mkHostsOption;
 
   _file = ./nixos.nix;
@@ -27,20 +27,30 @@ in {
             Nixos configuration for the current host.
           '';
           type = deferredModule;
-          apply = module:
-            config.nixpkgs.buildUsing.lib.nixosSystem {
-              inherit (hostArgs.config) system;
-              modules = [
-                (module // {key = "attr<host.nixos>";})
-                (config.nixos // {key = "attr<fleet.nixos>";})
-              ];
-              specialArgs = {
-                inherit fleetLib;
-              };
+          apply = module: let
+            modules = [
+              (module // {key = "attr<host.nixos>";})
+              (config.nixos // {key = "attr<fleet.nixos>";})
+            ];
+            specialArgs = {
+              inherit fleetLib;
             };
+            external = hostArgs.config.nixosConfiguration;
+          in
+            if external == null
+            then
+              config.nixpkgs.buildUsing.lib.nixosSystem {
+                inherit (hostArgs.config) system;
+                inherit modules specialArgs;
+              }
+            else external.extendModules {inherit modules specialArgs;};
         };
       };
       config = {
+        system = let
+          external = hostArgs.config.nixosConfiguration;
+        in
+          mkIf (external != null) (mkDefault external.pkgs.stdenv.hostPlatform.system);
         # imports = [
         #   (mkRemovedOptionModule ["nixosModules"] "replaced with hosts.*.nixos.imports.")
         # ];
@@ -0,0 +1,59 @@
+# Tied to builders.rs
+{
+  config,
+  lib,
+  pkgs,
+  ...
+}: let
+  inherit (lib.options) mkOption mkEnableOption;
+  inherit (lib.types) nullOr str listOf ints;
+  inherit (builtins) isInt;
+  maxJobs = config.nix.settings.max-jobs or "auto";
+in {
+  options.fleet.builder = {
+    enable = mkEnableOption ''
+      using this host as a nix remote builder for fleet builds, ran with `--builders-from fleet`.
+      Builds are performed over ssh-ng, with the ssh address and trusted host keys of the host,
+      connecting user should be trusted by the host nix daemon.
+    '';
+    systems = mkOption {
+      description = "Systems, this host can build for.";
+      type = listOf str;
+      default = [pkgs.stdenv.hostPlatform.system] ++ (config.nix.settings.extra-platforms or []);
+      defaultText = "host system and nix.settings.extra-platforms";
+    };
+    maxJobs = mkOption {
+      description = "Maximum number of builds, performed on this host at once.";
+      type = ints.positive;
+      default =
+        if isInt maxJobs
+        then maxJobs
+        else 1;
+      defaultText = "nix.settings.max-jobs, if it is set to a number, 1 otherwise";
+    };
+    speedFactor = mkOption {
+      description = "Relative speed of the host, faster builders are preferred.";
+      type = ints.positive;
+      default = 1;
+    };
+    supportedFeatures = mkOption {
+      description = "System features, i.e big-parallel or kvm, builds requiring them may run on this host.";
+      type = listOf str;
+      default = config.nix.settings.system-features or [];
+      defaultText = "nix.settings.system-features";
+    };
+    mandatoryFeatures = mkOption {
+      description = "Only builds requiring all of these features are run on this host.";
+      type = listOf str;
+      default = [];
+    };
+    sshKey = mkOption {
+      description = ''
+        Private ssh key on the deploying machine, used to connect to this host.
+        Builds are usually started by the nix daemon, so default is the root user key.
+      '';
+      type = nullOr str;
+      default = null;
+    };
+  };
+}
@@ -0,0 +1,107 @@
+# Tied to install-secrets env_files.rs
+{
+  config,
+  lib,
+  ...
+}: let
+  inherit (lib.options) mkOption;
+  inherit (lib.modules) mkIf mkMerge;
+  inherit (lib.attrsets) mapAttrs mapAttrsToList genAttrs;
+  inherit (lib.lists) concatLists;
+  inherit (lib.types) submodule attrsOf listOf nullOr str;
+  cfg = config.fleet.envFiles;
+in {
+  options.fleet.envFiles = mkOption {
+    description = ''
+      Environment files in the systemd EnvironmentFile format, rendered from host secrets when they are installed.
+      Files are added to the EnvironmentFile of the listed services, which are restarted when the file content changes.
+    '';
+    default = {};
+    example = {
+      grafana.services = ["grafana"];
+      grafana.variables.GF_SECURITY_ADMIN_PASSWORD.secret = "grafana-admin";
+    };
+    type = attrsOf (submodule ({
+      name,
+      config,
+      ...
+    }: {
+      options = {
+        services = mkOption {
+          description = "Names of systemd services, which use this environment file.";
+          type = listOf str;
+          default = [];
+        };
+        variables = mkOption {
+          description = "Variables of the file, taken either from the plain value, or from the part of a host secret.";
+          type = attrsOf (submodule {
+            options = {
+              value = mkOption {
+                description = "Plain value of the variable.";
+                type = nullOr str;
+                default = null;
+              };
+              secret = mkOption {
+                description = "Host secret to take the value from, single trailing newline of the value is removed.";
+                type = nullOr str;
+                default = null;
+              };
+              part = mkOption {
+                description = "Part of the secret to take the value from.";
+                type = str;
+                default = "secret";
+              };
+            };
+          });
+        };
+        owner = mkOption {
+          description = "Owner of the file, services read it as root, so it only needs to be changed for other readers.";
+          type = str;
+          default = "root";
+        };
+        group = mkOption {
+          description = "Group of the file.";
+          type = str;
+          default = "root";
+        };
+        mode = mkOption {
+          description = "Mode of the file.";
+          type = str;
+          default = "0400";
+        };
+        path = mkOption {
+          description = "Location of the rendered file.";
+          type = str;
+          readOnly = true;
+          default = "/run/secrets/env/${name}";
+        };
+      };
+    }));
+  };
+  config = mkIf (cfg != {}) {
+    assertions = concatLists (mapAttrsToList (name: file:
+      mapAttrsToList (var: v: {
+        assertion =
+          (v.value != null) != (v.secret != null)
+          && (v.secret == null || config.secrets ? ${v.secret} && config.secrets.${v.secret} ? ${v.part});
+        message = "fleet.envFiles.${name}.variables.${var} should have either value, or existing secret part set";
+      })
+      file.variables)
+    cfg);
+    fleet.secrets.envFiles = mapAttrs (_: file: {
+      inherit (file) path owner group mode;
+      restartUnits = map (s: "${s}.service") file.services;
+      variables = mapAttrs (_: v:
+        if v.value != null
+        then {inherit (v) value;}
+        else {path = config.secrets.${v.secret}.${v.part}.stablePath;})
+      file.variables;
+    })
+    cfg;
+    systemd.services = mkMerge (mapAttrsToList (_: file:
+      genAttrs file.services (_: {
+        serviceConfig.EnvironmentFile = [file.path];
+      }))
+    cfg);
+  };
+}
@@ -3,4 +3,8 @@
   ./secrets.nix
   ./rollback.nix
   ./nix-sign.nix
+  ./secure-boot.nix
+  ./builder.nix
+  ./env-files.nix
+  ./test-vm.nix
 ]
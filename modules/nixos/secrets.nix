@@ -8,13 +8,15 @@
   inherit (builtins) hashString;
   inherit (lib.stringsWithDeps) stringAfter;
   inherit (lib.options) mkOption;
-  inherit (lib.lists) optional;
-  inherit (lib.attrsets) mapAttrs;
+  inherit (lib.lists) optional concatLists;
+  inherit (lib.attrsets) mapAttrs mapAttrsToList filterAttrs optionalAttrs;
   inherit (lib.modules) mkIf;
-  inherit (lib.types) submodule str attrsOf nullOr unspecified lazyAttrsOf;
+  inherit (lib.strings) optionalString hasPrefix versionAtLeast;
+  inherit (lib.types) submodule str attrsOf nullOr unspecified lazyAttrsOf listOf bool;
   inherit (fleetLib.strings) decodeRawSecret;
 
   sysConfig = config;
+  cfg = config.fleet.secrets;
   secretPartType = secretName:
     submodule ({config, ...}: let
       partName = config._module.args.name;
@@ -41,6 +43,44 @@
           type = str;
           description = "Secret public data (only available for plaintext)";
         };
+        target = mkOption {
+          type = nullOr str;
+          description = ''
+            Additional path to place the decrypted part at, i.e to the location expected by some service.
+            Relative paths are placed under `fleet.secrets.targetDirectory`.
+            File is atomically replaced on secret change.
+          '';
+          default = null;
+          apply = target:
+            if target == null || hasPrefix "/" target
+            then target
+            else "${cfg.targetDirectory}/${target}";
+          example = "nginx/server.key";
+        };
+        mode = mkOption {
+          type = nullOr str;
+          description = "Part mode, overrides secret mode";
+          default = null;
+        };
+        owner = mkOption {
+          type = nullOr str;
+          description = "Owner of the part, overrides secret owner";
+          default = null;
+        };
+        group = mkOption {
+          type = nullOr str;
+          description = "Group of the part, overrides secret group";
+          default = null;
+        };
+        installerPath = mkOption {
+          type = nullOr str;
+          description = ''
+            For bootstrap secrets, path in the installer environment to place the decrypted part at,
+            before the disks are partitioned, i.e LUKS key file referenced by disko.
+          '';
+          default = null;
+          example = "/tmp/disk.key";
+        };
       };
       config = {
         hash = hashString "sha1" config.raw;
@@ -64,6 +104,15 @@
         description = "Derivation to evaluate for secret generation";
         default = null;
       };
+      bootstrap = mkOption {
+        type = bool;
+        description = ''
+          Secret is needed during the initial installation, i.e LUKS key or initrd ssh host key.
+          `fleet init-host` generates it for the new host key, places parts with `installerPath` into
+          the installer, and parts with `target` into the installed system.
+        '';
+        default = false;
+      };
       mode = mkOption {
         type = str;
         description = "Secret mode";
@@ -79,28 +128,64 @@
         description = "Group of the secret";
         default = sysConfig.users.users.${config.owner}.group;
       };
+      restartUnits = mkOption {
+        type = listOf str;
+        description = "Units to restart when the secret changes, units which aren't running are not started";
+        default = [];
+      };
+      reloadUnits = mkOption {
+        type = listOf str;
+        description = "Units to reload (or restart, if reload is not supported) when the secret changes";
+        default = [];
+      };
     };
   });
-  processPart = part: {
-    inherit (part) raw path stablePath;
-  };
+  # Secret attributes, which are not parts
+  secretOptions = [
+    "shared"
+    "generator"
+    "bootstrap"
+    "mode"
+    "group"
+    "owner"
+    "restartUnits"
+    "reloadUnits"
+  ];
+  secretParts = secret: removeAttrs secret secretOptions;
+  processPart = part:
+    {
+      inherit (part) path stablePath target mode owner group;
+    }
+    # Ephemeral secrets data is provisioned separately, see fleet.secrets.material
+    // (optionalAttrs (!cfg.ephemeral) {inherit (part) raw;});
   processSecret = secret:
     {
-      inherit (secret) group mode owner;
+      inherit (secret) group mode owner restartUnits reloadUnits;
     }
-    // (mapAttrs (_: processPart) (removeAttrs secret [
-      "shared"
-      "generator"
-      "mode"
-      "group"
-      "owner"
-    ]));
+    // (mapAttrs (_: processPart) (secretParts secret));
   secretsFile = pkgs.writeTextFile {
     name = "secrets.json";
     text =
       builtins.toJSON (mapAttrs (_: processSecret)
         config.secrets);
   };
+  envFilesFile = pkgs.writeTextFile {
+    name = "env-files.json";
+    text = builtins.toJSON cfg.envFiles;
+  };
+  hasEnvFiles = cfg.envFiles != {};
+  noswap = optionalString (versionAtLeast config.boot.kernelPackages.kernel.version "6.4") ",noswap";
+  installSecrets = ''
+    ${optionalString cfg.ephemeral ''
+      if ! ${pkgs.util-linux}/bin/mountpoint -q /run/secrets; then
+        mkdir -p /run/secrets
+        ${pkgs.util-linux}/bin/mount -t tmpfs -o mode=0751,size=${cfg.tmpfsSize}${noswap} fleet-secrets /run/secrets
+      fi
+    ''}
+    mkdir -p -m 0751 ${cfg.targetDirectory}
+    # systemd-creds unseals the host key, if it was sealed to the TPM by `fleet keys enroll-tpm`
+    PATH=${config.systemd.package}/bin:$PATH ${pkgs.fleet-install-secrets}/bin/fleet-install-secrets install ${secretsFile}${optionalString cfg.ephemeral " --material ${cfg.materialPath}"}${optionalString hasEnvFiles " --env-files ${envFilesFile}"}
+  '';
   useSysusers = (config.systemd ? sysusers && config.systemd.sysusers.enable) || (config ? userborn && config.userborn.enable);
 in {
   options = {
@@ -109,15 +194,94 @@ in {
       default = {};
       description = "Host-local secrets";
     };
+    # Tied to install-secrets and host.rs
+    fleet.secrets = {
+      ephemeral = mkOption {
+        type = bool;
+        default = false;
+        description = ''
+          Keep decrypted secrets only on the dedicated (non-swappable, if supported by the kernel) tmpfs.
+          Encrypted secret data is not embedded into the system, instead it is pushed by fleet during deployment
+          or by `fleet secret reprovision <host>`, stored in `materialPath`, and decrypted with the host key on every boot.
+        '';
+      };
+      materialPath = mkOption {
+        type = str;
+        default = "/var/lib/fleet/secrets.json";
+        description = "Where encrypted material of ephemeral secrets is stored.";
+      };
+      targetDirectory = mkOption {
+        type = str;
+        default = "/run/fleet-secrets";
+        description = "Directory for secret parts with relative `target`, created on activation.";
+      };
+      tmpfsSize = mkOption {
+        type = str;
+        default = "16M";
+        description = "Size of the ephemeral secrets tmpfs.";
+      };
+      material = mkOption {
+        type = attrsOf (attrsOf str);
+        internal = true;
+        readOnly = true;
+        description = "Encrypted data of secret parts, pushed to the host when secrets are ephemeral.";
+      };
+      bootstrap = mkOption {
+        type = attrsOf (attrsOf unspecified);
+        internal = true;
+        readOnly = true;
+        description = "Placement of bootstrap secret parts, used by `fleet init-host`.";
+      };
+      optionNames = mkOption {
+        type = listOf str;
+        internal = true;
+        readOnly = true;
+        description = "Attributes of `secrets.<name>`, which are not secret parts, used by test-vm.nix, `fleet verify` and `fleet lint`.";
+      };
+      envFiles = mkOption {
+        type = attrsOf unspecified;
+        internal = true;
+        default = {};
+        description = "Specification of environment files, rendered by fleet-install-secrets, see fleet.envFiles.";
+      };
+    };
   };
   config = {
+    fleet.secrets.optionNames = secretOptions;
+    fleet.secrets.material = mapAttrs (_: secret: mapAttrs (_: part: part.raw) (secretParts secret)) config.secrets;
+    fleet.secrets.bootstrap = mapAttrs (_: secret:
+      mapAttrs (_: part: {
+        inherit (part) target installerPath;
+        mode =
+          if part.mode != null
+          then part.mode
+          else secret.mode;
+      }) (secretParts secret))
+    (filterAttrs (_: secret: secret.bootstrap && !secret.shared) config.secrets);
+
+    assertions = mkIf cfg.ephemeral (concatLists (mapAttrsToList (name: secret:
+      mapAttrsToList (partName: part: {
+        assertion = hasPrefix "/run/" part.target;
+        message = "secret ${name} part ${partName} target ${part.target} is not on tmpfs, which is not allowed for ephemeral secrets";
+      }) (filterAttrs (_: part: part.target != null) (secretParts secret)))
+    config.secrets));
+
+    environment.etc."fleet/secrets.json" = mkIf cfg.ephemeral {
+      source = secretsFile;
+    };
+    # Env files are rendered again after the material is provisioned
+    environment.etc."fleet/env-files.json" = mkIf (cfg.ephemeral && hasEnvFiles) {
+      source = envFilesFile;
+    };
+
     environment.systemPackages = [pkgs.fleet-install-secrets];
 
     systemd.services.fleet-install-secrets = mkIf useSysusers {
       wantedBy = ["sysinit.target"];
-      after = ["systemd-sysusers.service"];
+      after = ["systemd-sysusers.service" "tpm2.target"];
       restartTriggers = [
         secretsFile
+        envFilesFile
       ];
       aliases = [
         "sops-install-secrets"
@@ -129,7 +293,7 @@ in {
       serviceConfig = {
         Type = "oneshot";
         RemainAfterExit = true;
-        ExecStart = "${pkgs.fleet-install-secrets}/bin/fleet-install-secrets install ${secretsFile}";
+        ExecStart = pkgs.writeShellScript "fleet-install-secrets" installSecrets;
       };
     };
     system.activationScripts.decryptSecrets =
@@ -148,7 +312,7 @@ in {
           ++ (optional (config.system.activationScripts ? "persist-files") "persist-files")
         ) ''
           1>&2 echo "setting up secrets"
-          ${pkgs.fleet-install-secrets}/bin/fleet-install-secrets install ${secretsFile}
+          ${installSecrets}
         ''
       );
   };
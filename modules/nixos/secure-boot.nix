@@ -0,0 +1,104 @@
+# Tied to secure_boot.rs
+{
+  config,
+  lib,
+  pkgs,
+  ...
+}: let
+  inherit (lib.options) mkOption mkEnableOption;
+  inherit (lib.modules) mkIf;
+  inherit (lib.types) nullOr str enum attrsOf;
+  inherit (lib.strings) toUpper;
+  cfg = config.fleet.secureBoot;
+  efiArch = pkgs.stdenv.hostPlatform.efiArch;
+  kernel = "${config.boot.kernelPackages.kernel}/${config.system.boot.loader.kernelFile}";
+  systemdBoot = "${config.systemd.package}/lib/systemd/boot/efi/systemd-boot${efiArch}.efi";
+  esp = config.boot.loader.efi.efiSysMountPoint;
+  # Lanzaboote builds and signs its own bootables on the host
+  lanzaboote = config.boot ? lanzaboote && config.boot.lanzaboote.enable;
+in {
+  options.fleet.secureBoot = {
+    enable = mkEnableOption ''
+      signing of bootables by fleet on deploy, after the system is built and before it is uploaded.
+      Signing keys never leave the deployer (or the signing host), signed files are uploaded along with the system,
+      and are installed over the unsigned ones on the ESP after every bootloader installation.
+    '';
+    signOn = mkOption {
+      description = "Host of the fleet to sign bootables on, which holds the signing keys. null for the deployer machine.";
+      type = nullOr str;
+      default = null;
+      example = "signer";
+    };
+    tool = mkOption {
+      description = ''
+        Signing tool, installed on the signing host.
+        sbctl uses its own key database and is ran as root, sbsign uses the key and certificate options.
+      '';
+      type = enum ["sbctl" "sbsign"];
+      default = "sbctl";
+    };
+    key = mkOption {
+      description = "Path of the db signing key on the signing host, for sbsign.";
+      type = nullOr str;
+      default = null;
+    };
+    certificate = mkOption {
+      description = "Path of the db certificate on the signing host, for sbsign.";
+      type = nullOr str;
+      default = null;
+    };
+    bootables = mkOption {
+      description = ''
+        Files to sign, ESP-relative install path => unsigned file in the store.
+        Defaults to the kernel and systemd-boot, as installed by systemd-boot-builder.
+        Nothing is signed with lanzaboote, as it signs its bootables on the host itself.
+      '';
+      type = attrsOf str;
+    };
+    rootsDir = mkOption {
+      description = "Directory of gc roots of the signed bootables, one per system.";
+      type = str;
+      default = "/nix/var/nix/gcroots/fleet-secure-boot";
+    };
+  };
+  config = mkIf cfg.enable {
+    assertions = [
+      {
+        assertion = cfg.tool != "sbsign" || (cfg.key != null && cfg.certificate != null);
+        message = "fleet.secureBoot.key and certificate should be set for sbsign";
+      }
+      {
+        assertion = config.boot.loader.systemd-boot.enable || lanzaboote;
+        message = "fleet.secureBoot only supports systemd-boot and lanzaboote";
+      }
+      {
+        assertion = !lanzaboote || cfg.bootables == {};
+        message = "fleet.secureBoot.bootables are only installed with systemd-boot, lanzaboote signs its bootables itself";
+      }
+    ];
+    fleet.secureBoot.bootables = mkIf (!lanzaboote) {
+      # Same naming, as used by systemd-boot-builder
+      "EFI/nixos/${builtins.unsafeDiscardStringContext "${baseNameOf (dirOf kernel)}-${baseNameOf kernel}"}.efi" = kernel;
+      "EFI/systemd/systemd-boot${efiArch}.efi" = systemdBoot;
+      "EFI/BOOT/BOOT${toUpper efiArch}.EFI" = systemdBoot;
+    };
+    # Signed files of every remaining generation are installed, oldest first, so files shared between generations
+    # (systemd-boot) are taken from the newest one. Roots of removed generations are dropped.
+    boot.loader.systemd-boot.extraInstallCommands = ''
+      declare -A used
+      for generation in $(ls -1v -d /nix/var/nix/profiles/system-*-link 2>/dev/null); do
+        system=$(basename "$(readlink -f "$generation")")
+        used[$system]=1
+        if [ -d "${cfg.rootsDir}/$system" ]; then
+          cp -rL --no-preserve=mode "${cfg.rootsDir}/$system/." "${esp}/"
+        fi
+      done
+      for root in "${cfg.rootsDir}"/*; do
+        [ -e "$root" ] || continue
+        if [ -z "''${used[$(basename "$root")]:-}" ]; then
+          rm -f "$root"
+        fi
+      done
+    '';
+  };
+}
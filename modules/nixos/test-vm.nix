@@ -0,0 +1,65 @@
+# Tied to test_vm.rs
+{
+  config,
+  lib,
+  pkgs,
+  ...
+}: let
+  inherit (lib.options) mkOption literalExpression;
+  inherit (lib.types) attrsOf lines;
+  inherit (lib.attrsets) mapAttrs mapAttrsToList;
+  inherit (lib.strings) hasPrefix concatStringsSep;
+  inherit (lib.modules) mkForce;
+  cfg = config.fleet;
+
+  # Host key is not available in the VM, so encrypted parts are replaced with plaintext placeholders.
+  dummyPart = secretName: partName: part:
+    if hasPrefix "<PLAINTEXT" part.raw
+    then {}
+    else {raw = mkForce "<PLAINTEXT>dummy-${secretName}-${partName}";};
+in {
+  options.fleet.healthChecks = mkOption {
+    description = ''
+      Shell scripts checking that the host works, executed as root inside the VM by `fleet test-vm`,
+      after the system has reached multi-user.target. Check fails if its script exits with non-zero code.
+    '';
+    type = attrsOf lines;
+    default = {};
+    example = literalExpression ''
+      {
+        nginx = "''${pkgs.curl}/bin/curl -sf http://localhost/";
+      }
+    '';
+  };
+  config.virtualisation.vmVariant = {
+    secrets = mapAttrs (secretName: secret: mapAttrs (dummyPart secretName) (removeAttrs secret cfg.secrets.optionNames)) config.secrets;
+
+    # Only started when booted by fleet, so that plain `nixos-rebuild build-vm` is not powered off.
+    systemd.services.fleet-test-vm = {
+      description = "Fleet VM health checks";
+      wantedBy = ["multi-user.target"];
+      after = ["multi-user.target"];
+      unitConfig.ConditionKernelCommandLine = "fleet.test-vm";
+      serviceConfig = {
+        Type = "oneshot";
+        StandardOutput = "journal+console";
+        StandardError = "journal+console";
+      };
+      script = ''
+        ${concatStringsSep "\n" (mapAttrsToList (name: script: ''
+            if ${pkgs.writeShellScript "health-check-${name}" script}; then
+              echo "fleet-test-vm: check ${name} passed"
+            else
+              echo "fleet-test-vm: check ${name} failed"
+            fi
+          '')
+          cfg.healthChecks)}
+        for unit in $(systemctl list-units --failed --plain --no-legend | cut -d' ' -f1); do
+          echo "fleet-test-vm: unit $unit failed"
+        done
+        echo "fleet-test-vm: done"
+        systemctl poweroff --no-block
+      '';
+    };
+  };
+}
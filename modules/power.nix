@@ -0,0 +1,68 @@
+# Tied to power.rs
+{fleetLib, lib, ...}: let
+  inherit (lib.options) mkOption;
+  inherit (lib.types) submodule nullOr enum str;
+  inherit (fleetLib.options) mkHostsOption;
+
+  _file = ./power.nix;
+in {
+  options = {
+    hosts = mkHostsOption {
+      inherit _file;
+      options = {
+        power = mkOption {
+          description = ''
+            Power management of the host, used by `fleet power` and `fleet deploy --wake`.
+            Without BMC, host is powered on with Wake-on-LAN, and powered off/rebooted over ssh.
+          '';
+          default = {};
+          type = submodule {
+            options = {
+              mac = mkOption {
+                description = "MAC address of the network interface with Wake-on-LAN enabled.";
+                type = nullOr str;
+                default = null;
+                example = "52:54:00:12:34:56";
+              };
+              broadcast = mkOption {
+                description = "Address to send Wake-on-LAN packet to.";
+                type = str;
+                default = "255.255.255.255";
+              };
+              bmc = mkOption {
+                description = "Baseboard management controller of the host.";
+                default = null;
+                type = nullOr (submodule {
+                  options = {
+                    protocol = mkOption {
+                      description = "Protocol used to talk to the BMC, ipmi requires ipmitool, redfish requires curl.";
+                      type = enum ["ipmi" "redfish"];
+                    };
+                    address = mkOption {
+                      description = "Address of the BMC.";
+                      type = str;
+                    };
+                    user = mkOption {
+                      description = "BMC user.";
+                      type = str;
+                    };
+                    passwordFile = mkOption {
+                      description = "Path to the file with BMC password on the deployer machine.";
+                      type = str;
+                    };
+                    redfishSystem = mkOption {
+                      description = "Redfish ComputerSystem resource of the host.";
+                      type = str;
+                      default = "/redfish/v1/Systems/1";
+                      example = "/redfish/v1/Systems/System.Embedded.1";
+                    };
+                  };
+                });
+              };
+            };
+          };
+        };
+      };
+    };
+  };
+}
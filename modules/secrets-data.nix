@@ -6,7 +6,7 @@
 }: let
   inherit (fleetLib.options) mkDataOption;
   inherit (lib.options) mkOption;
-  inherit (lib.types) nullOr listOf str attrsOf submodule bool;
+  inherit (lib.types) nullOr listOf str attrsOf submodule bool unspecified;
   inherit (lib.attrsets) mapAttrsToList mapAttrs filterAttrs genAttrs;
   inherit (lib.lists) sort unique concatLists;
   inherit (lib.strings) toJSON;
@@ -34,6 +34,12 @@
         description = "On which date this secret will expire, someone should regenerate this secret before it expires.";
         default = null;
       };
+      attribution = mkOption {
+        type = nullOr unspecified;
+        description = "Admins, who have created and last modified this secret, with their signature. Managed by fleet.";
+        default = null;
+        internal = true;
+      };
 
       owners = mkOption {
         type = listOf str;
@@ -62,6 +68,17 @@
         description = "On which date this secret will expire, someone should regenerate this secret before it expires.";
         default = null;
       };
+      bootstrappedAt = mkOption {
+        type = nullOr str;
+        description = "When this secret was delivered to the installer by `fleet init-host`";
+        default = null;
+      };
+      attribution = mkOption {
+        type = nullOr unspecified;
+        description = "Admins, who have created and last modified this secret, with their signature. Managed by fleet.";
+        default = null;
+        internal = true;
+      };
       shared = mkOption {
         type = bool;
         description = "On which date this secret will expire, someone should regenerate this secret before it expires.";
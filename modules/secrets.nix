@@ -40,10 +40,58 @@
         description = "Derivation to evaluate for secret generation";
         default = null;
       };
+      admins = mkOption {
+        type = listOf str;
+        description = ''
+          Public keys (age `age1...` or ssh) of team members allowed to modify this secret, they can also read it.
+          If empty, secret may be modified by anyone with access to fleet data.
+        '';
+        default = [];
+      };
+      readers = mkOption {
+        type = listOf str;
+        description = ''
+          Public keys (age `age1...` or ssh) of team members allowed to read this secret with `fleet secret read-shared`,
+          in addition to admins. Secret is encrypted to them along with the owner hosts.
+
+          If either admins or readers is set, secret is only decrypted locally, with the admin identity.
+        '';
+        default = [];
+      };
+      ci = mkOption {
+        type = bool;
+        description = ''
+          Secret is also encrypted to the `deployers` keys, so it can be read by CI with the deployer token.
+          Secrets without this flag can't be read with the deployer token.
+        '';
+        default = false;
+      };
     };
   };
 in {
   options = {
+    deployers = mkOption {
+      type = attrsOf str;
+      description = ''
+        Public keys of the restricted CI identities (deployer tokens), issued with `fleet keys issue-deployer`.
+
+        Deployer token can deploy hosts and read shared secrets with `ci = true`, but can't read other secrets
+        or modify any of them, so CI doesn't need the admin identity.
+      '';
+      default = {};
+      example = {github-actions = "age1...";};
+    };
+    adminKeys = mkOption {
+      type = attrsOf str;
+      description = ''
+        Ssh public keys of the fleet admins, by admin name. Fleet data entries are signed by the admin, who has
+        modified them, and entries signed by other keys are rejected on load.
+
+        If empty, signatures are only checked to match the entry, and the signing key is not validated.
+      '';
+      default = {};
+      example = {alice = "ssh-ed25519 AAAA...";};
+    };
     sharedSecrets = mkOption {
       type = attrsOf (submodule sharedSecret);
       default = {};
@@ -52,7 +100,7 @@ in {
   };
   config = {
     hosts = mapAttrs (_: secretMap: {
-      nixos.secrets = mapAttrs (_: s: removeAttrs s ["createdAt" "expiresAt"]) secretMap;
+      nixos.secrets = mapAttrs (_: s: removeAttrs s ["createdAt" "expiresAt" "bootstrappedAt" "attribution"]) secretMap;
     }) config.data.hostSecrets;
     nixpkgs.overlays = [
       (final: prev: {
@@ -65,6 +113,11 @@ in {
             # If set - script will be run on remote machine, otherwise it will be run with fleet project in CWD
             # (Some secrets-encryption-in-git/managed PKI solution is expected)
             impureOn ? null,
+            # Parts of other secrets, available to the script as files in $inputs directory, i.e
+            # { peer = { host = "peer"; secret = "wireguard"; part = "public"; }; ca = { shared = "ca"; part = "cert"; }; }
+            # Referenced host secrets are generated first if missing. Encrypted parts are decrypted by fleet,
+            # and can only be used without impureOn.
+            inputs ? {},
           }:
             (prev.writeShellScript "impureGenerator.sh" ''
               #!/bin/sh
@@ -92,7 +145,7 @@ in {
             '')
             .overrideAttrs (old: {
               passthru = {
-                inherit impureOn;
+                inherit impureOn inputs;
                 generatorKind = "impure";
               };
             });
@@ -15,7 +15,7 @@ craneLib.buildPackage rec {
   postInstall = ''
     for shell in bash fish zsh; do
       installShellCompletion --cmd fleet \
-        --$shell <($out/bin/fleet complete --shell $shell)
+        --$shell <($out/bin/fleet completions $shell)
     done
   '';
 }